@@ -0,0 +1,48 @@
+//! Time from writing a key to receiving its echo, with and without PtyBuilder::poll_after_write()
+//! run with `cargo bench --bench echo_latency`
+
+use std::error::Error;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use pty_exec::Pty;
+
+const SAMPLES: usize = 500;
+
+fn echo_latency(poll_after_write: bool) -> Result<Vec<Duration>, Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    let pty = Pty::builder()
+        .poll_after_write(poll_after_write)
+        .spawn(move |_id, res| { let _ = tx.send(res.map(|_| Instant::now()).ok()); }, |_id| {})?;
+
+    // wait for the shell to settle, startup output must not count as an echo
+    pty.write("echo ready\r")?;
+    while rx.recv_timeout(Duration::from_millis(500)).is_ok() {}
+
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for _ in 0..SAMPLES {
+        let written = Instant::now();
+        pty.write("x")?;
+        if let Ok(Some(echoed)) = rx.recv_timeout(Duration::from_secs(1)) {
+            samples.push(echoed - written);
+        }
+        // erase the character again so the line does not grow
+        pty.write("\x7f")?;
+        while rx.recv_timeout(Duration::from_millis(5)).is_ok() {}
+    }
+
+    pty.shutdown()?;
+    samples.sort();
+    Ok(samples)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    for poll_after_write in [false, true] {
+        let samples = echo_latency(poll_after_write)?;
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+        println!(
+            "poll_after_write={poll_after_write:<5} samples={} p50={:?} p90={:?} p99={:?}",
+            samples.len(), percentile(50), percentile(90), percentile(99)
+        );
+    }
+    Ok(())
+}
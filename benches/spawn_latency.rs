@@ -0,0 +1,54 @@
+//! Time to spawn a pty with each backend, in a small process and in one mapping a lot of memory,
+//! which fork has to copy the page tables of
+//! run with `cargo bench --bench spawn_latency --features vfork`
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+use nix::unistd::{getuid, User};
+use pty_exec::{Pty, ShellUser};
+use pty_exec::backend::{Fork, PosixSpawn, SpawnBackend};
+
+const SAMPLES: usize = 100;
+
+// memory touched before the second round, like a CI runner holding a lot of state
+const BALLAST: usize = 1 << 30;
+
+fn spawn_latency(backend: impl SpawnBackend + Clone + 'static) -> Result<Vec<Duration>, Box<dyn Error>> {
+    // /bin/sh exits as soon as it is told to, the user's shell may take a while to start
+    let name = User::from_uid(getuid())?.ok_or("no passwd entry")?.name;
+    let user = ShellUser { shell: "/bin/sh".into(), ..ShellUser::from_name(&name)? };
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for _ in 0..SAMPLES {
+        let started = Instant::now();
+        let pty = Pty::builder().backend(backend.clone()).user(user.clone()).spawn(|_id, _res| {}, |_id| {})?;
+        samples.push(started.elapsed());
+        // reaped before the next sample, so live shells do not pile up
+        pty.shutdown()?;
+    }
+    samples.sort();
+    Ok(samples)
+}
+
+fn report(name: &str, samples: &[Duration]) {
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+    println!("{name:<12} samples={} p50={:?} p90={:?} p99={:?}", samples.len(), percentile(50), percentile(90), percentile(99));
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    report("fork", &spawn_latency(Fork)?);
+    report("posix_spawn", &spawn_latency(PosixSpawn)?);
+    #[cfg(feature = "vfork")]
+    report("vfork", &spawn_latency(pty_exec::backend::Vfork)?);
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    println!("without ballast");
+    run()?;
+
+    let ballast = vec![1u8; BALLAST];
+    println!("with {} MiB of ballast", ballast.len() >> 20);
+    run()?;
+    drop(ballast);
+    Ok(())
+}
@@ -0,0 +1,45 @@
+#ifndef PTY_EXEC_H
+#define PTY_EXEC_H
+
+/* Generated with cbindgen from src/capi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Callbacks of a pty, called on the thread reading the pty with user_data as it was given,
+// a callback left NULL is not called, text is UTF-8 and not NUL terminated
+typedef struct PtyExecCallbacks {
+  void *user_data;
+  // output of the pty
+  void (*on_output)(void *user_data, int pty, const char *data, size_t len);
+  // the child set the window title
+  void (*on_title)(void *user_data, int pty, const char *title, size_t len);
+  // the pty died, no other callback follows
+  void (*on_exit)(void *user_data, int pty);
+} PtyExecCallbacks;
+
+// spawns a pty running the user's shell, returns its fd, callbacks may be NULL and are copied
+int pty_exec_spawn(const struct PtyExecCallbacks *callbacks);
+
+// replaces the callbacks of a pty spawned with pty_exec_spawn(), callbacks may be NULL
+int pty_exec_set_callbacks(int pty, const struct PtyExecCallbacks *callbacks);
+
+// writes len bytes of UTF-8 at data to the pty like Pty::write()
+int pty_exec_write(int pty, const char *data, size_t len);
+
+// resizes the pty like Pty::resize()
+int pty_exec_resize(int pty, uint16_t rows, uint16_t cols);
+
+// sends signal to the child's process group like Pty::signal()
+int pty_exec_signal(int pty, int signal);
+
+// shuts the pty down like Pty::shutdown(), blocks until the child is gone
+int pty_exec_shutdown(int pty);
+
+// message of the last failure on this thread, valid until the next call failing on it
+const char *pty_exec_last_error(void);
+
+#endif /* PTY_EXEC_H */
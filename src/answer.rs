@@ -0,0 +1,150 @@
+//! Replies to terminal queries for embedders that do not answer them, see PtyBuilder::answer_queries()
+//! programs like vim and fish ask the terminal about itself and wait for the reply, with nobody
+//! rendering the pty they wait until they time out, or forever, every reply is off by default
+//! and must not be turned on for queries the embedder answers itself, the child would get both
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::answer::Answers;
+//!
+//! // a headless pty driving TUI programs
+//! let pty = Pty::builder()
+//!     .answer_queries(Answers::all().xtversion(None))
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+/// A query of the child to its terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Query {
+    // CSI c
+    PrimaryDa,
+    // CSI > c
+    SecondaryDa,
+    // CSI 5 n
+    DeviceStatus,
+    // CSI 6 n
+    CursorPosition,
+    // CSI > q
+    XtVersion,
+}
+
+impl Query {
+    /**
+     * The query a CSI sequence is, from its parameters and final byte
+     */
+    pub(crate) fn from_csi(params: &str, final_byte: char) -> Option<Query> {
+        match (params, final_byte) {
+            ("" | "0", 'c') => Some(Query::PrimaryDa),
+            (">" | ">0", 'c') => Some(Query::SecondaryDa),
+            ("5", 'n') => Some(Query::DeviceStatus),
+            ("6", 'n') => Some(Query::CursorPosition),
+            (">" | ">0", 'q') => Some(Query::XtVersion),
+            _ => None
+        }
+    }
+}
+
+/// Which terminal queries are answered at the pty and with what, a typed builder
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Answers {
+    primary_da: bool,
+    secondary_da: bool,
+    device_status: bool,
+    cursor_position: Option<(u16, u16)>,
+    xtversion: Option<String>,
+}
+
+impl Answers {
+    /// nothing is answered
+    pub fn new() -> Answers {
+        Answers::default()
+    }
+
+    /// every query is answered, the cursor is reported at the top left
+    pub fn all() -> Answers {
+        Answers {
+            primary_da: true,
+            secondary_da: true,
+            device_status: true,
+            cursor_position: Some((1, 1)),
+            xtversion: Some(format!("pty-exec({})", env!("CARGO_PKG_VERSION"))),
+        }
+    }
+
+    /// Primary Device Attributes (CSI c), answered as a VT220 with ANSI colors
+    pub fn primary_da(mut self, enabled: bool) -> Answers {
+        self.primary_da = enabled;
+        self
+    }
+
+    /// Secondary Device Attributes (CSI > c), answered as a VT220 of firmware version 10
+    pub fn secondary_da(mut self, enabled: bool) -> Answers {
+        self.secondary_da = enabled;
+        self
+    }
+
+    /// Device Status Report (CSI 5 n), answered as ok
+    pub fn device_status(mut self, enabled: bool) -> Answers {
+        self.device_status = enabled;
+        self
+    }
+
+    /// Cursor Position Report (CSI 6 n), answered with this 1 based row and column, a stub
+    /// as the pty does not track the cursor, None to leave it unanswered
+    pub fn cursor_position(mut self, position: Option<(u16, u16)>) -> Answers {
+        self.cursor_position = position;
+        self
+    }
+
+    /// XTVERSION (CSI > q), answered with this name and version, None to leave it unanswered
+    pub fn xtversion(mut self, version: Option<&str>) -> Answers {
+        self.xtversion = version.map(str::to_owned);
+        self
+    }
+
+    /**
+     * The reply to query, None if it is not answered
+     */
+    pub(crate) fn reply(&self, query: Query) -> Option<String> {
+        match query {
+            Query::PrimaryDa if self.primary_da => Some("\x1b[?62;22c".into()),
+            Query::SecondaryDa if self.secondary_da => Some("\x1b[>1;10;0c".into()),
+            Query::DeviceStatus if self.device_status => Some("\x1b[0n".into()),
+            Query::CursorPosition => self.cursor_position.map(|(row, col)| format!("\x1b[{row};{col}R")),
+            Query::XtVersion => self.xtversion.as_ref().map(|version| format!("\x1bP>|{version}\x1b\\")),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::time::Duration;
+    use crate::{test_util, Pty};
+
+    #[test]
+    fn replies() {
+        let answers = Answers::new().primary_da(true).cursor_position(Some((3, 7)));
+        assert_eq!(answers.reply(Query::from_csi("", 'c').unwrap()).as_deref(), Some("\x1b[?62;22c"));
+        assert_eq!(answers.reply(Query::CursorPosition).as_deref(), Some("\x1b[3;7R"));
+        assert_eq!(answers.reply(Query::SecondaryDa), None);
+        assert_eq!(Query::from_csi("?6", 'n'), None);
+    }
+
+    #[test]
+    fn answers_cursor_position() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder()
+            .scrollback(0x10000)
+            .answer_queries(Answers::new().cursor_position(Some((4, 2))))
+            .spawn(|_id, _res| {}, |_id| {})?;
+        // read the reply without echo like a program asking for it would, and print it escaped
+        pty.write("stty -echo -icanon; printf '\\033[6n'; dd bs=6 count=1 2>/dev/null | od -c | head -1; stty sane\r")?;
+        test_util::wait_for_output(&pty, "033   [   4   ;   2   R", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        Ok(())
+    }
+}
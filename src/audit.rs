@@ -0,0 +1,177 @@
+//! Audit trail of privileged actions on a pty, passed to the sink set with PtyBuilder::audit()
+//! events are emitted synchronously on the thread performing the action, a slow sink slows it down
+//! ```rust
+//! use pty_exec::audit::AuditLog;
+//! use pty_exec::Pty;
+//!
+//! let path = std::env::temp_dir().join("pty-exec-doc-audit.log");
+//! // one line per event, e.g. `at=1760000000.123 pty=5:1 action=session_created pid=4242`
+//! let pty = Pty::builder().audit(AuditLog::append(&path)?).spawn(|_id, _res| {}, |_id| {})?;
+//! pty.shutdown()?;
+//! # std::fs::remove_file(path)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::clients::ClientId;
+use crate::id::PtyId;
+use crate::patch::Setting;
+use crate::registry::Session;
+
+/// A privileged action on a pty
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditEvent {
+    pub at: SystemTime,
+    pub pty: PtyId,
+    /// who did it, e.g. the identity of a served client, None when the owner of the Pty did
+    pub actor: Option<String>,
+    pub action: AuditAction,
+}
+
+/// What was done, see AuditEvent
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AuditAction {
+    SessionCreated { pid: i32 },
+    /// a client of server::Server attached, with the credentials of the connecting process
+    ClientAttached { client: ClientId, uid: Option<u32>, pid: Option<i32> },
+    ClientDetached { client: ClientId },
+    /// a signal was sent to the session's process group
+    SignalSent { signal: i32 },
+    /// Pty::kill() or Pty::shutdown() was called
+    SessionKilled,
+    /// the session was handed over to another process, see Pty::migrate()
+    SessionMigrated,
+    RecordingStarted { path: PathBuf },
+    /// settings of the session were changed, see Pty::update_config()
+    ConfigChanged { settings: Vec<Setting> },
+}
+
+impl fmt::Display for AuditEvent {
+    /// one logfmt line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(f, "at={}.{:03} pty={}", at.as_secs(), at.subsec_millis(), self.pty)?;
+        if let Some(actor) = &self.actor {
+            write!(f, " actor={actor:?}")?;
+        }
+
+        match &self.action {
+            AuditAction::SessionCreated { pid } => write!(f, " action=session_created pid={pid}"),
+            AuditAction::ClientAttached { client, uid, pid } => {
+                write!(f, " action=client_attached client={client}")?;
+                if let Some(uid) = uid { write!(f, " uid={uid}")? }
+                if let Some(pid) = pid { write!(f, " pid={pid}")? }
+                Ok(())
+            },
+            AuditAction::ClientDetached { client } => write!(f, " action=client_detached client={client}"),
+            AuditAction::SignalSent { signal } => write!(f, " action=signal_sent signal={signal}"),
+            AuditAction::SessionKilled => write!(f, " action=session_killed"),
+            AuditAction::SessionMigrated => write!(f, " action=session_migrated"),
+            AuditAction::RecordingStarted { path } => write!(f, " action=recording_started path={:?}", path.display().to_string()),
+            AuditAction::ConfigChanged { settings } => {
+                let settings: Vec<String> = settings.iter().map(Setting::to_string).collect();
+                write!(f, " action=config_changed settings={}", settings.join(","))
+            },
+        }
+    }
+}
+
+/// Receives audit events, implemented for closures
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: &AuditEvent);
+}
+
+impl<F> AuditSink for F
+    where F: Fn(&AuditEvent) + Send + Sync
+{
+    fn record(&self, event: &AuditEvent) {
+        self(event)
+    }
+}
+
+/// Sink appending each event as a logfmt line to a file, see AuditEvent's Display
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// appends to path, the file is created if needed
+    pub fn append(path: impl AsRef<Path>) -> Result<AuditLog, Box<dyn Error>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&self, event: &AuditEvent) {
+        // a single write per line keeps lines of concurrent writers whole
+        let _ = self.file.lock().unwrap().write_all(format!("{event}\n").as_bytes());
+    }
+}
+
+/**
+ * Sink of a session, a newtype so the config stays Debug
+ */
+#[derive(Clone)]
+pub(crate) struct Audit(pub Arc<dyn AuditSink>);
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Audit")
+    }
+}
+
+/**
+ * Passes an event to the sink of session, if it has one
+ */
+pub(crate) fn emit(session: &Session, actor: Option<&str>, action: AuditAction) {
+    let config = session.config();
+    if let Some(Audit(sink)) = &config.audit {
+        sink.record(&AuditEvent { at: config.clock.system_now(), pty: session.id(), actor: actor.map(str::to_owned), action });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+    use crate::recording::Recording;
+    use crate::test_util;
+
+    #[test]
+    fn privileged_actions() -> Result<(), Box<dyn Error>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_async = events.clone();
+        let pty = test_util::stub()
+            .audit(move |event: &AuditEvent| events_async.lock().unwrap().push(event.clone()))
+            .spawn(|_id, _res| {}, |_id| {})?;
+        pty.signal(Signal::SIGWINCH)?;
+        pty.shutdown()?;
+
+        let actions: Vec<AuditAction> = events.lock().unwrap().iter().map(|event| event.action.clone()).collect();
+        assert!(matches!(actions[0], AuditAction::SessionCreated { .. }));
+        assert_eq!(actions[1..], [AuditAction::SignalSent { signal: Signal::SIGWINCH as i32 }, AuditAction::SessionKilled]);
+        assert!(events.lock().unwrap()[1].to_string().ends_with(&format!("pty={} action=signal_sent signal=28", pty.id())));
+
+        // a spawn failing after the session was created ends it in the audit trail too
+        events.lock().unwrap().clear();
+        let events_async = events.clone();
+        let res = test_util::stub()
+            .audit(move |event: &AuditEvent| events_async.lock().unwrap().push(event.clone()))
+            .record(Recording::new("/nonexistent/pty-exec.cast"))
+            .spawn(|_id, _res| {}, |_id| {});
+        assert!(res.is_err());
+        let actions: Vec<AuditAction> = events.lock().unwrap().iter().map(|event| event.action.clone()).collect();
+        assert!(matches!(actions[..], [AuditAction::SessionCreated { .. }, AuditAction::SessionKilled]));
+        Ok(())
+    }
+}
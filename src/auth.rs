@@ -0,0 +1,217 @@
+//! Deciding who may attach to a served session, see server::Server::authenticator()
+//! an Authenticator sees the peer credentials of the socket and the token the client sent,
+//! the identity it returns is reported with the client's server events for auditing and
+//! decides the OutputProfile the client's output is sanitized by, replayed output included
+//! ```rust,no_run
+//! use pty_exec::auth::{Identity, Peer};
+//! use pty_exec::PtyError;
+//! use pty_exec::server::{default_socket_path, Server};
+//!
+//! // only the deploy user may attach, and only to sessions named after a service
+//! let server = Server::bind(default_socket_path())?.authenticator(|peer: &Peer, session: &str| {
+//!     match peer.uid() == 1001 && session.starts_with("svc-") {
+//!         true => Ok(Identity::new("deploy")),
+//!         false => Err(PtyError::new("Not allowed").into()),
+//!     }
+//! });
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use nix::unistd::Uid;
+use crate::clients::OutputProfile;
+use crate::error::PtyError;
+
+/// Who is on the other end of a connection, as far as the transport can tell
+#[derive(Clone, PartialEq, Eq)]
+pub struct Peer {
+    uid: u32,
+    gid: u32,
+    pid: Option<i32>,
+    token: Option<String>,
+}
+
+impl Peer {
+    /// for transports other than the unix socket, e.g. a bridge that checked the user itself
+    pub fn new(uid: u32, gid: u32, pid: Option<i32>, token: Option<String>) -> Peer {
+        Peer { uid, gid, pid, token }
+    }
+
+    /// credentials of the process connected to stream, the pid is only known on linux
+    pub fn of(stream: &UnixStream, token: Option<String>) -> Result<Peer, Box<dyn Error>> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+            let creds = getsockopt(stream.as_raw_fd(), PeerCredentials)
+                .map_err(|errno| PtyError::from_errno("Peer credentials failure", errno))?;
+            Ok(Peer::new(creds.uid(), creds.gid(), Some(creds.pid()), token))
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let (uid, gid) = nix::unistd::getpeereid(stream.as_raw_fd())
+                .map_err(|errno| PtyError::from_errno("Peer credentials failure", errno))?;
+            Ok(Peer::new(uid.as_raw(), gid.as_raw(), None, token))
+        }
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+
+    /// token the client sent before attaching, see client::Client::connect_with_token()
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+}
+
+// the token is a secret, it must not end up in logs
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Peer")
+            .field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("pid", &self.pid)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Who an authenticated client is, as decided by the Authenticator
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Identity {
+    name: String,
+    profile: OutputProfile,
+}
+
+impl Identity {
+    /// a trusted client, its output is not sanitized
+    pub fn new(name: impl Into<String>) -> Identity {
+        Identity { name: name.into(), profile: OutputProfile::Trusted }
+    }
+
+    /// identity of a peer known only by its uid
+    pub fn uid(uid: u32) -> Identity {
+        Identity::new(format!("uid={uid}"))
+    }
+
+    /// the client's output is sanitized by profile, see Pty::attach_with()
+    pub fn with_profile(mut self, profile: OutputProfile) -> Identity {
+        self.profile = profile;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn profile(&self) -> OutputProfile {
+        self.profile
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// Decides whether peer may attach to session, an error denies it and is sent to the client
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, peer: &Peer, session: &str) -> Result<Identity, Box<dyn Error>>;
+}
+
+impl<F> Authenticator for F
+    where F: Fn(&Peer, &str) -> Result<Identity, Box<dyn Error>> + Send + Sync
+{
+    fn authenticate(&self, peer: &Peer, session: &str) -> Result<Identity, Box<dyn Error>> {
+        self(peer, session)
+    }
+}
+
+/// Allows anyone who can connect, access is left to the permissions of the socket
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, peer: &Peer, _session: &str) -> Result<Identity, Box<dyn Error>> {
+        Ok(Identity::uid(peer.uid))
+    }
+}
+
+/// Allows the user running the server and root, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SameUser;
+
+impl Authenticator for SameUser {
+    fn authenticate(&self, peer: &Peer, _session: &str) -> Result<Identity, Box<dyn Error>> {
+        match peer.uid == Uid::effective().as_raw() || peer.uid == 0 {
+            true => Ok(Identity::uid(peer.uid)),
+            false => Err(denied(format!("uid {} may not attach", peer.uid))),
+        }
+    }
+}
+
+/// Allows clients presenting one of a set of tokens, each token stands for an identity
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    tokens: HashMap<String, Identity>,
+}
+
+impl Tokens {
+    pub fn new() -> Tokens {
+        Tokens::default()
+    }
+
+    pub fn allow(mut self, token: impl Into<String>, identity: Identity) -> Tokens {
+        self.tokens.insert(token.into(), identity);
+        self
+    }
+}
+
+impl Authenticator for Tokens {
+    fn authenticate(&self, peer: &Peer, _session: &str) -> Result<Identity, Box<dyn Error>> {
+        peer.token()
+            .and_then(|token| self.tokens.get(token))
+            .cloned()
+            .ok_or_else(|| denied("Invalid or missing token"))
+    }
+}
+
+fn denied(msg: impl Into<String>) -> Box<dyn Error> {
+    Box::new(PtyError::with_kind(msg, io::ErrorKind::PermissionDenied))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticators() -> Result<(), Box<dyn Error>> {
+        let me = Uid::effective().as_raw();
+        let stranger = Peer::new(me + 1, 0, None, Some("secret".into()));
+
+        assert_eq!(SameUser.authenticate(&Peer::new(me, 0, None, None), "main")?, Identity::uid(me));
+        assert!(SameUser.authenticate(&stranger, "main").is_err());
+        assert_eq!(AllowAll.authenticate(&stranger, "main")?, Identity::uid(me + 1));
+
+        let tokens = Tokens::new().allow("secret", Identity::new("ci"));
+        assert_eq!(tokens.authenticate(&stranger, "main")?, Identity::new("ci"));
+        assert!(tokens.authenticate(&Peer::new(me, 0, None, None), "main").is_err());
+        assert!(!format!("{stranger:?}").contains("secret"));
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! How the child of a pty is started, see PtyBuilder::backend()
+//! the default backend opens a pty and forks the shell of the user with everything the builder
+//! asks for, another backend can start the child any other way, e.g. through a setuid helper,
+//! a container runtime shim or an agent, reading, events, scrollback, clients and everything
+//! else work on what it returns the same as on a pty spawned by the default backend
+//!
+//! what a backend returns must be what the default backend would return: the master of a pty,
+//! or any fd that reads like one, and the pid of a child of this process leading its own
+//! process group, the child is reaped with waitpid and signals go to its process group
+//! ```rust
+//! use std::error::Error;
+//! use pty_exec::Pty;
+//! use pty_exec::backend::{Fork, SpawnBackend, SpawnRequest, Spawned};
+//!
+//! // logs every spawn, then spawns as usual
+//! struct Logged;
+//!
+//! impl SpawnBackend for Logged {
+//!     fn spawn(&self, request: &SpawnRequest) -> Result<Spawned, Box<dyn Error>> {
+//!         println!("spawning {:?} in {:?}", request.shells(), request.cwd());
+//!         Fork.spawn(request)
+//!     }
+//! }
+//!
+//! let pty = Pty::builder().backend(Logged).spawn(|_id, _res| {}, |_id| {})?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::io;
+use std::os::fd::{FromRawFd, IntoRawFd, OwnedFd};
+use std::path::Path;
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::Pid;
+use crate::builder::{PtyBuilder, StdioMode};
+use crate::error::PtyError;
+use crate::unix::pty::{self, Child};
+use crate::unix::shell::ShellUser;
+use crate::unix::window::WindowSize;
+
+/// Starts the child of a pty
+pub trait SpawnBackend: Send + Sync {
+    /// starts the child request describes, called on the thread spawning the pty
+    fn spawn(&self, request: &SpawnRequest) -> Result<Spawned, Box<dyn Error>>;
+}
+
+/// What a pty is spawned with, as set on the builder
+pub struct SpawnRequest<'a> {
+    builder: &'a PtyBuilder,
+}
+
+/// A child started by a backend
+#[derive(Debug)]
+pub struct Spawned {
+    /// master of the pty the child runs on
+    pub master: OwnedFd,
+    /// pid of the child, a child of this process leading its own process group
+    pub pid: i32,
+    /// the shell that was started
+    pub shell: String,
+    /// parent end of the child's stdin, if SpawnRequest::stdin() asks for a pipe
+    pub stdin: Option<OwnedFd>,
+    /// parent end of the child's stdout, if SpawnRequest::stdout() asks for a pipe
+    pub stdout: Option<OwnedFd>,
+    /// parent end of the child's stderr, if SpawnRequest::stderr_piped()
+    pub stderr: Option<OwnedFd>,
+}
+
+/// The default backend, opens a pty and forks the shell onto it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fork;
+
+/// Spawns the shell with posix_spawn instead of fork, quicker to start and safe to use from a
+/// process with many threads, where the new session and its controlling tty cannot be set up by
+/// posix_spawn, i.e. anywhere but linux with glibc, or when running as another user or in a
+/// sandbox, it spawns like Fork
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PosixSpawn;
+
+/// Spawns the shell with clone(CLONE_VM | CLONE_VFORK), the child borrows the memory of this
+/// process until it execs instead of getting a copy of it, quicker than fork the more memory
+/// this process maps, e.g. when spawning thousands of short lived ptys, Linux only, where it
+/// cannot be used it spawns like PosixSpawn
+#[cfg(feature = "vfork")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vfork;
+
+impl SpawnBackend for Fork {
+    fn spawn(&self, request: &SpawnRequest) -> Result<Spawned, Box<dyn Error>> {
+        Ok(pty::spawn(request.builder)?.into())
+    }
+}
+
+impl SpawnBackend for PosixSpawn {
+    fn spawn(&self, request: &SpawnRequest) -> Result<Spawned, Box<dyn Error>> {
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        if let Some(child) = crate::unix::spawn::posix_spawn(request.builder)? {
+            return Ok(child.into());
+        }
+        Fork.spawn(request)
+    }
+}
+
+#[cfg(feature = "vfork")]
+impl SpawnBackend for Vfork {
+    fn spawn(&self, request: &SpawnRequest) -> Result<Spawned, Box<dyn Error>> {
+        #[cfg(target_os = "linux")]
+        if let Some(child) = crate::unix::spawn::vfork(request.builder)? {
+            return Ok(child.into());
+        }
+        PosixSpawn.spawn(request)
+    }
+}
+
+impl From<Child> for Spawned {
+    fn from(child: Child) -> Spawned {
+        let owned = |fd| unsafe { OwnedFd::from_raw_fd(fd) };
+        Spawned {
+            master: owned(child.master),
+            pid: child.pid.as_raw(),
+            shell: child.shell,
+            stdin: child.stdin.map(owned),
+            stdout: child.stdout.map(owned),
+            stderr: child.stderr.map(owned),
+        }
+    }
+}
+
+impl SpawnRequest<'_> {
+    /// the account to run the shell as, see PtyBuilder::user(), resolved like the default
+    /// backend does, looking the current user up may take until PtyBuilder::user_lookup() gives up
+    pub fn user(&self) -> ShellUser {
+        match &self.builder.config.user {
+            Some(user) => user.clone(),
+            None => ShellUser::from_env(self.builder.user_lookup, &self.builder.default_shell)
+        }
+    }
+
+    /// the shells to try in order, the shell of the user() and then PtyBuilder::fallback_shells()
+    pub fn shells(&self) -> Vec<String> {
+        self.user().shells(&self.builder.fallback_shells)
+    }
+
+    /// program and its arguments to exec in place of the shells, see PtyBuilder::program(),
+    /// empty to start a shell
+    pub fn program(&self) -> &[String] {
+        &self.builder.program
+    }
+
+    /// variables to set, on top of the inherited ones unless env_clear()
+    pub fn env(&self) -> &[(String, String)] {
+        &self.builder.env
+    }
+
+    pub fn env_clear(&self) -> bool {
+        self.builder.env_clear
+    }
+
+    pub fn cwd(&self) -> Option<&Path> {
+        self.builder.cwd.as_deref()
+    }
+
+    pub fn window_size(&self) -> Option<WindowSize> {
+        self.builder.window_size
+    }
+
+    /// see PtyBuilder::tag(), to be exported as SESSION_ID_ENV
+    pub fn tag(&self) -> Option<&str> {
+        self.builder.config.tag.as_deref()
+    }
+
+    pub fn stdin(&self) -> StdioMode {
+        self.builder.config.stdin
+    }
+
+    pub fn stdout(&self) -> StdioMode {
+        self.builder.config.stdout
+    }
+
+    /// whether stderr goes through a pipe of its own, see PtyBuilder::on_stderr()
+    pub fn stderr_piped(&self) -> bool {
+        self.builder.on_stderr.is_some()
+    }
+}
+
+/**
+ * Starts the child of builder with its backend, fails if the backend left out a pipe the
+ * builder asked for or returned one it did not
+ */
+pub(crate) fn spawn(builder: &PtyBuilder) -> Result<Child, Box<dyn Error>> {
+    let request = SpawnRequest { builder };
+    let spawned = match &builder.backend {
+        Some(backend) => backend.spawn(&request)?,
+        None => Fork.spawn(&request)?
+    };
+    let pipes = [
+        ("stdin", spawned.stdin.is_some(), request.stdin() == StdioMode::Piped),
+        ("stdout", spawned.stdout.is_some(), request.stdout() == StdioMode::Piped),
+        ("stderr", spawned.stderr.is_some(), request.stderr_piped()),
+    ];
+    if let Some((stream, returned, _)) = pipes.iter().find(|(_, returned, asked)| returned != asked) {
+        let msg = match returned {
+            true => format!("Spawn backend returned a {stream} pipe that was not asked for"),
+            false => format!("Spawn backend did not return a {stream} pipe")
+        };
+        let pid = Pid::from_raw(spawned.pid);
+        drop(spawned);
+        let _ = signal::killpg(pid, Signal::SIGKILL);
+        let _ = waitpid(pid, None);
+        return Err(Box::new(PtyError::with_kind(msg, io::ErrorKind::InvalidData)));
+    }
+
+    let child = Child {
+        master: spawned.master.into_raw_fd(),
+        pid: Pid::from_raw(spawned.pid),
+        shell: spawned.shell,
+        stdin: spawned.stdin.map(IntoRawFd::into_raw_fd),
+        stdout: spawned.stdout.map(IntoRawFd::into_raw_fd),
+        stderr: spawned.stderr.map(IntoRawFd::into_raw_fd),
+    };
+    // whoever spawned them, the fds are polled without blocking and not inherited
+    for fd in [Some(child.master), child.stdin, child.stdout, child.stderr].into_iter().flatten() {
+        pty::set_cloexec(fd);
+        pty::set_nonblocking(fd);
+    }
+    Ok(child)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+    use nix::libc;
+    use nix::pty::openpty;
+    use crate::{test_util, Pty};
+
+    // /bin/sh on a pty of its own, with none of the builder's settings
+    struct Sh;
+
+    impl SpawnBackend for Sh {
+        fn spawn(&self, request: &SpawnRequest) -> Result<Spawned, Box<dyn Error>> {
+            let ends = openpty(request.window_size().map(WindowSize::to_winsize).as_ref(), None)?;
+            let slave = unsafe { OwnedFd::from_raw_fd(ends.slave) };
+            let raw_slave = slave.as_raw_fd();
+            let mut command = Command::new("/bin/sh");
+            command.env("PS1", "sh$ ").stdin(Stdio::from(slave.try_clone()?)).stdout(Stdio::from(slave.try_clone()?)).stderr(Stdio::from(slave));
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setsid() < 0 || libc::ioctl(raw_slave, libc::TIOCSCTTY as _, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            let child = command.spawn()?;
+            Ok(Spawned {
+                master: unsafe { OwnedFd::from_raw_fd(ends.master) },
+                pid: child.id() as i32,
+                shell: "/bin/sh".into(),
+                stdin: None,
+                stdout: None,
+                stderr: None,
+            })
+        }
+    }
+
+    #[test]
+    fn custom() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().backend(Sh).scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        assert_eq!(pty.shell()?, "/bin/sh");
+        pty.write("echo \"custom-$((1 + 1))\"\r")?;
+        let output = test_util::wait_for_output(&pty, "custom-2", Duration::from_secs(10))?;
+        assert!(output.contains("sh$ "), "{output:?}");
+        pty.shutdown()?;
+
+        // a pipe the builder asked for is missing
+        let res = Pty::builder().backend(Sh).stdin(StdioMode::Piped).spawn(|_id, _res| {}, |_id| {});
+        assert!(res.is_err_and(|err| err.to_string().contains("stdin pipe")));
+        Ok(())
+    }
+
+    #[test]
+    fn posix_spawn() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().backend(PosixSpawn).cwd("/").env("POSIX_SPAWNED", "yes").scrollback(0x10000)
+            .spawn(|_id, _res| {}, |_id| {})?;
+        // a session leader with the pty as its controlling tty
+        pty.write("set -- $(cat /proc/$$/stat); [ \"$6\" = \"$$\" ] && [ \"$7\" != 0 ] && echo \"posix-$((1 + 1))-$PWD-$POSIX_SPAWNED\"\r")?;
+        test_util::wait_for_output(&pty, "posix-2-/-yes", Duration::from_secs(10))?;
+        pty.shutdown()?;
+
+        // a shell given by name is looked up in the PATH of the child, not of this process
+        let dir = std::env::temp_dir().join(format!("pty-exec-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::os::unix::fs::symlink("/bin/sh", dir.join("pty-exec-sh"))?;
+        let user = ShellUser { shell: "pty-exec-sh".into(), ..ShellUser::from_env(Default::default(), "/bin/sh") };
+        let res = Pty::builder().backend(PosixSpawn).user(user).env("PATH", dir.to_string_lossy()).spawn(|_id, _res| {}, |_id| {});
+        std::fs::remove_dir_all(&dir)?;
+        let pty = res?;
+        assert_eq!(pty.shell()?, "pty-exec-sh");
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "vfork")]
+    #[test]
+    fn vfork() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().backend(Vfork).cwd("/").env("VFORKED", "yes").scrollback(0x10000)
+            .spawn(|_id, _res| {}, |_id| {})?;
+        pty.write("set -- $(cat /proc/$$/stat); [ \"$6\" = \"$$\" ] && [ \"$7\" != 0 ] && echo \"vfork-$((1 + 1))-$PWD-$VFORKED\"\r")?;
+        test_util::wait_for_output(&pty, "vfork-2-/-yes", Duration::from_secs(10))?;
+        pty.shutdown()?;
+
+        // a fallback given by name is looked up in PATH
+        let user = ShellUser { shell: "/nonexistent/shell".into(), ..ShellUser::from_env(Default::default(), "/bin/sh") };
+        let pty = Pty::builder().backend(Vfork).user(user).fallback_shells(["sh"]).spawn(|_id, _res| {}, |_id| {})?;
+        assert_eq!(pty.shell()?, "sh");
+        pty.shutdown()?;
+
+        // a failure in the child before it execs
+        let res = Pty::builder().backend(Vfork).cwd("/nonexistent").spawn(|_id, _res| {}, |_id| {});
+        assert!(res.is_err_and(|err| err.to_string().contains("No such file")));
+        Ok(())
+    }
+}
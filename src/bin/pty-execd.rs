@@ -0,0 +1,28 @@
+//! Hosts detachable shell sessions, clients attach to them by name over a unix socket
+//! usage: pty-execd [SOCKET]
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use pty_exec::compress::Codec;
+use pty_exec::server::{default_socket_path, Server};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let path = match std::env::args_os().nth(1) {
+        Some(arg) if arg == "-h" || arg == "--help" => {
+            println!("usage: pty-execd [SOCKET]");
+            println!("serves shell sessions on SOCKET, {} by default", default_socket_path().display());
+            return Ok(());
+        },
+        Some(arg) => PathBuf::from(arg),
+        None => default_socket_path(),
+    };
+
+    // clients that vanish without closing their connection are detached after a minute and a half
+    // and output is compressed for clients that can take it, with whatever this build has
+    let server = Server::bind(&path)?
+        .keepalive(Duration::from_secs(30), Duration::from_secs(90))
+        .compress(Codec::available());
+    eprintln!("pty-execd listening on {}", server.path().display());
+    server.run()
+}
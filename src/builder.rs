@@ -0,0 +1,752 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::os::fd::RawFd;
+#[cfg(feature = "migrate")]
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{self, Pid};
+use crate::answer::Answers;
+use crate::audit::{self, Audit, AuditAction, AuditSink};
+use crate::backend::{self, SpawnBackend};
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::clock::{Clock, SystemClock};
+use crate::error::PtyError;
+use crate::filter::{Filter, Pipeline};
+use crate::clients::{DetachPolicy, ResizePolicy};
+use crate::handler::{Callbacks, Dispatched, Executor, PtyHandler};
+use crate::id::PtyId;
+use crate::input::{Eol, Keymap, Sanitize, Xterm};
+use crate::limit;
+#[cfg(feature = "migrate")]
+use crate::migrate;
+use crate::quota::{self, Quota, QuotaPolicy, QuotaResource};
+use crate::recording::Recording;
+use crate::sequence::DEFAULT_MAX_LEN;
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+use crate::sandbox::Sandbox;
+use crate::shutdown::ShutdownTimeouts;
+use crate::unix::shell::{ShellUser, UserLookup};
+use crate::unix::window::WindowSize;
+use crate::{metrics, registry, unix, Pty};
+
+/// Builder for configuring a pty before it is spawned
+/// ```rust
+/// use pty_exec::PtyBuilder;
+///
+/// let pty = PtyBuilder::new()
+///     .shutdown_input("\x04")
+///     .spawn(|_id, _res| {}, |_id| {})?;
+///
+/// pty.shutdown()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct PtyBuilder {
+    pub(crate) config: Config,
+    pub(crate) on_stderr: Option<ReadCallback>,
+    pub(crate) executor: Option<Executor>,
+    pub(crate) recording: Option<Recording>,
+    pub(crate) user_lookup: UserLookup,
+    pub(crate) default_shell: String,
+    pub(crate) fallback_shells: Vec<String>,
+    pub(crate) program: Vec<String>,
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) env_clear: bool,
+    pub(crate) cwd: Option<PathBuf>,
+    pub(crate) window_size: Option<WindowSize>,
+    pub(crate) shell_integration: bool,
+    pub(crate) wait_for_slot: Duration,
+    pub(crate) backend: Option<Arc<dyn SpawnBackend>>,
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub(crate) sandbox: Vec<Sandbox>,
+}
+
+/// Variable the tag of a session is exported to the child as, see PtyBuilder::tag()
+pub const SESSION_ID_ENV: &str = "PTY_EXEC_SESSION_ID";
+
+pub(crate) type ReadCallback = Box<dyn FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static>;
+
+/**
+ * Settings of a pty that outlive spawning, kept by the session
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub shutdown_input: Vec<u8>,
+    pub shutdown_timeouts: ShutdownTimeouts,
+    pub stdin: StdioMode,
+    pub stdout: StdioMode,
+    // stderr goes to a callback through a pipe, see PtyBuilder::on_stderr()
+    pub stderr_piped: bool,
+    pub eol: Eol,
+    pub keymap: Arc<dyn Keymap>,
+    pub clock: Arc<dyn Clock>,
+    pub sanitize: Option<Sanitize>,
+    pub scrollback: usize,
+    pub scrollback_spill: usize,
+    pub poll_after_write: bool,
+    pub coalesce: Option<Duration>,
+    pub poll_cwd: Option<Duration>,
+    pub poll_foreground: Option<Duration>,
+    pub resize_policy: ResizePolicy,
+    pub detach_policy: DetachPolicy,
+    pub audit: Option<Audit>,
+    pub quotas: Vec<Quota>,
+    pub answers: Answers,
+    pub sync_hold: Option<Duration>,
+    pub max_sequence_len: usize,
+    pub filters: Pipeline,
+    pub tag: Option<String>,
+    // the account the child runs as, kept so a duplicate runs as it too
+    pub user: Option<ShellUser>,
+    pub startup: Vec<String>,
+    pub retain_exited: Option<Duration>,
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<Chaos>,
+    #[cfg(target_os = "macos")]
+    pub login: bool,
+    #[cfg(target_os = "linux")]
+    pub reader_nice: Option<i32>,
+    #[cfg(target_os = "linux")]
+    pub reader_affinity: Option<Vec<usize>>,
+}
+
+/// Where one of the child's stdio streams is attached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StdioMode {
+    /// the pty slave, the child sees a tty
+    #[default]
+    Pty,
+    /// a pipe to the parent, the child does not see a tty on this stream
+    Piped,
+}
+
+impl PtyBuilder {
+    pub fn new() -> PtyBuilder {
+        PtyBuilder {
+            config: Config {
+                shutdown_input: b"exit\r".to_vec(),
+                shutdown_timeouts: ShutdownTimeouts::default(),
+                stdin: StdioMode::Pty,
+                stdout: StdioMode::Pty,
+                stderr_piped: false,
+                eol: Eol::Raw,
+                keymap: Arc::new(Xterm),
+                clock: Arc::new(SystemClock),
+                sanitize: None,
+                scrollback: 0,
+                scrollback_spill: 0,
+                poll_after_write: false,
+                coalesce: None,
+                poll_cwd: None,
+                poll_foreground: None,
+                resize_policy: ResizePolicy::Smallest,
+                detach_policy: DetachPolicy::KeepRunning,
+                audit: None,
+                quotas: Vec::new(),
+                answers: Answers::new(),
+                sync_hold: None,
+                max_sequence_len: DEFAULT_MAX_LEN,
+                filters: Pipeline::default(),
+                tag: None,
+                user: None,
+                startup: Vec::new(),
+                retain_exited: None,
+                #[cfg(feature = "chaos")]
+                chaos: None,
+                #[cfg(target_os = "macos")]
+                login: false,
+                #[cfg(target_os = "linux")]
+                reader_nice: None,
+                #[cfg(target_os = "linux")]
+                reader_affinity: None,
+            },
+            on_stderr: None,
+            executor: None,
+            recording: None,
+            user_lookup: UserLookup::default(),
+            default_shell: "/bin/sh".to_owned(),
+            fallback_shells: vec!["/bin/sh".to_owned()],
+            program: Vec::new(),
+            env: Vec::new(),
+            env_clear: false,
+            cwd: None,
+            window_size: None,
+            shell_integration: false,
+            wait_for_slot: Duration::ZERO,
+            backend: None,
+            #[cfg(all(feature = "sandbox", target_os = "linux"))]
+            sandbox: Vec::new(),
+        }
+    }
+
+    /// bytes written to the child on Pty::kill() and Pty::shutdown(), "exit\r" by default
+    /// an empty input skips straight to signal escalation on shutdown
+    pub fn shutdown_input(mut self, input: impl Into<Vec<u8>>) -> PtyBuilder {
+        self.config.shutdown_input = input.into();
+        self
+    }
+
+    /// how long Pty::shutdown() waits for the child to exit before each escalation step
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> PtyBuilder {
+        self.config.shutdown_timeouts = ShutdownTimeouts::uniform(timeout);
+        self
+    }
+
+    /// shutdown_timeout() for each step on its own
+    pub fn shutdown_timeouts(mut self, timeouts: ShutdownTimeouts) -> PtyBuilder {
+        self.config.shutdown_timeouts = timeouts;
+        self
+    }
+
+    /// attach the child's stdin to the pty (default) or to a pipe,
+    /// when piped Pty::write() feeds the pipe and Pty::close_stdin() sends EOF
+    pub fn stdin(mut self, mode: StdioMode) -> PtyBuilder {
+        self.config.stdin = mode;
+        self
+    }
+
+    /// attach the child's stdout to the pty (default) or to a pipe,
+    /// output from the pipe is delivered through on_read like pty output
+    pub fn stdout(mut self, mode: StdioMode) -> PtyBuilder {
+        self.config.stdout = mode;
+        self
+    }
+
+    /// line ending Pty::write() translates every line ending of its input to, input is written
+    /// as is by default, Pty::write_raw() always bypasses the translation
+    pub fn eol(mut self, eol: Eol) -> PtyBuilder {
+        self.config.eol = eol;
+        self
+    }
+
+    /// clock the session takes the time from, SystemClock by default, see the clock module
+    pub fn clock(mut self, clock: impl Clock + 'static) -> PtyBuilder {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    /// keymap Pty::write_key() encodes keys with, Xterm by default
+    pub fn keymap(mut self, keymap: impl Keymap + 'static) -> PtyBuilder {
+        self.config.keymap = Arc::new(keymap);
+        self
+    }
+
+    /// treat input of attached clients as untrusted, sanitized like with Pty::write_untrusted()
+    /// with mode, such clients can't send keys written as escape sequences like arrows either,
+    /// input of attached clients is written as is by default
+    pub fn sanitize_input(mut self, mode: Sanitize) -> PtyBuilder {
+        self.config.sanitize = Some(mode);
+        self
+    }
+
+    /// keep up to max_len bytes of the most recent output for Pty::scrollback() and Pty::search(),
+    /// disabled by default
+    pub fn scrollback(mut self, max_len: usize) -> PtyBuilder {
+        self.config.scrollback = max_len;
+        self
+    }
+
+    /// output falling out of the scrollback goes to a memory-mapped temp file holding up to
+    /// max_len more bytes instead of being dropped, Pty::scrollback(), Pty::read_since() and
+    /// Pty::search() see it like the output in memory, quotas only count the latter, the disk
+    /// for the file is taken once output first spills, off by default
+    pub fn scrollback_spill(mut self, max_len: usize) -> PtyBuilder {
+        self.config.scrollback_spill = max_len;
+        self
+    }
+
+    /// how the size of the pty follows the sizes of attached clients, see Pty::client_resize(),
+    /// the smallest client wins by default
+    pub fn resize_policy(mut self, policy: ResizePolicy) -> PtyBuilder {
+        self.config.resize_policy = policy;
+        self
+    }
+
+    /// what happens to the pty when its last client detaches, see Pty::detach(),
+    /// it keeps running by default
+    pub fn detach_policy(mut self, policy: DetachPolicy) -> PtyBuilder {
+        self.config.detach_policy = policy;
+        self
+    }
+
+    /// after every write, have the thread reading the pty busy poll for a moment instead of
+    /// sleeping until the next wakeup, the echo of interactive typing then arrives without
+    /// a scheduler round trip at the cost of some cpu time per write, off by default
+    pub fn poll_after_write(mut self, enabled: bool) -> PtyBuilder {
+        self.config.poll_after_write = enabled;
+        self
+    }
+
+    /// hold small writes back for up to window and write them together with the writes
+    /// following within it, e.g. for the key repeat stream of a GUI, fewer syscalls and wakeups
+    /// of the child at the cost of up to window of latency, ^C, ^D, ^Z and ^\ are written at
+    /// once along with anything held back, off by default
+    pub fn coalesce_writes(mut self, window: Duration) -> PtyBuilder {
+        self.config.coalesce = Some(window);
+        self
+    }
+
+    /// cap resource at limit bytes, policy is applied once it is exceeded, see the quota module,
+    /// spawning fails for a policy that does not apply to resource
+    pub fn quota(mut self, resource: QuotaResource, limit: u64, policy: QuotaPolicy) -> PtyBuilder {
+        self.config.quotas.push(Quota { resource, limit, policy });
+        self
+    }
+
+    /// pass privileged actions on the pty to sink, see the audit module
+    pub fn audit(mut self, sink: impl AuditSink + 'static) -> PtyBuilder {
+        self.config.audit = Some(Audit(Arc::new(sink)));
+        self
+    }
+
+    /// run the shell of user as user, with its USER and HOME, e.g. from ShellUser::from_name(),
+    /// spawning as another account than the current one requires root,
+    /// the current user with $SHELL, $USER and $HOME taking precedence by default
+    pub fn user(mut self, user: ShellUser) -> PtyBuilder {
+        self.config.user = Some(user);
+        self
+    }
+
+    /// how the current user's shell, USER and HOME are found when no user() is set,
+    /// the environment with a password database lookup giving up after 2 seconds by default
+    pub fn user_lookup(mut self, lookup: UserLookup) -> PtyBuilder {
+        self.user_lookup = lookup;
+        self
+    }
+
+    /// shell used when neither $SHELL nor the password database name one, /bin/sh by default
+    pub fn default_shell(mut self, shell: impl Into<String>) -> PtyBuilder {
+        self.default_shell = shell.into();
+        self
+    }
+
+    /// shells tried in order when the user's shell is missing or cannot be executed,
+    /// e.g. in a container without it, Pty::shell() tells which one was started, /bin/sh by default
+    pub fn fallback_shells<I, S>(mut self, shells: I) -> PtyBuilder
+        where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.fallback_shells = shells.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// program and its arguments, exec'd as the child in place of the user's shell, looked up
+    /// in PATH like the shell is, SHELL still names the user's shell and the shell integration
+    /// is left out, the user's shell by default
+    pub fn program<I, S>(mut self, program: I) -> PtyBuilder
+        where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.program = program.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /**
+     * What spawning tries to exec in order, the program() if there is one, otherwise the shell
+     * of user and then the fallback shells
+     */
+    pub(crate) fn execs(&self, user: &ShellUser) -> Vec<String> {
+        match self.program.first() {
+            Some(program) => vec![program.clone()],
+            None => user.shells(&self.fallback_shells)
+        }
+    }
+
+    /// set an environment variable of the shell, USER, HOME and SHELL are always set by spawn
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> PtyBuilder {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// start the shell with only the variables set with env() instead of inheriting ours
+    pub fn env_clear(mut self) -> PtyBuilder {
+        self.env_clear = true;
+        self.env.clear();
+        self
+    }
+
+    /// directory the shell starts in, ours by default
+    pub fn cwd(mut self, dir: impl AsRef<Path>) -> PtyBuilder {
+        self.cwd = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// size of the pty from the start, so the shell never sees a 0x0 window
+    pub fn window_size(mut self, window_size: WindowSize) -> PtyBuilder {
+        self.window_size = Some(window_size);
+        self
+    }
+
+    /// read the working directory of the foreground process every interval, for shells that
+    /// do not report it (OSC 7), changes are reported through PtyHandler::on_cwd() like
+    /// reported ones, an interval of a second or so is plenty, off by default
+    pub fn poll_cwd(mut self, interval: Duration) -> PtyBuilder {
+        self.config.poll_cwd = Some(interval);
+        self
+    }
+
+    /// look at the foreground process every interval, PtyHandler::on_foreground() hears when
+    /// another one takes its place or it execs another program, e.g. to title a pane after
+    /// what runs in it, off by default
+    pub fn poll_foreground(mut self, interval: Duration) -> PtyBuilder {
+        self.config.poll_foreground = Some(interval);
+        self
+    }
+
+    /// have bash, zsh and fish source the snippet of shell_integration after the user's rc files,
+    /// so PtyHandler::on_cwd() and PtyHandler::on_shell_mark() fire for users who never set
+    /// their shell up, other shells are started as usual, off by default
+    pub fn shell_integration(mut self, enabled: bool) -> PtyBuilder {
+        self.shell_integration = enabled;
+        self
+    }
+
+    /// restrict the child with a sandbox preset before it starts the shell, presets add up,
+    /// spawning fails if the kernel cannot apply one, see the sandbox module
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub fn sandbox(mut self, sandbox: Sandbox) -> PtyBuilder {
+        self.sandbox.push(sandbox);
+        self
+    }
+
+    /// disturb the timing of the pty, for testing code built on it, see the chaos module
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: Chaos) -> PtyBuilder {
+        self.config.chaos = Some(chaos);
+        self
+    }
+
+    /// how long spawning waits for a pty to close once the cap of limit::set_max_sessions()
+    /// is reached before failing, not at all by default
+    pub fn wait_for_slot(mut self, timeout: Duration) -> PtyBuilder {
+        self.wait_for_slot = timeout;
+        self
+    }
+
+    /// terminal queries of the child answered by the pty itself, for embedders that do not
+    /// answer them, none by default, see the answer module
+    pub fn answer_queries(mut self, answers: Answers) -> PtyBuilder {
+        self.config.answers = answers;
+        self
+    }
+
+    /// hold output passed to PtyHandler::on_output() while the child is in the middle of a
+    /// synchronized update (DEC mode 2026) and pass the whole update at once when it ends, so a
+    /// renderer never draws half a frame, a child that never ends it is given up on after
+    /// max_hold, clients attached to the pty get the output as it comes, off by default
+    pub fn hold_synchronized_output(mut self, max_hold: Duration) -> PtyBuilder {
+        self.config.sync_hold = Some(max_hold);
+        self
+    }
+
+    /// longest control string of the child acted on, e.g. a title or an inline image, or held
+    /// back until it ends when a read splits it, longer ones are dropped and reported with
+    /// PtyHandler::on_sequence_overflow(), in bytes, sequence::DEFAULT_MAX_LEN by default, see
+    /// the sequence module
+    pub fn max_sequence_len(mut self, len: usize) -> PtyBuilder {
+        self.config.max_sequence_len = len;
+        self
+    }
+
+    /// pass every chunk of output and input through filter, after the filters added before,
+    /// see the filter module
+    pub fn filter(mut self, filter: impl Filter + 'static) -> PtyBuilder {
+        self.config.filters.push(Box::new(filter));
+        self
+    }
+
+    /// tag the session, e.g. with the id a host knows it by, so logs and recordings can be told
+    /// apart from inside the session too, the tag is exported to the child as PTY_EXEC_SESSION_ID,
+    /// written into the header of recordings and reported back by the shell integration at
+    /// every prompt, see PtyHandler::on_session_tag(), only ASCII letters, digits and -_.: are
+    /// allowed so it can go anywhere unquoted
+    pub fn tag(mut self, tag: impl Into<String>) -> PtyBuilder {
+        self.config.tag = Some(tag.into());
+        self
+    }
+
+    /// commands written to the shell once it is ready for input, each followed by "\r", e.g. to
+    /// cd into a project and source its environment, see PtyHandler::on_ready(), the commands
+    /// are written like Pty::inject() so only their output is seen, none by default
+    pub fn startup_commands<I, S>(mut self, commands: I) -> PtyBuilder
+        where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.config.startup = commands.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// keep the session readable for period after the child exited, for its scrollback, history,
+    /// title and exit status, see the retention module, sessions go once the child is reaped by
+    /// default
+    pub fn retain_exited(mut self, period: Duration) -> PtyBuilder {
+        self.config.retain_exited = Some(period);
+        self
+    }
+
+    /// start the child with backend instead of forking it onto a new pty, see the backend module
+    pub fn backend(mut self, backend: impl SpawnBackend + 'static) -> PtyBuilder {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// record the session from the start, see Pty::start_recording()
+    pub fn record(mut self, recording: Recording) -> PtyBuilder {
+        self.recording = Some(recording);
+        self
+    }
+
+    /// start the shell through `/usr/bin/login -fp <user>` like Terminal.app and iTerm2 do,
+    /// the session is then registered in utmpx so `w` and `last` see it, off by default
+    #[cfg(target_os = "macos")]
+    pub fn login(mut self, enabled: bool) -> PtyBuilder {
+        self.config.login = enabled;
+        self
+    }
+
+    /// nice value of the thread reading the pty, failures are reported through PtyHandler::on_error
+    #[cfg(target_os = "linux")]
+    pub fn reader_nice(mut self, nice: i32) -> PtyBuilder {
+        self.config.reader_nice = Some(nice);
+        self
+    }
+
+    /// cpus the thread reading the pty may run on, failures are reported through PtyHandler::on_error
+    #[cfg(target_os = "linux")]
+    pub fn reader_affinity(mut self, cpus: impl Into<Vec<usize>>) -> PtyBuilder {
+        self.config.reader_affinity = Some(cpus.into());
+        self
+    }
+
+    /// route the child's stderr through a separate pipe instead of the pty,
+    /// stdin and stdout stay on the pty so the child still sees a real tty
+    /// on_stderr: callback called when there is something to read on stderr
+    pub fn on_stderr<H>(mut self, on_stderr: H) -> PtyBuilder
+        where H: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static
+    {
+        self.on_stderr = Some(Box::new(on_stderr));
+        self.config.stderr_piped = true;
+        self
+    }
+
+    /// run every callback through executor instead of on the thread reading the pty,
+    /// e.g. to get callbacks onto a GUI main thread, tasks must be run in the order submitted
+    /// ```rust
+    /// use std::sync::mpsc;
+    /// use pty_exec::PtyBuilder;
+    ///
+    /// let (tx, rx) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+    ///
+    /// let pty = PtyBuilder::new()
+    ///     .executor(move |task| { let _ = tx.send(task); })
+    ///     .spawn(|_id, res| println!("{}", res.unwrap()), |_id| {})?;
+    /// pty.shutdown()?;
+    ///
+    /// // callbacks run here, on the thread draining the channel
+    /// for task in rx.try_iter() {
+    ///     task();
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn executor<E>(mut self, executor: E) -> PtyBuilder
+        where E: Fn(Box<dyn FnOnce() + Send>) + Send + Sync + 'static
+    {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Spawns a new pty with this configuration,
+    /// on_read: callback called when there is something to read
+    /// on_death: callback called when there the pty dies
+    pub fn spawn<F, G>(self, on_read: F, on_death: G) -> Result<Pty, Box<dyn Error>>
+        where
+            F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+            G: FnMut(PtyId) + Send + 'static
+    {
+        self.spawn_handler(Callbacks { on_read, on_death })
+    }
+
+    /// Spawns a new pty with this configuration, everything happening on it is passed to handler
+    pub fn spawn_handler<H: PtyHandler>(self, handler: H) -> Result<Pty, Box<dyn Error>> {
+        let started = Instant::now();
+        quota::validate(&self.config.quotas)?;
+        if let Some(tag) = &self.config.tag {
+            validate_tag(tag)?;
+        }
+        let slot = limit::acquire(self.wait_for_slot).inspect_err(|_| metrics::spawn_failed())?;
+        let child = backend::spawn(&self).inspect_err(|_| metrics::spawn_failed())?;
+        let on_stderr = match (self.on_stderr, self.executor.clone()) {
+            (Some(on_stderr), Some(executor)) => Some(dispatch_stderr(on_stderr, executor)),
+            (on_stderr, _) => on_stderr
+        };
+        let stderr = child.stderr.zip(on_stderr);
+        let session = match registry::register(child.master, child.pid, child.shell, child.stdin, self.config, slot) {
+            Ok(session) => session,
+            Err(err) => {
+                abandon_child(child.pid, [Some(child.master), child.stdin, child.stdout, child.stderr]);
+                return Err(err);
+            }
+        };
+        let id = session.id();
+        metrics::spawned(started.elapsed());
+        audit::emit(&session, None, AuditAction::SessionCreated { pid: child.pid.as_raw() });
+
+        // nothing reads the pty until poll() started its thread, undone if it never does
+        let stderr_fd = stderr.as_ref().map(|(fd, _)| *fd);
+        let recording = self.recording.as_ref().map_or(Ok(()), |recording| session.start_recording(recording));
+        let res = recording.and_then(|()| match self.executor {
+            Some(executor) => unix::pty::poll(session.clone(), child.stdout, stderr, Dispatched::new(handler, executor)),
+            None => unix::pty::poll(session.clone(), child.stdout, stderr, handler)
+        });
+        if let Err(err) = res {
+            // the session was reported created, it is reported ended too
+            audit::emit(&session, None, AuditAction::SessionKilled);
+            session.close();
+            abandon_child(child.pid, [child.stdout, stderr_fd]);
+            return Err(err);
+        }
+
+        Ok(Pty { id })
+    }
+}
+
+#[cfg(feature = "migrate")]
+impl PtyBuilder {
+    /// takes over the session another process hands over on source with Pty::migrate(),
+    /// settings of the session such as the scrollback size or the policies come from this
+    /// builder, settings of the child such as the shell, its env or the size are left as they
+    /// were, see the migrate module
+    pub fn adopt<F, G>(self, source: &UnixStream, on_read: F, on_death: G) -> Result<Pty, Box<dyn Error>>
+        where
+            F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+            G: FnMut(PtyId) + Send + 'static
+    {
+        self.adopt_handler(source, Callbacks { on_read, on_death })
+    }
+
+    /// adopt() passing everything happening on the pty to handler
+    pub fn adopt_handler<H: PtyHandler>(mut self, source: &UnixStream, handler: H) -> Result<Pty, Box<dyn Error>> {
+        let started = Instant::now();
+        let offer = migrate::receive(source)?;
+        let slot = match quota::validate(&self.config.quotas).and_then(|()| limit::acquire(self.wait_for_slot)) {
+            Ok(slot) => slot,
+            Err(err) => {
+                migrate::refuse(source, &err.to_string());
+                let _ = unistd::close(offer.master);
+                return Err(err);
+            }
+        };
+        let snapshot = offer.snapshot;
+        if self.config.tag.is_none() {
+            self.config.tag = snapshot.tag;
+        }
+        let child = Pid::from_raw(snapshot.child_pid);
+        let session = match registry::register(offer.master, child, snapshot.shell, None, self.config, slot) {
+            Ok(session) => session,
+            Err(err) => {
+                migrate::refuse(source, &err.to_string());
+                let _ = unistd::close(offer.master);
+                return Err(err);
+            }
+        };
+        session.scrollback().push(&snapshot.scrollback);
+        session.history().restore(snapshot.command_history);
+        {
+            let mut titles = session.titles();
+            titles.title = snapshot.title;
+            titles.icon_name = snapshot.icon_name;
+        }
+        // the other side lets go once it hears of it, nothing may read the pty before
+        if let Err(err) = migrate::adopted(source) {
+            session.close();
+            return Err(Box::new(err));
+        }
+        let id = session.id();
+        metrics::spawned(started.elapsed());
+
+        let recording = self.recording.as_ref().map_or(Ok(()), |recording| session.start_recording(recording));
+        let res = recording.and_then(|()| match self.executor {
+            Some(executor) => unix::pty::poll(session.clone(), None, None, Dispatched::new(handler, executor)),
+            None => unix::pty::poll(session.clone(), None, None, handler)
+        });
+        if let Err(err) = res {
+            session.close();
+            return Err(err);
+        }
+        Ok(Pty { id })
+    }
+}
+
+/**
+ * Undoes a spawn failing after the child was started, fds nothing else owns are closed and
+ * the child is killed and reaped
+ */
+fn abandon_child<const N: usize>(pid: Pid, fds: [Option<RawFd>; N]) {
+    for fd in fds.into_iter().flatten() {
+        let _ = unistd::close(fd);
+    }
+    let _ = signal::killpg(pid, Signal::SIGKILL);
+    let _ = waitpid(pid, None);
+    metrics::spawn_failed();
+}
+
+fn validate_tag(tag: &str) -> Result<(), Box<dyn Error>> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.:".contains(c);
+    if tag.is_empty() || !tag.chars().all(allowed) {
+        return Err(Box::new(PtyError::with_kind(format!("Invalid session tag {tag:?}"), io::ErrorKind::InvalidInput)));
+    }
+    Ok(())
+}
+
+/**
+ * Forwards the stderr callback to an executor like Dispatched does for handlers
+ */
+fn dispatch_stderr(on_stderr: ReadCallback, executor: Executor) -> ReadCallback {
+    let on_stderr = Arc::new(Mutex::new(on_stderr));
+
+    Box::new(move |id, res| {
+        let on_stderr = on_stderr.clone();
+        let res = res.map_err(|err| PtyError::copy_of(err.as_ref()));
+        executor(Box::new(move || {
+            let mut on_stderr = on_stderr.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                on_stderr(id, res.map_err(|err| Box::new(err) as Box<dyn Error>))
+            }));
+        }));
+    })
+}
+
+impl fmt::Debug for PtyBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("PtyBuilder");
+        debug
+            .field("config", &self.config)
+            .field("on_stderr", &self.on_stderr.is_some())
+            .field("executor", &self.executor.is_some())
+            .field("recording", &self.recording)
+            .field("user_lookup", &self.user_lookup)
+            .field("default_shell", &self.default_shell)
+            .field("fallback_shells", &self.fallback_shells)
+            .field("program", &self.program)
+            .field("env", &self.env)
+            .field("env_clear", &self.env_clear)
+            .field("cwd", &self.cwd)
+            .field("window_size", &self.window_size)
+            .field("shell_integration", &self.shell_integration)
+            .field("wait_for_slot", &self.wait_for_slot)
+            .field("backend", &self.backend.is_some());
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        debug.field("sandbox", &self.sandbox);
+        debug.finish()
+    }
+}
+
+impl Default for PtyBuilder {
+    fn default() -> PtyBuilder {
+        PtyBuilder::new()
+    }
+}
@@ -0,0 +1,168 @@
+//! Aborting blocking calls from another thread without ending the session, e.g. when the user
+//! of a UI gives up on a command, see Pty::wait(), Paste::wait_cancellable() and
+//! test_util::wait_for_output_cancellable()
+//! ```rust
+//! use std::thread;
+//! use pty_exec::Pty;
+//! use pty_exec::cancel::CancellationToken;
+//!
+//! let pty = Pty::spawn(|_id, _res| {}, |_id| {})?;
+//! let cancel = CancellationToken::new();
+//! let cancel_async = cancel.clone();
+//! thread::spawn(move || cancel_async.cancel());
+//!
+//! // returns once cancelled, the shell keeps running
+//! assert!(pty.wait(&cancel).is_err());
+//! assert!(pty.is_alive());
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::error::PtyError;
+
+type Waker = Box<dyn Fn() + Send>;
+
+/// Cancels the blocking calls it was passed to, clones share their state, once cancelled a
+/// token stays cancelled
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    // how to wake each call blocked on the token
+    wakers: Mutex<HashMap<u64, Waker>>,
+    next_waker: AtomicU64,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// makes every call blocked on the token return, and every later one right away
+    pub fn cancel(&self) {
+        if self.0.cancelled.swap(true, Ordering::SeqCst) { return }
+        let wakers = std::mem::take(&mut *self.0.wakers.lock().unwrap());
+        for waker in wakers.into_values() {
+            waker();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Runs waker when the token is cancelled, right away if it already is, until the returned
+     * guard is dropped
+     */
+    pub(crate) fn on_cancel(&self, waker: impl Fn() + Send + 'static) -> OnCancel<'_> {
+        let key = self.0.next_waker.fetch_add(1, Ordering::Relaxed);
+        {
+            // cancel() sets the flag before taking the wakers, so either it sees this one or
+            // the flag is seen here
+            let mut wakers = self.0.wakers.lock().unwrap();
+            if !self.is_cancelled() {
+                wakers.insert(key, Box::new(waker));
+                return OnCancel { token: self, key };
+            }
+        }
+        waker();
+        OnCancel { token: self, key }
+    }
+
+    /**
+     * Error of a call that was cancelled
+     */
+    pub(crate) fn error(&self) -> PtyError {
+        PtyError::with_kind("Cancelled", std::io::ErrorKind::Interrupted)
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancellationToken").field("cancelled", &self.is_cancelled()).finish()
+    }
+}
+
+/**
+ * Registration of a waker, see CancellationToken::on_cancel()
+ */
+pub(crate) struct OnCancel<'a> {
+    token: &'a CancellationToken,
+    key: u64,
+}
+
+impl Drop for OnCancel<'_> {
+    fn drop(&mut self) {
+        self.token.0.wakers.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::test_util;
+    use super::*;
+
+    #[test]
+    fn cancel_before_wait() -> Result<(), Box<dyn Error>> {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let (tx, rx) = mpsc::channel();
+        let _waker = cancel.on_cancel(move || { let _ = tx.send(()); });
+        assert!(rx.try_recv().is_ok());
+
+        // a blocking call returns right away, the session is left alone
+        let pty = test_util::stub().spawn(|_id, _res| {}, |_id| {})?;
+        let err = pty.wait(&cancel).unwrap_err();
+        assert_eq!(PtyError::copy_of(err.as_ref()).kind(), std::io::ErrorKind::Interrupted);
+        assert!(pty.is_alive());
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_wakes_waiter() -> Result<(), Box<dyn Error>> {
+        let pty = test_util::stub().spawn(|_id, _res| {}, |_id| {})?;
+        let cancel = CancellationToken::new();
+        let cancel_async = cancel.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel_async.cancel();
+        });
+        assert!(pty.wait(&cancel).is_err());
+        canceller.join().unwrap();
+        assert!(pty.is_alive());
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn clones_share_cancellation() {
+        let cancel = CancellationToken::new();
+        let clone = cancel.clone();
+        let (tx, rx) = mpsc::channel();
+        let _waker = cancel.on_cancel(move || { let _ = tx.send(()); });
+        // a waker gone with its guard is not run
+        let (dropped_tx, dropped_rx) = mpsc::channel();
+        drop(clone.on_cancel(move || { let _ = dropped_tx.send(()); }));
+        assert!(!cancel.is_cancelled());
+
+        clone.cancel();
+        assert!(cancel.is_cancelled());
+        assert!(rx.try_recv().is_ok());
+        assert!(dropped_rx.try_recv().is_err());
+        // once cancelled a token stays cancelled, wakers run once
+        clone.cancel();
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! C API for frontends not written in Rust, e.g. Swift apps or native modules of Electron,
+//! built with `cargo rustc --release --features capi --crate-type cdylib` (or `staticlib`),
+//! declared by include/pty_exec.h, which is generated with `cbindgen --output include/pty_exec.h`
+//! a pty is identified by the fd of its master, like with Pty::from_raw_fd(), functions
+//! returning an int return -1 on failure, pty_exec_last_error() then tells why
+//! ```c
+//! #include <stdio.h>
+//! #include "pty_exec.h"
+//!
+//! static void on_output(void *user_data, int pty, const char *data, size_t len) {
+//!     fwrite(data, 1, len, stdout);
+//! }
+//!
+//! int main(void) {
+//!     PtyExecCallbacks callbacks = { .user_data = NULL, .on_output = on_output };
+//!     int pty = pty_exec_spawn(&callbacks);
+//!     if (pty < 0) {
+//!         fprintf(stderr, "%s\n", pty_exec_last_error());
+//!         return 1;
+//!     }
+//!     pty_exec_write(pty, "echo hi\r", 8);
+//!     pty_exec_shutdown(pty);
+//!     return 0;
+//! }
+//! ```
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::os::fd::{FromRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use nix::sys::signal::Signal;
+use crate::error::PtyError;
+use crate::handler::PtyHandler;
+use crate::id::PtyId;
+use crate::unix::window::WindowSize;
+use crate::Pty;
+
+/// Callbacks of a pty, called on the thread reading the pty with user_data as it was given,
+/// a callback left NULL is not called, text is UTF-8 and not NUL terminated
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PtyExecCallbacks {
+    pub user_data: *mut c_void,
+    /// output of the pty
+    pub on_output: Option<unsafe extern "C" fn(user_data: *mut c_void, pty: c_int, data: *const c_char, len: usize)>,
+    /// the child set the window title
+    pub on_title: Option<unsafe extern "C" fn(user_data: *mut c_void, pty: c_int, title: *const c_char, len: usize)>,
+    /// the pty died, no other callback follows
+    pub on_exit: Option<unsafe extern "C" fn(user_data: *mut c_void, pty: c_int)>,
+}
+
+// user_data is the caller's to make safe to use from the reading thread
+unsafe impl Send for PtyExecCallbacks {}
+
+type Shared = Arc<Mutex<PtyExecCallbacks>>;
+
+// callbacks of the ptys spawned through the C API, by fd, so they can be replaced
+static CALLBACKS: Mutex<Option<HashMap<RawFd, Shared>>> = Mutex::new(None);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+struct Handler(Shared);
+
+impl PtyHandler for Handler {
+    fn on_output(&mut self, id: PtyId, output: String) {
+        let callbacks = *self.0.lock().unwrap();
+        if let Some(on_output) = callbacks.on_output {
+            unsafe { on_output(callbacks.user_data, id.fd(), output.as_ptr().cast(), output.len()) }
+        }
+    }
+
+    fn on_title(&mut self, id: PtyId, title: String) {
+        let callbacks = *self.0.lock().unwrap();
+        if let Some(on_title) = callbacks.on_title {
+            unsafe { on_title(callbacks.user_data, id.fd(), title.as_ptr().cast(), title.len()) }
+        }
+    }
+
+    fn on_exit(&mut self, id: PtyId) {
+        if let Some(callbacks) = CALLBACKS.lock().unwrap().as_mut() {
+            callbacks.remove(&id.fd());
+        }
+        let callbacks = *self.0.lock().unwrap();
+        if let Some(on_exit) = callbacks.on_exit {
+            unsafe { on_exit(callbacks.user_data, id.fd()) }
+        }
+    }
+}
+
+/**
+ * Runs f, a failure or panic is kept for pty_exec_last_error() and turned into -1
+ */
+fn ffi(f: impl FnOnce() -> Result<c_int, Box<dyn Error>>) -> c_int {
+    let err = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(res)) => return res,
+        Ok(Err(err)) => err.to_string(),
+        Err(_) => "Panic in pty-exec".to_owned(),
+    };
+    let err = CString::new(err.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = err);
+    -1
+}
+
+fn callbacks(callbacks: *const PtyExecCallbacks) -> PtyExecCallbacks {
+    match unsafe { callbacks.as_ref() } {
+        Some(callbacks) => *callbacks,
+        None => PtyExecCallbacks { user_data: std::ptr::null_mut(), on_output: None, on_title: None, on_exit: None },
+    }
+}
+
+/// spawns a pty running the user's shell, returns its fd, callbacks may be NULL and are copied
+#[no_mangle]
+pub extern "C" fn pty_exec_spawn(callbacks: *const PtyExecCallbacks) -> c_int {
+    let shared = Arc::new(Mutex::new(self::callbacks(callbacks)));
+    ffi(|| {
+        let pty = Pty::spawn_handler(Handler(shared.clone()))?;
+        let fd = pty.id().fd();
+        CALLBACKS.lock().unwrap().get_or_insert_with(HashMap::new).insert(fd, shared);
+        Ok(fd)
+    })
+}
+
+/// replaces the callbacks of a pty spawned with pty_exec_spawn(), callbacks may be NULL
+#[no_mangle]
+pub extern "C" fn pty_exec_set_callbacks(pty: c_int, callbacks: *const PtyExecCallbacks) -> c_int {
+    let callbacks = self::callbacks(callbacks);
+    ffi(|| match CALLBACKS.lock().unwrap().as_ref().and_then(|shared| shared.get(&pty)) {
+        Some(shared) => {
+            *shared.lock().unwrap() = callbacks;
+            Ok(0)
+        },
+        None => Err(Box::new(PtyError::new(format!("No pty spawned on fd {pty}"))))
+    })
+}
+
+/// writes len bytes of UTF-8 at data to the pty like Pty::write()
+#[no_mangle]
+pub extern "C" fn pty_exec_write(pty: c_int, data: *const c_char, len: usize) -> c_int {
+    ffi(|| {
+        if data.is_null() && len > 0 {
+            return Err(Box::new(PtyError::new("Data is NULL")));
+        }
+        let data = if len == 0 { &[] } else { unsafe { std::slice::from_raw_parts(data.cast::<u8>(), len) } };
+        unsafe { Pty::from_raw_fd(pty) }.write(std::str::from_utf8(data)?)?;
+        Ok(0)
+    })
+}
+
+/// resizes the pty like Pty::resize()
+#[no_mangle]
+pub extern "C" fn pty_exec_resize(pty: c_int, rows: u16, cols: u16) -> c_int {
+    ffi(|| {
+        unsafe { Pty::from_raw_fd(pty) }.resize(WindowSize::new(rows, cols, 0, 0))?;
+        Ok(0)
+    })
+}
+
+/// sends signal to the child's process group like Pty::signal()
+#[no_mangle]
+pub extern "C" fn pty_exec_signal(pty: c_int, signal: c_int) -> c_int {
+    ffi(|| {
+        unsafe { Pty::from_raw_fd(pty) }.signal(Signal::try_from(signal)?)?;
+        Ok(0)
+    })
+}
+
+/// shuts the pty down like Pty::shutdown(), blocks until the child is gone
+#[no_mangle]
+pub extern "C" fn pty_exec_shutdown(pty: c_int) -> c_int {
+    ffi(|| {
+        unsafe { Pty::from_raw_fd(pty) }.shutdown()?;
+        Ok(0)
+    })
+}
+
+/// message of the last failure on this thread, valid until the next call failing on it
+#[no_mangle]
+pub extern "C" fn pty_exec_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    #[derive(Default)]
+    struct Seen {
+        output: String,
+        exited: bool,
+    }
+
+    unsafe extern "C" fn on_output(user_data: *mut c_void, _pty: c_int, data: *const c_char, len: usize) {
+        let seen = &*(user_data as *const Mutex<Seen>);
+        let data = std::slice::from_raw_parts(data.cast::<u8>(), len);
+        seen.lock().unwrap().output.push_str(std::str::from_utf8(data).unwrap());
+    }
+
+    unsafe extern "C" fn on_exit(user_data: *mut c_void, _pty: c_int) {
+        (*(user_data as *const Mutex<Seen>)).lock().unwrap().exited = true;
+    }
+
+    #[test]
+    fn spawn_write_shutdown() {
+        let seen: &'static Mutex<Seen> = Box::leak(Box::new(Mutex::new(Seen::default())));
+        let callbacks = PtyExecCallbacks {
+            user_data: seen as *const Mutex<Seen> as *mut c_void,
+            on_output: Some(on_output),
+            on_title: None,
+            on_exit: None,
+        };
+        let pty = pty_exec_spawn(&callbacks);
+        assert!(pty >= 0);
+        assert_eq!(pty_exec_set_callbacks(pty, &PtyExecCallbacks { on_exit: Some(on_exit), ..callbacks }), 0);
+
+        let input = "echo \"capi-$((1 + 1))\"\r";
+        assert_eq!(pty_exec_write(pty, input.as_ptr().cast(), input.len()), 0);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !seen.lock().unwrap().output.contains("capi-2") {
+            assert!(Instant::now() < deadline, "{:?}", seen.lock().unwrap().output);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(pty_exec_shutdown(pty), 0);
+        assert!(seen.lock().unwrap().exited);
+        assert_eq!(pty_exec_set_callbacks(pty, std::ptr::null()), -1);
+        let err = unsafe { std::ffi::CStr::from_ptr(pty_exec_last_error()) };
+        assert!(err.to_str().unwrap().ends_with(&format!("No pty spawned on fd {pty}")));
+    }
+}
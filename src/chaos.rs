@@ -0,0 +1,166 @@
+//! Artificial pty timing for testing code built on a pty, enabled with the `chaos` feature
+//! output is delayed and split where real reads may split it, writes go missing and the exit
+//! is reported late, deterministically, so a consumer's handling of each can be tested
+//! without hoping for a slow machine, see PtyBuilder::chaos()
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//! use pty_exec::chaos::{Chaos, Split};
+//!
+//! let pty = Pty::builder()
+//!     .chaos(Chaos::new()
+//!         .read_delay(Duration::from_millis(5))
+//!         .split(Split::MidUtf8)
+//!         .split(Split::MidEscape)
+//!         .exit_delay(Duration::from_millis(100)))
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//! pty.write("printf '\\033[1mgrüß\\033[0m\\n'\r")?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where output is split into chunks of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Split {
+    /// after every n bytes
+    Every(usize),
+    /// after the first byte of every character taking more than one, the halves show up as
+    /// U+FFFD, as they do when a read ends inside a character
+    MidUtf8,
+    /// right after the ESC of every escape sequence
+    MidEscape,
+}
+
+/// Disturbances of a pty, none by default
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    read_delay: Duration,
+    splits: Vec<Split>,
+    drop_writes: u64,
+    exit_delay: Duration,
+    // writes so far, shared by the copies of the config of a session
+    writes: Arc<AtomicU64>,
+}
+
+impl Chaos {
+    pub fn new() -> Chaos {
+        Chaos::default()
+    }
+
+    /// how long the polling thread sleeps before delivering each chunk of output
+    pub fn read_delay(mut self, delay: Duration) -> Chaos {
+        self.read_delay = delay;
+        self
+    }
+
+    /// split output here as well, splits add up
+    pub fn split(mut self, split: Split) -> Chaos {
+        self.splits.push(split);
+        self
+    }
+
+    /// drop every nth write, it is reported as written, 1 drops them all, 0 none
+    pub fn drop_writes(mut self, every: u64) -> Chaos {
+        self.drop_writes = every;
+        self
+    }
+
+    /// how long the exit of the child goes unreported after it was reaped
+    pub fn exit_delay(mut self, delay: Duration) -> Chaos {
+        self.exit_delay = delay;
+        self
+    }
+
+    pub(crate) fn read_delay_of(&self) -> Duration {
+        self.read_delay
+    }
+
+    pub(crate) fn exit_delay_of(&self) -> Duration {
+        self.exit_delay
+    }
+
+    /**
+     * Counts a write, returns whether it is dropped
+     */
+    pub(crate) fn drops_write(&self) -> bool {
+        let n = self.writes.fetch_add(1, Ordering::Relaxed) + 1;
+        n.is_multiple_of(self.drop_writes)
+    }
+
+    /**
+     * output split where the splits say, every piece decoded on its own like a read
+     */
+    pub(crate) fn pieces(&self, output: &str) -> Vec<String> {
+        let mut cuts = Vec::new();
+        for split in &self.splits {
+            match *split {
+                Split::Every(n) if n > 0 => cuts.extend((n..output.len()).step_by(n)),
+                Split::Every(_) => {},
+                Split::MidUtf8 => cuts.extend(output.char_indices().filter(|(_, c)| c.len_utf8() > 1).map(|(at, _)| at + 1)),
+                Split::MidEscape => cuts.extend(output.match_indices('\x1b').map(|(at, _)| at + 1)),
+            }
+        }
+        cuts.sort_unstable();
+        cuts.dedup();
+
+        let bytes = output.as_bytes();
+        let mut start = 0;
+        let mut pieces = Vec::with_capacity(cuts.len() + 1);
+        for cut in cuts.into_iter().chain([bytes.len()]) {
+            if cut > start {
+                pieces.push(String::from_utf8_lossy(&bytes[start..cut]).into_owned());
+                start = cut;
+            }
+        }
+        pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use crate::cancel::CancellationToken;
+    use crate::{test_util, Pty};
+    use super::*;
+
+    #[test]
+    fn disturbed() -> Result<(), Box<dyn Error>> {
+        let chaos = Chaos::new().split(Split::MidUtf8).split(Split::MidEscape);
+        assert_eq!(chaos.pieces("aü\x1b[1mb"), ["a\u{fffd}", "\u{fffd}\x1b", "[1mb"]);
+        assert_eq!(Chaos::new().split(Split::Every(2)).pieces("abcde"), ["ab", "cd", "e"]);
+        assert_eq!(Chaos::new().pieces("abc"), ["abc"]);
+
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let sink = chunks.clone();
+        let pty = Pty::builder()
+            .scrollback(0x10000)
+            .chaos(Chaos::new()
+                .read_delay(Duration::from_millis(1))
+                .split(Split::Every(3))
+                .drop_writes(2)
+                .exit_delay(Duration::from_millis(300)))
+            .spawn(move |_id, res| sink.lock().unwrap().push(res.unwrap()), |_id| {})?;
+        pty.write("echo \"one-$((1 + 1))\"\r")?;
+        pty.write("echo \"two-$((1 + 1))\"\r")?;
+        pty.write("echo \"three-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "three-2", Duration::from_secs(10))?;
+        let output = pty.scrollback()?;
+        assert!(output.contains("one-2") && !output.contains("two-2"));
+        // control strings, e.g. of the prompt, are joined again
+        assert!(chunks.lock().unwrap().iter().filter(|chunk| !chunk.contains('\x1b')).all(|chunk| chunk.len() <= 3));
+
+        // the fourth write is dropped, the fifth makes it
+        pty.write("\r")?;
+        let exited = Instant::now();
+        pty.write("exit\r")?;
+        pty.wait(&CancellationToken::new())?;
+        assert!(exited.elapsed() >= Duration::from_millis(300));
+        Ok(())
+    }
+}
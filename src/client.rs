@@ -0,0 +1,460 @@
+//! Client side of the attach protocol, a session served by server::Server used like a local pty
+//! ```rust,no_run
+//! use pty_exec::{PtyHandler, PtyId, Terminal};
+//! use pty_exec::client::Client;
+//! use pty_exec::server::default_socket_path;
+//!
+//! struct Print;
+//!
+//! impl PtyHandler for Print {
+//!     fn on_output(&mut self, _id: PtyId, output: String) {
+//!         print!("{output}");
+//!     }
+//! }
+//!
+//! let client = Client::connect(default_socket_path(), "main", Print)?;
+//! client.write("echo 'Hello, World'\r")?;
+//! // the session keeps running on the server
+//! client.detach()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use nix::sys::signal::Signal;
+use crate::compress::{Codec, Decoder};
+use crate::error::PtyError;
+use crate::handler::{contain, Callbacks, PtyHandler};
+use crate::id::PtyId;
+use crate::message::Message;
+use crate::protocol::{read_frame, write_frame, Frame};
+use crate::registry;
+use crate::scanner::Scanner;
+use crate::sequence::DEFAULT_MAX_LEN;
+use crate::scrollback::Cursor;
+use crate::unix::window::WindowSize;
+use crate::Terminal;
+
+/// Where a client left off in the output of a session, pass it to Client::resume() to reattach
+/// and get the output missed in between, the string form is `<cursor>:<session>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResumeToken {
+    session: String,
+    cursor: Cursor,
+}
+
+impl ResumeToken {
+    pub fn new(session: &str, cursor: Cursor) -> ResumeToken {
+        ResumeToken { session: session.to_owned(), cursor }
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// position right after the last output the client received
+    pub fn cursor(&self) -> Cursor {
+        self.cursor
+    }
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.cursor.offset(), self.session)
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = PtyError;
+
+    fn from_str(s: &str) -> Result<ResumeToken, PtyError> {
+        let invalid = || PtyError::with_kind(format!("Invalid resume token {s:?}"), io::ErrorKind::InvalidInput);
+        let (cursor, session) = s.split_once(':').ok_or_else(invalid)?;
+        let cursor = cursor.parse().map_err(|_| invalid())?;
+        Ok(ResumeToken::new(session, Cursor::new(cursor)))
+    }
+}
+
+/// Connection to a served session, events of the session are passed to a PtyHandler
+/// exactly like for a local pty, dropping the client detaches it
+pub struct Client {
+    id: PtyId,
+    session: String,
+    stream: Arc<Mutex<UnixStream>>,
+    detached: Arc<AtomicBool>,
+    // offset of the next output byte
+    cursor: Arc<AtomicU64>,
+    // number of the last write_predicted()
+    predicted: AtomicU64,
+}
+
+impl Client {
+    /// attaches to the session called name on the server listening on path,
+    /// the server spawns the session if it has none by that name
+    pub fn connect<H: PtyHandler>(path: impl AsRef<Path>, name: &str, handler: H) -> Result<Client, Box<dyn Error>> {
+        Client::open(path, None, false, Frame::Attach { session: name.to_owned() }, handler)
+    }
+
+    /// connect() presenting token to the server's authenticator, see auth::Tokens
+    pub fn connect_with_token<H: PtyHandler>(path: impl AsRef<Path>, name: &str, token: &str, handler: H) -> Result<Client, Box<dyn Error>> {
+        Client::open(path, Some(token), false, Frame::Attach { session: name.to_owned() }, handler)
+    }
+
+    /// reattaches to the session of token, the output since the token was taken is passed to
+    /// on_output first, if the scrollback of the session no longer has all of it on_error
+    /// reports how much was lost, fails if the session is gone
+    pub fn resume<H: PtyHandler>(path: impl AsRef<Path>, token: &ResumeToken, handler: H) -> Result<Client, Box<dyn Error>> {
+        Client::open(path, None, false, Frame::Resume { session: token.session.clone(), cursor: token.cursor.offset() }, handler)
+    }
+
+    /// connect() getting the rows of the screen that changed in place of output, passed to
+    /// on_screen_diff, the first diff has the whole screen, for clients on a slow link to a
+    /// session redrawing much of the screen, see vt::ScreenMirror
+    #[cfg(feature = "vt")]
+    pub fn connect_diffs<H: PtyHandler>(path: impl AsRef<Path>, name: &str, handler: H) -> Result<Client, Box<dyn Error>> {
+        Client::open(path, None, true, Frame::Attach { session: name.to_owned() }, handler)
+    }
+
+    /// connect_diffs() presenting token to the server's authenticator
+    #[cfg(feature = "vt")]
+    pub fn connect_diffs_with_token<H: PtyHandler>(path: impl AsRef<Path>, name: &str, token: &str, handler: H) -> Result<Client, Box<dyn Error>> {
+        Client::open(path, Some(token), true, Frame::Attach { session: name.to_owned() }, handler)
+    }
+
+    /// resume() presenting token to the server's authenticator
+    pub fn resume_with_token<H: PtyHandler>(path: impl AsRef<Path>, resume: &ResumeToken, token: &str, handler: H) -> Result<Client, Box<dyn Error>> {
+        Client::open(path, Some(token), false, Frame::Resume { session: resume.session.clone(), cursor: resume.cursor.offset() }, handler)
+    }
+
+    fn open<H: PtyHandler>(path: impl AsRef<Path>, token: Option<&str>, diffs: bool, attach: Frame, mut handler: H) -> Result<Client, Box<dyn Error>> {
+        let mut stream = UnixStream::connect(path)?;
+        // ids of clients are unique among ptys and clients alike
+        let id = PtyId::new(stream.as_raw_fd(), registry::next_generation());
+
+        if let Some(token) = token {
+            write_frame(&mut stream, &Frame::Auth(token.to_owned()))?;
+        }
+        // servers that do not compress answer with no codec
+        let codecs = Codec::available();
+        if !codecs.is_empty() {
+            write_frame(&mut stream, &Frame::Compress(codecs))?;
+        }
+        // only connect_diffs() asks for diffs, it comes with the vt feature
+        if diffs {
+            #[cfg(feature = "vt")]
+            write_frame(&mut stream, &Frame::ScreenDiffs)?;
+        }
+        write_frame(&mut stream, &attach)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut answer = read_frame(&mut reader)?;
+        let mut decoder = None;
+        if let Some(Frame::Compress(chosen)) = &answer {
+            decoder = chosen.first().copied().map(Decoder::new).transpose()?;
+            answer = read_frame(&mut reader)?;
+        }
+        let (session, cursor, missed) = match answer {
+            Some(Frame::Attached { session, cursor, missed }) => (session, cursor, missed),
+            Some(Frame::Error(err)) => return Err(Box::new(PtyError::new(err))),
+            frame => return Err(Box::new(PtyError::new(format!("Unexpected answer to Attach: {frame:?}"))))
+        };
+        if missed > 0 {
+            contain(&mut handler, id, |handler| handler.on_error(id, Box::new(PtyError::new(format!("{missed} bytes of output were lost while detached")))));
+        }
+
+        let stream = Arc::new(Mutex::new(stream));
+        let stream_async = stream.clone();
+        let detached = Arc::new(AtomicBool::new(false));
+        let detached_async = detached.clone();
+        let cursor = Arc::new(AtomicU64::new(cursor));
+        let cursor_async = cursor.clone();
+        thread::Builder::new().name(format!("pty-exec/client={id}")).spawn(move || {
+            let mut scanner = Scanner::new(DEFAULT_MAX_LEN);
+            loop {
+                let frame = match read_frame(&mut reader).and_then(|frame| decompress(&mut decoder, frame)) {
+                    Ok(Some(frame)) => frame,
+                    // a hang up after detaching is expected
+                    _ if detached_async.load(Ordering::Relaxed) => break,
+                    Ok(None) => {
+                        contain(&mut handler, id, |handler| handler.on_error(id, Box::new(PtyError::new("Connection closed by server"))));
+                        break;
+                    },
+                    Err(err) => {
+                        contain(&mut handler, id, |handler| handler.on_error(id, Box::new(PtyError::from(err))));
+                        break;
+                    }
+                };
+
+                match frame {
+                    Frame::Output(output) => {
+                        cursor_async.fetch_add(output.len() as u64, Ordering::Relaxed);
+                        let sequences = scanner.scan(&output);
+                        contain(&mut handler, id, |handler| handler.on_output(id, output));
+                        for sequence in sequences {
+                            sequence.notify(&mut handler, id);
+                        }
+                    },
+                    #[cfg(feature = "vt")]
+                    Frame::Screen(diff) => contain(&mut handler, id, |handler| handler.on_screen_diff(id, diff)),
+                    Frame::Message(message) => contain(&mut handler, id, |handler| handler.on_message(id, None, message)),
+                    Frame::Echo { seq, echoed } => contain(&mut handler, id, |handler| handler.on_echo(id, seq, echoed)),
+                    Frame::Resized(rows, cols) => {
+                        contain(&mut handler, id, |handler| handler.on_resize_ack(id, WindowSize::new(rows, cols, 0, 0)))
+                    },
+                    Frame::Error(err) => {
+                        contain(&mut handler, id, |handler| handler.on_error(id, Box::new(PtyError::new(err))))
+                    },
+                    Frame::Exited => {
+                        contain(&mut handler, id, |handler| handler.on_exit(id));
+                        break;
+                    },
+                    // a server with keepalive detaches clients that stop answering
+                    Frame::Ping => {
+                        let _ = write_frame(&mut *stream_async.lock().unwrap(), &Frame::Pong);
+                    },
+                    _ => {}
+                }
+            }
+        })?;
+
+        Ok(Client { id, session, stream, detached, cursor, predicted: AtomicU64::new(0) })
+    }
+
+    /// connect() with on_read/on_death closures like Pty::spawn()
+    pub fn connect_callbacks<F, G>(path: impl AsRef<Path>, name: &str, on_read: F, on_death: G) -> Result<Client, Box<dyn Error>>
+        where
+            F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+            G: FnMut(PtyId) + Send + 'static
+    {
+        Client::connect(path, name, Callbacks { on_read, on_death })
+    }
+
+    /// name of the session
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// token to resume() this client later, taken right after the output received so far
+    pub fn resume_token(&self) -> ResumeToken {
+        ResumeToken::new(&self.session, Cursor::new(self.cursor.load(Ordering::Relaxed)))
+    }
+
+    /// detach from the session, which keeps running on the server
+    pub fn detach(self) -> Result<(), Box<dyn Error>> {
+        self.detached.store(true, Ordering::Relaxed);
+        self.send(&Frame::Detach)
+    }
+
+    /// write s to be shown right away rather than once its echo makes the round trip, for a
+    /// slow link, mosh-style: returns the number of the prediction, the caller shows s marked
+    /// as predicted, e.g. underlined, until on_echo settles it, `None` if s has control
+    /// characters, it is written like write() then, see Pty::write_predicted()
+    pub fn write_predicted(&self, s: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        if s.is_empty() || s.chars().any(char::is_control) {
+            return self.write(s).map(|_| None);
+        }
+        let seq = self.predicted.fetch_add(1, Ordering::Relaxed) + 1;
+        self.send(&Frame::PredictedInput { seq, input: s.to_owned() })?;
+        Ok(Some(seq))
+    }
+
+    /// send message to the application hosting the session, it reaches its handler's
+    /// on_message, see the message module
+    pub fn send_message(&self, message: Message) -> Result<(), Box<dyn Error>> {
+        self.send(&Frame::Message(message))
+    }
+
+    fn send(&self, frame: &Frame) -> Result<(), Box<dyn Error>> {
+        write_frame(&mut *self.stream.lock().unwrap(), frame)?;
+        Ok(())
+    }
+}
+
+/**
+ * A Compressed frame as the Output frame it was
+ */
+fn decompress(decoder: &mut Option<Decoder>, frame: Option<Frame>) -> io::Result<Option<Frame>> {
+    match (decoder, frame) {
+        (Some(decoder), Some(Frame::Compressed(data))) => {
+            let output = String::from_utf8(decoder.decode(&data)?)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Compressed frame is not valid UTF-8"))?;
+            Ok(Some(Frame::Output(output)))
+        },
+        (None, Some(Frame::Compressed(_))) => Err(io::Error::new(io::ErrorKind::InvalidData, "Compressed frame without compression")),
+        (_, frame) => Ok(frame)
+    }
+}
+
+impl Terminal for Client {
+    fn id(&self) -> PtyId {
+        self.id
+    }
+
+    fn write(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        self.send(&Frame::Input(s.to_owned()))
+    }
+
+    /// the size of this client, the session's size follows the server's resize policy
+    fn resize(&self, window_size: WindowSize) -> Result<(), Box<dyn Error>> {
+        self.send(&Frame::Resize(window_size))
+    }
+
+    fn signal(&self, signal: Signal) -> Result<(), Box<dyn Error>> {
+        self.send(&Frame::Signal(signal as i32))
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.detached.store(true, Ordering::Relaxed);
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+    use crate::server::Server;
+
+    #[derive(Default, Clone)]
+    struct Log(Arc<Mutex<Vec<String>>>);
+
+    impl PtyHandler for Log {
+        fn on_output(&mut self, _id: PtyId, output: String) {
+            self.0.lock().unwrap().push(output);
+        }
+
+        fn on_exit(&mut self, _id: PtyId) {
+            self.0.lock().unwrap().push("[exited]".into());
+        }
+
+        fn on_resize_ack(&mut self, _id: PtyId, size: WindowSize) {
+            self.0.lock().unwrap().push(format!("resize {}x{}", size.rows(), size.cols()));
+        }
+
+        fn on_echo(&mut self, _id: PtyId, seq: u64, echoed: bool) {
+            self.0.lock().unwrap().push(format!("[echo {seq} {echoed}]"));
+        }
+    }
+
+    impl Log {
+        fn wait_for(&self, s: &str) -> bool {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !self.0.lock().unwrap().concat().contains(s) {
+                if Instant::now() > deadline { return false }
+                thread::sleep(Duration::from_millis(10));
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn remote_like_local() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-execd-client-{}.sock", std::process::id()));
+        let server = Server::bind(&path)?;
+        thread::spawn(move || { let _ = server.run(); });
+
+        let log = Log::default();
+        let client = Client::connect(&path, "main", log.clone())?;
+        client.write("echo \"remote-$((1 + 1))\"\r")?;
+        assert!(log.wait_for("remote-2\r\n"));
+        client.resize(WindowSize::new(30, 90, 0, 0))?;
+        assert!(log.wait_for("resize 30x90"));
+        client.detach()?;
+
+        let log = Log::default();
+        let client = Client::connect(&path, "main", log.clone())?;
+        client.write("exit\r")?;
+        assert!(log.wait_for("[exited]"));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn predicted_echo() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-execd-predicted-{}.sock", std::process::id()));
+        let server = Server::bind(&path)?;
+        thread::spawn(move || { let _ = server.run(); });
+
+        let log = Log::default();
+        let client = Client::connect(&path, "predicted", log.clone())?;
+        client.write("echo \"ready-$((1 + 1))\"\r")?;
+        assert!(log.wait_for("ready-2\r\n"));
+        assert_eq!(client.write_predicted("echo predicted")?, Some(1));
+        assert!(log.wait_for("predicted[echo 1 true]"));
+        assert_eq!(client.write_predicted("\r")?, None);
+
+        // nothing is echoed at a password prompt
+        client.write("stty -echo; echo \"hidden-$((1 + 1))\"; cat\r")?;
+        assert!(log.wait_for("hidden-2\r\n"));
+        assert_eq!(client.write_predicted("secret")?, Some(2));
+        assert!(log.wait_for("[echo 2 false]"));
+        client.write("\r\x04exit\r")?;
+        assert!(log.wait_for("[exited]"));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[cfg(any(feature = "deflate", feature = "zstd"))]
+    #[test]
+    fn compressed() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-execd-compressed-{}.sock", std::process::id()));
+        let server = Server::bind(&path)?.compress(Codec::available());
+        thread::spawn(move || { let _ = server.run(); });
+
+        let log = Log::default();
+        let client = Client::connect(&path, "compressed", log.clone())?;
+        client.write("for i in 1 2 3; do echo \"line-$i-$((1 + 1))\"; done\r")?;
+        assert!(log.wait_for("line-3-2\r\n"));
+        client.write("exit\r")?;
+        assert!(log.wait_for("[exited]"));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resume() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-execd-resume-{}.sock", std::process::id()));
+        let server = Server::bind(&path)?;
+        thread::spawn(move || { let _ = server.run(); });
+
+        let client = Client::connect(&path, "resumed", Log::default())?;
+        let token = client.resume_token();
+        client.detach()?;
+        assert_eq!(token.to_string().parse::<ResumeToken>()?, token);
+
+        // output produced while nobody is attached
+        let log = Log::default();
+        let other = Client::connect(&path, "resumed", log.clone())?;
+        other.write("echo \"missed-$((1 + 1))\"\r")?;
+        assert!(log.wait_for("missed-2\r\n"));
+        other.detach()?;
+
+        let log = Log::default();
+        let client = Client::resume(&path, &token, log.clone())?;
+        assert!(log.wait_for("missed-2\r\n"));
+        assert!(client.resume_token().cursor() > token.cursor());
+        client.write("exit\r")?;
+        assert!(log.wait_for("[exited]"));
+
+        // a dead session cannot be resumed, it may take a moment to be reaped after on_exit
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Client::resume(&path, &token, Log::default()).is_ok() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
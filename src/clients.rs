@@ -0,0 +1,472 @@
+//! Clients attached to a pty, e.g. several windows showing the same session
+//! every client receives the output of the pty and reports its own size, the size of the
+//! pty itself is decided by the ResizePolicy of the pty and announced to all clients
+//! ```rust
+//! use pty_exec::{Pty, WindowSize};
+//! use pty_exec::clients::{ClientEvent, ResizePolicy};
+//!
+//! let pty = Pty::builder()
+//!     .resize_policy(ResizePolicy::Smallest)
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//!
+//! let laptop = pty.attach(|event| if let ClientEvent::Resized(rows, cols) = event {
+//!     println!("laptop now shows {rows}x{cols}");
+//! })?;
+//! let phone = pty.attach(|_event| {})?;
+//!
+//! pty.client_resize(laptop, WindowSize::new(50, 200, 0, 0))?;
+//! // the smallest client wins
+//! assert_eq!(pty.client_resize(phone, WindowSize::new(30, 60, 0, 0))?, WindowSize::new(30, 60, 0, 0));
+//!
+//! pty.detach(phone)?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::echo::Predictions;
+use crate::message::Message;
+use crate::unix::window::WindowSize;
+
+/// Identifies a client attached to a pty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientId(u64);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "client-{}", self.0)
+    }
+}
+
+/// Events delivered to attached clients, in the order they happened, see Pty::attach_sequenced()
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClientEvent {
+    /// output of the pty
+    Output(String),
+    /// the pty now has rows and cols
+    Resized(u16, u16),
+    /// a message of the host, see Pty::send_message()
+    Message(Message),
+    /// to the client only, what became of the input it predicted the echo of, see
+    /// Pty::write_predicted(), right after the output holding the echo if echoed
+    Echo { seq: u64, echoed: bool },
+    /// the pty died, no other event follows
+    Exited,
+}
+
+/// How the size of a pty follows the sizes of its clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ResizePolicy {
+    /// the smallest rows and smallest cols of all clients, everyone sees the whole screen
+    #[default]
+    Smallest,
+    /// the size of the client that resized last
+    LastWriter,
+    /// always this size, client sizes are ignored
+    Fixed(WindowSize),
+}
+
+/// What happens to a pty when its last client detaches, nothing happens to a pty that never had one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DetachPolicy {
+    /// nothing, the session keeps running like a multiplexer's would
+    #[default]
+    KeepRunning,
+    /// SIGSTOP the process group, it gets SIGCONT when a client attaches again
+    Stop,
+    /// SIGHUP the process group unless a client attaches within the grace period
+    HangUp(Duration),
+    /// SIGKILL the process group at once
+    Kill,
+}
+
+/// Escape sequences kept from an attached client, so a client that is not the primary one,
+/// e.g. a read-only viewer in a browser, can't be attacked through sequences meant for the
+/// primary client's terminal, see Pty::attach_with()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OutputProfile {
+    /// output as is
+    #[default]
+    Trusted,
+    /// no clipboard access (OSC 52), file: hyperlinks (OSC 8) or title changes (OSC 0, 1 and 2)
+    Viewer,
+    /// no OSC, DCS, APC, PM or SOS strings at all, only text, controls and CSI sequences
+    Strict,
+}
+
+impl OutputProfile {
+    /// a filter sanitizing output by the profile chunk by chunk, e.g. for output replayed
+    /// with Pty::read_since() before the client attached
+    pub fn filter(self) -> OutputFilter {
+        OutputFilter { profile: self, state: FilterState::Ground, held: String::new(), escape: false }
+    }
+
+    /**
+     * Whether the string sequence seq, introducer included, is kept, `None` if that can't be
+     * told before more of it is seen, complete tells that seq ended
+     */
+    fn keeps(self, seq: &str, complete: bool) -> Option<bool> {
+        let osc = match self {
+            OutputProfile::Trusted => return Some(true),
+            OutputProfile::Strict => return Some(false),
+            OutputProfile::Viewer => match seq.strip_prefix("\x1b]").or_else(|| seq.strip_prefix('\u{9d}')) {
+                Some(osc) => osc,
+                None => return Some(true),
+            }
+        };
+        let Some((code, payload)) = osc.split_once(';') else {
+            return complete.then_some(true);
+        };
+        match code {
+            "0" | "1" | "2" | "52" => Some(false),
+            "8" => {
+                // OSC 8 ; params ; uri
+                let Some((_, uri)) = payload.split_once(';') else { return complete.then_some(true) };
+                let scheme = uri.get(..5).unwrap_or(uri).to_ascii_lowercase();
+                if scheme == "file:" {
+                    Some(false)
+                } else if !complete && "file:".starts_with(&scheme) {
+                    None
+                } else {
+                    Some(true)
+                }
+            },
+            _ => Some(true)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilterState {
+    Ground,
+    Escape,
+    // in a string sequence not known to be kept yet, held back
+    Held,
+    // in a string sequence passed on
+    Passing,
+    // in a string sequence left out
+    Dropping,
+}
+
+// longer string sequences still undecided are left out
+const MAX_HELD_LEN: usize = 0x1000;
+
+/// Sanitizes output by an OutputProfile, keeps state between chunks so sequences split
+/// across chunks are still recognized, see OutputProfile::filter()
+#[derive(Debug, Clone)]
+pub struct OutputFilter {
+    profile: OutputProfile,
+    state: FilterState,
+    held: String,
+    // an ESC was seen in a string sequence, either ending it or aborting it
+    escape: bool,
+}
+
+impl OutputFilter {
+    /// output with the sequences the profile leaves out taken out, a string sequence not
+    /// decided on yet at the end of output is held back until the next chunk
+    pub fn apply(&mut self, output: &str) -> String {
+        if self.profile == OutputProfile::Trusted {
+            return output.to_owned();
+        }
+        let mut filtered = String::with_capacity(output.len());
+        for c in output.chars() {
+            self.next(c, &mut filtered);
+        }
+        filtered
+    }
+
+    fn next(&mut self, c: char, out: &mut String) {
+        match self.state {
+            FilterState::Ground => match c {
+                '\x1b' => self.state = FilterState::Escape,
+                // C1 introducers of DCS, SOS, OSC, PM and APC
+                '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => self.start(c.to_string(), out),
+                c => out.push(c),
+            },
+            FilterState::Escape => match c {
+                'P' | 'X' | ']' | '^' | '_' => self.start(format!("\x1b{c}"), out),
+                c => {
+                    out.push('\x1b');
+                    self.state = FilterState::Ground;
+                    self.next(c, out);
+                }
+            },
+            _ if self.escape => {
+                self.escape = false;
+                if c == '\\' {
+                    self.end("\x1b\\", out);
+                } else {
+                    // any other escape aborts the string and starts a sequence of its own
+                    self.held.clear();
+                    self.state = FilterState::Escape;
+                    self.next(c, out);
+                }
+            },
+            _ => match c {
+                '\x1b' => self.escape = true,
+                // BEL ends an OSC, ST any string
+                '\x07' | '\u{9c}' => self.end(c.encode_utf8(&mut [0; 4]), out),
+                c => match self.state {
+                    FilterState::Held => {
+                        self.held.push(c);
+                        self.decide(false, out);
+                    },
+                    FilterState::Passing => out.push(c),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, introducer: String, out: &mut String) {
+        self.held = introducer;
+        self.state = FilterState::Held;
+        self.decide(false, out);
+    }
+
+    /**
+     * Passes on or leaves out the held string sequence once the profile can tell
+     */
+    fn decide(&mut self, complete: bool, out: &mut String) {
+        match self.profile.keeps(&self.held, complete) {
+            Some(true) => {
+                out.push_str(&self.held);
+                self.state = FilterState::Passing;
+            },
+            Some(false) => self.state = FilterState::Dropping,
+            None if self.held.len() > MAX_HELD_LEN => self.state = FilterState::Dropping,
+            None => return,
+        }
+        self.held.clear();
+    }
+
+    fn end(&mut self, terminator: &str, out: &mut String) {
+        if self.state == FilterState::Held {
+            self.decide(true, out);
+        }
+        if self.state == FilterState::Passing {
+            out.push_str(terminator);
+        }
+        self.held.clear();
+        self.state = FilterState::Ground;
+    }
+}
+
+pub(crate) type ClientCallback = Arc<Mutex<dyn FnMut(u64, ClientEvent) + Send>>;
+
+struct Client {
+    id: ClientId,
+    size: Option<WindowSize>,
+    on_event: ClientCallback,
+    predictions: Predictions,
+}
+
+/**
+ * Clients attached to one session
+ */
+#[derive(Default)]
+pub(crate) struct Clients {
+    next: u64,
+    attached: Vec<Client>,
+    last_writer: Option<WindowSize>,
+    // sequence number of the last event, see Pty::attach_sequenced()
+    seq: u64,
+    // stopped by DetachPolicy::Stop
+    pub stopped: bool,
+}
+
+impl Clients {
+    pub(crate) fn attach(&mut self, on_event: ClientCallback) -> ClientId {
+        let id = ClientId(self.next);
+        self.next += 1;
+        self.attached.push(Client { id, size: None, on_event, predictions: Predictions::default() });
+        id
+    }
+
+    pub(crate) fn detach(&mut self, id: ClientId) -> bool {
+        let len = self.attached.len();
+        self.attached.retain(|client| client.id != id);
+        self.attached.len() != len
+    }
+
+    pub(crate) fn is_attached(&self, id: ClientId) -> bool {
+        self.attached.iter().any(|client| client.id == id)
+    }
+
+    pub(crate) fn set_size(&mut self, id: ClientId, size: WindowSize) {
+        if let Some(client) = self.attached.iter_mut().find(|client| client.id == id) {
+            client.size = Some(size);
+            self.last_writer = Some(size);
+        }
+    }
+
+    pub(crate) fn predict(&mut self, id: ClientId, seq: u64, input: &str, now: Instant) {
+        if let Some(client) = self.attached.iter_mut().find(|client| client.id == id) {
+            client.predictions.expect(seq, input, now);
+        }
+    }
+
+    pub(crate) fn forget_prediction(&mut self, id: ClientId, seq: u64) {
+        if let Some(client) = self.attached.iter_mut().find(|client| client.id == id) {
+            client.predictions.forget(seq);
+        }
+    }
+
+    /**
+     * How long until the oldest prediction of any client times out
+     */
+    pub(crate) fn prediction_left(&self, now: Instant) -> Option<Duration> {
+        self.attached.iter().filter_map(|client| client.predictions.left(now)).min()
+    }
+
+    /**
+     * ClientEvent::Echo for every prediction output settles, numbered and with the callback
+     * of its client like next_event()
+     */
+    pub(crate) fn settle(&mut self, output: &str, now: Instant) -> Vec<((u64, Vec<ClientCallback>), ClientEvent)> {
+        let mut events = Vec::new();
+        for client in &mut self.attached {
+            for (seq, echoed) in client.predictions.settle(output, now) {
+                self.seq += 1;
+                events.push(((self.seq, vec![client.on_event.clone()]), ClientEvent::Echo { seq, echoed }));
+            }
+        }
+        events
+    }
+
+    /**
+     * Size the pty should have under policy, `None` if no client reported a size
+     */
+    pub(crate) fn effective_size(&self, policy: ResizePolicy) -> Option<WindowSize> {
+        match policy {
+            ResizePolicy::Fixed(size) => Some(size),
+            ResizePolicy::LastWriter => self.last_writer,
+            ResizePolicy::Smallest => self.attached.iter().filter_map(|client| client.size).reduce(|a, b| {
+                let (rows, cols) = (a.rows().min(b.rows()), a.cols().min(b.cols()));
+                // pixel sizes follow whichever client has the fewer cols
+                let (width, height) = if a.cols() <= b.cols() { (a.cell_width(), a.cell_height()) } else { (b.cell_width(), b.cell_height()) };
+                WindowSize::new(rows, cols, width, height)
+            }),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.attached.is_empty()
+    }
+
+    /**
+     * Number of clients ever attached, tells whether someone attached in the meantime
+     */
+    pub(crate) fn attachments(&self) -> u64 {
+        self.next
+    }
+
+    /**
+     * Sequence number of the next event and the callbacks of every client to deliver it to,
+     * so events can be delivered without holding the lock
+     */
+    pub(crate) fn next_event(&mut self) -> (u64, Vec<ClientCallback>) {
+        self.seq += 1;
+        (self.seq, self.attached.iter().map(|client| client.on_event.clone()).collect())
+    }
+
+    /**
+     * next_event() for an event of client id only, no callback if it is gone
+     */
+    pub(crate) fn next_event_for(&mut self, id: ClientId) -> (u64, Vec<ClientCallback>) {
+        self.seq += 1;
+        (self.seq, self.attached.iter().filter(|client| client.id == id).map(|client| client.on_event.clone()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies() {
+        let mut clients = Clients::default();
+        let a = clients.attach(Arc::new(Mutex::new(|_, _| {})));
+        let b = clients.attach(Arc::new(Mutex::new(|_, _| {})));
+        assert_eq!(clients.effective_size(ResizePolicy::Smallest), None);
+
+        clients.set_size(a, WindowSize::new(50, 100, 0, 0));
+        clients.set_size(b, WindowSize::new(60, 80, 0, 0));
+        assert_eq!(clients.effective_size(ResizePolicy::Smallest), Some(WindowSize::new(50, 80, 0, 0)));
+        assert_eq!(clients.effective_size(ResizePolicy::LastWriter), Some(WindowSize::new(60, 80, 0, 0)));
+
+        let fixed = WindowSize::new(24, 80, 0, 0);
+        assert_eq!(clients.effective_size(ResizePolicy::Fixed(fixed)), Some(fixed));
+
+        clients.detach(b);
+        assert_eq!(clients.effective_size(ResizePolicy::Smallest), Some(WindowSize::new(50, 100, 0, 0)));
+    }
+
+    #[test]
+    fn output_profiles() {
+        let output = "\x1b]0;title\x07a\x1b]8;;file:///etc/passwd\x1b\\link\x1b]8;;\x1b\\\x1b]8;;https://x.org\x07b\x1b]52;c;?\x07\x1bPq#0\x1b\\\x1b[1mc";
+        let apply = |profile: OutputProfile| {
+            // split everywhere to see sequences across chunks recognized
+            let mut filter = profile.filter();
+            output.chars().map(|c| filter.apply(&c.to_string())).collect::<String>()
+        };
+        assert_eq!(apply(OutputProfile::Trusted), output);
+        assert_eq!(apply(OutputProfile::Viewer), "alink\x1b]8;;\x1b\\\x1b]8;;https://x.org\x07b\x1bPq#0\x1b\\\x1b[1mc");
+        assert_eq!(apply(OutputProfile::Strict), "alinkb\x1b[1mc");
+
+        // an escape aborting a string starts a sequence of its own
+        assert_eq!(OutputProfile::Strict.filter().apply("\x1b]52;c;YQ==\x1b[2Jd"), "\x1b[2Jd");
+    }
+
+    #[test]
+    fn ordered_delivery() -> Result<(), Box<dyn std::error::Error>> {
+        let pty = crate::Pty::builder().resize_policy(ResizePolicy::LastWriter).spawn(|_id, _res| {}, |_id| {})?;
+        let mut seen = Vec::new();
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let sink = events.clone();
+            let (id, _) = pty.attach_sequenced(move |seq, event| sink.lock().unwrap().push((seq, event)))?;
+            seen.push(events);
+            ids.push(id);
+        }
+        pty.write("i=0; while [ $i -lt 2000 ]; do echo \"line-$i\"; i=$((i + 1)); done; exit\r")?;
+        for rows in 20..60 {
+            pty.client_resize(ids[rows as usize % ids.len()], WindowSize::new(rows, 80, 0, 0))?;
+        }
+        pty.wait(&crate::cancel::CancellationToken::new())?;
+
+        let first = seen[0].lock().unwrap().clone();
+        assert!(first.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(first.iter().filter(|(_, event)| *event == ClientEvent::Exited).count(), 1);
+        assert_eq!(first.last().map(|(_, event)| event), Some(&ClientEvent::Exited));
+        // same events with the same numbers for everyone attached before the first one
+        for events in &seen[1..] {
+            assert_eq!(*events.lock().unwrap(), first);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() -> Result<(), serde_json::Error> {
+        let events = vec![ClientEvent::Output("\x1b[1mhi".into()), ClientEvent::Resized(24, 80), ClientEvent::Exited];
+        let json = serde_json::to_string(&events)?;
+        assert_eq!(json, r#"[{"Output":"\u001b[1mhi"},{"Resized":[24,80]},"Exited"]"#);
+        assert_eq!(serde_json::from_str::<Vec<ClientEvent>>(&json)?, events);
+
+        let policy = ResizePolicy::Fixed(WindowSize::new(24, 80, 8, 16));
+        let json = serde_json::to_string(&policy)?;
+        assert_eq!(json, r#"{"Fixed":{"rows":24,"cols":80,"cell_width":8,"cell_height":16}}"#);
+        assert_eq!(serde_json::from_str::<ResizePolicy>(&json)?, policy);
+        Ok(())
+    }
+}
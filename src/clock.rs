@@ -0,0 +1,140 @@
+//! What time it is for a session, see PtyBuilder::clock()
+//! idle times, timeouts such as the max hold of synchronized output, recording timestamps,
+//! command durations and audit times are taken from the clock of the session, a MockClock
+//! makes them deterministic, it only moves when told to
+//! the clock says what time it is, not how long threads sleep: a polling thread waiting for a
+//! deadline still wakes up in real time, with a MockClock a deadline passed in virtual time is
+//! acted on the next time the thread wakes up, e.g. for output
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//! use pty_exec::clock::{Clock, MockClock};
+//!
+//! let clock = MockClock::new();
+//! let pty = Pty::builder().clock(clock.clone()).spawn(|_id, _res| {}, |_id| {})?;
+//! clock.advance(Duration::from_secs(3600));
+//! assert!(clock.elapsed(pty.health()?.last_io) >= Duration::from_secs(3600));
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// how often a thread waiting for a time of the clock looks at it, a MockClock wakes nobody up
+const NAP: Duration = Duration::from_millis(10);
+
+/// Source of the time of a session, SystemClock by default
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// now, for measuring durations
+    fn now(&self) -> Instant;
+
+    /// now, as a wall clock time
+    fn system_now(&self) -> SystemTime;
+
+    /// time passed since, zero for a time after now
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+/// The time of the system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Virtual time starting when the clock was created, moved only by MockClock::advance(),
+/// clones share their time
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<MockTime>);
+
+#[derive(Debug)]
+struct MockTime {
+    started: Instant,
+    system_started: SystemTime,
+    passed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock(Arc::new(MockTime { started: Instant::now(), system_started: SystemTime::now(), passed: Mutex::new(Duration::ZERO) }))
+    }
+
+    /// moves the time forward by duration
+    pub fn advance(&self, duration: Duration) {
+        *self.0.passed.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.0.started + *self.0.passed.lock().unwrap()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.0.system_started + *self.0.passed.lock().unwrap()
+    }
+}
+
+/**
+ * Sleeps until clock says it is due
+ */
+pub(crate) fn sleep_until(clock: &dyn Clock, due: Instant) {
+    loop {
+        let left = due.saturating_duration_since(clock.now());
+        if left.is_zero() { return }
+        thread::sleep(left.min(NAP));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+    use crate::recording::Recording;
+    use crate::{test_util, Pty};
+    use super::*;
+
+    #[test]
+    fn virtual_time() -> Result<(), Box<dyn Error>> {
+        let clock = MockClock::new();
+        let path = std::env::temp_dir().join(format!("pty-exec-clock-{}.cast", std::process::id()));
+        let pty = Pty::builder().scrollback(0x10000).clock(clock.clone()).spawn(|_id, _res| {}, |_id| {})?;
+        let spawned = clock.now();
+        pty.start_recording(Recording::new(&path))?;
+
+        clock.advance(Duration::from_millis(2500));
+        pty.write("echo \"tick-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "tick-2", Duration::from_secs(10))?;
+        // the output was read at 2.5s virtual time, however long it really took
+        assert_eq!(clock.elapsed(pty.health()?.last_io), Duration::ZERO);
+        assert_eq!(pty.health()?.last_io, spawned + Duration::from_millis(2500));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(clock.elapsed(pty.health()?.last_io), Duration::from_secs(3600));
+
+        pty.stop_recording()?;
+        let data = fs::read_to_string(&path)?;
+        let event = data.lines().find(|event| event.contains("tick-2")).unwrap_or_default();
+        assert!(event.starts_with("[2.500000, "), "{data}");
+        pty.shutdown()?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+//! Completions of a partial command as the shell offers them, enabled with the experimental
+//! `completion` feature, see Pty::complete()
+//! the line being edited is replaced with the partial command and TAB pressed until the shell
+//! lists candidates or completes one, up to three times as bash lists on the third, what the shell
+//! draws in response is taken apart into candidates and the line is left holding the partial
+//! command again, best effort: the shell draws the completion like for any TAB, every client
+//! sees it, input written meanwhile ends up in the line, and a shell without completion, such
+//! as sh, offers none
+//! candidates are what the shell lists, e.g. file names without their directory, a single
+//! candidate the shell completes in place is the last word of the partial command completed
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//!
+//! let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+//! let candidates = pty.complete("ech", Duration::from_secs(2))?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::clients::ClientEvent;
+use crate::error::PtyError;
+use crate::registry::Session;
+use crate::scrollback;
+use crate::Pty;
+
+// output quiet for this long ends a burst
+const QUIET: Duration = Duration::from_millis(150);
+
+// what bash and zsh ask before listing many candidates, and the pager of bash
+const TOO_MANY: [(&str, &str); 3] = [("(y or n)", "n"), ("possibilities?", "n"), ("--More--", "q")];
+
+/**
+ * Completions of partial offered by the shell of session, waiting up to timeout for each
+ * response to TAB
+ */
+pub(crate) fn complete(pty: &Pty, session: &Arc<Session>, partial: &str, timeout: Duration) -> Result<Vec<String>, Box<dyn Error>> {
+    if session.on_reader_thread() {
+        return Err(Box::new(PtyError::with_kind(format!("Completion in {} from its own callback", pty.id()), io::ErrorKind::WouldBlock)));
+    }
+    let (tx, events) = mpsc::channel();
+    let client = pty.attach(move |event| { let _ = tx.send(event); })?;
+    let res = capture(pty, &events, partial, timeout);
+    // the line holds partial again, whatever the shell made of it
+    let restored = pty.replace_current_line(partial);
+    let _ = pty.detach(client);
+    restored?;
+    res
+}
+
+/**
+ * Writes partial and TAB, returns the candidates in what the shell drew
+ */
+fn capture(pty: &Pty, events: &Receiver<ClientEvent>, partial: &str, timeout: Duration) -> Result<Vec<String>, Box<dyn Error>> {
+    pty.replace_current_line(partial)?;
+    burst(events, timeout)?;
+
+    // bash inserts a common prefix on the first TAB and lists on the third
+    let mut drawn = String::new();
+    for _ in 0..3 {
+        pty.write_raw("\t")?;
+        drawn.push_str(&burst(events, timeout)?);
+        // a candidate completed in place ends the word
+        let completed = drawn.trim_end_matches('\x07').ends_with([' ', '/']);
+        if drawn.contains('\n') || completed {
+            break;
+        }
+    }
+    if let Some((_, answer)) = TOO_MANY.iter().find(|(question, _)| drawn.contains(question)) {
+        pty.write_raw(answer)?;
+        burst(events, timeout)?;
+        return Err(Box::new(PtyError::new(format!("Too many completions of {partial:?} in {}", pty.id()))));
+    }
+    Ok(candidates(partial, &drawn))
+}
+
+/**
+ * Output up to QUIET after the last of it, waiting up to timeout for the first, escape
+ * sequences stripped
+ */
+fn burst(events: &Receiver<ClientEvent>, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let mut output = String::new();
+    let mut deadline = Instant::now() + timeout;
+    loop {
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ClientEvent::Output(s)) => {
+                output.push_str(&s);
+                deadline = Instant::now() + QUIET;
+            },
+            Ok(ClientEvent::Exited) | Err(RecvTimeoutError::Disconnected) => {
+                return Err(Box::new(PtyError::with_kind("Shell exited during completion".to_owned(), io::ErrorKind::UnexpectedEof)));
+            },
+            Ok(_) => {},
+            Err(RecvTimeoutError::Timeout) => break,
+        }
+    }
+    Ok(scrollback::plain_text(&output).0)
+}
+
+/**
+ * Candidates in what the shell drew after partial, a listing below the line or the rest of
+ * the one candidate written in place
+ */
+fn candidates(partial: &str, drawn: &str) -> Vec<String> {
+    let drawn = drawn.replace(['\x07', '\r'], "");
+    let mut lines = drawn.split('\n');
+    let in_place = lines.next().unwrap_or_default();
+    let mut listing: Vec<&str> = lines.collect();
+
+    if listing.is_empty() {
+        let word = partial.rsplit(char::is_whitespace).next().unwrap_or_default();
+        return match in_place.trim_end() {
+            "" => Vec::new(),
+            rest => vec![format!("{word}{rest}")],
+        };
+    }
+    // a shell redrawing the prompt after the listing redraws the line last
+    let line = format!("{partial}{in_place}");
+    if listing.last().is_some_and(|last| last.trim_end().ends_with(line.trim_end())) {
+        listing.pop();
+    }
+    listing.iter().flat_map(|line| line.split_whitespace()).map(str::to_owned).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn completions() -> Result<(), Box<dyn Error>> {
+        assert_eq!(candidates("git chec", "kout \x07"), ["checkout"]);
+        assert_eq!(candidates("ls l", "i\x07\x07\r\nlib.rs    limit.rs\r\n$ ls li"), ["lib.rs", "limit.rs"]);
+        assert_eq!(candidates("ls x", "\x07"), Vec::<String>::new());
+
+        let dir = std::env::temp_dir().join(format!("pty-exec-completion-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        for name in ["alpha-one", "alpha-two", "beta"] {
+            fs::write(dir.join(name), "")?;
+        }
+        let pty = Pty::builder().default_shell("/bin/bash").scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        if pty.shell()? != "/bin/bash" {
+            pty.shutdown()?;
+            return Ok(());
+        }
+        // completion needs the line editor of a shell waiting at its prompt
+        pty.write(&format!("cd {}; echo \"ready-$((1 + 1))\"\r", dir.display()))?;
+        crate::test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+
+        let mut listed = pty.complete("cat al", Duration::from_secs(5))?;
+        listed.sort();
+        assert_eq!(listed, ["alpha-one", "alpha-two"]);
+        assert_eq!(pty.complete("cat be", Duration::from_secs(5))?, ["beta"]);
+        // the line is back to the partial command, nothing was run
+        pty.write("\x05\x15echo \"done-$((1 + 1))\"\r")?;
+        crate::test_util::wait_for_output(&pty, "done-2", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
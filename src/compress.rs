@@ -0,0 +1,217 @@
+//! Compression of the output a server sends to its clients, for clients on slow links, e.g. a
+//! socket forwarded over ssh, terminal output like logs and build output compresses very well
+//!
+//! each codec is behind a feature of its own, `deflate` or `zstd`, a client offers every codec
+//! it was built with and a server picks the first of its own list the client offered, see
+//! Server::compress(), output is then sent as protocol::Frame::Compressed, compressed as one
+//! stream per connection, so later output refers back to earlier output, and flushed after
+//! every frame so the client can decode each frame as it arrives
+//! ```rust,no_run
+//! use pty_exec::compress::Codec;
+//! use pty_exec::server::{default_socket_path, Server};
+//!
+//! let server = Server::bind(default_socket_path())?.compress([Codec::Zstd, Codec::Deflate]);
+//! server.run()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+// wasm32 builds have no server or client to compress for, see the wasm module
+#![cfg_attr(any(target_arch = "wasm32", not(any(feature = "deflate", feature = "zstd"))), allow(dead_code))]
+
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+
+// output frames are never larger, decompressing more is refused
+const MAX_DECOMPRESSED: usize = 0x100_0000;
+
+/// A compression algorithm of the attach protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Codec {
+    /// needs the `deflate` feature
+    Deflate,
+    /// needs the `zstd` feature, compresses better and faster than deflate
+    Zstd,
+}
+
+impl Codec {
+    /// whether this build can compress and decompress with the codec
+    pub fn is_available(self) -> bool {
+        match self {
+            Codec::Deflate => cfg!(feature = "deflate"),
+            Codec::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// every codec of this build, best first
+    pub fn available() -> Vec<Codec> {
+        [Codec::Zstd, Codec::Deflate].into_iter().filter(|codec| codec.is_available()).collect()
+    }
+
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            Codec::Deflate => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_code(code: u8) -> Option<Codec> {
+        match code {
+            1 => Some(Codec::Deflate),
+            2 => Some(Codec::Zstd),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Deflate => "deflate",
+            Codec::Zstd => "zstd",
+        })
+    }
+}
+
+/**
+ * Compressing side of a connection
+ */
+pub(crate) struct Encoder(Box<dyn Pass>);
+
+/**
+ * Decompressing side of a connection
+ */
+pub(crate) struct Decoder(Box<dyn Pass>);
+
+impl Encoder {
+    pub(crate) fn new(codec: Codec) -> io::Result<Encoder> {
+        match codec {
+            #[cfg(feature = "deflate")]
+            Codec::Deflate => Ok(Encoder(Flushing::boxed(
+                flate2::write::DeflateEncoder::new(Bounded::default(), flate2::Compression::fast()),
+                flate2::write::DeflateEncoder::get_mut,
+            ))),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(Encoder(Flushing::boxed(zstd::stream::write::Encoder::new(Bounded::default(), 3)?, zstd::stream::write::Encoder::get_mut))),
+            #[allow(unreachable_patterns)]
+            codec => Err(unavailable(codec))
+        }
+    }
+
+    /**
+     * Compresses data, everything up to its end can be decompressed from what is returned
+     */
+    pub(crate) fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.pass(data, usize::MAX)
+    }
+}
+
+impl Decoder {
+    pub(crate) fn new(codec: Codec) -> io::Result<Decoder> {
+        match codec {
+            #[cfg(feature = "deflate")]
+            Codec::Deflate => Ok(Decoder(Flushing::boxed(flate2::write::DeflateDecoder::new(Bounded::default()), flate2::write::DeflateDecoder::get_mut))),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(Decoder(Flushing::boxed(zstd::stream::write::Decoder::new(Bounded::default())?, zstd::stream::write::Decoder::get_mut))),
+            #[allow(unreachable_patterns)]
+            codec => Err(unavailable(codec))
+        }
+    }
+
+    /**
+     * Decompresses data, fails as soon as the output of a frame grows past MAX_DECOMPRESSED
+     * instead of inflating all of it first, the stream is broken afterwards
+     */
+    pub(crate) fn decode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.pass(data, MAX_DECOMPRESSED)
+    }
+}
+
+trait Pass: Send {
+    /**
+     * Passes data through, failing once more than limit bytes came out
+     */
+    fn pass(&mut self, data: &[u8], limit: usize) -> io::Result<Vec<u8>>;
+}
+
+/**
+ * A compressing or decompressing writer into a buffer, flushed after every write
+ */
+struct Flushing<W> {
+    w: W,
+    buf: fn(&mut W) -> &mut Bounded,
+}
+
+impl<W: Write + Send + 'static> Flushing<W> {
+    fn boxed(w: W, buf: fn(&mut W) -> &mut Bounded) -> Box<dyn Pass> {
+        Box::new(Flushing { w, buf })
+    }
+}
+
+impl<W: Write + Send> Pass for Flushing<W> {
+    fn pass(&mut self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        (self.buf)(&mut self.w).limit = limit;
+        self.w.write_all(data)?;
+        self.w.flush()?;
+        Ok(mem::take(&mut (self.buf)(&mut self.w).buf))
+    }
+}
+
+/**
+ * Buffer at the end of a Flushing, refusing writes that would grow it past limit
+ */
+#[derive(Default)]
+struct Bounded {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for Bounded {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Compressed frame too large"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn unavailable(codec: Codec) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("Compression with {codec} is not available in this build"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams() -> io::Result<()> {
+        let line = "cc -O2 -c src/main.c -o build/main.o\r\n";
+        for codec in Codec::available() {
+            let (mut encoder, mut decoder) = (Encoder::new(codec)?, Decoder::new(codec)?);
+            let first = encoder.encode(line.repeat(10).as_bytes())?;
+            assert!(first.len() < line.len() * 2, "{codec}");
+            assert_eq!(decoder.decode(&first)?, line.repeat(10).as_bytes());
+
+            // the same output again costs next to nothing
+            let again = encoder.encode(line.as_bytes())?;
+            assert!(again.len() < line.len() / 2, "{codec}");
+            assert_eq!(decoder.decode(&again)?, line.as_bytes());
+        }
+        for codec in [Codec::Deflate, Codec::Zstd] {
+            assert_eq!(Encoder::new(codec).is_ok(), codec.is_available());
+        }
+
+        // a small frame inflating to more than any frame is refused
+        for codec in Codec::available() {
+            let bomb = Encoder::new(codec)?.encode(&vec![0; MAX_DECOMPRESSED + 1])?;
+            assert!(bomb.len() < MAX_DECOMPRESSED / 100, "{codec}");
+            assert!(Decoder::new(codec)?.decode(&bomb).is_err(), "{codec}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::clock::Clock;
+
+// an echo that has not shown up by then is not coming, e.g. because echo is off
+const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/**
+ * Echo of input written with Pty::inject() still expected in the output, taken out of the
+ * output as it shows up, best effort: the echo is matched char by char as the tty echoes it,
+ * output before the echo of each line starts and escape sequences within it are passed on, a
+ * partial match that stops matching within a chunk is passed on too, e.g. a prompt sharing its
+ * last chars with the start of the echo, one that stops matching in a later chunk is held back
+ * until then, passed on with the later chunk and gives up on the echo
+ */
+#[derive(Debug)]
+pub(crate) struct Echo {
+    expected: VecDeque<char>,
+    // the start of the echo of a line seen in earlier chunks, passed on if it stops matching
+    held: String,
+    escape: Escape,
+    // when input was last injected
+    since: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+/**
+ * Input of one client shown ahead of its echo, see Pty::write_predicted(), matched against the
+ * output char by char like Echo, but nothing is taken out: escape sequences, control characters
+ * and other output before the echo of a prediction starts are skipped, a prediction is echoed
+ * once all of it was seen, a char that is not its echo after it started means the child drew
+ * something else than predicted, that prediction and those after it are rejected
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Predictions {
+    pending: VecDeque<Prediction>,
+    escape: Escape,
+}
+
+#[derive(Debug)]
+struct Prediction {
+    seq: u64,
+    expected: VecDeque<char>,
+    started: bool,
+    since: Instant,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    #[default]
+    None,
+    Esc,
+    Csi,
+    Osc,
+    OscEsc,
+}
+
+impl Escape {
+    /**
+     * The state after c, c is part of a sequence if either state is not None
+     */
+    fn next(self, c: char) -> Escape {
+        match (self, c) {
+            (Escape::None, '\x1b') => Escape::Esc,
+            (Escape::None, _) => Escape::None,
+            (Escape::Esc, '[') => Escape::Csi,
+            (Escape::Esc, ']') => Escape::Osc,
+            (Escape::Csi, '\x40'..='\x7e') => Escape::None,
+            (Escape::Osc, '\x07') | (Escape::OscEsc, '\\') => Escape::None,
+            (Escape::Osc | Escape::OscEsc, '\x1b') => Escape::OscEsc,
+            (Escape::OscEsc, _) => Escape::Osc,
+            (Escape::Esc, _) => Escape::None,
+            (escape, _) => escape,
+        }
+    }
+}
+
+impl Echo {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Echo {
+        Echo { expected: VecDeque::new(), held: String::new(), escape: Escape::None, since: None, clock }
+    }
+
+    /**
+     * Input is about to be written, expect its echo
+     */
+    pub(crate) fn expect(&mut self, input: &str) {
+        for c in input.chars() {
+            match c {
+                // ICRNL and ONLCR, or readline moving to the next line
+                '\r' | '\n' => self.expected.extend(['\r', '\n']),
+                // ECHOCTL
+                c if c.is_ascii_control() && c != '\t' && c != '\x1b' => {
+                    self.expected.extend(['^', char::from(c as u8 ^ 0x40)])
+                },
+                c => self.expected.push_back(c),
+            }
+        }
+        self.since = Some(self.clock.now());
+    }
+
+    /**
+     * Output without the echo expected
+     */
+    pub(crate) fn strip(&mut self, output: String) -> String {
+        let mut stripped = String::new();
+        if self.since.is_some_and(|since| self.clock.elapsed(since) > ECHO_TIMEOUT) {
+            stripped = self.give_up();
+        }
+        if self.expected.is_empty() {
+            return stripped + &output;
+        }
+        stripped.reserve(output.len());
+        // the echo matched in this chunk so far
+        let mut matched = String::new();
+        for c in output.chars() {
+            if self.expected.is_empty() {
+                stripped.push(c);
+                continue;
+            }
+            let escaped = self.escape != Escape::None || c == '\x1b';
+            self.escape = self.escape.next(c);
+            if escaped {
+                stripped.push(c);
+                continue;
+            }
+            if self.expected.front() != Some(&c) && !matched.is_empty() && self.held.is_empty() {
+                // not the echo after all, start over
+                stripped.push_str(&matched);
+                for m in matched.drain(..).rev() {
+                    self.expected.push_front(m);
+                }
+            }
+            if self.expected.front() == Some(&c) {
+                self.expected.pop_front();
+                matched.push(c);
+                // a whole line is the echo for sure, the echo of the next one starts over
+                if c == '\n' {
+                    matched.clear();
+                    self.held.clear();
+                }
+            } else {
+                if !self.held.is_empty() {
+                    stripped.push_str(&self.give_up());
+                    stripped.push_str(&matched);
+                    matched.clear();
+                }
+                stripped.push(c);
+            }
+        }
+        match self.expected.is_empty() {
+            true => self.held.clear(),
+            false => self.held.push_str(&matched)
+        }
+        stripped
+    }
+
+    /**
+     * Stops expecting an echo, returns what was held back of it
+     */
+    fn give_up(&mut self) -> String {
+        self.expected.clear();
+        self.since = None;
+        std::mem::take(&mut self.held)
+    }
+}
+
+impl Predictions {
+    /**
+     * Input predicted by the client is about to be written, expect its echo
+     */
+    pub(crate) fn expect(&mut self, seq: u64, input: &str, now: Instant) {
+        if self.pending.is_empty() {
+            // escapes are not followed while nothing is pending
+            self.escape = Escape::None;
+        }
+        self.pending.push_back(Prediction { seq, expected: input.chars().collect(), started: false, since: now });
+    }
+
+    /**
+     * Stops expecting the echo of seq, e.g. its input could not be written
+     */
+    pub(crate) fn forget(&mut self, seq: u64) {
+        self.pending.retain(|prediction| prediction.seq != seq);
+    }
+
+    /**
+     * How long until the oldest prediction times out, output may not come to settle it
+     */
+    pub(crate) fn left(&self, now: Instant) -> Option<Duration> {
+        let prediction = self.pending.front()?;
+        Some(ECHO_TIMEOUT.saturating_sub(now.saturating_duration_since(prediction.since)))
+    }
+
+    /**
+     * The predictions output settles, in order, each with whether it was echoed, timed out ones
+     * are settled without output as well
+     */
+    pub(crate) fn settle(&mut self, output: &str, now: Instant) -> Vec<(u64, bool)> {
+        let mut settled = Vec::new();
+        while self.pending.front().is_some_and(|prediction| now.saturating_duration_since(prediction.since) >= ECHO_TIMEOUT) {
+            settled.extend(self.pending.pop_front().map(|prediction| (prediction.seq, false)));
+        }
+        for c in output.chars() {
+            let Some(prediction) = self.pending.front_mut() else { break };
+            let escaped = self.escape != Escape::None || c == '\x1b';
+            self.escape = self.escape.next(c);
+            if escaped || c.is_control() {
+                continue;
+            }
+            if prediction.expected.front() == Some(&c) {
+                prediction.expected.pop_front();
+                prediction.started = true;
+                if prediction.expected.is_empty() {
+                    settled.push((prediction.seq, true));
+                    self.pending.pop_front();
+                }
+            } else if prediction.started {
+                settled.extend(self.pending.drain(..).map(|prediction| (prediction.seq, false)));
+            }
+        }
+        settled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use std::error::Error;
+    use crate::{test_util, Pty};
+
+    #[test]
+    fn strip() -> Result<(), Box<dyn Error>> {
+        let mut echo = Echo::new(Arc::new(SystemClock));
+        echo.expect("ls -l\r\x03");
+        // the prompt before the echo, an escape sequence within and a chunk ending inside it
+        assert_eq!(echo.strip("$ l".to_owned()), "$ ");
+        assert_eq!(echo.strip("s\x1b[K -l\r\n^".to_owned()), "\x1b[K");
+        assert_eq!(echo.strip("Ctotal 0\r\n".to_owned()), "total 0\r\n");
+
+        // output between the echo of two lines
+        echo.expect("cd /\recho\r");
+        assert_eq!(echo.strip("cd /\r\n\x1b[?2004l\r$ echo".to_owned()), "\x1b[?2004l\r$ ");
+        assert_eq!(echo.strip("\r\n".to_owned()), "");
+
+        // starts over on an echo that stops matching within a chunk, gives up on it after one,
+        // passing on what was held back of it
+        echo.expect("true\r");
+        assert_eq!(echo.strip("trap\r\n".to_owned()), "trap\r\n");
+        assert_eq!(echo.strip("tr".to_owned()), "");
+        assert_eq!(echo.strip("ap\r\n".to_owned()), "trap\r\n");
+        assert_eq!(echo.strip("true".to_owned()), "true");
+
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        pty.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+        // input typed ahead of the prompt is echoed twice, by the tty and by the shell
+        while !pty.scrollback()?.trim_end().ends_with(['$', '#']) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        pty.inject("echo \"injected-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "injected-2", Duration::from_secs(10))?;
+        let output = pty.scrollback()?;
+        assert!(!output.contains("echo \"injected") && output.contains("echo \"ready"), "{output:?}");
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn predictions() {
+        let now = Instant::now();
+        let mut predictions = Predictions::default();
+        predictions.expect(1, "ls", now);
+        predictions.expect(2, " -l", now);
+        // output before the echo, escapes within it and the echo split across chunks
+        assert_eq!(predictions.settle("$ \x1b[32ml", now), vec![]);
+        assert_eq!(predictions.settle("s\x1b[0m -", now), vec![(1, true)]);
+        assert_eq!(predictions.settle("l", now), vec![(2, true)]);
+
+        // something else drawn, the later predictions go too
+        predictions.expect(3, "cd", now);
+        predictions.expect(4, " /", now);
+        assert_eq!(predictions.settle("cx", now), vec![(3, false), (4, false)]);
+
+        // no echo in time, a forgotten prediction is not settled
+        predictions.expect(5, "pw", now);
+        predictions.expect(6, "d", now);
+        predictions.forget(6);
+        assert_eq!(predictions.settle("", now + ECHO_TIMEOUT * 2), vec![(5, false)]);
+    }
+}
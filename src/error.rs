@@ -1,8 +1,105 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
+use nix::errno::Errno;
 
-#[derive(Debug)]
-pub struct PtyError(pub String);
+/// Error of a pty operation, carries the errno of the failing syscall if there was one
+/// ```rust
+/// use std::io::ErrorKind;
+/// use pty_exec::PtyError;
+/// use nix::errno::Errno;
+///
+/// let err = PtyError::from_errno("Write failure", Errno::EIO);
+/// assert_eq!(err.errno(), Some(Errno::EIO));
+///
+/// let err = std::io::Error::from(PtyError::new("Stale pty handle"));
+/// assert_eq!(err.kind(), ErrorKind::Other);
+/// assert_eq!(err.to_string(), "Pseudo Terminal Error: Stale pty handle");
+///
+/// let err = std::io::Error::from(PtyError::from_errno("Write failure", Errno::EPIPE));
+/// assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+/// assert!(err.to_string().starts_with("Pseudo Terminal Error: Write failure"));
+/// let errno = err.get_ref().and_then(|inner| inner.downcast_ref::<PtyError>()).and_then(PtyError::errno);
+/// assert_eq!(errno, Some(Errno::EPIPE));
+/// ```
+///
+/// the fields are private, which breaks building one as `PtyError(message)` and reading `.0`
+/// on purpose so what it carries can grow, PtyError::new(), `PtyError::from(message)` and
+/// PtyError::message() stand in for them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PtyError(String, Cause);
+
+/**
+ * What is known of why a PtyError happened
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cause {
+    kind: io::ErrorKind,
+    errno: Option<Errno>,
+}
+
+impl PtyError {
+    pub fn new(message: impl Into<String>) -> PtyError {
+        PtyError::with_kind(message, io::ErrorKind::Other)
+    }
+
+    /// error caused by a syscall failing with errno
+    pub fn from_errno(message: impl fmt::Display, errno: Errno) -> PtyError {
+        let kind = io::Error::from(errno).kind();
+        PtyError(format!("{message} {errno}"), Cause { kind, errno: Some(errno) })
+    }
+
+    /// error with a kind other than ErrorKind::Other but no errno
+    pub(crate) fn with_kind(message: impl Into<String>, kind: io::ErrorKind) -> PtyError {
+        PtyError(message.into(), Cause { kind, errno: None })
+    }
+
+    /**
+     * A spawn turned away by the cap of limit::set_max_sessions()
+     */
+    pub(crate) fn limit_reached(message: impl Into<String>) -> PtyError {
+        PtyError::with_kind(message, io::ErrorKind::QuotaExceeded)
+    }
+
+    /**
+     * Copy of err that can be sent across threads, which Box<dyn Error> cannot,
+     * errno and kind survive if err is a PtyError or an io::Error, also one wrapping a PtyError
+     */
+    pub(crate) fn copy_of(err: &(dyn Error + 'static)) -> PtyError {
+        if let Some(err) = err.downcast_ref::<PtyError>() {
+            return err.clone();
+        }
+        match err.downcast_ref::<io::Error>() {
+            Some(io_err) => match io_err.get_ref().and_then(|inner| inner.downcast_ref::<PtyError>()) {
+                Some(err) => err.clone(),
+                None => PtyError(io_err.to_string(), Cause {
+                    kind: io_err.kind(),
+                    errno: io_err.raw_os_error().map(Errno::from_i32)
+                })
+            },
+            None => PtyError::new(err.to_string())
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.0
+    }
+
+    /// what kind of io error this is, ErrorKind::Other if nothing more specific is known
+    pub fn kind(&self) -> io::ErrorKind {
+        self.1.kind
+    }
+
+    /// whether a spawn failed because the cap of limit::set_max_sessions() was reached
+    pub fn is_limit_reached(&self) -> bool {
+        self.1.kind == io::ErrorKind::QuotaExceeded && self.1.errno.is_none()
+    }
+
+    /// errno of the syscall that failed, `None` if the error did not come from a syscall
+    pub fn errno(&self) -> Option<Errno> {
+        self.1.errno
+    }
+}
 
 impl fmt::Display for PtyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -10,4 +107,65 @@ impl fmt::Display for PtyError {
     }
 }
 
-impl Error for PtyError {}
+/// the errno is the source of an error coming from a syscall
+impl Error for PtyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.1.errno.as_ref().map(|errno| errno as &(dyn Error + 'static))
+    }
+}
+
+impl From<Errno> for PtyError {
+    fn from(errno: Errno) -> PtyError {
+        PtyError::from_errno("System call failure", errno)
+    }
+}
+
+impl From<String> for PtyError {
+    fn from(message: String) -> PtyError {
+        PtyError::new(message)
+    }
+}
+
+impl From<&str> for PtyError {
+    fn from(message: &str) -> PtyError {
+        PtyError::new(message)
+    }
+}
+
+impl From<io::Error> for PtyError {
+    fn from(err: io::Error) -> PtyError {
+        PtyError::copy_of(&err)
+    }
+}
+
+/// keeps the kind, the message and the errno, reached by downcasting io::Error::get_ref() to
+/// PtyError, raw_os_error() is None as an io::Error cannot have both an os error and a message
+impl From<PtyError> for io::Error {
+    fn from(err: PtyError) -> io::Error {
+        io::Error::new(err.1.kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_interop() {
+        let err = io::Error::from(PtyError::from_errno("Write failure", Errno::EPERM));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("Write failure"));
+        assert_eq!(err.get_ref().and_then(|inner| inner.downcast_ref::<PtyError>()).and_then(PtyError::errno), Some(Errno::EPERM));
+        assert_eq!(PtyError::copy_of(&err).errno(), Some(Errno::EPERM));
+        assert_eq!(PtyError::new("Stale pty handle").message(), "Stale pty handle");
+        assert!(matches!(PtyError::from("Stale pty handle"), PtyError(message, ..) if message == "Stale pty handle"));
+
+        let err: Box<dyn Error> = Box::new(io::Error::from_raw_os_error(Errno::EIO as i32));
+        assert_eq!(PtyError::copy_of(err.as_ref()).errno(), Some(Errno::EIO));
+
+        let err = io::Error::from(PtyError::with_kind("Stale pty handle", io::ErrorKind::NotConnected));
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert_eq!(err.raw_os_error(), None);
+        assert!(err.to_string().contains("Stale pty handle"));
+    }
+}
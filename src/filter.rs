@@ -0,0 +1,173 @@
+//! Filters every chunk passing through a pty, see PtyBuilder::filter()
+//! output is filtered as soon as it is read and input before it is written, so a policy such
+//! as redaction or a rate limit applies the same to the handler, attached clients, clients of
+//! a server, the scrollback, recordings and triggers, nobody sees what a filter took out
+//!
+//! filters run in the order they were added, in both directions, and see the chunks of a
+//! pty one at a time in the order they were read or written, input is written in the order it
+//! left the filters even with several writers, input of triggers, of Pty::paste_large() and
+//! of PtyBuilder::startup_commands() is not filtered
+//!
+//! with the `metrics` feature the time each filter takes is recorded as
+//! `pty_exec_filter_seconds` and the chunks it drops as `pty_exec_filter_dropped_total`,
+//! labelled with the name of the filter and the direction
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::filter::{self, Direction};
+//!
+//! let pty = Pty::builder()
+//!     .filter(filter::from_fn("redact", |_id, _direction, chunk: String| Some(chunk.replace("hunter2", "*******"))))
+//!     .filter(filter::from_fn("no-reboot", |_id, direction, chunk: String| {
+//!         (direction == Direction::Output || !chunk.contains("reboot")).then_some(chunk)
+//!     }))
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use crate::id::PtyId;
+use crate::metrics;
+
+/// Which way a chunk is going
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    /// read from the pty
+    Output,
+    /// about to be written to the pty
+    Input,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Output => "output",
+            Direction::Input => "input",
+        })
+    }
+}
+
+/// A stage of the filters of a pty
+pub trait Filter: Send {
+    /// names the filter in metrics
+    fn name(&self) -> &str;
+
+    /// the chunk to pass on to the next filter, None or an empty chunk drops it, called on the
+    /// thread reading the pty for output and on the writing thread for input
+    fn filter(&mut self, id: PtyId, direction: Direction, chunk: String) -> Option<String>;
+}
+
+/// Filter calling a function, see from_fn()
+pub struct FnFilter<F> {
+    name: String,
+    f: F,
+}
+
+/// A filter named name calling f
+pub fn from_fn<F>(name: impl Into<String>, f: F) -> FnFilter<F>
+    where F: FnMut(PtyId, Direction, String) -> Option<String> + Send
+{
+    FnFilter { name: name.into(), f }
+}
+
+impl<F> Filter for FnFilter<F>
+    where F: FnMut(PtyId, Direction, String) -> Option<String> + Send
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn filter(&mut self, id: PtyId, direction: Direction, chunk: String) -> Option<String> {
+        (self.f)(id, direction, chunk)
+    }
+}
+
+/**
+ * Filters of a session, shared by its config, empty unless PtyBuilder::filter() was used
+ */
+#[derive(Clone, Default)]
+pub(crate) struct Pipeline {
+    stages: Arc<Mutex<Vec<Box<dyn Filter>>>>,
+}
+
+impl Pipeline {
+    pub(crate) fn push(&mut self, filter: Box<dyn Filter>) {
+        self.stages.lock().unwrap().push(filter);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.stages.lock().unwrap_or_else(PoisonError::into_inner).is_empty()
+    }
+
+    /**
+     * Passes chunk through every filter, None if one of them dropped it
+     */
+    pub(crate) fn run(&self, id: PtyId, direction: Direction, chunk: String) -> Option<String> {
+        let mut stages = self.stages.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut chunk = chunk;
+        for stage in stages.iter_mut() {
+            let started = Instant::now();
+            let res = stage.filter(id, direction, chunk).filter(|chunk| !chunk.is_empty());
+            metrics::filtered(stage.name(), direction, started.elapsed(), res.is_none());
+            chunk = res?;
+        }
+        Some(chunk)
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages = self.stages.lock().unwrap_or_else(PoisonError::into_inner);
+        f.debug_list().entries(stages.iter().map(|stage| stage.name())).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::time::Duration;
+    use crate::clients::ClientEvent;
+    use crate::{test_util, Pty};
+
+    #[test]
+    fn pipeline() -> Result<(), Box<dyn Error>> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (first, second) = (seen.clone(), seen.clone());
+        let pty = Pty::builder()
+            .scrollback(0x10000)
+            .filter(from_fn("redact", move |_id, direction, chunk: String| {
+                first.lock().unwrap().push(("redact", direction));
+                Some(chunk.replace("secret", "******"))
+            }))
+            .filter(from_fn("deny", move |_id, direction, chunk: String| {
+                second.lock().unwrap().push(("deny", direction));
+                (direction == Direction::Output || !chunk.contains("forbidden")).then_some(chunk)
+            }))
+            .spawn(|_id, _res| {}, |_id| {})?;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_async = events.clone();
+        pty.attach(move |event| events_async.lock().unwrap().push(event))?;
+
+        pty.write("echo forbidden\r")?;
+        pty.write("echo \"sec\"\"ret-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "******-2", Duration::from_secs(10))?;
+
+        let output = pty.scrollback()?;
+        assert!(!output.contains("secret") && !output.contains("forbidden"), "{output}");
+        let events = events.lock().unwrap();
+        assert!(events.iter().all(|event| !matches!(event, ClientEvent::Output(output) if output.contains("secret"))));
+
+        // every chunk went through both filters, in the order they were added
+        let seen = seen.lock().unwrap();
+        assert!(seen.chunks(2).all(|pair| pair[0].0 == "redact" && pair[1].0 == "deny" && pair[0].1 == pair[1].1));
+        assert_eq!(seen.iter().filter(|(_, direction)| *direction == Direction::Input).count(), 4);
+        drop((seen, events));
+        pty.shutdown()?;
+        Ok(())
+    }
+}
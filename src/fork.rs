@@ -0,0 +1,87 @@
+//! Using pty-exec in a process that forks, e.g. to daemonize after spawning ptys
+//! a forked child inherits the registry and every master fd but none of the threads reading
+//! them, its handles would keep writing to the parent's ptys and holding their fds open, so a
+//! shell would not see a hangup when the parent closes its pty, at_fork() drops all of it
+//! ```rust
+//! use pty_exec::Pty;
+//!
+//! let pty = Pty::spawn(|_id, _res| {}, |_id| {})?;
+//! match unsafe { nix::unistd::fork() }? {
+//!     nix::unistd::ForkResult::Child => {
+//!         pty_exec::at_fork().unwrap();
+//!         // the pty belongs to the parent
+//!         assert!(!pty.is_alive());
+//!         unsafe { nix::libc::_exit(0) };
+//!     },
+//!     nix::unistd::ForkResult::Parent { child } => {
+//!         nix::sys::wait::waitpid(child, None)?;
+//!     },
+//! }
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use crate::registry;
+
+/// Forgets every pty inherited from the parent, to be called in the child right after fork()
+/// handles of those ptys become stale and their fds are closed, the processes running in them
+/// are left alone, they belong to the parent, ptys spawned afterwards work as usual
+///
+/// only the thread calling fork() survives in the child, a lock another thread of the parent
+/// held inside pty-exec at the time stays locked, at_fork() does not wait for it but leaves
+/// what it guards behind, it fails with ErrorKind::WouldBlock if that leaves the inherited ptys
+/// open, so fork while no other thread spawns or closes a pty, the read ends of piped stdout
+/// and stderr are not closed
+pub fn at_fork() -> Result<(), Box<dyn Error>> {
+    registry::abandon_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use nix::libc;
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+    use crate::{test_util, Pty};
+
+    #[test]
+    fn child_forgets() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        let fd = pty.id().fd();
+
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                let forgot = at_fork().is_ok();
+                let closed = unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0;
+                let stale = !pty.is_alive() && pty.write("echo child\r").is_err();
+                unsafe { libc::_exit(if forgot && closed && stale { 0 } else { 1 }) };
+            },
+            ForkResult::Parent { child } => {
+                // at_fork() must not block on a lock another test held at the fork
+                let deadline = Instant::now() + Duration::from_secs(2);
+                let status = loop {
+                    match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+                        WaitStatus::StillAlive if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                        WaitStatus::StillAlive => {
+                            kill(child, Signal::SIGKILL)?;
+                            waitpid(child, None)?;
+                            panic!("forked child hung in at_fork()");
+                        },
+                        status => break status,
+                    }
+                };
+                assert_eq!(status, WaitStatus::Exited(child, 0), "forked child kept the parent's pty");
+            },
+        }
+
+        // the parent's pty is untouched
+        pty.write("echo \"parent-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "parent-2", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        Ok(())
+    }
+}
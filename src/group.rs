@@ -0,0 +1,312 @@
+//! Groups of ptys, an InputGroup writes the same input to several ptys at once, e.g. to type
+//! into every pane of a cluster-ssh style layout, a SessionGroup also controls a set of related
+//! ptys as one, e.g. the build, server and logs of a project, and tells when the last one exits,
+//! groups only know the ptys, not how they are laid out
+//!
+//! a broadcast holds the write lock of every member while it writes, so no other input of a
+//! member is written in the middle of it, and broadcasts of groups sharing members reach the
+//! shared members in the same order
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::group::InputGroup;
+//!
+//! let (first, second) = (Pty::spawn(|_id, _res| {}, |_id| {})?, Pty::spawn(|_id, _res| {}, |_id| {})?);
+//! let group = InputGroup::new();
+//! group.add(&first);
+//! group.add(&second);
+//!
+//! let report = group.broadcast("uptime\r");
+//! assert_eq!(report.succeeded.len(), 2);
+//! group.broadcast("exit\r");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
+use nix::sys::signal::Signal;
+use crate::error::PtyError;
+use crate::id::PtyId;
+use crate::registry::{self, Session};
+use crate::shutdown::{self, ShutdownPolicy, ShutdownReport};
+use crate::unix::proc::Usage;
+use crate::unix::window::WindowSize;
+use crate::Pty;
+
+/// Ptys input is broadcast to, clones share their members
+#[derive(Debug, Clone, Default)]
+pub struct InputGroup {
+    members: Arc<Mutex<Vec<PtyId>>>,
+}
+
+/// Ptys controlled as one, clones share their members
+#[derive(Clone)]
+pub struct SessionGroup {
+    name: Arc<str>,
+    shared: Arc<Shared>,
+}
+
+type OnEmpty = Box<dyn FnMut(&str) + Send>;
+
+#[derive(Default)]
+struct Shared {
+    members: Mutex<Vec<PtyId>>,
+    on_empty: Mutex<Option<OnEmpty>>,
+}
+
+/// Outcome of an operation on every member of a group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupReport {
+    /// members the operation succeeded on
+    pub succeeded: Vec<PtyId>,
+    /// members the operation failed on, members that died are left out of the group from then on
+    pub failed: Vec<(PtyId, PtyError)>,
+}
+
+impl InputGroup {
+    pub fn new() -> InputGroup {
+        InputGroup::default()
+    }
+
+    /// adds pty to the group, once
+    pub fn add(&self, pty: &Pty) {
+        let mut members = self.members.lock().unwrap_or_else(PoisonError::into_inner);
+        if !members.contains(&pty.id()) {
+            members.push(pty.id());
+        }
+    }
+
+    /// takes a pty out of the group, false if it was not a member
+    pub fn remove(&self, id: PtyId) -> bool {
+        let mut members = self.members.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = members.len();
+        members.retain(|member| *member != id);
+        members.len() != len
+    }
+
+    /// members in the order they were added
+    pub fn members(&self) -> Vec<PtyId> {
+        self.members.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// writes s to every member like Pty::write()
+    pub fn broadcast(&self, s: &str) -> GroupReport {
+        let mut report = GroupReport::default();
+        let sessions = live(&self.members, &mut report);
+        broadcast(sessions, s, report)
+    }
+}
+
+impl SessionGroup {
+    pub fn new(name: impl Into<String>) -> SessionGroup {
+        SessionGroup { name: name.into().into(), shared: Arc::default() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// adds pty to the group, once, fails if the pty is gone
+    pub fn add(&self, pty: &Pty) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(pty.id())?;
+        {
+            let mut members = self.shared.members.lock().unwrap_or_else(PoisonError::into_inner);
+            if members.contains(&pty.id()) {
+                return Ok(());
+            }
+            members.push(pty.id());
+        }
+        let (name, shared) = (self.name.clone(), Arc::downgrade(&self.shared));
+        session.on_exited(Box::new(move |id| exited(&name, &shared, id)));
+        Ok(())
+    }
+
+    /// takes a pty out of the group, false if it was not a member
+    pub fn remove(&self, id: PtyId) -> bool {
+        let mut members = self.shared.members.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = members.len();
+        members.retain(|member| *member != id);
+        members.len() != len
+    }
+
+    /// members in the order they were added, members leave the group as they exit
+    pub fn members(&self) -> Vec<PtyId> {
+        self.shared.members.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// on_empty is called with the name of the group once its last member exits, on the thread
+    /// that read the pty of that member, not when members are removed with remove()
+    pub fn on_empty<F>(&self, on_empty: F)
+        where F: FnMut(&str) + Send + 'static
+    {
+        *self.shared.on_empty.lock().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(on_empty));
+    }
+
+    /// writes s to every member like Pty::write(), see InputGroup::broadcast()
+    pub fn broadcast(&self, s: &str) -> GroupReport {
+        let mut report = GroupReport::default();
+        let sessions = live(&self.shared.members, &mut report);
+        broadcast(sessions, s, report)
+    }
+
+    /// resizes every member like Pty::resize()
+    pub fn resize(&self, window_size: WindowSize) -> GroupReport {
+        self.each(|pty| pty.resize(window_size))
+    }
+
+    /// signals every member like Pty::signal()
+    pub fn signal(&self, signal: Signal) -> GroupReport {
+        self.each(|pty| pty.signal(signal))
+    }
+
+    /// kills every member like Pty::kill(), without waiting for them to exit
+    pub fn kill(&self) -> GroupReport {
+        self.each(|pty| { pty.kill(); Ok(()) })
+    }
+
+    /// ends every member like shutdown_all() does every pty
+    pub fn shutdown(&self, policy: ShutdownPolicy, timeout: Duration) -> ShutdownReport {
+        let sessions = live(&self.shared.members, &mut GroupReport::default());
+        shutdown::shutdown_sessions(sessions, policy, timeout)
+    }
+
+    /// resources used by all members together, see Pty::resource_usage(), members whose usage
+    /// cannot be read are left out
+    pub fn resource_usage(&self) -> Usage {
+        self.members().into_iter().filter_map(|id| Pty { id }.resource_usage().ok()).fold(Usage::default(), |sum, usage| Usage {
+            cpu_time: sum.cpu_time + usage.cpu_time,
+            rss: sum.rss + usage.rss,
+            n_processes: sum.n_processes + usage.n_processes,
+        })
+    }
+
+    fn each(&self, f: impl Fn(&Pty) -> Result<(), Box<dyn Error>>) -> GroupReport {
+        let mut report = GroupReport::default();
+        for session in live(&self.shared.members, &mut report) {
+            match f(&Pty { id: session.id() }) {
+                Ok(()) => report.succeeded.push(session.id()),
+                Err(err) => report.failed.push((session.id(), PtyError::copy_of(err.as_ref()))),
+            }
+        }
+        report
+    }
+}
+
+impl fmt::Debug for SessionGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionGroup").field("name", &self.name).field("members", &self.members()).finish()
+    }
+}
+
+/**
+ * Sessions of the members still alive, the others are reported as failed and leave the group
+ */
+fn live(members: &Mutex<Vec<PtyId>>, report: &mut GroupReport) -> Vec<Arc<Session>> {
+    let mut sessions = Vec::new();
+    members.lock().unwrap_or_else(PoisonError::into_inner).retain(|&id| match registry::get(id) {
+        Ok(session) => { sessions.push(session); true },
+        Err(err) => { report.failed.push((id, PtyError::copy_of(err.as_ref()))); false }
+    });
+    sessions
+}
+
+fn broadcast(mut sessions: Vec<Arc<Session>>, s: &str, mut report: GroupReport) -> GroupReport {
+    // members whose input is locked by someone else, see Pty::lock_input()
+    sessions.retain(|session| match session.wait_input_lock(None) {
+        Ok(()) => true,
+        Err(err) => { report.failed.push((session.id(), PtyError::copy_of(err.as_ref()))); false }
+    });
+    // always locked in the same order, two broadcasts cannot wait on each other
+    sessions.sort_by_key(|session| session.id().fd());
+    let _writing: Vec<_> = sessions.iter().map(|session| session.writing()).collect();
+    for session in &sessions {
+        // the input may have been locked while waiting for writing()
+        if let Err(err) = session.check_input_lock(None) {
+            report.failed.push((session.id(), PtyError::copy_of(err.as_ref())));
+            continue;
+        }
+        let input = session.config().eol.translate(s);
+        match session.write_held(&input, false) {
+            Ok(()) => report.succeeded.push(session.id()),
+            Err(err) => report.failed.push((session.id(), PtyError::copy_of(err.as_ref()))),
+        }
+    }
+    report
+}
+
+/**
+ * A member of the group named name exited, the group is told once it was the last one
+ */
+fn exited(name: &str, shared: &Weak<Shared>, id: PtyId) {
+    let Some(shared) = shared.upgrade() else { return };
+    let empty = {
+        let mut members = shared.members.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = members.len();
+        members.retain(|member| *member != id);
+        members.is_empty() && members.len() != len
+    };
+    if !empty { return }
+    let mut on_empty = shared.on_empty.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(on_empty) = on_empty.as_mut() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| on_empty(name)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::time::Duration;
+    use crate::test_util;
+
+    #[test]
+    fn broadcast() -> Result<(), Box<dyn Error>> {
+        let spawn = || test_util::stub().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {});
+        let (first, second, dead) = (spawn()?, spawn()?, spawn()?);
+        let group = InputGroup::new();
+        for pty in [&first, &second, &dead, &first] {
+            group.add(pty);
+        }
+        assert_eq!(group.members(), [first.id(), second.id(), dead.id()]);
+
+        dead.shutdown()?;
+        let report = group.broadcast("all\n");
+        assert_eq!(report.succeeded, [first.id(), second.id()]);
+        assert_eq!(report.failed.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [dead.id()]);
+        assert_eq!(group.members(), [first.id(), second.id()]);
+        for pty in [&first, &second] {
+            test_util::wait_for_output(pty, "all\r\nall", Duration::from_secs(10))?;
+        }
+
+        assert!(group.remove(second.id()) && !group.remove(second.id()));
+        first.shutdown()?;
+        second.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn lifecycle() -> Result<(), Box<dyn Error>> {
+        let spawn = || test_util::stub().spawn(|_id, _res| {}, |_id| {});
+        let (build, server) = (spawn()?, spawn()?);
+        let group = SessionGroup::new("project");
+        group.add(&build)?;
+        group.add(&server)?;
+        let (tx, rx) = std::sync::mpsc::channel();
+        group.on_empty(move |name| { let _ = tx.send(name.to_owned()); });
+
+        assert_eq!(group.resize(WindowSize::new(30, 100, 0, 0)).succeeded.len(), 2);
+        assert!(group.resource_usage().n_processes >= 2);
+
+        // members leave as they exit, the group is empty once the last one did
+        build.shutdown()?;
+        assert_eq!(group.members(), [server.id()]);
+        assert!(rx.try_recv().is_err());
+        let report = group.shutdown(ShutdownPolicy::HangUp, Duration::from_secs(10));
+        assert_eq!(report.exited, [server.id()]);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10))?, "project");
+        assert!(group.members().is_empty() && group.signal(Signal::SIGINT).succeeded.is_empty());
+        Ok(())
+    }
+}
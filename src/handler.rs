@@ -0,0 +1,333 @@
+use std::any::Any;
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::error::PtyError;
+use crate::clients::ClientId;
+use crate::id::PtyId;
+use crate::image::InlineImage;
+use crate::message::Message;
+use crate::metrics;
+use crate::patch::Setting;
+use crate::quota::QuotaEvent;
+use crate::sequence::SequenceOverflow;
+use crate::shell_integration::ShellMark;
+use crate::shutdown::ShutdownProgress;
+#[cfg(feature = "triggers")]
+use crate::trigger::TriggerMatch;
+use crate::unix::proc::{ForegroundProcess, StatusLine};
+use crate::unix::window::WindowSize;
+#[cfg(feature = "vt")]
+use crate::vt::ScreenDiff;
+use crate::watchdog::ReaderFailure;
+
+/// Receives everything happening on a pty, an alternative to the on_read/on_death closures
+/// for stateful consumers, the handler lives on the polling thread of its pty
+/// only on_output is required, every other event is ignored unless overridden
+/// a panicking callback does not stop the pty, the panic is passed to on_error instead
+/// ```rust
+/// use pty_exec::{PtyBuilder, PtyHandler, PtyId};
+///
+/// struct Transcript(String);
+///
+/// impl PtyHandler for Transcript {
+///     fn on_output(&mut self, _id: PtyId, output: String) {
+///         self.0.push_str(&output);
+///     }
+///
+///     fn on_exit(&mut self, id: PtyId) {
+///         println!("{id} died after printing {} bytes", self.0.len());
+///     }
+/// }
+///
+/// let pty = PtyBuilder::new().spawn_handler(Transcript(String::new()))?;
+/// pty.shutdown()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub trait PtyHandler: Send + 'static {
+    /// called when there is something to read
+    fn on_output(&mut self, id: PtyId, output: String);
+
+    /// called when the pty dies, after this no other method is called
+    fn on_exit(&mut self, _id: PtyId) {}
+
+    /// called once when the shell looks ready for input, after the startup commands were written,
+    /// see PtyBuilder::startup_commands(), the shell is ready once the shell integration marks
+    /// its first prompt (OSC 133;A), or else once its first output is followed by a moment of
+    /// quiet and does not end with a line break, or at the latest 5 seconds after spawning
+    fn on_ready(&mut self, _id: PtyId) {}
+
+    /// called once the pty has been resized
+    fn on_resize_ack(&mut self, _id: PtyId, _size: WindowSize) {}
+
+    /// called when the title of the session changes, the child set the window title (OSC 0
+    /// or OSC 2) or the fallback title stands in for it, see Pty::title()
+    fn on_title(&mut self, _id: PtyId, _title: String) {}
+
+    /// called when the child sets the icon name (OSC 0 or OSC 1), the shorter name some
+    /// terminals show on tabs, see Pty::icon_name()
+    fn on_icon_name(&mut self, _id: PtyId, _name: String) {}
+
+    /// called when the child rings the bell
+    fn on_bell(&mut self, _id: PtyId) {}
+
+    /// called when the working directory of the shell changes, as the shell reports it (OSC 7),
+    /// see PtyBuilder::shell_integration(), or as PtyBuilder::poll_cwd() finds it
+    fn on_cwd(&mut self, _id: PtyId, _cwd: PathBuf) {}
+
+    /// called when another process comes to the foreground of the pty or the one there execs
+    /// another program, the shell included, see PtyBuilder::poll_foreground()
+    fn on_foreground(&mut self, _id: PtyId, _process: ForegroundProcess) {}
+
+    /// called with the status line the tty printed in answer to Pty::request_status()
+    fn on_status(&mut self, _id: PtyId, _status: StatusLine) {}
+
+    /// called when the shell marks a prompt or command boundary (OSC 133)
+    fn on_shell_mark(&mut self, _id: PtyId, _mark: ShellMark) {}
+
+    /// called when the shell integration reports the tag of the session the shell runs in and
+    /// it differs from the one reported before, see PtyBuilder::tag(), a shell of a nested
+    /// session, e.g. over ssh, reports the tag of its own session
+    fn on_session_tag(&mut self, _id: PtyId, _tag: String) {}
+
+    /// called when the child draws an image with the protocol of iTerm2 or kitty, the sequence
+    /// whole however it was split across reads, see the image module
+    fn on_inline_image(&mut self, _id: PtyId, _image: InlineImage) {}
+
+    /// called when a control string of the child ends that was longer than
+    /// PtyBuilder::max_sequence_len() and so was dropped, see the sequence module
+    fn on_sequence_overflow(&mut self, _id: PtyId, _overflow: SequenceOverflow) {}
+
+    /// called when the child begins (true) or ends a synchronized update (DEC mode 2026), after
+    /// the output holding the sequence, see PtyBuilder::hold_synchronized_output()
+    fn on_synchronized_output(&mut self, _id: PtyId, _active: bool) {}
+
+    /// called when input starts (true) or stops being hidden, the tty not echoing it while not
+    /// handing it to a line editor either, like at a password prompt, checked as output arrives,
+    /// see Pty::is_echo_off()
+    fn on_echo_change(&mut self, _id: PtyId, _hidden: bool) {}
+
+    /// called for every step of Pty::shutdown(), e.g. to show a session as closing,
+    /// Reaped tells a clean exit from a forced one
+    fn on_shutdown(&mut self, _id: PtyId, _progress: ShutdownProgress) {}
+
+    /// called when a quota is exceeded, after its policy was applied, see PtyBuilder::quota()
+    fn on_quota(&mut self, _id: PtyId, _event: QuotaEvent) {}
+
+    /// called once settings of the session were changed, see Pty::update_config()
+    fn on_config_change(&mut self, _id: PtyId, _changed: Vec<Setting>) {}
+
+    /// called when a trigger with a TriggerAction::Event action matches, see Pty::add_trigger()
+    #[cfg(feature = "triggers")]
+    fn on_trigger(&mut self, _id: PtyId, _name: String, _matched: TriggerMatch) {}
+
+    /// called with a message of an attached client, or of the host for the handler of a
+    /// client::Client, where from is `None`, see the message module
+    fn on_message(&mut self, _id: PtyId, _from: Option<ClientId>, _message: Message) {}
+
+    /// called by a client::Client with what became of input written with
+    /// Client::write_predicted(), numbered seq, right after the output holding its echo if
+    /// echoed, else the input shown ahead of its echo should be taken back, e.g. echo is off
+    fn on_echo(&mut self, _id: PtyId, _seq: u64, _echoed: bool) {}
+
+    /// called by a client connected with Client::connect_diffs() in place of on_output, with
+    /// the rows of the screen that changed, see vt::ScreenMirror
+    #[cfg(feature = "vt")]
+    fn on_screen_diff(&mut self, _id: PtyId, _diff: ScreenDiff) {}
+
+    /// called when the thread polling the pty failed, restarted tells whether it polls again,
+    /// otherwise the pty is closed as if it had hung up, see the watchdog module
+    /// passed to on_error unless overridden
+    fn on_reader_failure(&mut self, id: PtyId, failure: ReaderFailure, _restarted: bool) {
+        self.on_error(id, Box::new(failure));
+    }
+
+    /// called when reading from the pty fails, or when another callback panicked
+    fn on_error(&mut self, _id: PtyId, _err: Box<dyn Error>) {}
+}
+
+/**
+ * Adapts a pair of on_read/on_death closures to a handler
+ */
+pub(crate) struct Callbacks<F, G> {
+    pub on_read: F,
+    pub on_death: G,
+}
+
+impl<F, G> PtyHandler for Callbacks<F, G>
+    where
+        F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+        G: FnMut(PtyId) + Send + 'static
+{
+    fn on_output(&mut self, id: PtyId, output: String) {
+        (self.on_read)(id, Ok(output))
+    }
+
+    fn on_exit(&mut self, id: PtyId) {
+        (self.on_death)(id)
+    }
+
+    fn on_error(&mut self, id: PtyId, err: Box<dyn Error>) {
+        (self.on_read)(id, Err(err))
+    }
+}
+
+/// Runs a task wherever the embedder wants callbacks to happen, see PtyBuilder::executor()
+pub type Executor = Arc<dyn Fn(Box<dyn FnOnce() + Send>) + Send + Sync>;
+
+/**
+ * Forwards every callback to an executor, the handler is shared with the submitted tasks
+ * errors are not Send so they cross over as PtyError holding their message
+ */
+pub(crate) struct Dispatched<H> {
+    handler: Arc<Mutex<H>>,
+    executor: Executor,
+}
+
+impl<H: PtyHandler> Dispatched<H> {
+    pub(crate) fn new(handler: H, executor: Executor) -> Dispatched<H> {
+        Dispatched { handler: Arc::new(Mutex::new(handler)), executor }
+    }
+
+    fn dispatch(&self, id: PtyId, f: impl FnOnce(&mut H) + Send + 'static) {
+        let handler = self.handler.clone();
+        (self.executor)(Box::new(move || {
+            // a task panicking while the lock is held poisons it, the handler is still usable
+            let mut handler = handler.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            contain(&mut *handler, id, f)
+        }));
+    }
+}
+
+impl<H: PtyHandler> PtyHandler for Dispatched<H> {
+    fn on_output(&mut self, id: PtyId, output: String) {
+        self.dispatch(id, move |handler| handler.on_output(id, output))
+    }
+
+    fn on_exit(&mut self, id: PtyId) {
+        self.dispatch(id, move |handler| handler.on_exit(id))
+    }
+
+    fn on_ready(&mut self, id: PtyId) {
+        self.dispatch(id, move |handler| handler.on_ready(id))
+    }
+
+    fn on_resize_ack(&mut self, id: PtyId, size: WindowSize) {
+        self.dispatch(id, move |handler| handler.on_resize_ack(id, size))
+    }
+
+    fn on_title(&mut self, id: PtyId, title: String) {
+        self.dispatch(id, move |handler| handler.on_title(id, title))
+    }
+
+    fn on_bell(&mut self, id: PtyId) {
+        self.dispatch(id, move |handler| handler.on_bell(id))
+    }
+
+    fn on_cwd(&mut self, id: PtyId, cwd: PathBuf) {
+        self.dispatch(id, move |handler| handler.on_cwd(id, cwd))
+    }
+
+    fn on_foreground(&mut self, id: PtyId, process: ForegroundProcess) {
+        self.dispatch(id, move |handler| handler.on_foreground(id, process))
+    }
+
+    fn on_status(&mut self, id: PtyId, status: StatusLine) {
+        self.dispatch(id, move |handler| handler.on_status(id, status))
+    }
+
+    fn on_shell_mark(&mut self, id: PtyId, mark: ShellMark) {
+        self.dispatch(id, move |handler| handler.on_shell_mark(id, mark))
+    }
+
+    fn on_session_tag(&mut self, id: PtyId, tag: String) {
+        self.dispatch(id, move |handler| handler.on_session_tag(id, tag))
+    }
+
+    fn on_inline_image(&mut self, id: PtyId, image: InlineImage) {
+        self.dispatch(id, move |handler| handler.on_inline_image(id, image))
+    }
+
+    fn on_sequence_overflow(&mut self, id: PtyId, overflow: SequenceOverflow) {
+        self.dispatch(id, move |handler| handler.on_sequence_overflow(id, overflow))
+    }
+
+    fn on_synchronized_output(&mut self, id: PtyId, active: bool) {
+        self.dispatch(id, move |handler| handler.on_synchronized_output(id, active))
+    }
+
+    fn on_echo_change(&mut self, id: PtyId, hidden: bool) {
+        self.dispatch(id, move |handler| handler.on_echo_change(id, hidden))
+    }
+
+    fn on_icon_name(&mut self, id: PtyId, name: String) {
+        self.dispatch(id, move |handler| handler.on_icon_name(id, name))
+    }
+
+    fn on_shutdown(&mut self, id: PtyId, progress: ShutdownProgress) {
+        self.dispatch(id, move |handler| handler.on_shutdown(id, progress))
+    }
+
+    fn on_quota(&mut self, id: PtyId, event: QuotaEvent) {
+        self.dispatch(id, move |handler| handler.on_quota(id, event))
+    }
+
+    fn on_config_change(&mut self, id: PtyId, changed: Vec<Setting>) {
+        self.dispatch(id, move |handler| handler.on_config_change(id, changed))
+    }
+
+    #[cfg(feature = "triggers")]
+    fn on_trigger(&mut self, id: PtyId, name: String, matched: TriggerMatch) {
+        self.dispatch(id, move |handler| handler.on_trigger(id, name, matched))
+    }
+
+    fn on_message(&mut self, id: PtyId, from: Option<ClientId>, message: Message) {
+        self.dispatch(id, move |handler| handler.on_message(id, from, message))
+    }
+
+    fn on_echo(&mut self, id: PtyId, seq: u64, echoed: bool) {
+        self.dispatch(id, move |handler| handler.on_echo(id, seq, echoed))
+    }
+
+    #[cfg(feature = "vt")]
+    fn on_screen_diff(&mut self, id: PtyId, diff: ScreenDiff) {
+        self.dispatch(id, move |handler| handler.on_screen_diff(id, diff))
+    }
+
+    fn on_reader_failure(&mut self, id: PtyId, failure: ReaderFailure, restarted: bool) {
+        self.dispatch(id, move |handler| handler.on_reader_failure(id, failure, restarted))
+    }
+
+    fn on_error(&mut self, id: PtyId, err: Box<dyn Error>) {
+        let err = PtyError::copy_of(err.as_ref());
+        self.dispatch(id, move |handler| handler.on_error(id, Box::new(err)))
+    }
+}
+
+/**
+ * Runs a callback of the handler, a panic is caught and passed to on_error so it cannot
+ * take down the polling thread and strand the pty
+ */
+pub(crate) fn contain<H: PtyHandler + ?Sized>(handler: &mut H, id: PtyId, f: impl FnOnce(&mut H)) {
+    let started = Instant::now();
+    let res = panic::catch_unwind(AssertUnwindSafe(|| f(handler)));
+    metrics::callback(started.elapsed());
+    if let Err(panic) = res {
+        let err = Box::new(PtyError::new(format!("Callback panicked: {}", panic_message(panic.as_ref()))));
+        // a panicking on_error has nowhere left to go
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| handler.on_error(id, err)));
+    }
+}
+
+/**
+ * The message a panic was raised with
+ */
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(msg), _) => msg.to_string(),
+        (_, Some(msg)) => msg.clone(),
+        _ => "unknown panic".into()
+    }
+}
@@ -0,0 +1,73 @@
+//! Telling a working session from a wedged one, see Pty::health()
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//!
+//! let pty = Pty::spawn(|_id, _res| {}, |_id| {})?;
+//! let health = pty.health()?;
+//! // nothing reads the output of a shell that is still running, it can only be killed
+//! if health.child_alive && !health.reader_alive {
+//!     pty.kill();
+//! } else if health.last_io.elapsed() > Duration::from_secs(3600) {
+//!     pty.shutdown()?;
+//! }
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::time::Instant;
+use crate::registry::Session;
+use crate::unix;
+
+/// State of a pty's parts, see Pty::health()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    /// the child has not exited yet
+    pub child_alive: bool,
+    /// the thread reading the pty is still running
+    pub reader_alive: bool,
+    /// the master fd is still open
+    pub fd_valid: bool,
+    /// when output was last read or input written, when the pty was spawned if neither happened,
+    /// by the clock of the session
+    pub last_io: Instant,
+}
+
+impl Health {
+    /// every part works, the session may still be idle
+    pub fn is_healthy(&self) -> bool {
+        self.child_alive && self.reader_alive && self.fd_valid
+    }
+}
+
+pub(crate) fn check(session: &Session) -> Result<Health, Box<dyn Error>> {
+    Ok(Health {
+        child_alive: unix::pty::child_alive(session.child())?,
+        reader_alive: session.reader_alive(),
+        fd_valid: session.with_fd(|fd| Ok(unix::pty::fd_valid(fd))).unwrap_or(false),
+        last_io: session.last_io(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::time::{Duration, Instant};
+    use crate::test_util;
+
+    #[test]
+    fn healthy() -> Result<(), Box<dyn Error>> {
+        let pty = test_util::stub().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        let before = Instant::now();
+        pty.write("alive\n")?;
+        test_util::wait_for_output(&pty, "alive\r\nalive", Duration::from_secs(10))?;
+
+        let health = pty.health()?;
+        assert!(health.is_healthy(), "{health:?}");
+        assert!(health.last_io >= before);
+        pty.shutdown()?;
+        assert!(pty.health().is_err());
+        Ok(())
+    }
+}
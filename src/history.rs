@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use crate::clock::Clock;
+use crate::shell_integration::ShellMark;
+
+// older commands are dropped, a session running for weeks should not grow without bound
+const MAX_RECORDS: usize = 1000;
+
+/// A command run in a pty, see Pty::command_history()
+/// commands are only seen when the shell marks them with OSC 133, e.g. with
+/// PtyBuilder::shell_integration(), the text is the command line as the shell echoed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommandRecord {
+    pub text: String,
+    pub started_at: SystemTime,
+    pub duration: Duration,
+    /// None if the shell did not report it
+    pub exit_code: Option<i32>,
+}
+
+/**
+ * Commands of a session, fed with the command lines and marks the scanner finds
+ */
+#[derive(Debug)]
+pub(crate) struct History {
+    records: VecDeque<CommandRecord>,
+    // command line of the command running, and when it started
+    running: Option<(String, SystemTime, Instant)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl History {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> History {
+        History { records: VecDeque::new(), running: None, clock }
+    }
+
+    /**
+     * A command line was entered, it runs until the shell marks it finished
+     */
+    pub(crate) fn started(&mut self, text: String) {
+        self.running = Some((text, self.clock.system_now(), self.clock.now()));
+    }
+
+    pub(crate) fn mark(&mut self, mark: ShellMark) {
+        // bash marks empty command lines finished too, without a command running
+        let ShellMark::CommandFinished { exit_code } = mark else { return };
+        let Some((text, started_at, started)) = self.running.take() else { return };
+
+        if self.records.len() == MAX_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back(CommandRecord { text, started_at, duration: self.clock.elapsed(started), exit_code });
+    }
+
+    /**
+     * Commands of a session adopted from another process, see the migrate module
+     */
+    #[cfg(feature = "migrate")]
+    pub(crate) fn restore(&mut self, records: Vec<CommandRecord>) {
+        self.records = records.into_iter().rev().take(MAX_RECORDS).rev().collect();
+    }
+
+    pub(crate) fn records(&self) -> Vec<CommandRecord> {
+        self.records.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::SystemClock;
+    use super::*;
+
+    #[test]
+    fn records() {
+        let mut history = History::new(Arc::new(SystemClock));
+        history.mark(ShellMark::CommandFinished { exit_code: Some(0) });
+        history.started("make".into());
+        history.mark(ShellMark::PromptStart);
+        history.mark(ShellMark::CommandFinished { exit_code: Some(2) });
+
+        let records = history.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].text.as_str(), records[0].exit_code), ("make", Some(2)));
+    }
+}
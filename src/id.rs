@@ -0,0 +1,34 @@
+use std::fmt;
+use std::os::fd::RawFd;
+
+/// Identity of a spawned pty, the master fd tagged with a generation
+/// the kernel reuses fd numbers once a pty dies, the generation makes sure a stale
+/// handle can never address a newer session that happens to get the same fd
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PtyId {
+    fd: RawFd,
+    generation: u64,
+}
+
+impl PtyId {
+    pub(crate) fn new(fd: RawFd, generation: u64) -> PtyId {
+        PtyId { fd, generation }
+    }
+
+    /// master file descriptor of the pty
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// generation of the fd, unique for the lifetime of the process
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl fmt::Display for PtyId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}#{}", self.fd, self.generation)
+    }
+}
@@ -0,0 +1,41 @@
+//! Inline images the child draws with the image protocols of iTerm2 (OSC 1337 File=) and kitty
+//! (APC G), found in its output and passed whole to PtyHandler::on_inline_image(), however the
+//! sequence was split across reads, the sequences stay in the output as well
+//! images longer than PtyBuilder::max_sequence_len() are dropped, see the sequence module,
+//! kitty splits larger images into chunks of its own, each chunk is an InlineImage of its own
+//! with m=1 among its options but the last
+//! ```rust
+//! use pty_exec::{PtyHandler, PtyId};
+//! use pty_exec::image::{ImageFormat, InlineImage};
+//!
+//! struct Images(Vec<InlineImage>);
+//!
+//! impl PtyHandler for Images {
+//!     fn on_output(&mut self, _id: PtyId, _output: String) {}
+//!
+//!     fn on_inline_image(&mut self, _id: PtyId, image: InlineImage) {
+//!         if image.format == ImageFormat::Iterm2 {
+//!             self.0.push(image);
+//!         }
+//!     }
+//! }
+//! ```
+
+/// The protocol an inline image was drawn with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ImageFormat {
+    /// OSC 1337 ; File=options:base64 BEL
+    Iterm2,
+    /// APC G options;base64 ST
+    Kitty,
+}
+
+/// An image found in the output of a pty, see the image module
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InlineImage {
+    pub format: ImageFormat,
+    /// the sequence past File= or G, its options and the base64 of the image as the child sent them
+    pub data: String,
+}
@@ -0,0 +1,78 @@
+//! Facts about a spawned pty in one place, see Pty::info()
+//! ```rust
+//! use pty_exec::Pty;
+//!
+//! let pty = Pty::spawn(|_id, _res| {}, |_id| {})?;
+//! let info = pty.info()?;
+//! println!("{} runs {} (pid {}) on {:?}", info.id, info.shell, info.child_pid, info.slave_path);
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::path::PathBuf;
+use std::time::SystemTime;
+use crate::id::PtyId;
+use crate::registry::Session;
+use crate::unix::window::WindowSize;
+
+/// What a pty was spawned as, none of it changes while it lives, see Pty::info()
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PtyInfo {
+    pub id: PtyId,
+    /// pid of the shell, also its process group and session
+    pub child_pid: i32,
+    /// path of the pty slave, e.g. /dev/pts/3, `None` if the backend did not spawn on a pty
+    pub slave_path: Option<PathBuf>,
+    /// the shell that was started, the user's or one of PtyBuilder::fallback_shells()
+    pub shell: String,
+    /// see PtyBuilder::tag()
+    pub tag: Option<String>,
+    /// window size the child started with, see Pty::resize() for the size since
+    pub initial_size: WindowSize,
+    pub spawned_at: SystemTime,
+}
+
+pub(crate) fn collect(session: &Session) -> PtyInfo {
+    PtyInfo {
+        id: session.id(),
+        child_pid: session.child().as_raw(),
+        slave_path: session.slave_path().map(PathBuf::from),
+        shell: session.shell().to_owned(),
+        tag: session.config().tag.clone(),
+        initial_size: session.initial_size(),
+        spawned_at: session.spawned_at(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::time::{Duration, SystemTime};
+    use crate::unix::window::WindowSize;
+    use crate::{test_util, Pty};
+
+    #[test]
+    fn spawn_facts() -> Result<(), Box<dyn Error>> {
+        let before = SystemTime::now();
+        let pty = Pty::builder()
+            .scrollback(0x10000)
+            .tag("info")
+            .window_size(WindowSize::new(30, 100, 0, 0))
+            .spawn(|_id, _res| {}, |_id| {})?;
+        let info = pty.info()?;
+        assert_eq!((info.id, info.tag.as_deref()), (pty.id(), Some("info")));
+        assert_eq!(info.shell, pty.shell()?);
+        assert!(info.spawned_at >= before);
+
+        // the shell sees the same pty and is the pid
+        pty.write("echo \"$$:$(tty)\"\r")?;
+        let slave = info.slave_path.as_ref().ok_or("no slave path")?.display().to_string();
+        test_util::wait_for_output(&pty, &format!("{}:{slave}", info.child_pid), Duration::from_secs(10))?;
+
+        pty.resize(WindowSize::new(40, 120, 0, 0))?;
+        assert_eq!(pty.info()?.initial_size, WindowSize::new(30, 100, 0, 0));
+        pty.shutdown()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! Translation applied to input before it reaches the child
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::input::{Eol, Key};
+//!
+//! let pty = Pty::builder().eol(Eol::Cr).spawn(|_id, _res| {}, |_id| {})?;
+//! // both lines reach the shell as if enter was pressed
+//! pty.write("echo one\necho two\r\n")?;
+//! // bytes meant for the child as they are
+//! pty.write_raw("\x1b[A")?;
+//! // keys are encoded by the keymap of the pty, xterm by default
+//! pty.write_key(Key::Up)?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// Line ending written for every line ending in the input of Pty::write()
+/// "\r\n", "\n" and a lone "\r" all count as one line ending, a "\r\n" split across
+/// two writes counts as two
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Eol {
+    /// input is written as is
+    #[default]
+    Raw,
+    /// "\r", what the enter key sends, for a child reading a tty
+    Cr,
+    /// "\n", for a child reading a piped stdin
+    Lf,
+}
+
+impl Eol {
+    pub(crate) fn translate(self, input: &str) -> Cow<'_, str> {
+        let eol = match self {
+            Eol::Raw => return Cow::Borrowed(input),
+            Eol::Cr => "\r",
+            Eol::Lf => "\n",
+        };
+        if !input.contains(['\r', '\n']) {
+            return Cow::Borrowed(input);
+        }
+
+        let mut translated = String::with_capacity(input.len());
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\r' => {
+                    chars.next_if_eq(&'\n');
+                    translated.push_str(eol);
+                },
+                '\n' => translated.push_str(eol),
+                c => translated.push(c),
+            }
+        }
+        Cow::Owned(translated)
+    }
+}
+
+/// What input sanitized for coming from an untrusted source becomes of control characters,
+/// all but tab and line endings are taken: ESC and with it every escape sequence, C1 controls
+/// like DCS or CSI, backspace and DEL, so pasted text can't drive the child or the terminal,
+/// see Pty::write_untrusted() and PtyBuilder::sanitize_input()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Sanitize {
+    /// control characters are dropped
+    #[default]
+    Strip,
+    /// control characters are written in caret notation like cat -v shows them, "^[" for ESC
+    Escape,
+}
+
+impl Sanitize {
+    /// input with the control characters it may not hold stripped or escaped
+    pub fn apply(self, input: &str) -> Cow<'_, str> {
+        let unsafe_char = |c: char| c.is_control() && !matches!(c, '\t' | '\r' | '\n');
+        if !input.contains(unsafe_char) {
+            return Cow::Borrowed(input);
+        }
+
+        let mut sanitized = String::with_capacity(input.len());
+        for c in input.chars() {
+            if !unsafe_char(c) {
+                sanitized.push(c);
+                continue;
+            }
+            if self == Sanitize::Escape {
+                let byte = c as u32 as u8;
+                if byte >= 0x80 {
+                    sanitized.push_str("M-");
+                }
+                sanitized.push('^');
+                sanitized.push(if byte & 0x7f == 0x7f { '?' } else { ((byte & 0x7f) ^ 0x40) as char });
+            }
+        }
+        Cow::Owned(sanitized)
+    }
+}
+
+/// A key pressed in a frontend, written to the child with Pty::write_key()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Right,
+    Left,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// function key F1 to F12
+    F(u8),
+}
+
+/// The control character ctrl and c send, '\x03' for 'c' or 'C', '\x7f' for '?', None for a c
+/// without one, control characters are what line editors bind their commands to, see
+/// Pty::write_control()
+pub fn control(c: char) -> Option<char> {
+    match c.to_ascii_uppercase() {
+        c @ '@'..='_' => Some((c as u8 ^ 0x40) as char),
+        '?' => Some('\x7f'),
+        _ => None,
+    }
+}
+
+/// Encodes keys as the bytes a terminal sends for them, set per pty with PtyBuilder::keymap()
+/// applications built for one terminal often misread the keys of another, e.g. a curses app
+/// expecting rxvt's Home and End, a keymap lets the frontend stay unaware of that
+/// ```rust
+/// use std::borrow::Cow;
+/// use pty_exec::input::{Key, Keymap, Xterm};
+///
+/// // xterm, except for a backspace sending ^H
+/// #[derive(Debug)]
+/// struct CtrlH;
+///
+/// impl Keymap for CtrlH {
+///     fn encode(&self, key: Key) -> Cow<'static, str> {
+///         match key {
+///             Key::Backspace => Cow::Borrowed("\x08"),
+///             key => Xterm.encode(key),
+///         }
+///     }
+/// }
+/// ```
+pub trait Keymap: fmt::Debug + Send + Sync {
+    /// bytes sent for key, empty if the terminal has no sequence for it
+    fn encode(&self, key: Key) -> Cow<'static, str>;
+}
+
+/// Keys of xterm and most terminals modelled on it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xterm;
+
+/// Keys of rxvt, which differ from xterm in Home, End and F1 to F4
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rxvt;
+
+/// Keys of the vt220 and the linux console, which differ from xterm in Home and End
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vt220;
+
+impl Keymap for Xterm {
+    fn encode(&self, key: Key) -> Cow<'static, str> {
+        Cow::Borrowed(match key {
+            Key::Char(c) => return Cow::Owned(c.to_string()),
+            Key::Enter => "\r",
+            Key::Tab => "\t",
+            Key::Backspace => "\x7f",
+            Key::Escape => "\x1b",
+            Key::Up => "\x1b[A",
+            Key::Down => "\x1b[B",
+            Key::Right => "\x1b[C",
+            Key::Left => "\x1b[D",
+            Key::Home => "\x1b[H",
+            Key::End => "\x1b[F",
+            Key::Insert => "\x1b[2~",
+            Key::Delete => "\x1b[3~",
+            Key::PageUp => "\x1b[5~",
+            Key::PageDown => "\x1b[6~",
+            Key::F(1) => "\x1bOP",
+            Key::F(2) => "\x1bOQ",
+            Key::F(3) => "\x1bOR",
+            Key::F(4) => "\x1bOS",
+            Key::F(5) => "\x1b[15~",
+            Key::F(6) => "\x1b[17~",
+            Key::F(7) => "\x1b[18~",
+            Key::F(8) => "\x1b[19~",
+            Key::F(9) => "\x1b[20~",
+            Key::F(10) => "\x1b[21~",
+            Key::F(11) => "\x1b[23~",
+            Key::F(12) => "\x1b[24~",
+            Key::F(_) => "",
+        })
+    }
+}
+
+impl Keymap for Rxvt {
+    fn encode(&self, key: Key) -> Cow<'static, str> {
+        Cow::Borrowed(match key {
+            Key::Home => "\x1b[7~",
+            Key::End => "\x1b[8~",
+            Key::F(1) => "\x1b[11~",
+            Key::F(2) => "\x1b[12~",
+            Key::F(3) => "\x1b[13~",
+            Key::F(4) => "\x1b[14~",
+            key => return Xterm.encode(key),
+        })
+    }
+}
+
+impl Keymap for Vt220 {
+    fn encode(&self, key: Key) -> Cow<'static, str> {
+        Cow::Borrowed(match key {
+            Key::Home => "\x1b[1~",
+            Key::End => "\x1b[4~",
+            key => return Xterm.encode(key),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_eol() {
+        let input = "a\nb\r\nc\rd";
+        assert_eq!(Eol::Raw.translate(input), input);
+        assert_eq!(Eol::Cr.translate(input), "a\rb\rc\rd");
+        assert_eq!(Eol::Lf.translate(input), "a\nb\nc\nd");
+        assert!(matches!(Eol::Cr.translate("no line ending"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn sanitize() {
+        let input = "ls\x1b[2J\x1b]52;c;aGk=\x07\tx\x08\x7f\u{9b}31m\r\n";
+        assert_eq!(Sanitize::Strip.apply(input), "ls[2J]52;c;aGk=\tx31m\r\n");
+        assert_eq!(Sanitize::Escape.apply(input), "ls^[[2J^[]52;c;aGk=^G\tx^H^?M-^[31m\r\n");
+        assert!(matches!(Sanitize::Strip.apply("é\tok\r\n"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn keymaps() {
+        assert_eq!(Xterm.encode(Key::Home), "\x1b[H");
+        assert_eq!(Rxvt.encode(Key::Home), "\x1b[7~");
+        assert_eq!(Vt220.encode(Key::End), "\x1b[4~");
+        assert_eq!(Rxvt.encode(Key::Delete), Xterm.encode(Key::Delete));
+        assert_eq!(Xterm.encode(Key::Char('é')), "é");
+        assert_eq!(Xterm.encode(Key::F(13)), "");
+    }
+
+    #[test]
+    fn controls() {
+        assert_eq!(control('c'), Some('\x03'));
+        assert_eq!(control('U'), Some('\x15'));
+        assert_eq!(control('['), Some('\x1b'));
+        assert_eq!(control('?'), Some('\x7f'));
+        assert_eq!(control('1'), None);
+        assert_eq!(control('é'), None);
+    }
+}
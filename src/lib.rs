@@ -3,10 +3,10 @@
 //! use pty_exec::Pty;
 //!
 //! // spawn Pty
-//! let pty = Pty::spawn(move |_fd, res| {
+//! let pty = Pty::spawn(move |_id, res| {
 //!     println!("-> {}", res.unwrap());
-//! }, move |fd| {
-//!     println!("-> {fd} died");
+//! }, move |id| {
+//!     println!("-> {id} died");
 //! })?;
 //!
 //! // (optional) create new pty, this maintains the on_read and on_death callbacks
@@ -16,72 +16,1013 @@
 //! pty.write("echo 'Hello, World'\r")?;
 //!
 //! pty.kill();
+//! # Ok::<(), Box<dyn std::error::Error>>(())
 //! ```
 
-pub mod error;
+#[cfg(feature = "attach")]
+pub mod compress;
+pub mod message;
+#[cfg(feature = "attach")]
+pub mod protocol;
 mod unix;
+// the screen of a session is left out of wasm32 builds, see native!
+#[cfg(feature = "vt")]
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub mod vt;
+#[cfg(feature = "wasm-bridge")]
+pub mod wasm;
 
-pub use error::PtyError;
-use std::error::Error;
-use std::os::fd::{FromRawFd, AsRawFd, RawFd};
-use crate::unix::window::WindowSize;
+/**
+ * Items of the native crate, a wasm32 build only has the framing of the attach protocol for
+ * browser frontends, see the wasm module
+ */
+macro_rules! native {
+    ($($item:item)*) => { $(#[cfg(not(target_arch = "wasm32"))] $item)* };
+}
+
+native! {
+    pub mod answer;
+    pub mod audit;
+    #[cfg(feature = "attach")]
+    pub mod auth;
+    pub mod backend;
+    pub mod builder;
+    pub mod cancel;
+    #[cfg(feature = "chaos")]
+    pub mod chaos;
+    #[cfg(feature = "capi")]
+    pub mod capi;
+    #[cfg(feature = "attach")]
+    pub mod client;
+    pub mod clients;
+    pub mod clock;
+    #[cfg(feature = "completion")]
+    pub mod completion;
+    mod echo;
+    pub mod error;
+    pub mod filter;
+    pub mod fork;
+    pub mod group;
+    pub mod handler;
+    pub mod health;
+    pub mod history;
+    pub mod id;
+    pub mod image;
+    pub mod info;
+    pub mod input;
+    pub mod limit;
+    pub mod lock;
+    pub mod macros;
+    pub mod metrics;
+    #[cfg(feature = "migrate")]
+    pub mod migrate;
+    pub mod paste;
+    pub mod patch;
+    #[cfg(feature = "profiles")]
+    pub mod profile;
+    #[cfg(feature = "python")]
+    mod python;
+    pub mod quota;
+    pub mod recording;
+    mod registry;
+    pub mod retention;
+    mod scanner;
+    pub mod sequence;
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    pub mod sandbox;
+    pub mod scope;
+    pub mod scrollback;
+    #[cfg(feature = "attach")]
+    pub mod server;
+    pub mod shell_integration;
+    pub mod shutdown;
+    pub mod split;
+    pub mod state;
+    pub mod template;
+    #[cfg(feature = "triggers")]
+    pub mod trigger;
+    #[cfg(any(test, feature = "test-util"))]
+    pub mod test_util;
+    pub mod watchdog;
+}
+
+pub use crate::unix::window::WindowSize;
+
+native! {
+    pub use builder::{PtyBuilder, StdioMode, SESSION_ID_ENV};
+    pub use error::PtyError;
+    pub use fork::at_fork;
+    pub use handler::{Executor, PtyHandler};
+    pub use health::Health;
+    pub use id::PtyId;
+    pub use info::PtyInfo;
+    pub use scrollback::{Cursor, Direction, MatchPos, OutputSince, Search};
+    pub use shutdown::shutdown_all;
+    pub use split::{PtyReader, PtyWriter};
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::fs::File;
+    use std::os::fd::{FromRawFd, AsRawFd, RawFd};
+    use std::path::Path;
+    use nix::sys::signal::Signal;
+    use nix::sys::termios::{FlowArg, FlushArg};
+    use crate::audit::AuditAction;
+    use crate::cancel::CancellationToken;
+    use crate::clients::{ClientEvent, ClientId, DetachPolicy, OutputProfile};
+    use crate::history::CommandRecord;
+    use crate::input::Key;
+    use crate::lock::{Contention, InputGuard};
+    use crate::macros::Macro;
+    use crate::message::Message;
+    use crate::paste::Paste;
+    use crate::patch::ConfigPatch;
+    #[cfg(feature = "profiles")]
+    use crate::profile::SessionConfig;
+    use crate::recording::Recording;
+    use crate::registry::{Notice, Session};
+    use crate::retention::SessionState;
+    use crate::scope::Scope;
+    use crate::state::SessionSnapshot;
+    #[cfg(feature = "triggers")]
+    use crate::trigger::{Regex, TriggerAction, TriggerId};
+    #[cfg(feature = "vt")]
+    use crate::vt::Position;
+    pub use crate::unix::proc::{ForegroundProcess, StatusLine, Usage};
+    pub use crate::unix::shell::{ShellUser, UserLookup};
+}
 
-/// Pty struct that encapsulates pid of our tty
-/// _DOES NOT_ close pty on drop() _ONLY_ on Pty::kill()
+#[cfg(not(target_arch = "wasm32"))]
+/// Pty struct that encapsulates the id of our tty
+/// _DOES NOT_ close pty on drop() _ONLY_ on Pty::kill() or Pty::shutdown()
 /// this is so that a pty process can outlive this struct
+/// once the pty dies every handle to it goes stale, even if the kernel reuses its fd
 pub struct Pty {
-    pid: RawFd
+    id: PtyId
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl Pty {
     /// Spawns a new pty,
     /// on_read: callback called when there is something to read
     /// on_death: callback called when there the pty dies
     pub fn spawn<F, G>(on_read: F, on_death: G) -> Result<Pty, Box<dyn Error>>
         where
-            F: FnMut(RawFd, Result<String, Box<dyn Error>>) + Send + 'static,
-            G: FnMut(RawFd) + Send + 'static
+            F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+            G: FnMut(PtyId) + Send + 'static
+    {
+        PtyBuilder::new().spawn(on_read, on_death)
+    }
+
+    /// Spawns a new pty, everything happening on it is passed to handler
+    pub fn spawn_handler<H: PtyHandler>(handler: H) -> Result<Pty, Box<dyn Error>> {
+        PtyBuilder::new().spawn_handler(handler)
+    }
+
+    /// builder for spawning a configured pty
+    pub fn builder() -> PtyBuilder {
+        PtyBuilder::new()
+    }
+
+    /// Spawns the session config describes, see the profile module
+    #[cfg(feature = "profiles")]
+    pub fn from_config<F, G>(config: &SessionConfig, on_read: F, on_death: G) -> Result<Pty, Box<dyn Error>>
+        where
+            F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+            G: FnMut(PtyId) + Send + 'static
     {
-        let master = unix::pty::spawn()?;
-        unix::pty::poll(master, on_read, on_death)?;
+        config.builder().spawn(on_read, on_death)
+    }
+
+    /// runs f with a scope whose ptys are shut down, closed and their threads joined before
+    /// scope() returns, even if f panics, see the scope module
+    pub fn scope<T>(f: impl FnOnce(&Scope) -> T) -> T {
+        let scope = Scope::new();
+        f(&scope)
+    }
 
-        Ok(Pty { pid: master })
+    /// id of the pty, stable for its whole lifetime
+    pub fn id(&self) -> PtyId {
+        self.id
     }
 
-    /// write to pty
+    /// write to pty, or to the stdin pipe if stdin is piped
+    /// line endings are translated as configured with PtyBuilder::eol()
     pub fn write(&self, s: &str) -> Result<(), Box<dyn Error>> {
-        unix::pty::write(self.pid, s.as_bytes())
+        let session = registry::get(self.id)?;
+        let s = session.config().eol.translate(s);
+        session.write_input(&s, false)
+    }
+
+    /// write to pty like Pty::write() but without translating line endings
+    pub fn write_raw(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        session.write_input(s, false)
+    }
+
+    /// write input from an untrusted source like text pasted in a web client to pty like
+    /// Pty::write(), control characters other than tab and line endings are sanitized, stripped
+    /// unless PtyBuilder::sanitize_input() says otherwise, see input::Sanitize
+    pub fn write_untrusted(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let s = session.config().sanitize.unwrap_or_default().apply(s);
+        let s = session.config().eol.translate(&s);
+        session.write_input(&s, false)
+    }
+
+    /**
+     * Writes input of an attached client, sanitized if clients are untrusted
+     */
+    #[cfg(feature = "attach")]
+    pub(crate) fn write_client(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        match session.config().sanitize {
+            Some(mode) => session.write_input(&mode.apply(s), false),
+            None => session.write_input(s, false)
+        }
+    }
+
+    /// write to pty like Pty::write(), for automation such as setup commands run at the start
+    /// of a session, the echo of the input is taken out of the output before anyone sees it,
+    /// best effort: echo the child draws differently than the tty would, e.g. after completion,
+    /// echo mixed with other input written at the same time, or the second echo of input
+    /// written while the shell was busy, when its line editor reads it, may show up anyway
+    pub fn inject(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let s = session.config().eol.translate(s);
+        session.write_input(&s, true)
+    }
+
+    /// write a key as encoded by the keymap of the pty, see PtyBuilder::keymap()
+    pub fn write_key(&self, key: Key) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let s = session.config().keymap.encode(key);
+        session.write_input(&s, false)
     }
 
-    /// resize pty with syscall
+    /// give owner the input of the pty until the guard is dropped, it writes with
+    /// InputGuard::write(), writes of anyone else fail or wait as contention says, fails with
+    /// ErrorKind::ResourceBusy if the input is locked already, see the lock module
+    pub fn lock_input(&self, owner: &str, contention: Contention) -> Result<InputGuard, Box<dyn Error>> {
+        let token = registry::get(self.id)?.lock_input(owner, contention)?;
+        Ok(InputGuard::new(self.id, token, owner))
+    }
+
+    /// write the control character ctrl and c send, e.g. 'c' for ^C, see input::control(),
+    /// fails with ErrorKind::InvalidInput for a c without one
+    pub fn write_control(&self, c: char) -> Result<(), Box<dyn Error>> {
+        let Some(control) = input::control(c) else {
+            return Err(Box::new(PtyError::with_kind(format!("No control character for {c:?}"), std::io::ErrorKind::InvalidInput)));
+        };
+        registry::get(self.id)?.write_input(control.encode_utf8(&mut [0; 4]), false)
+    }
+
+    /// replace the line being edited with text without running it, text is written as is, for
+    /// line editors with the emacs bindings bash, zsh and fish default to: ^E moves to the end
+    /// of the line and ^U kills it, a shell without a line editor kills it as the tty does, in
+    /// one write so no other input lands in between
+    pub fn replace_current_line(&self, text: &str) -> Result<(), Box<dyn Error>> {
+        registry::get(self.id)?.write_input(&format!("\x05\x15{text}"), false)
+    }
+
+    /// move the cursor to the start of the line being edited, ^A in emacs bindings
+    pub fn cursor_home(&self) -> Result<(), Box<dyn Error>> {
+        self.write_control('a')
+    }
+
+    /// move the cursor to the end of the line being edited, ^E in emacs bindings
+    pub fn cursor_end(&self) -> Result<(), Box<dyn Error>> {
+        self.write_control('e')
+    }
+
+    /// have the line editor clear the screen and redraw the prompt and line being edited, ^L,
+    /// unlike running clear the line is kept
+    pub fn clear_screen(&self) -> Result<(), Box<dyn Error>> {
+        self.write_control('l')
+    }
+
+    /// the completions the shell offers for partial, a partial command, waiting up to timeout
+    /// for each response to TAB, the line being edited is left holding partial, see the
+    /// completion module
+    #[cfg(feature = "completion")]
+    pub fn complete(&self, partial: &str, timeout: std::time::Duration) -> Result<Vec<String>, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        completion::complete(self, &session, partial, timeout)
+    }
+
+    /// paste input of any size in the background, bytes are written as is in chunks the tty
+    /// can take, waiting for the child to read each one, so nothing is dropped or blocks
+    pub fn paste_large(&self, input: impl Into<Vec<u8>>) -> Result<Paste, Box<dyn Error>> {
+        registry::get(self.id)?.wait_input_lock(None)?;
+        Paste::start(self.id, std::io::Cursor::new(input.into()))
+    }
+
+    /// paste the contents of a file like Pty::paste_large(), the file is read as the paste goes
+    pub fn paste_file(&self, path: impl AsRef<Path>) -> Result<Paste, Box<dyn Error>> {
+        registry::get(self.id)?.wait_input_lock(None)?;
+        Paste::start(self.id, File::open(path)?)
+    }
+
+    /// close a piped stdin so the child reads EOF, fails if stdin is the pty
+    pub fn close_stdin(&self) -> Result<(), Box<dyn Error>> {
+        registry::get(self.id)?.close_stdin()
+    }
+
+    /// resize pty with syscall, acknowledged through PtyHandler::on_resize_ack and
+    /// ClientEvent::Resized, the resize policy is bypassed
     pub fn resize(&self, window_size: WindowSize) -> Result<(), Box<dyn Error>> {
-        unix::pty::resize(self.pid, window_size)
+        let session = registry::get(self.id)?;
+        session.with_fd(|fd| unix::pty::resize(fd, window_size))?;
+        session.notify(Notice::Resized(window_size))
+    }
+
+    /// attach a client to the pty, on_event receives its output and size changes
+    /// on the thread reading the pty, see the clients module
+    pub fn attach<F>(&self, on_event: F) -> Result<ClientId, Box<dyn Error>>
+        where F: FnMut(ClientEvent) + Send + 'static
+    {
+        self.attach_at(on_event).map(|(client, _)| client)
+    }
+
+    /// attach() returning the cursor the client's first ClientEvent::Output starts at,
+    /// output before it can be read with read_since() without being seen twice or missed,
+    /// e.g. to replay what a reconnecting client missed
+    pub fn attach_at<F>(&self, mut on_event: F) -> Result<(ClientId, Cursor), Box<dyn Error>>
+        where F: FnMut(ClientEvent) + Send + 'static
+    {
+        self.attach_sequenced(move |_seq, event| on_event(event))
+    }
+
+    /// attach_at() with the sequence number of every event, events of a pty are numbered in the
+    /// order they happened, an event has the same number for every client and the numbers a
+    /// client sees only go up, every client gets the same output chunks in the same order from
+    /// the moment it attached, ClientEvent::Exited comes exactly once and nothing follows it,
+    /// callbacks run on the thread polling the pty, one event at a time
+    pub fn attach_sequenced<F>(&self, on_event: F) -> Result<(ClientId, Cursor), Box<dyn Error>>
+        where F: FnMut(u64, ClientEvent) + Send + 'static
+    {
+        let session = registry::get(self.id)?;
+        session.with_fd(|_| {
+            let scrollback = session.scrollback();
+            let mut clients = session.clients();
+            let client = clients.attach(Arc::new(Mutex::new(on_event)));
+            if clients.stopped {
+                clients.stopped = false;
+                unix::pty::signal(session.child(), Signal::SIGCONT)?;
+            }
+            Ok((client, scrollback.cursor()))
+        })
+    }
+
+    /// attach_at() for a client that is not the primary one, its output is sanitized by profile
+    /// so it can't be attacked through escape sequences meant for the primary client, output
+    /// read with read_since() can be sanitized with OutputProfile::filter()
+    pub fn attach_with<F>(&self, profile: OutputProfile, mut on_event: F) -> Result<(ClientId, Cursor), Box<dyn Error>>
+        where F: FnMut(ClientEvent) + Send + 'static
+    {
+        if profile == OutputProfile::Trusted {
+            return self.attach_at(on_event);
+        }
+        let mut filter = profile.filter();
+        self.attach_at(move |event| match event {
+            ClientEvent::Output(output) => {
+                let output = filter.apply(&output);
+                if !output.is_empty() {
+                    on_event(ClientEvent::Output(output));
+                }
+            },
+            event => on_event(event),
+        })
+    }
+
+    /// detach a client, the pty is resized if the client was holding its size,
+    /// if it was the last client PtyBuilder::detach_policy() is applied
+    pub fn detach(&self, client: ClientId) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let (size, last) = {
+            let mut clients = session.clients();
+            if !clients.detach(client) {
+                return Err(Box::new(PtyError::new(format!("{client} is not attached to {}", self.id))));
+            }
+            (clients.effective_size(session.config().resize_policy), clients.is_empty().then(|| clients.attachments()))
+        };
+        if let Some(attachments) = last {
+            self.on_last_detach(&session, attachments)?;
+        }
+        match size {
+            Some(size) => self.apply_size(size).map(|_| ()),
+            None => Ok(())
+        }
+    }
+
+    /**
+     * Applies the detach policy, attachments tells a grace timer whether a client came back
+     */
+    fn on_last_detach(&self, session: &Session, attachments: u64) -> Result<(), Box<dyn Error>> {
+        match session.config().detach_policy {
+            DetachPolicy::KeepRunning => Ok(()),
+            DetachPolicy::Stop => session.with_fd(|_| {
+                // checked again under the lock, a client may have attached since
+                let mut clients = session.clients();
+                if clients.is_empty() && clients.attachments() == attachments {
+                    clients.stopped = true;
+                    unix::pty::signal(session.child(), Signal::SIGSTOP)?;
+                }
+                Ok(())
+            }),
+            DetachPolicy::HangUp(grace) => {
+                let id = self.id;
+                thread::Builder::new().name(format!("pty-exec/detach={id}")).spawn(move || {
+                    thread::sleep(grace);
+                    let Ok(session) = registry::get(id) else { return };
+                    let _ = session.with_fd(|_| {
+                        let clients = session.clients();
+                        if clients.is_empty() && clients.attachments() == attachments {
+                            unix::pty::signal(session.child(), Signal::SIGHUP)?;
+                        }
+                        Ok(())
+                    });
+                })?;
+                Ok(())
+            },
+            DetachPolicy::Kill => {
+                audit::emit(session, None, AuditAction::SessionKilled);
+                session.with_fd(|_| unix::pty::signal(session.child(), Signal::SIGKILL))
+            },
+        }
+    }
+
+    /// send message to every attached client as ClientEvent::Message, in order with the output,
+    /// it is not written to the pty, see the message module
+    pub fn send_message(&self, message: Message) -> Result<(), Box<dyn Error>> {
+        registry::get(self.id)?.notify(Notice::Message(message))
+    }
+
+    /// pass message of client to PtyHandler::on_message, it is not written to the pty
+    pub fn client_message(&self, client: ClientId, message: Message) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        if !session.clients().is_attached(client) {
+            return Err(Box::new(PtyError::new(format!("{client} is not attached to {}", self.id))));
+        }
+        session.notify(Notice::ClientMessage(client, message))
+    }
+
+    /// write input of client like Pty::write_raw(), for a client showing the input ahead of its
+    /// echo on a slow link, mosh-style, seq numbers the prediction, the client is sent
+    /// ClientEvent::Echo right after the output holding the echo, or once it is clear none is
+    /// coming: echo is off, the input has control characters, the child drew something else or
+    /// nothing within two seconds, input is sanitized like that of served clients
+    pub fn write_predicted(&self, client: ClientId, seq: u64, input: &str) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let input = session.config().sanitize.map_or(input.into(), |mode| mode.apply(input));
+        let echoed = !input.is_empty() && !input.chars().any(char::is_control) && session.config().stdin == StdioMode::Pty
+            && !session.with_fd(|fd| Ok(unix::pty::input_hidden(fd)))?;
+        {
+            let mut clients = session.clients();
+            if !clients.is_attached(client) {
+                return Err(Box::new(PtyError::new(format!("{client} is not attached to {}", self.id))));
+            }
+            // before the write, the echo may be read before it returns
+            if echoed {
+                clients.predict(client, seq, &input, session.config().clock.now());
+            }
+        }
+        let res = session.write_input(&input, false);
+        if !echoed || res.is_err() {
+            session.clients().forget_prediction(client, seq);
+            session.notify(Notice::EchoRejected(client, seq))?;
+        }
+        res
+    }
+
+    /// report the size of a client, the pty is resized according to PtyBuilder::resize_policy()
+    /// and every client is sent ClientEvent::Resized if the size changed, returns the effective size
+    pub fn client_resize(&self, client: ClientId, window_size: WindowSize) -> Result<WindowSize, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let size = {
+            let mut clients = session.clients();
+            if !clients.is_attached(client) {
+                return Err(Box::new(PtyError::new(format!("{client} is not attached to {}", self.id))));
+            }
+            clients.set_size(client, window_size);
+            clients.effective_size(session.config().resize_policy).unwrap_or(window_size)
+        };
+        self.apply_size(size)
+    }
+
+    /**
+     * Resizes the pty to the size decided by the clients, unless it already has it
+     */
+    fn apply_size(&self, window_size: WindowSize) -> Result<WindowSize, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let changed = session.with_fd(|fd| {
+            if unix::pty::window_size(fd)? == window_size { return Ok(false) }
+            unix::pty::resize(fd, window_size).map(|_| true)
+        })?;
+        if changed {
+            session.notify(Notice::Resized(window_size))?;
+        }
+        Ok(window_size)
+    }
+
+    /// title of the session, the window title the child set, or else the fallback title,
+    /// `None` if there is neither, see PtyHandler::on_title()
+    pub fn title(&self) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.titles().current())
+    }
+
+    /// icon name the child set (OSC 0 or OSC 1), `None` if it set none
+    pub fn icon_name(&self) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.titles().icon_name.clone())
+    }
+
+    /// working directory of the shell as it last reported it (OSC 7) or PtyBuilder::poll_cwd()
+    /// last found it, `None` before either
+    pub fn cwd(&self) -> Result<Option<std::path::PathBuf>, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.cwd())
+    }
+
+    /// the lines on the screen of the pty as its output drew them, trailing blanks trimmed,
+    /// see the vt module
+    #[cfg(feature = "vt")]
+    pub fn screen_text(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.screen().text())
+    }
+
+    /// where the cursor is on the screen of the pty, see Pty::screen_text()
+    #[cfg(feature = "vt")]
+    pub fn cursor_position(&self) -> Result<Position, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.screen().cursor())
+    }
+
+    /// rows and columns of the screen of the pty, a pty without a size has a screen of 24x80,
+    /// see Pty::screen_text()
+    #[cfg(feature = "vt")]
+    pub fn screen_size(&self) -> Result<(u16, u16), Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.screen().size())
+    }
+
+    /// title of the session while the child sets none, e.g. the name of the foreground process,
+    /// reported through PtyHandler::on_title() unless the child's title stands, a child setting
+    /// an empty title hands the title back to the fallback
+    pub fn set_fallback_title(&self, title: impl Into<String>) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let title = title.into();
+        let shown = {
+            let mut titles = session.titles();
+            titles.fallback = Some(title.clone());
+            titles.title.is_none()
+        };
+        match shown {
+            true => session.notify(Notice::Title(title)),
+            false => Ok(())
+        }
+    }
+
+    /// whether input written now is hidden, echo off with the tty handing lines over like at a
+    /// password prompt, a line editor echoing by itself does not count, see
+    /// PtyHandler::on_echo_change()
+    pub fn is_echo_off(&self) -> Result<bool, Box<dyn Error>> {
+        registry::get(self.id)?.with_fd(|fd| Ok(unix::pty::input_hidden(fd)))
+    }
+
+    /// output retained by the scrollback, spilled output included, see PtyBuilder::scrollback()
+    pub fn scrollback(&self) -> Result<String, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.scrollback().all().into_owned())
+    }
+
+    /// metadata of the session in a form other versions of the crate read, see the state module
+    pub fn snapshot(&self) -> Result<SessionSnapshot, Box<dyn Error>> {
+        let session = registry::get_any(self.id)?;
+        Ok(SessionSnapshot::of(&session))
+    }
+
+    /// commands run in the pty, oldest first, only commands marked by the shell are seen,
+    /// see PtyBuilder::shell_integration() and history::CommandRecord
+    pub fn command_history(&self) -> Result<Vec<CommandRecord>, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.history().records())
+    }
+
+    /// run action whenever the output from now on matches pattern, see the trigger module
+    #[cfg(feature = "triggers")]
+    pub fn add_trigger(&self, pattern: Regex, action: TriggerAction) -> Result<TriggerId, Box<dyn Error>> {
+        Ok(registry::get(self.id)?.triggers().add(pattern, action))
+    }
+
+    #[cfg(feature = "triggers")]
+    pub fn remove_trigger(&self, trigger: TriggerId) -> Result<(), Box<dyn Error>> {
+        match registry::get(self.id)?.triggers().remove(trigger) {
+            true => Ok(()),
+            false => Err(Box::new(PtyError::new(format!("{trigger} is not a trigger of {}", self.id))))
+        }
+    }
+
+    /// pause or resume a trigger, a resumed trigger does not fire on output it missed
+    #[cfg(feature = "triggers")]
+    pub fn set_trigger_enabled(&self, trigger: TriggerId, enabled: bool) -> Result<(), Box<dyn Error>> {
+        match registry::get(self.id)?.triggers().set_enabled(trigger, enabled) {
+            true => Ok(()),
+            false => Err(Box::new(PtyError::new(format!("{trigger} is not a trigger of {}", self.id))))
+        }
+    }
+
+    /// up to n_bytes of the latest output from the scrollback, e.g. for the preview of a hidden
+    /// pane, only the tail is copied however large the scrollback, it may start inside an
+    /// escape sequence
+    pub fn last_output_tail(&self, n_bytes: usize) -> Result<String, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.scrollback().tail(n_bytes).to_owned())
+    }
+
+    /// position right after the latest output, pass it to Pty::read_since() later
+    pub fn cursor(&self) -> Result<Cursor, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.scrollback().cursor())
+    }
+
+    /// everything the scrollback holds after cursor, e.g. for clients polling for new output
+    pub fn read_since(&self, cursor: Cursor) -> Result<OutputSince, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.scrollback().since(cursor))
     }
 
-    /// kill pty
+    /// find every occurrence of pattern in the scrollback, escape sequences in the output are skipped
+    pub fn search(&self, pattern: &str, direction: Direction) -> Result<Vec<MatchPos>, Box<dyn Error>> {
+        let mut matches = registry::get_any(self.id)?.scrollback().search(pattern);
+        if direction == Direction::Backward {
+            matches.reverse();
+        }
+        Ok(matches)
+    }
+
+    /// search the scrollback for pattern again whenever new output arrives, see Search::update()
+    pub fn incremental_search(&self, pattern: &str) -> Search {
+        Search::new(self.id, pattern)
+    }
+
+    /// record the session to an asciicast v2 file, replaces a running recording
+    pub fn start_recording(&self, recording: Recording) -> Result<(), Box<dyn Error>> {
+        registry::get(self.id)?.start_recording(&recording)
+    }
+
+    /// stop recording the session, the recording is complete once this returns
+    pub fn stop_recording(&self) -> Result<(), Box<dyn Error>> {
+        registry::get(self.id)?.stop_recording();
+        Ok(())
+    }
+
+    /// record input written to the pty from now on as a macro named name, until
+    /// Pty::stop_macro(), replaces a macro being recorded, see the macros module
+    pub fn start_macro(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        registry::get(self.id)?.start_macro(name);
+        Ok(())
+    }
+
+    /// the macro recorded since Pty::start_macro(), fails if none is being recorded
+    pub fn stop_macro(&self) -> Result<Macro, Box<dyn Error>> {
+        match registry::get(self.id)?.stop_macro() {
+            Some(recorded) => Ok(recorded),
+            None => Err(Box::new(PtyError::new(format!("No macro is being recorded on {}", self.id))))
+        }
+    }
+
+    /// write the input of a macro to the pty as it was written, waiting between writes as long
+    /// as while it was recorded divided by speed, e.g. 2.0 plays it twice as fast and
+    /// f64::INFINITY without waiting, by the clock of the session, returns once it was written
+    pub fn play_macro(&self, recorded: &Macro, speed: f64) -> Result<(), Box<dyn Error>> {
+        let invalid = || Box::new(PtyError::with_kind(format!("Invalid macro speed {speed}"), std::io::ErrorKind::InvalidInput));
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(invalid());
+        }
+        let clock = registry::get(self.id)?.config().clock.clone();
+        for step in recorded.steps() {
+            // a step may take longer than time goes at a tiny speed
+            let delay = std::time::Duration::try_from_secs_f64(step.delay.as_secs_f64() / speed).map_err(|_| invalid())?;
+            clock::sleep_until(clock.as_ref(), clock.now().checked_add(delay).ok_or_else(invalid)?);
+            registry::get(self.id)?.write_input(&step.input, false)?;
+        }
+        Ok(())
+    }
+
+    /// ask the tty what the foreground process is doing, the status character (VSTATUS, ^T
+    /// unless the child changed it) is written like a keystroke and the status line the kernel
+    /// prints in answer is passed to PtyHandler::on_status(), it stays in the output as well,
+    /// the tty only answers while it handles signal characters, macOS and the BSDs only, fails
+    /// with ErrorKind::Unsupported elsewhere
+    pub fn request_status(&self) -> Result<(), Box<dyn Error>> {
+        #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd"))]
+        {
+            let session = registry::get(self.id)?;
+            let status = session.with_fd(unix::pty::status_char)?;
+            session.expect_status(true);
+            session.write_input(char::from(status).encode_utf8(&mut [0; 4]), false)
+        }
+        #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd")))]
+        Err(Box::new(PtyError::with_kind("Status requests are not supported on this platform", std::io::ErrorKind::Unsupported)))
+    }
+
+    /// discard input written to the pty that the child has not read yet, e.g. a cancelled paste
+    pub fn flush_input(&self) -> Result<(), Box<dyn Error>> {
+        // the master's output queue is the child's input
+        registry::get(self.id)?.with_fd(|fd| unix::pty::flush(fd, FlushArg::TCOFLUSH))
+    }
+
+    /// discard output of the child that has not been read from the pty yet
+    pub fn flush_output(&self) -> Result<(), Box<dyn Error>> {
+        // the master's input queue is the child's output
+        registry::get(self.id)?.with_fd(|fd| unix::pty::flush(fd, FlushArg::TCIFLUSH))
+    }
+
+    /// suspend or resume the child's output like ^S/^Q would, needs IXON on the tty (the default)
+    pub fn flow(&self, flow: Flow) -> Result<(), Box<dyn Error>> {
+        let action = match flow {
+            Flow::Stop => FlowArg::TCIOFF,
+            Flow::Start => FlowArg::TCION,
+        };
+        let session = registry::get(self.id)?;
+        session.with_fd(|fd| unix::pty::flow(fd, action))?;
+        session.set_output_stopped(flow == Flow::Stop);
+        Ok(())
+    }
+
+    /// environment the shell was started with, e.g. to start another pty like it,
+    /// variables the shell exported since are not included, from /proc on linux
+    pub fn child_env(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        unix::proc::environ(session.child())
+    }
+
+    /// change policies of the live session, see the patch module, fails without changing
+    /// anything if the patch is invalid
+    pub fn update_config(&self, patch: ConfigPatch) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let changed = session.update_config(patch)?;
+        if changed.is_empty() {
+            return Ok(());
+        }
+        audit::emit(&session, None, AuditAction::ConfigChanged { settings: changed.clone() });
+        session.notify(Notice::ConfigChanged(changed))
+    }
+
+    /// builder for a pty like this one, "new pane in the same directory": same settings and user, the
+    /// shell's current directory and environment, the same window size, TERM comes with the environment
+    pub fn duplicate_builder(&self) -> Result<PtyBuilder, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let mut builder = PtyBuilder::new();
+        builder.config = (*session.config()).clone();
+        builder.fallback_shells = vec![session.shell().to_owned()];
+
+        let mut builder = builder
+            .env_clear()
+            .cwd(unix::proc::cwd(session.child())?)
+            .window_size(session.with_fd(unix::pty::window_size)?);
+        builder.env.extend(unix::proc::environ(session.child())?);
+        Ok(builder)
+    }
+
+    /// spawn a pty like this one, see duplicate_builder()
+    pub fn duplicate<H: PtyHandler>(&self, handler: H) -> Result<Pty, Box<dyn Error>> {
+        self.duplicate_builder()?.spawn_handler(handler)
+    }
+
+    /// cpu time, memory and number of the processes in the session of the pty, i.e. the shell
+    /// and everything started from it that did not start a session of its own
+    pub fn resource_usage(&self) -> Result<Usage, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        unix::proc::usage(session.child())
+    }
+
+    /// the process in the foreground of the pty, the shell while it waits at its prompt
+    pub fn foreground(&self) -> Result<ForegroundProcess, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        session.with_fd(unix::pty::foreground_process)
+    }
+
+    /// whether the child, the thread reading the pty and the master fd are still there,
+    /// and when the pty last saw input or output, see the health module
+    pub fn health(&self) -> Result<Health, Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        health::check(&session)
+    }
+
+    /// what the pty was spawned as, its child, slave, shell, tag, initial size and when,
+    /// in one go, see the info module
+    pub fn info(&self) -> Result<PtyInfo, Box<dyn Error>> {
+        let session = registry::get_any(self.id)?;
+        Ok(info::collect(&session))
+    }
+
+    /// the shell that was started, the user's or one of PtyBuilder::fallback_shells()
+    pub fn shell(&self) -> Result<String, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.shell().to_owned())
+    }
+
+    /// tag of the session, see PtyBuilder::tag()
+    pub fn tag(&self) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(registry::get_any(self.id)?.config().tag.clone())
+    }
+
+    /// whether the pty is still alive, a handle to a dead pty is stale, a retained one can
+    /// only be read, see PtyBuilder::retain_exited()
+    pub fn is_alive(&self) -> bool {
+        registry::get(self.id).is_ok()
+    }
+
+    /// whether the child still runs or how it ended, known as long as the session is retained
+    /// after the child exited, see PtyBuilder::retain_exited(), never waits for the child
+    pub fn state(&self) -> Result<SessionState, Box<dyn Error>> {
+        let session = registry::get_any(self.id)?;
+        if registry::get(self.id).is_ok() {
+            return Ok(SessionState::Running);
+        }
+        match session.exit_status() {
+            Some(status) => Ok(SessionState::Exited(status)),
+            // closed but not reaped yet
+            None => Ok(SessionState::Exiting)
+        }
+    }
+
+    /// drop a session retained after its child exited before its retention period is over,
+    /// fails if it is not retained
+    pub fn discard(&self) -> Result<(), Box<dyn Error>> {
+        match retention::discard(self.id) {
+            true => Ok(()),
+            false => Err(Box::new(PtyError::new(format!("{} is not retained", self.id))))
+        }
+    }
+
+    /// send signal to the child's process group, e.g. SIGINT like ^C would
+    pub fn signal(&self, signal: Signal) -> Result<(), Box<dyn Error>> {
+        self.signal_as(signal, None)
+    }
+
+    /**
+     * signal() on behalf of actor, for the audit trail
+     */
+    pub(crate) fn signal_as(&self, signal: Signal, actor: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        session.with_fd(|_| unix::pty::signal(session.child(), signal))?;
+        audit::emit(&session, actor, AuditAction::SignalSent { signal: signal as i32 });
+        Ok(())
+    }
+
+    /**
+     * Emits an audit event for an action taken outside of the pty, e.g. a client of the server
+     */
+    #[cfg(feature = "attach")]
+    pub(crate) fn audit(&self, actor: Option<&str>, action: AuditAction) {
+        if let Ok(session) = registry::get(self.id) {
+            audit::emit(&session, actor, action);
+        }
+    }
+
+    /// blocks until the child has exited, right away if the handle is already stale, fails with
+    /// ErrorKind::Interrupted once cancel is cancelled, the pty is left running then
+    pub fn wait(&self, cancel: &CancellationToken) -> Result<(), Box<dyn Error>> {
+        let Ok(session) = registry::get_any(self.id) else { return Ok(()) };
+        match session.wait_exited_cancellable(cancel) {
+            true => Ok(()),
+            false => Err(Box::new(cancel.error()))
+        }
+    }
+
+    /// kill pty by writing the shutdown input, does not wait for the child to exit
     pub fn kill(&self) {
-        unix::pty::kill(self.pid)
+        if let Ok(session) = registry::get(self.id) {
+            audit::emit(&session, None, AuditAction::SessionKilled);
+            let _ = session.flush_coalesced(true);
+            let _ = session.with_input_fd(|fd| {
+                unix::pty::kill(fd, &session.config().shutdown_input);
+                Ok(())
+            });
+        }
+    }
+
+    /// gracefully shut the pty down, blocks until the child has exited
+    /// writes the shutdown input then signals the child with SIGHUP, SIGTERM and finally SIGKILL,
+    /// waiting PtyBuilder::shutdown_timeout() after each step, each step is passed to
+    /// PtyHandler::on_shutdown()
+    /// once it returns Ok the fd is closed, on_exit was called exactly once and no other callback
+    /// follows it, with PtyBuilder::executor() they were all handed to the executor by then,
+    /// a pty exiting by itself meanwhile is waited for the same way, called from a callback of
+    /// the pty it fails rather than wait on itself, see Pty::kill()
+    pub fn shutdown(&self) -> Result<(), Box<dyn Error>> {
+        let session = match registry::get(self.id) {
+            Ok(session) => session,
+            Err(err) => match registry::exiting(self.id) {
+                Some(session) => return wait_reported(&session),
+                None => return Err(err)
+            }
+        };
+        if session.on_reader_thread() {
+            return Err(Box::new(PtyError::with_kind(format!("Shutdown of {} from its own callback", self.id), std::io::ErrorKind::WouldBlock)));
+        }
+        audit::emit(&session, None, AuditAction::SessionKilled);
+        unix::pty::shutdown(&session)
+    }
+
+    /// hands the session over to the process at the other end of target, which takes it over
+    /// with PtyBuilder::adopt(), once it returns the pty is gone from this process like one
+    /// that exited, it stays if the handoff fails, see the migrate module
+    #[cfg(feature = "migrate")]
+    pub fn migrate(&self, target: &std::os::unix::net::UnixStream) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        migrate::send(&session, target)?;
+        audit::emit(&session, None, AuditAction::SessionMigrated);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/**
+ * Waits for the exit of a session closed already to be reported
+ */
+fn wait_reported(session: &Arc<Session>) -> Result<(), Box<dyn Error>> {
+    if session.on_reader_thread() {
+        return Err(Box::new(PtyError::with_kind(format!("Shutdown of {} from its own callback", session.id()), std::io::ErrorKind::WouldBlock)));
+    }
+    session.wait_exited_cancellable(&CancellationToken::new());
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Flow control action for Pty::flow()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Flow {
+    /// sends the tty's STOP character, the child's output is suspended
+    Stop,
+    /// sends the tty's START character, the child's output resumes
+    Start,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// What local ptys and remote sessions have in common, so code can drive either
+pub trait Terminal {
+    /// id of the pty, or of the connection for a remote session
+    fn id(&self) -> PtyId;
+
+    /// write input for the child
+    fn write(&self, s: &str) -> Result<(), Box<dyn Error>>;
+
+    /// resize the window
+    fn resize(&self, window_size: WindowSize) -> Result<(), Box<dyn Error>>;
+
+    /// send signal to the child's process group
+    fn signal(&self, signal: Signal) -> Result<(), Box<dyn Error>>;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Terminal for Pty {
+    fn id(&self) -> PtyId {
+        Pty::id(self)
+    }
+
+    fn write(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        Pty::write(self, s)
+    }
+
+    fn resize(&self, window_size: WindowSize) -> Result<(), Box<dyn Error>> {
+        Pty::resize(self, window_size)
+    }
+
+    fn signal(&self, signal: Signal) -> Result<(), Box<dyn Error>> {
+        Pty::signal(self, signal)
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl FromRawFd for Pty {
+    /// adopts the pty currently spawned on fd, if there is none the handle is stale
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
-        Pty { pid: fd }
+        Pty { id: registry::current(fd).unwrap_or(PtyId::new(fd, 0)) }
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl AsRawFd for Pty {
     fn as_raw_fd(&self) -> RawFd {
-        self.pid
+        self.id.fd()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use std::sync::{Arc, Mutex};
+    use crate::clock::MockClock;
+    use crate::macros::MacroStep;
+    use crate::retention::ExitStatus;
     use super::*;
 
+    /// waits up to 10 seconds for cond, shell startup time varies a lot between machines
+    fn wait_for(cond: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !cond() {
+            if Instant::now() > deadline { return false }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
     #[test]
     fn spawn() -> Result<(), Box<dyn Error>> {
         let read_buf = Arc::new(Mutex::new(String::new()));
@@ -90,27 +1031,824 @@ mod tests {
         let (read_buf_async, die_buf_async) = (read_buf.clone(), die_buf.clone());
 
         // spawn Pty
-        let pty = Pty::spawn(move |_fd, res| {
+        let pty = Pty::spawn(move |_id, res| {
             read_buf_async.lock().unwrap().push_str(res.unwrap().as_str());
-        }, move |fd| {
-            die_buf_async.lock().unwrap().push_str(format!("{fd} dead").as_str());
+        }, move |id| {
+            die_buf_async.lock().unwrap().push_str(format!("{id} dead").as_str());
         })?;
-        std::thread::sleep(Duration::from_millis(100));
 
         // create new pty, this maintains the on_read and on_death callbacks
         let pty = unsafe { Pty::from_raw_fd(pty.as_raw_fd()) };
         // write to original pty with new pty from_raw_fd
         pty.write("echo 'Hello, World'\r")?;
-        std::thread::sleep(Duration::from_millis(100));
 
         pty.kill();
-        std::thread::sleep(Duration::from_millis(100));
+        wait_for(|| !die_buf.lock().unwrap().is_empty());
 
         // read_buf are effected whether using Pty::spawn or Pty::from_raw_fd() on a
         // pre-existing spawned pty
         assert!(read_buf.lock().unwrap().contains("echo 'Hello, World'"));
-        assert_eq!(die_buf.lock().unwrap().as_str(), format!("{} dead", pty.as_raw_fd()).as_str());
+        assert_eq!(die_buf.lock().unwrap().as_str(), format!("{} dead", pty.id()).as_str());
+
+        Ok(())
+    }
+
+    #[test]
+    fn stale_handle() -> Result<(), Box<dyn Error>> {
+        let dead = Arc::new(Mutex::new(false));
+        let dead_async = dead.clone();
+
+        let pty = test_util::stub().spawn(|_id, _res| {}, move |_id| *dead_async.lock().unwrap() = true)?;
+        pty.kill();
+        assert!(wait_for(|| *dead.lock().unwrap()));
+
+        // a new pty may reuse the fd, the old handle must not reach it
+        let next = test_util::stub().spawn(|_id, _res| {}, |_id| {})?;
+        assert_ne!(pty.id(), next.id());
+        assert!(pty.write("stale\n").is_err());
+        assert!(next.write("fresh\n").is_ok());
+
+        next.kill();
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_escalates() -> Result<(), Box<dyn Error>> {
+        let dead = Arc::new(Mutex::new(false));
+        let dead_async = dead.clone();
+
+        // no shutdown input, the child only goes away through signals
+        let pty = Pty::builder()
+            .shutdown_input("")
+            .shutdown_timeout(Duration::from_millis(200))
+            .spawn(|_id, _res| {}, move |_id| *dead_async.lock().unwrap() = true)?;
+
+        pty.shutdown()?;
+        assert!(*dead.lock().unwrap());
+        assert!(pty.write("echo 'stale'\r").is_err());
+
+        Ok(())
+    }
 
+    #[test]
+    fn separate_stderr() -> Result<(), Box<dyn Error>> {
+        let read_buf = Arc::new(Mutex::new(String::new()));
+        let err_buf = Arc::new(Mutex::new(String::new()));
+        let (read_buf_async, err_buf_async) = (read_buf.clone(), err_buf.clone());
+
+        let pty = Pty::builder()
+            .on_stderr(move |_id, res| err_buf_async.lock().unwrap().push_str(res.unwrap().as_str()))
+            .spawn(move |_id, res| read_buf_async.lock().unwrap().push_str(res.unwrap().as_str()), |_id| {})?;
+
+        pty.write("echo 'to-stdout'; echo 'to-stderr' 1>&2\r")?;
+        assert!(wait_for(|| err_buf.lock().unwrap().contains("to-stderr\n")));
+        assert!(read_buf.lock().unwrap().contains("to-stdout\r\n"));
+        assert!(!read_buf.lock().unwrap().contains("to-stderr\r\n"));
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        output: Arc<Mutex<String>>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PtyHandler for Recorder {
+        fn on_output(&mut self, _id: PtyId, output: String) {
+            self.output.lock().unwrap().push_str(&output);
+        }
+
+        fn on_exit(&mut self, _id: PtyId) {
+            self.events.lock().unwrap().push("exit".into());
+        }
+
+        fn on_resize_ack(&mut self, _id: PtyId, size: WindowSize) {
+            self.events.lock().unwrap().push(format!("resize {}x{}", size.rows(), size.cols()));
+        }
+
+        fn on_title(&mut self, _id: PtyId, title: String) {
+            self.events.lock().unwrap().push(format!("title {title}"));
+        }
+
+        fn on_icon_name(&mut self, _id: PtyId, name: String) {
+            self.events.lock().unwrap().push(format!("icon {name}"));
+        }
+
+        fn on_bell(&mut self, _id: PtyId) {
+            self.events.lock().unwrap().push("bell".into());
+        }
+    }
+
+    #[test]
+    fn handler_events() -> Result<(), Box<dyn Error>> {
+        let recorder = Recorder::default();
+        let (output, events) = (recorder.output.clone(), recorder.events.clone());
+        let has_event = |event: &str| events.lock().unwrap().iter().any(|e| e == event);
+
+        let pty = Pty::spawn_handler(recorder)?;
+        pty.resize(WindowSize::new(24, 80, 0, 0))?;
+        assert!(wait_for(|| has_event("resize 24x80")));
+
+        pty.write("printf '\\033]2;%s\\007' \"title-$((1 + 1))\"; printf '\\a'\r")?;
+        assert!(wait_for(|| has_event("title title-2")));
+        assert!(wait_for(|| has_event("bell")));
+        assert!(output.lock().unwrap().contains("printf"));
+
+        // the fallback only stands in while the child's title is unset
+        pty.set_fallback_title("vim")?;
+        assert_eq!(pty.title()?.as_deref(), Some("title-2"));
+        pty.write("printf '\\033]1;%s\\007\\033]2;\\007' \"icon-$((1 + 1))\"\r")?;
+        assert!(wait_for(|| has_event("icon icon-2") && has_event("title vim")));
+        assert_eq!(pty.icon_name()?.as_deref(), Some("icon-2"));
+        assert_eq!(pty.title()?.as_deref(), Some("vim"));
+
+        pty.shutdown()?;
+        assert_eq!(events.lock().unwrap().last().map(String::as_str), Some("exit"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn synchronized_output() -> Result<(), Box<dyn Error>> {
+        struct Chunks(Arc<Mutex<Vec<String>>>);
+
+        impl PtyHandler for Chunks {
+            fn on_output(&mut self, _id: PtyId, output: String) {
+                self.0.lock().unwrap().push(output);
+            }
+        }
+
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let pty = Pty::builder().hold_synchronized_output(Duration::from_secs(5)).spawn_handler(Chunks(chunks.clone()))?;
+        // the halves of the frame are read apart, but passed on together
+        pty.write("printf '\\033[?2026hhalf-'; sleep 0.3; printf 'frame-%s\\033[?2026l' \"$((1 + 1))\"\r")?;
+        let frame = || chunks.lock().unwrap().iter().find(|chunk| chunk.contains("frame-2")).cloned();
+        assert!(wait_for(|| frame().is_some()));
+        assert!(frame().unwrap().contains("half-frame-2"));
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn callback_panic() -> Result<(), Box<dyn Error>> {
+        struct Panicky(Arc<Mutex<Vec<String>>>);
+
+        impl PtyHandler for Panicky {
+            fn on_output(&mut self, _id: PtyId, output: String) {
+                if output.contains("boom") { panic!("boom") }
+                self.0.lock().unwrap().push(output);
+            }
+
+            fn on_error(&mut self, _id: PtyId, err: Box<dyn Error>) {
+                self.0.lock().unwrap().push(err.to_string());
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let pty = test_util::stub().spawn_handler(Panicky(log.clone()))?;
+        let logged = |s: &str| log.lock().unwrap().iter().any(|line| line.contains(s));
+
+        pty.write("boom\n")?;
+        assert!(wait_for(|| logged("Callback panicked: boom")));
+
+        // the reader survived the panic
+        pty.write("alive\n")?;
+        assert!(wait_for(|| logged("alive")));
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn executor() -> Result<(), Box<dyn Error>> {
+        let (tx, rx) = std::sync::mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let threads_async = threads.clone();
+
+        let pty = test_util::stub()
+            .executor(move |task| { let _ = tx.send(task); })
+            .spawn(move |_id, _res| {
+                threads_async.lock().unwrap().push(std::thread::current().id());
+            }, |_id| {})?;
+        pty.write("Hello, World\n")?;
+
+        // callbacks only run once this thread drains the channel
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(threads.lock().unwrap().is_empty());
+        assert!(wait_for(|| {
+            rx.try_iter().for_each(|task| task());
+            !threads.lock().unwrap().is_empty()
+        }));
+        assert!(threads.lock().unwrap().iter().all(|&thread| thread == std::thread::current().id()));
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn search() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        let mut search = pty.incremental_search("found-2");
+
+        pty.write("echo \"found-$((1 + 1))\"\r")?;
+        // the echoed command does not match, only its output does
+        assert!(wait_for(|| !pty.search("found-2", Direction::Forward).unwrap().is_empty()));
+        assert_eq!(search.update()?.len(), 1);
+        assert!(search.update()?.is_empty());
+
+        pty.write("echo \"found-$((1 + 1))\"\r")?;
+        assert!(wait_for(|| pty.search("found-2", Direction::Forward).unwrap().len() == 2));
+        let matches = pty.search("found-2", Direction::Backward)?;
+        assert!(matches[0].offset > matches[1].offset);
+        assert_eq!(search.update()?, vec![matches[0]]);
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn macros() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().spawn(|_id, _res| {}, |_id| {})?;
+        pty.start_macro("ready")?;
+        pty.write("echo \"macro-$((1 + 1))\"\r")?;
+        // automation is not part of the macro
+        pty.inject("true\r")?;
+        let recorded = pty.stop_macro()?;
+        assert_eq!((recorded.name(), recorded.steps().len()), ("ready", 1));
+        assert!(pty.stop_macro().is_err());
+
+        let other = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        assert!(other.play_macro(&recorded, 0.0).is_err());
+        let slow = Macro::new("slow", vec![MacroStep { delay: Duration::from_secs(1), input: "x".into() }]);
+        assert!(other.play_macro(&slow, 1e-300).is_err());
+        other.play_macro(&recorded, f64::INFINITY)?;
+        test_util::wait_for_output(&other, "macro-2", Duration::from_secs(10))?;
+        other.shutdown()?;
+        pty.shutdown()?;
+
+        // the wait is in the time of the session
+        let clock = MockClock::new();
+        let mocked = test_util::stub().clock(clock.clone()).spawn(|_id, _res| {}, |_id| {})?;
+        let hour = Macro::new("hour", vec![MacroStep { delay: Duration::from_secs(3600), input: "x".into() }]);
+        let advance = clock.clone();
+        let advancing = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            advance.advance(Duration::from_secs(3600));
+        });
+        let started = Instant::now();
+        mocked.play_macro(&hour, 1.0)?;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        advancing.join().unwrap();
+        mocked.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn lock_input() -> Result<(), Box<dyn Error>> {
+        let pty = test_util::stub().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        let guard = pty.lock_input("script", Contention::Reject)?;
+        assert!(pty.lock_input("other", Contention::Reject).is_err());
+        assert!(pty.write("rejected\n").is_err());
+        guard.write("locked\n")?;
+        drop(guard);
+        pty.write("unlocked\n")?;
+        test_util::wait_for_output(&pty, "unlocked", Duration::from_secs(10))?;
+
+        // a queued write goes once the guard is dropped
+        let guard = pty.lock_input("script", Contention::Queue)?;
+        let queued = {
+            let pty = unsafe { Pty::from_raw_fd(pty.as_raw_fd()) };
+            std::thread::spawn(move || pty.write("queued\n").is_ok())
+        };
+        guard.write("first\n")?;
+        std::thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        assert!(queued.join().unwrap());
+        test_util::wait_for_output(&pty, "queued", Duration::from_secs(10))?;
+        let output = pty.scrollback()?;
+        assert!(!output.contains("rejected"));
+        assert!(output.find("first").unwrap() < output.find("queued").unwrap());
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "triggers")]
+    #[test]
+    fn lock_input_triggers() -> Result<(), Box<dyn Error>> {
+        struct Errors(Arc<Mutex<Vec<String>>>);
+
+        impl PtyHandler for Errors {
+            fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+            fn on_error(&mut self, _id: PtyId, err: Box<dyn Error>) {
+                self.0.lock().unwrap().push(err.to_string());
+            }
+        }
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let pty = test_util::stub().scrollback(0x10000).spawn_handler(Errors(errors.clone()))?;
+        pty.add_trigger(trigger::Regex::new("ping")?, TriggerAction::input("pong\n"))?;
+        let guard = pty.lock_input("script", Contention::Reject)?;
+        guard.write("ping\n")?;
+        assert!(wait_for(|| errors.lock().unwrap().iter().any(|err| err.contains("locked by script"))));
+        drop(guard);
+
+        pty.write("ping again\n")?;
+        test_util::wait_for_output(&pty, "pong", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn status_expires() -> Result<(), Box<dyn Error>> {
+        let clock = MockClock::new();
+        let pty = test_util::stub().clock(clock.clone()).spawn(|_id, _res| {}, |_id| {})?;
+        let session = registry::get(pty.id())?;
+        session.expect_status(true);
+        clock.advance(Duration::from_secs(1));
+        assert!(session.status_expected());
+        // the tty never answered
+        clock.advance(Duration::from_secs(1));
+        assert!(!session.status_expected());
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn flow_control() -> Result<(), Box<dyn Error>> {
+        let read_buf = Arc::new(Mutex::new(String::new()));
+        let read_buf_async = read_buf.clone();
+
+        let pty = Pty::spawn(move |_id, res| {
+            read_buf_async.lock().unwrap().push_str(res.unwrap().as_str());
+        }, |_id| {})?;
+        pty.write("echo 'ready'\r")?;
+        assert!(wait_for(|| read_buf.lock().unwrap().contains("ready\r\n")));
+
+        pty.flow(Flow::Stop)?;
+        pty.write("echo \"flowing-$((1 + 1))\"\r")?;
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!read_buf.lock().unwrap().contains("flowing-2"));
+
+        // a paste waits for the output to resume
+        let paste = pty.paste_large(": pasted\r")?;
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(paste.written(), 0);
+
+        pty.flow(Flow::Start)?;
+        assert!(wait_for(|| read_buf.lock().unwrap().contains("flowing-2")));
+        assert_eq!(paste.wait()?, ": pasted\r".len());
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn attached_clients() -> Result<(), Box<dyn Error>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let pty = test_util::stub().spawn(|_id, _res| {}, |_id| {})?;
+
+        let attach = |name: &'static str| {
+            let events = events.clone();
+            pty.attach(move |event| if let ClientEvent::Resized(rows, cols) = event {
+                events.lock().unwrap().push(format!("{name} {rows}x{cols}"));
+            })
+        };
+        let (a, b) = (attach("a")?, attach("b")?);
+        let count = |event: &str| events.lock().unwrap().iter().filter(|e| *e == event).count();
+
+        pty.client_resize(a, WindowSize::new(50, 100, 0, 0))?;
+        assert!(wait_for(|| count("a 50x100") == 1 && count("b 50x100") == 1));
+
+        assert_eq!(pty.client_resize(b, WindowSize::new(60, 80, 0, 0))?, WindowSize::new(50, 80, 0, 0));
+        assert!(wait_for(|| count("a 50x80") == 1 && count("b 50x80") == 1));
+
+        // b was holding the cols down, once it is gone only a hears about it
+        pty.detach(b)?;
+        assert!(wait_for(|| count("a 50x100") == 2));
+        assert_eq!(count("b 50x100"), 1);
+        assert!(pty.client_resize(b, WindowSize::new(10, 10, 0, 0)).is_err());
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn split_control_string() -> Result<(), Box<dyn Error>> {
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let chunks_async = chunks.clone();
+        let pty = Pty::spawn(move |_id, res| chunks_async.lock().unwrap().push(res.unwrap()), |_id| {})?;
+
+        // the title comes in two reads, it is passed on whole
+        pty.write("printf '\\033]2;split'; sleep 0.05; printf ' title\\007done-%s\\n' $((1 + 1))\r")?;
+        assert!(wait_for(|| chunks.lock().unwrap().concat().contains("done-2")));
+        assert!(chunks.lock().unwrap().iter().any(|chunk| chunk.contains("\x1b]2;split title\x07")));
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn prediction_times_out() -> Result<(), Box<dyn Error>> {
+        let echoes = Arc::new(Mutex::new(Vec::new()));
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        let client = {
+            let echoes = echoes.clone();
+            pty.attach(move |event| if let ClientEvent::Echo { seq, echoed } = event {
+                echoes.lock().unwrap().push((seq, echoed));
+            })?
+        };
+
+        // echo is off but the input is not hidden, nothing is written while sleep runs
+        pty.write("stty raw -echo; echo \"quiet-$((1 + 1))\"; sleep 6; stty sane\r")?;
+        test_util::wait_for_output(&pty, "quiet-2", Duration::from_secs(10))?;
+        let written = Instant::now();
+        pty.write_predicted(client, 1, "x")?;
+        assert!(wait_for(|| !echoes.lock().unwrap().is_empty()));
+        assert_eq!(*echoes.lock().unwrap(), [(1, false)]);
+        assert!(written.elapsed() < Duration::from_secs(5));
+
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn child_env() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        pty.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+        let env = pty.child_env()?;
+        assert_eq!(env.get("SHELL"), Some(&pty.shell()?));
+        assert!(env.contains_key("HOME"));
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn resource_usage() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        // the rc files may start processes of their own
+        pty.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+        assert!(wait_for(|| pty.resource_usage().is_ok_and(|usage| usage.n_processes == 1)));
+        // without the pty as stdio, a background job holding it would keep it from hanging up
+        pty.write("sleep 10 </dev/null >/dev/null 2>&1 &\r")?;
+        assert!(wait_for(|| pty.resource_usage().is_ok_and(|usage| usage.n_processes == 2)));
+        assert!(pty.resource_usage()?.rss > 0);
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().canonicalize()?;
+        let user = crate::unix::shell::ShellUser::from_env(crate::unix::shell::UserLookup::Env, "/bin/sh");
+        let pty = Pty::builder()
+            .user(user.clone())
+            .cwd(&dir)
+            .env("PTY_EXEC_PANE", "left")
+            .window_size(WindowSize::new(40, 120, 0, 0))
+            .scrollback(0x10000)
+            .spawn(|_id, _res| {}, |_id| {})?;
+        pty.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+
+        assert_eq!(pty.duplicate_builder()?.config.user, Some(user));
+
+        let read_buf = Arc::new(Mutex::new(String::new()));
+        let read_buf_async = read_buf.clone();
+        let twin = pty.duplicate(crate::handler::Callbacks {
+            on_read: move |_id, res: Result<String, Box<dyn Error>>| read_buf_async.lock().unwrap().push_str(&res.unwrap()),
+            on_death: |_id| {},
+        })?;
+        twin.write("echo \"$PTY_EXEC_PANE $(pwd) $(stty size)\"\r")?;
+        assert!(wait_for(|| read_buf.lock().unwrap().contains(&format!("left {} 40 120", dir.display()))));
+
+        twin.shutdown()?;
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn fallback_shells() -> Result<(), Box<dyn Error>> {
+        let user = ShellUser { shell: "/no/such/shell".into(), ..ShellUser::from_env(UserLookup::Env, "/bin/sh") };
+        let pty = Pty::builder()
+            .user(user.clone())
+            .fallback_shells(["/no/such/zsh", "/bin/sh"])
+            .spawn(|_id, _res| {}, |_id| {})?;
+        assert_eq!(pty.shell()?, "/bin/sh");
+        pty.shutdown()?;
+
+        let res = Pty::builder().user(user).fallback_shells(["/no/such/zsh"]).spawn(|_id, _res| {}, |_id| {});
+        assert!(res.is_err_and(|err| PtyError::copy_of(err.as_ref()).kind() == std::io::ErrorKind::NotFound));
+        Ok(())
+    }
+
+    #[test]
+    fn detach_policies() -> Result<(), Box<dyn Error>> {
+        let read_buf = Arc::new(Mutex::new(String::new()));
+        let read_buf_async = read_buf.clone();
+        let pty = Pty::builder()
+            .detach_policy(DetachPolicy::Stop)
+            .shutdown_timeout(Duration::from_secs(3))
+            .spawn(move |_id, res| read_buf_async.lock().unwrap().push_str(res.unwrap().as_str()), |_id| {})?;
+
+        // the shell is stopped while nobody is attached, its input waits
+        pty.detach(pty.attach(|_event| {})?)?;
+        pty.write("echo \"thawed-$((1 + 1))\"\r")?;
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(!read_buf.lock().unwrap().contains("thawed-2"));
+        let client = pty.attach(|_event| {})?;
+        assert!(wait_for(|| read_buf.lock().unwrap().contains("thawed-2")));
+        // stopped again, it is continued to read the shutdown input
+        pty.detach(client)?;
+        let started = Instant::now();
+        pty.shutdown()?;
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        let pty = Pty::builder()
+            .scrollback(0x10000)
+            .detach_policy(DetachPolicy::HangUp(Duration::from_millis(50)))
+            .spawn(|_id, _res| {}, |_id| {})?;
+        // hanging up a shell still reading its rc files can leave their locks behind
+        pty.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+        pty.detach(pty.attach(|_event| {})?)?;
+        assert!(wait_for(|| !pty.is_alive()));
+        Ok(())
+    }
+
+    #[test]
+    fn paste_large() -> Result<(), Box<dyn Error>> {
+        let read_buf = Arc::new(Mutex::new(String::new()));
+        let read_buf_async = read_buf.clone();
+
+        let pty = Pty::builder()
+            .stdin(StdioMode::Piped)
+            .spawn(move |_id, res| read_buf_async.lock().unwrap().push_str(res.unwrap().as_str()), |_id| {})?;
+
+        // far more than a pipe or tty buffers, wc only sees it all if nothing was dropped
+        pty.write("wc -c\n")?;
+        let paste = pty.paste_large(vec![b'a'; 0x100000])?;
+        assert_eq!(paste.wait()?, 0x100000);
+        pty.close_stdin()?;
+        assert!(wait_for(|| read_buf.lock().unwrap().contains("1048576")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_whole() -> Result<(), Box<dyn Error>> {
+        // without echo the output of cat is exactly what was written
+        let pty = Pty::builder().scrollback(0x100000).program(["sh", "-c", "stty -echo && echo ready && exec cat"]).spawn(|_id, _res| {}, |_id| {})?;
+        test_util::wait_for_output(&pty, "ready\r\n", Duration::from_secs(10))?;
+
+        // far more than the tty buffers, a write waits for the child rather than drop any
+        let input: String = (0..0x1400).map(|i| format!("{i:015}\n")).collect();
+        pty.write(&input)?;
+        let expected = input.replace('\n', "\r\n");
+        test_util::wait_for_output(&pty, &format!("{:015}\r\n", 0x13ff), Duration::from_secs(10))?;
+        let scrollback = pty.scrollback()?;
+        assert_eq!(scrollback.split_once("ready\r\n").map(|(_, output)| output), Some(expected.as_str()));
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn piped_stdin() -> Result<(), Box<dyn Error>> {
+        let read_buf = Arc::new(Mutex::new(String::new()));
+        let dead = Arc::new(Mutex::new(false));
+        let (read_buf_async, dead_async) = (read_buf.clone(), dead.clone());
+
+        let pty = Pty::builder()
+            .stdin(StdioMode::Piped)
+            .spawn(move |_id, res| {
+                read_buf_async.lock().unwrap().push_str(res.unwrap().as_str());
+            }, move |_id| *dead_async.lock().unwrap() = true)?;
+
+        // input is not echoed by the tty, only the output of the command shows up
+        pty.write("test -t 0 || echo \"piped-$((1 + 1))\"\n")?;
+        assert!(wait_for(|| read_buf.lock().unwrap().contains("piped-2")));
+        assert!(!read_buf.lock().unwrap().contains("test -t 0"));
+
+        // the shell exits on EOF
+        pty.close_stdin()?;
+        assert!(wait_for(|| *dead.lock().unwrap()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn startup_commands() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder()
+            .scrollback(0x10000)
+            .startup_commands(["cd /", "echo \"started-$((1 + 1))-$PWD\""])
+            .spawn(|_id, _res| {}, |_id| {})?;
+
+        // written once, after the first prompt, without their echo
+        test_util::wait_for_output(&pty, "started-2-/", Duration::from_secs(10))?;
+        let output = pty.scrollback()?;
+        assert_eq!(output.matches("started-2").count(), 1, "{output:?}");
+        assert!(!output.contains("cd /"), "{output:?}");
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn on_ready() -> Result<(), Box<dyn Error>> {
+        struct Ready(Arc<Mutex<usize>>);
+
+        impl PtyHandler for Ready {
+            fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+            fn on_ready(&mut self, _id: PtyId) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let ready = Arc::new(Mutex::new(0));
+        let pty = Pty::builder().scrollback(0x10000).spawn_handler(Ready(ready.clone()))?;
+        assert!(wait_for(|| *ready.lock().unwrap() == 1));
+
+        // input written once ready is read by the shell's line editor, not typed ahead
+        pty.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+        let output = pty.scrollback()?;
+        assert_eq!(output.matches("echo \"ready").count(), 1, "{output:?}");
+        assert_eq!(*ready.lock().unwrap(), 1);
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn foreground_changes() -> Result<(), Box<dyn Error>> {
+        struct Foreground(Arc<Mutex<Vec<String>>>);
+
+        impl PtyHandler for Foreground {
+            fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+            fn on_foreground(&mut self, _id: PtyId, process: ForegroundProcess) {
+                self.0.lock().unwrap().push(process.name);
+            }
+        }
+
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let pty = Pty::builder().poll_foreground(Duration::from_millis(20)).spawn_handler(Foreground(names.clone()))?;
+        let shell = Path::new(&pty.shell()?).file_name().unwrap().to_string_lossy().into_owned();
+        assert!(wait_for(|| names.lock().unwrap().first() == Some(&shell)));
+        assert_eq!(pty.foreground()?, ForegroundProcess { pid: pty.info()?.child_pid, name: shell.clone() });
+
+        pty.write("sleep 1\r")?;
+        assert!(wait_for(|| names.lock().unwrap().ends_with(&["sleep".to_owned(), shell.clone()])));
+        // the shell execing another program is a change as well
+        pty.write("exec sleep 1\r")?;
+        assert!(wait_for(|| names.lock().unwrap().len() == 4));
+        assert_eq!(names.lock().unwrap()[3], "sleep");
+        Ok(())
+    }
+
+    #[test]
+    fn echo_off() -> Result<(), Box<dyn Error>> {
+        struct Echo(Arc<Mutex<Vec<bool>>>);
+
+        impl PtyHandler for Echo {
+            fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+            fn on_echo_change(&mut self, _id: PtyId, hidden: bool) {
+                self.0.lock().unwrap().push(hidden);
+            }
+        }
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let pty = Pty::builder().scrollback(0x10000).spawn_handler(Echo(changes.clone()))?;
+        pty.write("read -s -p \"secret-$((1 + 1)): \" secret\r")?;
+        test_util::wait_for_output(&pty, "secret-2: ", Duration::from_secs(10))?;
+        assert!(wait_for(|| *changes.lock().unwrap() == [true]));
+        assert!(pty.is_echo_off()?);
+
+        // back at the prompt, the line editor echoes by itself
+        pty.write("hunter2\r")?;
+        assert!(wait_for(|| *changes.lock().unwrap() == [true, false]));
+        assert!(!pty.is_echo_off()?);
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn exit_through_pidfd() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).retain_exited(Duration::from_secs(60)).spawn(|_id, _res| {}, |_id| {})?;
+        // the background process keeps the pty from hanging up after the shell exited, started
+        // from a subshell so it is no job of the shell, which some shells refuse to exit with
+        pty.write("(sleep 30 &); echo \"bye-$((1 + 1))\"; exit 4\r")?;
+        let started = Instant::now();
+        let cancel = CancellationToken::new();
+        let deadline = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(10));
+            deadline.cancel();
+        });
+        pty.wait(&cancel)?;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(pty.state()?, SessionState::Exited(ExitStatus::Code(4)));
+        assert!(pty.scrollback()?.contains("bye-2"));
+        pty.discard()?;
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_ordering() -> Result<(), Box<dyn Error>> {
+        #[derive(Default)]
+        struct Seen {
+            events: Vec<&'static str>,
+            own_shutdown: Option<std::io::ErrorKind>,
+        }
+
+        struct Order(Arc<Mutex<Seen>>);
+
+        impl PtyHandler for Order {
+            fn on_output(&mut self, _id: PtyId, _output: String) {
+                self.0.lock().unwrap().events.push("output");
+            }
+
+            fn on_exit(&mut self, id: PtyId) {
+                // the exit is being reported on this very thread, waiting for it would hang
+                let res = Pty { id }.shutdown();
+                let mut seen = self.0.lock().unwrap();
+                seen.own_shutdown = res.err().map(|err| PtyError::copy_of(err.as_ref()).kind());
+                seen.events.push("exit");
+            }
+        }
+
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let pty = Pty::builder().scrollback(0x10000).spawn_handler(Order(seen.clone()))?;
+        let session = registry::get(pty.id())?;
+        pty.write("while :; do echo \"spam-$((1 + 1))\"; done\r")?;
+        test_util::wait_for_output(&pty, "spam-2", Duration::from_secs(10))?;
+
+        pty.shutdown()?;
+        let events = seen.lock().unwrap().events.clone();
+        assert_eq!(events.iter().filter(|&&event| event == "exit").count(), 1);
+        assert_eq!(events.last(), Some(&"exit"));
+        assert_eq!(seen.lock().unwrap().own_shutdown, Some(std::io::ErrorKind::WouldBlock));
+        assert!(session.with_fd(|_| Ok(())).is_err());
+
+        // nothing trails in
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(seen.lock().unwrap().events.len(), events.len());
+        assert!(pty.shutdown().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn coalesced_writes() -> Result<(), Box<dyn Error>> {
+        let clock = clock::MockClock::new();
+        let window = Duration::from_millis(100);
+        let pty = Pty::builder().scrollback(0x10000).clock(clock.clone()).coalesce_writes(window).spawn(|_id, _res| {}, |_id| {})?;
+        for c in "echo \"held-$((1 + 1))\"".chars() {
+            pty.write(&c.to_string())?;
+        }
+        // the window never ends without the clock moving
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!pty.scrollback()?.contains("held"));
+        clock.advance(window);
+        test_util::wait_for_output(&pty, "echo \"held-$((1 + 1))\"", Duration::from_secs(10))?;
+
+        // ^D takes what is held back along at once, ^C would have the tty flush the echo
+        pty.write("echo urgent")?;
+        pty.write("\x04")?;
+        test_util::wait_for_output(&pty, "urgent", Duration::from_secs(10))?;
+
+        // turned off, what is held back is written without waiting for the window
+        pty.write("echo released")?;
+        pty.update_config(ConfigPatch::new().coalesce_writes(None))?;
+        test_util::wait_for_output(&pty, "released", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn line_editing() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        pty.write("echo \"wrong-$((1 + 1))\"")?;
+        pty.cursor_home()?;
+        pty.replace_current_line("echo \"right-$((1 + 1))\"")?;
+        pty.write("\r")?;
+        test_util::wait_for_output(&pty, "right-2", Duration::from_secs(10))?;
+        assert!(!pty.scrollback()?.contains("wrong-2"));
+        assert!(pty.write_control('1').is_err());
+        pty.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn failed_spawn_cleanup() -> Result<(), Box<dyn Error>> {
+        // the recording fails after the child started, nothing of the session may stay behind
+        let res = test_util::stub().tag("recording-fails").record(Recording::new("/nonexistent/pty-exec.cast")).spawn(|_id, _res| {}, |_id| {});
+        assert!(res.is_err());
+        assert!(!registry::sessions().iter().any(|session| session.config().tag.as_deref() == Some("recording-fails")));
         Ok(())
     }
 }
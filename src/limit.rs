@@ -0,0 +1,151 @@
+//! A cap on the ptys of the process, for hosts where untrusted clients can cause spawns
+//! every pty takes a master fd, a wake pipe and a pty of the kernel's limited pool, once the cap
+//! is reached spawning fails with a PtyError of kind ErrorKind::QuotaExceeded, or waits for a
+//! pty to close first with PtyBuilder::wait_for_slot(), waiters are served in order
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//!
+//! pty_exec::limit::set_max_sessions(Some(64));
+//! let pty = Pty::builder()
+//!     .wait_for_slot(Duration::from_secs(5))
+//!     .spawn(|_id, _res| {}, |_id| {})
+//!     .map_err(|err| match err.downcast_ref::<pty_exec::PtyError>() {
+//!         Some(err) if err.is_limit_reached() => "too many sessions, try again later".into(),
+//!         _ => err,
+//!     })?;
+//! pty.shutdown()?;
+//! pty_exec::limit::set_max_sessions(None);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use crate::error::PtyError;
+
+static LIMITER: Limiter = Limiter::new();
+
+/// Caps the number of live ptys, None (the default) lifts the cap,
+/// lowering it under the number of live ptys only holds off new spawns
+pub fn set_max_sessions(max: Option<usize>) {
+    LIMITER.set_max(max);
+}
+
+/// The current cap, see set_max_sessions()
+pub fn max_sessions() -> Option<usize> {
+    LIMITER.state.lock().unwrap().max
+}
+
+struct State {
+    max: Option<usize>,
+    used: usize,
+    // tickets of the spawns waiting for a slot, in the order they arrived
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+/**
+ * Slots for live ptys, a pty holds one from before it is spawned until it is closed
+ */
+pub(crate) struct Limiter {
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+/**
+ * A taken slot, given back on drop
+ */
+pub(crate) struct Slot {
+    limiter: &'static Limiter,
+}
+
+impl Limiter {
+    const fn new() -> Limiter {
+        let state = State { max: None, used: 0, waiting: VecDeque::new(), next_ticket: 0 };
+        Limiter { state: Mutex::new(state), freed: Condvar::new() }
+    }
+
+    fn set_max(&self, max: Option<usize>) {
+        self.state.lock().unwrap().max = max;
+        // a raised cap may let waiters through
+        self.freed.notify_all();
+    }
+
+    /**
+     * Takes a slot, waiting up to wait for one to be freed, fails once the cap is still reached
+     */
+    fn acquire(&'static self, wait: Duration) -> Result<Slot, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let has_room = |state: &State| state.max.is_none_or(|max| state.used < max);
+        // a spawn must not jump the queue, even if it would not wait
+        if state.waiting.is_empty() && has_room(&state) {
+            state.used += 1;
+            return Ok(Slot { limiter: self });
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        let deadline = Instant::now() + wait;
+        loop {
+            if state.waiting.front() == Some(&ticket) && has_room(&state) {
+                state.waiting.pop_front();
+                state.used += 1;
+                // the next in line may fit too
+                self.freed.notify_all();
+                return Ok(Slot { limiter: self });
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                state.waiting.retain(|&waiting| waiting != ticket);
+                self.freed.notify_all();
+                let max = state.max.unwrap_or(state.used);
+                return Err(Box::new(PtyError::limit_reached(format!("Session limit of {max} reached"))));
+            }
+            state = self.freed.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().used -= 1;
+        self.limiter.freed.notify_all();
+    }
+}
+
+/**
+ * Takes a slot for a pty about to be spawned
+ */
+pub(crate) fn acquire(wait: Duration) -> Result<Slot, Box<dyn Error>> {
+    LIMITER.acquire(wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn slots() {
+        // not the crate's limiter, other tests spawn ptys meanwhile
+        static LIMITER: Limiter = Limiter::new();
+        LIMITER.set_max(Some(1));
+
+        let slot = LIMITER.acquire(Duration::ZERO).unwrap();
+        let err = LIMITER.acquire(Duration::from_millis(10)).err().unwrap();
+        assert!(err.downcast_ref::<PtyError>().unwrap().is_limit_reached());
+
+        let waiter = thread::spawn(|| LIMITER.acquire(Duration::from_secs(10)).is_ok());
+        thread::sleep(Duration::from_millis(50));
+        drop(slot);
+        assert!(waiter.join().unwrap());
+        assert_eq!(LIMITER.state.lock().unwrap().used, 0);
+
+        LIMITER.set_max(None);
+        let slots: Vec<_> = (0..3).map(|_| LIMITER.acquire(Duration::ZERO).unwrap()).collect();
+        assert_eq!(LIMITER.state.lock().unwrap().used, slots.len());
+    }
+}
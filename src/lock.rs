@@ -0,0 +1,90 @@
+//! Exclusive input, Pty::lock_input() gives one writer the input of a pty for as long as it
+//! holds the InputGuard, e.g. an expect script or setup commands that keystrokes of the user
+//! must not land in the middle of, other writes either fail with ErrorKind::ResourceBusy or
+//! wait for the guard to be dropped, see Contention
+//!
+//! the lock covers Pty::write() and its kin, input of attached clients and of groups, triggers,
+//! startup commands and the start of pastes, answers to terminal queries are not held back,
+//! startup commands and trigger input are written by the thread reading the pty, which cannot
+//! wait, while the input is locked they are dropped and reported to PtyHandler::on_error()
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::lock::Contention;
+//!
+//! let pty = Pty::builder().spawn(|_id, _res| {}, |_id| {})?;
+//! let guard = pty.lock_input("deploy-script", Contention::Reject)?;
+//! guard.write("cd /tmp\r")?;
+//! assert!(pty.write("typed by the user\r").is_err());
+//! drop(guard);
+//! pty.write("typed by the user\r")?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use crate::id::PtyId;
+use crate::registry;
+
+/// What writes of others do while the input of a pty is locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Contention {
+    /// fail with ErrorKind::ResourceBusy
+    #[default]
+    Reject,
+    /// wait until the guard is dropped, writes from callbacks of the pty fail instead, the
+    /// thread running them has to read the output the holder of the guard waits for
+    Queue,
+}
+
+/// Exclusive input of a pty, released when dropped, see Pty::lock_input()
+#[derive(Debug)]
+pub struct InputGuard {
+    id: PtyId,
+    token: u64,
+    owner: String,
+}
+
+/**
+ * The holder of the input of a session
+ */
+#[derive(Debug)]
+pub(crate) struct InputLock {
+    pub owner: String,
+    pub token: u64,
+    pub contention: Contention,
+}
+
+impl InputGuard {
+    pub(crate) fn new(id: PtyId, token: u64, owner: &str) -> InputGuard {
+        InputGuard { id, token, owner: owner.to_owned() }
+    }
+
+    pub fn id(&self) -> PtyId {
+        self.id
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// write to the pty like Pty::write()
+    pub fn write(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        let session = registry::get(self.id)?;
+        let s = session.config().eol.translate(s);
+        session.write_locked(self.token, &s)
+    }
+
+    /// write to the pty like Pty::write_raw()
+    pub fn write_raw(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        registry::get(self.id)?.write_locked(self.token, s)
+    }
+}
+
+impl Drop for InputGuard {
+    fn drop(&mut self) {
+        if let Ok(session) = registry::get_any(self.id) {
+            session.unlock_input(self.token);
+        }
+    }
+}
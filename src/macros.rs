@@ -0,0 +1,190 @@
+//! Macros, input written to a pty between Pty::start_macro() and Pty::stop_macro() along with
+//! the time between writes, replayed into any pty with Pty::play_macro(), hidden input like a
+//! password is left out and so is input of Pty::inject()
+//! macros are saved as asciicast v2 files holding only input ("i") events, a recording made with
+//! InputRecording::All loads as a macro too
+//! ```rust
+//! use pty_exec::Pty;
+//!
+//! let pty = Pty::builder().spawn(|_id, _res| {}, |_id| {})?;
+//! pty.start_macro("greet")?;
+//! pty.write("echo hello\n")?;
+//! let greet = pty.stop_macro()?;
+//! // twice as fast
+//! pty.play_macro(&greet, 2.0)?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crate::error::PtyError;
+use crate::recording::json_string;
+
+/// Input recorded to be written again, see the macros module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    name: String,
+    steps: Vec<MacroStep>,
+}
+
+/// One write of a macro
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroStep {
+    /// time since the write before, or since the macro started for the first
+    pub delay: Duration,
+    pub input: String,
+}
+
+impl Macro {
+    pub fn new(name: &str, steps: Vec<MacroStep>) -> Macro {
+        Macro { name: name.to_owned(), steps }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn steps(&self) -> &[MacroStep] {
+        &self.steps
+    }
+
+    /// how long the macro takes to play at speed 1
+    pub fn duration(&self) -> Duration {
+        self.steps.iter().map(|step| step.delay).sum()
+    }
+
+    /// save the macro to path as an asciicast v2 file, an existing file is overwritten
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, r#"{{"version": 2, "width": 80, "height": 24, "title": {}}}"#, json_string(&self.name))?;
+        let mut time = Duration::ZERO;
+        for step in &self.steps {
+            time += step.delay;
+            writeln!(file, r#"[{:.6}, "i", {}]"#, time.as_secs_f64(), json_string(&step.input))?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    /// load a macro saved with save(), or the input of an asciicast v2 recording, named by
+    /// the title of the recording or else the stem of path
+    pub fn load(path: impl AsRef<Path>) -> Result<Macro, Box<dyn Error>> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path)?;
+        let invalid = |line: usize| PtyError::with_kind(format!("Invalid macro {}, line {line}", path.display()), ErrorKind::InvalidData);
+
+        let mut lines = data.lines();
+        let header = lines.next().filter(|header| header.contains(r#""version": 2"#)).ok_or_else(|| invalid(1))?;
+        let name = match header.split_once(r#""title": "#) {
+            Some((_, title)) => parse_json_string(title).ok_or_else(|| invalid(1))?.0,
+            None => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+        };
+
+        let mut steps = Vec::new();
+        let mut last = 0.0;
+        for (i, line) in lines.enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            // [time, code, data]
+            let event = || {
+                let (time, rest) = line.strip_prefix('[')?.split_once(',')?;
+                let time = time.trim().parse::<f64>().ok().filter(|time| time.is_finite() && *time >= last)?;
+                let (code, rest) = parse_json_string(rest.trim_start())?;
+                let (data, _) = parse_json_string(rest.trim_start().strip_prefix(',')?.trim_start())?;
+                Some((time, code, data))
+            };
+            let (time, code, data) = event().ok_or_else(|| invalid(i + 2))?;
+            if code == "i" {
+                let delay = Duration::try_from_secs_f64(time - last).map_err(|_| invalid(i + 2))?;
+                steps.push(MacroStep { delay, input: data });
+                last = time;
+            }
+        }
+        Ok(Macro { name, steps })
+    }
+}
+
+/**
+ * Input written to a pty since Pty::start_macro()
+ */
+pub(crate) struct MacroRecorder {
+    name: String,
+    last: Instant,
+    steps: Vec<MacroStep>,
+}
+
+impl MacroRecorder {
+    pub(crate) fn new(name: &str, now: Instant) -> MacroRecorder {
+        MacroRecorder { name: name.to_owned(), last: now, steps: Vec::new() }
+    }
+
+    pub(crate) fn input(&mut self, input: &str, now: Instant) {
+        self.steps.push(MacroStep { delay: now.saturating_duration_since(self.last), input: input.to_owned() });
+        self.last = now;
+    }
+
+    pub(crate) fn finish(self) -> Macro {
+        Macro { name: self.name, steps: self.steps }
+    }
+}
+
+/**
+ * The JSON string s starts with and what follows it, only the escapes json_string() writes
+ * and those of other JSON writers are known
+ */
+fn parse_json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &s[i + 2..])),
+            '\\' => string.push(match chars.next()?.1 {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'b' => '\x08',
+                'f' => '\x0c',
+                'u' => {
+                    let hex: String = (0..4).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<_>>()?;
+                    // a lone surrogate, pairs are not written for the control characters escaped
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?).unwrap_or(char::REPLACEMENT_CHARACTER)
+                },
+                c => c,
+            }),
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-exec-macro-{}.cast", std::process::id()));
+        let steps = vec![
+            MacroStep { delay: Duration::from_millis(250), input: "echo \"hi\"\t\\".into() },
+            MacroStep { delay: Duration::from_secs(1), input: "\x1b[A\r".into() },
+        ];
+        let greet = Macro::new("gre\"et", steps);
+        greet.save(&path)?;
+        assert_eq!(Macro::load(&path)?, greet);
+        assert_eq!(greet.duration(), Duration::from_millis(1250));
+
+        // a recording, output and resizes are skipped, it is named after the file
+        fs::write(&path, "{\"version\": 2, \"width\": 80, \"height\": 24}\n[0.5, \"o\", \"$ \"]\n[1.0, \"i\", \"ls\\u000d\"]\n[1.5, \"r\", \"80x40\"]\n")?;
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        assert_eq!(Macro::load(&path)?, Macro::new(&name, vec![MacroStep { delay: Duration::from_secs(1), input: "ls\r".into() }]));
+        fs::write(&path, "{\"version\": 2}\n[1.0, \"i\", \"cut")?;
+        assert!(Macro::load(&path).is_err());
+        fs::write(&path, "{\"version\": 2}\n[1e300, \"i\", \"late\"]\n")?;
+        assert!(Macro::load(&path).is_err());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
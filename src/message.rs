@@ -0,0 +1,81 @@
+//! Messages between the application hosting a pty and its attached clients that are not
+//! written to the pty, e.g. a client asking for the clipboard of the host or the host asking
+//! a client to open a URL, see Pty::send_message() and Pty::client_message()
+//! messages of the host reach every client as ClientEvent::Message, in order with the output,
+//! messages of a client reach the handler of the pty with on_message, clients of a server send
+//! and receive them as Message frames, see Client::send_message()
+//! ```rust
+//! use std::sync::mpsc;
+//! use pty_exec::Pty;
+//! use pty_exec::clients::ClientEvent;
+//! use pty_exec::message::Message;
+//!
+//! let pty = Pty::builder().spawn(|_id, _res| {}, |_id| {})?;
+//! let (tx, events) = mpsc::channel();
+//! let client = pty.attach(move |event| if let ClientEvent::Message(message) = event {
+//!     let _ = tx.send(message);
+//! })?;
+//! pty.send_message(Message::OpenUrl("https://example.com".into()))?;
+//! assert_eq!(events.recv()?, Message::OpenUrl("https://example.com".into()));
+//! // reaches PtyHandler::on_message
+//! pty.client_message(client, Message::ClipboardRequest)?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+/// A message that is not written to the pty, see the message module
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Message {
+    /// asks the other side for the contents of its clipboard, answered with Clipboard
+    ClipboardRequest,
+    /// the contents of a clipboard
+    Clipboard(String),
+    /// asks the other side to open a URL, e.g. in a browser
+    OpenUrl(String),
+    /// a message of the application, kind tells applications' messages apart
+    Custom { kind: String, data: Vec<u8> },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::mpsc::{self, Sender};
+    use std::time::Duration;
+    use crate::clients::{ClientEvent, ClientId};
+    use crate::{test_util, PtyHandler, PtyId};
+    use super::*;
+
+    struct Host(Sender<(Option<ClientId>, Message)>);
+
+    impl PtyHandler for Host {
+        fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+        fn on_message(&mut self, _id: PtyId, from: Option<ClientId>, message: Message) {
+            let _ = self.0.send((from, message));
+        }
+    }
+
+    #[test]
+    fn messages() -> Result<(), Box<dyn Error>> {
+        let (tx, received) = mpsc::channel();
+        let pty = test_util::stub().spawn_handler(Host(tx))?;
+        let (tx, events) = mpsc::channel();
+        let client = pty.attach(move |event| { let _ = tx.send(event); })?;
+
+        let custom = Message::Custom { kind: "app/theme".into(), data: b"dark".to_vec() };
+        pty.send_message(custom.clone())?;
+        // in order with the output of the child
+        let sent = loop {
+            if let ClientEvent::Message(message) = events.recv_timeout(Duration::from_secs(10))? { break message }
+        };
+        assert_eq!(sent, custom);
+        pty.client_message(client, Message::Clipboard("copied".into()))?;
+        assert_eq!(received.recv_timeout(Duration::from_secs(10))?, (Some(client), Message::Clipboard("copied".into())));
+
+        pty.detach(client)?;
+        assert!(pty.client_message(client, Message::ClipboardRequest).is_err());
+        pty.shutdown()?;
+        Ok(())
+    }
+}
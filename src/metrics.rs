@@ -0,0 +1,79 @@
+//! Metrics recorded through the `metrics` facade when the `metrics` feature is enabled,
+//! install any `metrics` compatible recorder (e.g. a Prometheus exporter) to collect them
+//!
+//! | name | kind | labels |
+//! |------|------|--------|
+//! | `pty_exec_sessions_active` | gauge | |
+//! | `pty_exec_spawn_seconds` | histogram | |
+//! | `pty_exec_spawn_failures_total` | counter | |
+//! | `pty_exec_bytes_read_total` | counter | |
+//! | `pty_exec_bytes_written_total` | counter | |
+//! | `pty_exec_write_seconds` | histogram | |
+//! | `pty_exec_read_errors_total` | counter | |
+//! | `pty_exec_callback_seconds` | histogram | |
+//! | `pty_exec_filter_seconds` | histogram | `filter`, `direction` |
+//! | `pty_exec_filter_dropped_total` | counter | `filter`, `direction` |
+//!
+//! metrics are crate wide, a series once recorded stays with the recorder, one per session
+//! would outlive it, `pty_exec_spawn_seconds` includes adopting a migrated session,
+//! `pty_exec_write_seconds` is how long a write took to be taken by the tty,
+//! `pty_exec_callback_seconds` covers every call into a PtyHandler, `filter` is the name of
+//! a filter::Filter and `direction` either `output` or `input`
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+use crate::filter::Direction;
+
+pub(crate) fn spawned(latency: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::gauge!("pty_exec_sessions_active").increment(1);
+        metrics::histogram!("pty_exec_spawn_seconds").record(latency);
+    }
+}
+
+pub(crate) fn spawn_failed() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("pty_exec_spawn_failures_total").increment(1);
+}
+
+pub(crate) fn died() {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!("pty_exec_sessions_active").decrement(1);
+}
+
+pub(crate) fn read(bytes: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("pty_exec_bytes_read_total").increment(bytes as u64);
+}
+
+pub(crate) fn written(bytes: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("pty_exec_bytes_written_total").increment(bytes as u64);
+}
+
+pub(crate) fn write(latency: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("pty_exec_write_seconds").record(latency);
+}
+
+pub(crate) fn read_failed() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("pty_exec_read_errors_total").increment(1);
+}
+
+pub(crate) fn callback(latency: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("pty_exec_callback_seconds").record(latency);
+}
+
+pub(crate) fn filtered(filter: &str, direction: Direction, latency: Duration, dropped: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let labels = [("filter", filter.to_owned()), ("direction", direction.to_string())];
+        metrics::histogram!("pty_exec_filter_seconds", &labels).record(latency);
+        if dropped {
+            metrics::counter!("pty_exec_filter_dropped_total", &labels).increment(1);
+        }
+    }
+}
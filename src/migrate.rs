@@ -0,0 +1,244 @@
+//! Handing a live session over to another process on the same machine, e.g. to upgrade a
+//! terminal daemon without killing the shells it serves, enabled with the experimental
+//! `migrate` feature, see Pty::migrate() and PtyBuilder::adopt()
+//! the master fd is passed over a unix socket (SCM_RIGHTS) along with the state of the
+//! session, see the state module, so either side may run another version, the sending side
+//! stops reading before the handoff and resumes if the other side does not adopt the session,
+//! output the child writes meanwhile waits in the pty for whoever reads next
+//! the child stays a child of the sending process, the adopting side cannot reap it and
+//! reports its exit status as ExitStatus::Unknown, sessions with piped stdio cannot move
+//! ```rust
+//! use std::os::unix::net::UnixStream;
+//! use std::thread;
+//! use pty_exec::{Pty, PtyBuilder};
+//!
+//! let (old_daemon, new_daemon) = UnixStream::pair()?;
+//! let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+//! let handing_over = thread::spawn(move || pty.migrate(&old_daemon).map_err(|err| err.to_string()));
+//!
+//! let pty = PtyBuilder::new().scrollback(0x10000).adopt(&new_daemon, |_id, _res| {}, |_id| {})?;
+//! handing_over.join().unwrap()?;
+//! pty.write("echo 'still here'\r")?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+use nix::unistd;
+use crate::builder::StdioMode;
+use crate::cancel::CancellationToken;
+use crate::error::PtyError;
+use crate::registry::{Notice, Session};
+use crate::state::SessionSnapshot;
+use crate::unix;
+
+// offers larger than this are refused, it bounds what a peer can make the other side allocate
+const MAX_OFFER: usize = 0x1000_0000;
+
+const OFFER: u8 = b'O';
+const ADOPTED: u8 = b'A';
+const REFUSED: u8 = b'R';
+
+/**
+ * Pauses the polling thread of a session for the length of a handoff
+ */
+pub(crate) struct Handoff {
+    paused: Sender<()>,
+    outcome: Receiver<bool>,
+}
+
+impl Handoff {
+    /**
+     * Called by the polling thread once it stopped reading, returns whether the session was
+     * adopted, it resumes reading otherwise
+     */
+    pub(crate) fn pause(self) -> bool {
+        if self.paused.send(()).is_err() {
+            return false;
+        }
+        self.outcome.recv().unwrap_or(false)
+    }
+}
+
+/**
+ * A session as handed over, the master fd is ours now
+ */
+pub(crate) struct Offer {
+    pub master: RawFd,
+    pub snapshot: SessionSnapshot,
+}
+
+/**
+ * Hands session over to the process at the other end of target, returns once its exit from
+ * this process was reported
+ */
+pub(crate) fn send(session: &Arc<Session>, target: &UnixStream) -> Result<(), Box<dyn Error>> {
+    let id = session.id();
+    let config = session.config();
+    if config.stdin == StdioMode::Piped || config.stdout == StdioMode::Piped || config.stderr_piped {
+        return Err(Box::new(PtyError::with_kind(format!("Migration of {id} with piped stdio"), io::ErrorKind::Unsupported)));
+    }
+    if session.on_reader_thread() {
+        return Err(Box::new(PtyError::with_kind(format!("Migration of {id} from its own callback"), io::ErrorKind::WouldBlock)));
+    }
+
+    let (paused, paused_rx) = mpsc::channel();
+    let (outcome, outcome_rx) = mpsc::channel();
+    session.notify(Notice::Migrate(Handoff { paused, outcome: outcome_rx }))?;
+    if paused_rx.recv().is_err() {
+        return Err(Box::new(PtyError::with_kind(format!("{id} exited before its migration"), io::ErrorKind::BrokenPipe)));
+    }
+    let res = offer(session, target);
+    let _ = outcome.send(res.is_ok());
+    if res.is_ok() {
+        session.wait_exited_cancellable(&CancellationToken::new());
+    }
+    res
+}
+
+/**
+ * Sends the offer of session with its master fd, waits for the other side to adopt it
+ */
+fn offer(session: &Session, target: &UnixStream) -> Result<(), Box<dyn Error>> {
+    let payload = SessionSnapshot::of(session).encode();
+
+    let mut header = vec![OFFER];
+    header.extend((payload.len() as u32).to_be_bytes());
+    // the fd goes along with the first byte, the rest is written as usual
+    let fds = [session.id().fd()];
+    let sent = sendmsg::<UnixAddr>(target.as_raw_fd(), &[IoSlice::new(&header)], &[ControlMessage::ScmRights(&fds)], MsgFlags::empty(), None)?;
+    let mut writer = target;
+    writer.write_all(&header[sent..])?;
+    writer.write_all(&payload)?;
+
+    let mut reader = target;
+    match read_frame(&mut reader)? {
+        (ADOPTED, _) => Ok(()),
+        (REFUSED, reason) => Err(Box::new(PtyError::new(format!("Migration of {} refused: {}", session.id(), String::from_utf8_lossy(&reason))))),
+        (kind, _) => Err(Box::new(PtyError::with_kind(format!("Unexpected migration frame {kind:#x}"), io::ErrorKind::InvalidData))),
+    }
+}
+
+/**
+ * Receives the offer of a session from source, one that cannot be read is refused and its
+ * master fd closed
+ */
+pub(crate) fn receive(source: &UnixStream) -> Result<Offer, Box<dyn Error>> {
+    let mut header = [0; 5];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+    let (received, master) = {
+        let mut iov = [IoSliceMut::new(&mut header)];
+        let msg = recvmsg::<UnixAddr>(source.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+        let master = msg.cmsgs().find_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+            _ => None
+        });
+        (msg.bytes, master)
+    };
+    let Some(master) = master else {
+        return Err(Box::new(PtyError::with_kind("Migration offer without a pty".to_owned(), io::ErrorKind::InvalidData)));
+    };
+    unix::pty::set_cloexec(master);
+
+    let snapshot = (|| -> Result<SessionSnapshot, Box<dyn Error>> {
+        if received == 0 {
+            return Err(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        let mut reader = source;
+        reader.read_exact(&mut header[received..])?;
+        let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+        if header[0] != OFFER || len > MAX_OFFER {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Invalid migration offer")));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        Ok(SessionSnapshot::decode(&payload)?)
+    })();
+    match snapshot {
+        Ok(snapshot) => Ok(Offer { master, snapshot }),
+        Err(err) => {
+            refuse(source, &err.to_string());
+            let _ = unistd::close(master);
+            Err(err)
+        }
+    }
+}
+
+/**
+ * Tells the sending side it may let go of the session
+ */
+pub(crate) fn adopted(source: &UnixStream) -> io::Result<()> {
+    let mut writer = source;
+    writer.write_all(&[ADOPTED, 0, 0, 0, 0])
+}
+
+/**
+ * Tells the sending side to keep the session
+ */
+pub(crate) fn refuse(source: &UnixStream, reason: &str) {
+    let mut frame = vec![REFUSED];
+    frame.extend((reason.len() as u32).to_be_bytes());
+    frame.extend(reason.as_bytes());
+    let mut writer = source;
+    let _ = writer.write_all(&frame);
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    if len > MAX_OFFER {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Migration frame too large"));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use crate::{test_util, Pty, PtyBuilder};
+    use super::*;
+
+    #[test]
+    fn handoff() -> Result<(), Box<dyn Error>> {
+        let (old, new) = UnixStream::pair()?;
+        let pty = Pty::builder().scrollback(0x10000).tag("migrating").spawn(|_id, _res| {}, |_id| {})?;
+        pty.write("echo \"before-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "before-2", Duration::from_secs(10))?;
+
+        // refused, the session stays
+        let (sender, refusing) = UnixStream::pair()?;
+        let refused = thread::spawn(move || {
+            let offer = receive(&refusing).unwrap();
+            let _ = unistd::close(offer.master);
+            refuse(&refusing, "no room");
+        });
+        assert!(pty.migrate(&sender).is_err());
+        refused.join().unwrap();
+        pty.write("echo \"stayed-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "stayed-2", Duration::from_secs(10))?;
+
+        let old_id = pty.id();
+        let handing_over = thread::spawn(move || pty.migrate(&old).map_err(|err| err.to_string()));
+        let pty = PtyBuilder::new().scrollback(0x10000).adopt(&new, |_id, _res| {}, |_id| {})?;
+        handing_over.join().unwrap()?;
+        assert!(!Pty { id: old_id }.is_alive());
+
+        assert!(pty.scrollback()?.contains("before-2"));
+        assert_eq!(pty.tag()?.as_deref(), Some("migrating"));
+        assert!(pty.health()?.child_alive);
+        pty.write("echo \"after-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "after-2", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        Ok(())
+    }
+}
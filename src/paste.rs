@@ -0,0 +1,112 @@
+//! Pasting large input without blocking the caller or overrunning the tty
+//! ```rust
+//! use pty_exec::Pty;
+//!
+//! let pty = Pty::spawn(|_id, _res| {}, |_id| {})?;
+//! let paste = pty.paste_large(": ".repeat(0x10000) + "\r")?;
+//! // ... the paste runs in the background
+//! println!("{} bytes pasted", paste.wait()?);
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::cancel::CancellationToken;
+use crate::error::PtyError;
+use crate::id::PtyId;
+use crate::{metrics, registry, unix};
+
+// bytes handed to the tty at once, the size of its line buffer in canonical mode
+const CHUNK: usize = 0x1000;
+// longest wait for the tty to drain while holding the fd, bounds how long a paste delays closing it
+const DRAIN_WAIT: Duration = Duration::from_millis(50);
+
+/// Paste running in the background, see Pty::paste_large() and Pty::paste_file()
+/// dropping it lets the paste run to completion, it waits while the output is stopped,
+/// see Pty::flow()
+#[derive(Debug)]
+pub struct Paste {
+    thread: JoinHandle<Result<usize, PtyError>>,
+    written: Arc<AtomicUsize>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Paste {
+    pub(crate) fn start<R: Read + Send + 'static>(id: PtyId, mut input: R) -> Result<Paste, Box<dyn Error>> {
+        let written = Arc::new(AtomicUsize::new(0));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (written_async, cancelled_async) = (written.clone(), cancelled.clone());
+
+        let thread = thread::Builder::new().name(format!("pty-exec/paste={id}")).spawn(move || {
+            // errors cross threads as PtyError, Box<dyn Error> is not Send
+            let fail = |err: Box<dyn Error>| PtyError::copy_of(err.as_ref());
+            let mut buf = vec![0; CHUNK];
+
+            loop {
+                let len = input.read(&mut buf).map_err(PtyError::from)?;
+                if len == 0 { break }
+
+                let mut chunk = &buf[..len];
+                while !chunk.is_empty() {
+                    if cancelled_async.load(Ordering::Relaxed) {
+                        return Ok(written_async.load(Ordering::Relaxed));
+                    }
+                    // the tty only takes what it has room for, the rest waits until the child reads
+                    let session = registry::get(id).map_err(fail)?;
+                    // the child could not echo it, as typing would not go on while it is stopped
+                    if session.output_stopped() {
+                        thread::sleep(DRAIN_WAIT);
+                        continue;
+                    }
+                    let n = session.with_input_fd(|fd| match unix::pty::write_some(fd, chunk)? {
+                        0 => unix::pty::wait_writable(fd, DRAIN_WAIT).map(|_| 0),
+                        n => Ok(n)
+                    }).map_err(fail)?;
+
+                    metrics::written(n);
+                    written_async.fetch_add(n, Ordering::Relaxed);
+                    chunk = &chunk[n..];
+                }
+            }
+            Ok(written_async.load(Ordering::Relaxed))
+        })?;
+
+        Ok(Paste { thread, written, cancelled })
+    }
+
+    /// bytes pasted so far
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// stop pasting, input already written to the tty is not taken back, see Pty::flush_input()
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// whether the paste has finished, been cancelled or failed
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// wait() that cancels the paste once cancel is cancelled, returning the bytes pasted until then
+    pub fn wait_cancellable(self, cancel: &CancellationToken) -> Result<usize, Box<dyn Error>> {
+        let cancelled = self.cancelled.clone();
+        let _waker = cancel.on_cancel(move || cancelled.store(true, Ordering::Relaxed));
+        self.wait()
+    }
+
+    /// waits for the paste to finish, returns the bytes pasted
+    /// fails if the input could not be read or the pty died before the paste was complete
+    pub fn wait(self) -> Result<usize, Box<dyn Error>> {
+        match self.thread.join() {
+            Ok(res) => res.map_err(|err| Box::new(err) as Box<dyn Error>),
+            Err(_) => Err(Box::new(PtyError::new("Paste thread panicked")))
+        }
+    }
+}
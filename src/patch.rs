@@ -0,0 +1,222 @@
+//! Changing the policies of a live session without respawning it, see Pty::update_config()
+//! a patch holds only the settings it changes, a patch failing validation changes nothing,
+//! the change is reported through PtyHandler::on_config_change() and the audit trail
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//! use pty_exec::patch::ConfigPatch;
+//! use pty_exec::quota::{QuotaPolicy, QuotaResource};
+//!
+//! let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+//! pty.update_config(ConfigPatch::new()
+//!     .hold_synchronized_output(Some(Duration::from_millis(50)))
+//!     .quota(QuotaResource::Scrollback, 0x1000, QuotaPolicy::DropOldest))?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use crate::builder::Config;
+use crate::clients::{DetachPolicy, ResizePolicy};
+use crate::filter::{Filter, Pipeline};
+use crate::input::{Eol, Sanitize};
+use crate::quota::{self, Quota, QuotaPolicy, QuotaResource};
+
+/// A setting a patch changed, see PtyHandler::on_config_change()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Setting {
+    Eol,
+    Sanitize,
+    ResizePolicy,
+    DetachPolicy,
+    PollAfterWrite,
+    Coalesce,
+    SyncHold,
+    Quotas,
+    Filters,
+    RetainExited,
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Setting::Eol => "eol",
+            Setting::Sanitize => "sanitize",
+            Setting::ResizePolicy => "resize_policy",
+            Setting::DetachPolicy => "detach_policy",
+            Setting::PollAfterWrite => "poll_after_write",
+            Setting::Coalesce => "coalesce",
+            Setting::SyncHold => "sync_hold",
+            Setting::Quotas => "quotas",
+            Setting::Filters => "filters",
+            Setting::RetainExited => "retain_exited",
+        })
+    }
+}
+
+/// Settings to change on a live session, each like the PtyBuilder method of the same name,
+/// anything not set is left as it is
+#[derive(Debug, Default)]
+pub struct ConfigPatch {
+    eol: Option<Eol>,
+    sanitize: Option<Option<Sanitize>>,
+    resize_policy: Option<ResizePolicy>,
+    detach_policy: Option<DetachPolicy>,
+    poll_after_write: Option<bool>,
+    coalesce: Option<Option<Duration>>,
+    sync_hold: Option<Option<Duration>>,
+    quotas: Option<Vec<Quota>>,
+    filters: Option<Pipeline>,
+    retain_exited: Option<Option<Duration>>,
+}
+
+impl ConfigPatch {
+    pub fn new() -> ConfigPatch {
+        ConfigPatch::default()
+    }
+
+    pub fn eol(mut self, eol: Eol) -> ConfigPatch {
+        self.eol = Some(eol);
+        self
+    }
+
+    /// `None` writes input of attached clients as is again
+    pub fn sanitize_input(mut self, mode: Option<Sanitize>) -> ConfigPatch {
+        self.sanitize = Some(mode);
+        self
+    }
+
+    /// applies from the next resize of a client on
+    pub fn resize_policy(mut self, policy: ResizePolicy) -> ConfigPatch {
+        self.resize_policy = Some(policy);
+        self
+    }
+
+    /// applies the next time the last client detaches
+    pub fn detach_policy(mut self, policy: DetachPolicy) -> ConfigPatch {
+        self.detach_policy = Some(policy);
+        self
+    }
+
+    pub fn poll_after_write(mut self, enabled: bool) -> ConfigPatch {
+        self.poll_after_write = Some(enabled);
+        self
+    }
+
+    /// `None` stops coalescing, input held back already is written at once
+    pub fn coalesce_writes(mut self, window: Option<Duration>) -> ConfigPatch {
+        self.coalesce = Some(window);
+        self
+    }
+
+    /// `None` stops holding synchronized output, an update held already is held to the end
+    pub fn hold_synchronized_output(mut self, max_hold: Option<Duration>) -> ConfigPatch {
+        self.sync_hold = Some(max_hold);
+        self
+    }
+
+    /// the quotas of the patch replace all quotas of the session, exceeded ones are reported again
+    pub fn quota(mut self, resource: QuotaResource, limit: u64, policy: QuotaPolicy) -> ConfigPatch {
+        self.quotas.get_or_insert_with(Vec::new).push(Quota { resource, limit, policy });
+        self
+    }
+
+    pub fn clear_quotas(mut self) -> ConfigPatch {
+        self.quotas = Some(Vec::new());
+        self
+    }
+
+    /// the filters of the patch replace all filters of the session, from the next chunk on
+    pub fn filter(mut self, filter: impl Filter + 'static) -> ConfigPatch {
+        self.filters.get_or_insert_with(Pipeline::default).push(Box::new(filter));
+        self
+    }
+
+    pub fn clear_filters(mut self) -> ConfigPatch {
+        self.filters = Some(Pipeline::default());
+        self
+    }
+
+    /// `None` drops the session once the child is reaped
+    pub fn retain_exited(mut self, period: Option<Duration>) -> ConfigPatch {
+        self.retain_exited = Some(period);
+        self
+    }
+
+    /**
+     * Checks the patch can be applied as a whole before anything is changed
+     */
+    pub(crate) fn validate(&self) -> Result<(), Box<dyn Error>> {
+        quota::validate(self.quotas.as_deref().unwrap_or_default())
+    }
+
+    /**
+     * Applies the patch to config, returns the settings it changed
+     */
+    pub(crate) fn apply(self, config: &mut Config) -> Vec<Setting> {
+        let mut changed = Vec::new();
+        let mut set = |setting, applied: bool| if applied { changed.push(setting) };
+        set(Setting::Eol, self.eol.map(|eol| config.eol = eol).is_some());
+        set(Setting::Sanitize, self.sanitize.map(|mode| config.sanitize = mode).is_some());
+        set(Setting::ResizePolicy, self.resize_policy.map(|policy| config.resize_policy = policy).is_some());
+        set(Setting::DetachPolicy, self.detach_policy.map(|policy| config.detach_policy = policy).is_some());
+        set(Setting::PollAfterWrite, self.poll_after_write.map(|enabled| config.poll_after_write = enabled).is_some());
+        set(Setting::Coalesce, self.coalesce.map(|window| config.coalesce = window).is_some());
+        set(Setting::SyncHold, self.sync_hold.map(|max_hold| config.sync_hold = max_hold).is_some());
+        set(Setting::Quotas, self.quotas.map(|quotas| config.quotas = quotas).is_some());
+        set(Setting::Filters, self.filters.map(|filters| config.filters = filters).is_some());
+        set(Setting::RetainExited, self.retain_exited.map(|period| config.retain_exited = period).is_some());
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::filter::{self, Direction};
+    use crate::handler::PtyHandler;
+    use crate::id::PtyId;
+    use crate::{test_util, Pty};
+    use super::*;
+
+    struct Changes(Arc<Mutex<Vec<Setting>>>);
+
+    impl PtyHandler for Changes {
+        fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+        fn on_config_change(&mut self, _id: PtyId, changed: Vec<Setting>) {
+            self.0.lock().unwrap().extend(changed);
+        }
+    }
+
+    #[test]
+    fn live_update() -> Result<(), Box<dyn Error>> {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let pty = Pty::builder().scrollback(0x10000).spawn_handler(Changes(changes.clone()))?;
+
+        // invalid as a whole, the filter is not added either
+        let invalid = ConfigPatch::new()
+            .filter(filter::from_fn("shout", |_id, _direction, chunk: String| Some(chunk.to_uppercase())))
+            .quota(QuotaResource::Scrollback, 0x1000, QuotaPolicy::StopRecording);
+        assert!(pty.update_config(invalid).is_err());
+        pty.update_config(ConfigPatch::new())?;
+
+        pty.update_config(ConfigPatch::new()
+            .eol(Eol::Cr)
+            .filter(filter::from_fn("shout", |_id, direction, chunk: String| {
+                Some(if direction == Direction::Output { chunk.replace("quiet", "LOUD") } else { chunk })
+            })))?;
+        pty.write("echo \"quiet-$((1 + 1))\"\n")?;
+        test_util::wait_for_output(&pty, "LOUD-2", Duration::from_secs(10))?;
+        assert_eq!(*changes.lock().unwrap(), [Setting::Eol, Setting::Filters]);
+
+        pty.update_config(ConfigPatch::new().clear_filters())?;
+        pty.write("echo \"quiet-$((2 + 2))\"\n")?;
+        test_util::wait_for_output(&pty, "quiet-4", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! Sessions described by config, e.g. the profiles of a terminal app, read from TOML or JSON,
+//! every string in it may refer to environment variables as `${VAR}`, or `${VAR:-default}` for
+//! one that may be unset, `$$` is a literal `$`, see Pty::from_config()
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::profile::SessionConfig;
+//!
+//! let config = SessionConfig::from_toml(r#"
+//!     command = ["sh", "-c", "echo hi; exec sh"]
+//!     cwd = "${HOME}"
+//!     rows = 30
+//!     cols = 100
+//!     scrollback = 65536
+//!
+//!     [env]
+//!     EDITOR = "${EDITOR:-vi}"
+//! "#)?;
+//! let pty = Pty::from_config(&config, |_id, _res| {}, |_id| {})?;
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::builder::PtyBuilder;
+use crate::clients::{DetachPolicy, ResizePolicy};
+use crate::error::PtyError;
+use crate::input::Eol;
+use crate::unix::window::WindowSize;
+
+/// A session as described by config, anything left out is as with PtyBuilder::new()
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// program and its arguments, spawned in place of the user's shell, see
+    /// PtyBuilder::program(), the user's shell if empty
+    pub command: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    /// start with only the variables of env instead of inheriting ours
+    pub env_clear: bool,
+    pub cwd: Option<PathBuf>,
+    /// size from the start, both or neither are given
+    pub rows: Option<u16>,
+    pub cols: Option<u16>,
+    pub scrollback: Option<usize>,
+    pub eol: Option<Eol>,
+    pub resize_policy: Option<ResizePolicy>,
+    pub detach_policy: Option<DetachPolicy>,
+    pub tag: Option<String>,
+    pub shell_integration: bool,
+    /// written to the command or shell once it is ready
+    pub startup_commands: Vec<String>,
+}
+
+impl SessionConfig {
+    /// config in TOML, with environment variables interpolated
+    pub fn from_toml(s: &str) -> Result<SessionConfig, Box<dyn Error>> {
+        let value: toml::Table = toml::from_str(s).map_err(|err| invalid(format!("Invalid session config: {err}")))?;
+        SessionConfig::from_value(serde_json::to_value(value)?, &|name| std::env::var(name).ok())
+    }
+
+    /// config in JSON, with environment variables interpolated
+    pub fn from_json(s: &str) -> Result<SessionConfig, Box<dyn Error>> {
+        let value: Value = serde_json::from_str(s).map_err(|err| invalid(format!("Invalid session config: {err}")))?;
+        SessionConfig::from_value(value, &|name| std::env::var(name).ok())
+    }
+
+    /**
+     * Config from value, with the variables lookup finds interpolated
+     */
+    fn from_value(mut value: Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<SessionConfig, Box<dyn Error>> {
+        interpolate_all(&mut value, lookup)?;
+        let config: SessionConfig = serde_json::from_value(value).map_err(|err| invalid(format!("Invalid session config: {err}")))?;
+        if config.rows.is_some() != config.cols.is_some() {
+            return Err(invalid("Invalid session config: rows and cols go together".to_owned()));
+        }
+        Ok(config)
+    }
+
+    /// builder spawning the session, for settings config has nothing to say about
+    pub fn builder(&self) -> PtyBuilder {
+        let mut builder = PtyBuilder::new();
+        if self.env_clear {
+            builder = builder.env_clear();
+        }
+        for (key, value) in &self.env {
+            builder = builder.env(key, value);
+        }
+        if let Some(cwd) = &self.cwd {
+            builder = builder.cwd(cwd);
+        }
+        if let (Some(rows), Some(cols)) = (self.rows, self.cols) {
+            builder = builder.window_size(WindowSize::new(rows, cols, 0, 0));
+        }
+        if let Some(scrollback) = self.scrollback {
+            builder = builder.scrollback(scrollback);
+        }
+        if let Some(eol) = self.eol {
+            builder = builder.eol(eol);
+        }
+        if let Some(policy) = self.resize_policy {
+            builder = builder.resize_policy(policy);
+        }
+        if let Some(policy) = self.detach_policy {
+            builder = builder.detach_policy(policy);
+        }
+        if let Some(tag) = &self.tag {
+            builder = builder.tag(tag);
+        }
+        builder
+            .program(&self.command)
+            .shell_integration(self.shell_integration)
+            .startup_commands(&self.startup_commands)
+    }
+}
+
+fn invalid(message: String) -> Box<dyn Error> {
+    Box::new(PtyError::with_kind(message, io::ErrorKind::InvalidInput))
+}
+
+/**
+ * Interpolates every string in value, object keys are left alone
+ */
+fn interpolate_all(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<(), Box<dyn Error>> {
+    match value {
+        Value::String(s) => *s = interpolate(s, lookup)?,
+        Value::Array(values) => for value in values {
+            interpolate_all(value, lookup)?;
+        },
+        Value::Object(values) => for value in values.values_mut() {
+            interpolate_all(value, lookup)?;
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/**
+ * s with `${VAR}` and `${VAR:-default}` replaced by what lookup finds and `$$` by `$`
+ */
+fn interpolate(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, Box<dyn Error>> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| invalid(format!("Unterminated variable in {s:?}")))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None)
+            };
+            match lookup(name).or_else(|| default.map(str::to_owned)) {
+                Some(value) => out.push_str(&value),
+                None => return Err(invalid(format!("Undefined variable {name} in session config"))),
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::{test_util, Pty};
+    use super::*;
+
+    #[test]
+    fn interpolated_config() -> Result<(), Box<dyn Error>> {
+        let lookup = |name: &str| (name == "WHO").then(|| "world".to_owned());
+        assert_eq!(interpolate("hi ${WHO}, $$HOME ${NOPE:-x}$", &lookup)?, "hi world, $HOME x$");
+        assert!(interpolate("${NOPE}", &lookup).is_err());
+        assert!(interpolate("${WHO", &lookup).is_err());
+
+        let json = serde_json::json!({ "command": ["echo", "${WHO}"], "env": { "GREETING": "hi ${WHO}" }, "rows": 30 });
+        assert!(SessionConfig::from_value(json, &lookup).is_err());
+        let json = serde_json::json!({ "command": ["echo", "${WHO}"], "env": { "GREETING": "hi ${WHO}" } });
+        let config = SessionConfig::from_value(json, &lookup)?;
+        assert_eq!(config.command, ["echo", "world"]);
+        assert_eq!(config.env["GREETING"], "hi world");
+        assert!(SessionConfig::from_json(r#"{ "comand": [] }"#).is_err());
+
+        let config = SessionConfig::from_toml(r#"
+            command = ["sh", "-c", "echo \"$GREETING-$((1 + 1)) it's $0\"; exec sh"]
+            tag = "profile"
+            scrollback = 65536
+            env = { GREETING = "hi" }
+            eol = "Cr"
+        "#)?;
+        let pty = Pty::from_config(&config, |_id, _res| {}, |_id| {})?;
+        test_util::wait_for_output(&pty, "hi-2 it's sh", Duration::from_secs(10))?;
+        assert_eq!(pty.tag()?.as_deref(), Some("profile"));
+        pty.shutdown()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+//! Wire format of the attach protocol spoken by server::Server and client::Client
+//! every frame is a type byte, a big endian u32 payload length and the payload,
+//! a connection starts with the client sending Attach or Resume, optionally preceded by Auth
+//! and Compress, and the server answering Attached or Error if the client is denied, a
+//! Compress of the client is answered with a Compress of the server before Attached once the
+//! client is authenticated, a client asking for ScreenDiffs after it gets Screen frames in
+//! place of Output, with the `vt` feature
+//! ```rust
+//! use pty_exec::protocol::{read_frame, write_frame, Frame};
+//!
+//! let mut buf = Vec::new();
+//! write_frame(&mut buf, &Frame::Input("ls\r".into()))?;
+//! assert_eq!(read_frame(&mut buf.as_slice())?, Some(Frame::Input("ls\r".into())));
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::io::{self, Read, Write};
+use crate::compress::Codec;
+use crate::message::Message;
+use crate::unix::window::WindowSize;
+#[cfg(feature = "vt")]
+use crate::vt::{Position, ScreenDiff};
+
+// frames larger than this are rejected, it bounds what a peer can make the other side allocate
+pub(crate) const MAX_PAYLOAD: usize = 0x100_0000;
+
+/// A message of the attach protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Frame {
+    /// client to server, a token for the server's auth::Authenticator, sent before Attach
+    Auth(String),
+    /// client to server, attach to the named session, it is spawned if it does not exist
+    Attach { session: String },
+    /// client to server, attach to the named session and replay the output since cursor,
+    /// fails if the session does not exist
+    Resume { session: String, cursor: u64 },
+    /// server to client, the client is attached and the Output frames that follow start at
+    /// cursor, missed bytes after the cursor of a Resume were no longer in the scrollback
+    Attached { session: String, cursor: u64, missed: u64 },
+    /// client to server, input for the session
+    Input(String),
+    /// client to server, input the client shows ahead of its echo, numbered by seq, see
+    /// Pty::write_predicted()
+    PredictedInput { seq: u64, input: String },
+    /// server to client, what became of the PredictedInput numbered seq, sent right after the
+    /// Output holding its echo if echoed
+    Echo { seq: u64, echoed: bool },
+    /// server to client, output of the session
+    Output(String),
+    /// client to server, the client's window has this size
+    Resize(WindowSize),
+    /// server to client, the session now has rows and cols
+    Resized(u16, u16),
+    /// client to server, send this signal to the session's process group
+    Signal(i32),
+    /// client to server, detach without ending the session
+    Detach,
+    /// server to client, the session died, the server closes the connection
+    Exited,
+    /// either way, answered with Pong
+    Ping,
+    Pong,
+    /// server to client, a request of the client failed
+    Error(String),
+    /// client to server, the codecs the client can decompress, sent before Attach,
+    /// server to client, the codec chosen, if any, see the compress module
+    Compress(Vec<Codec>),
+    /// server to client, the text of an Output frame compressed with the chosen codec
+    Compressed(Vec<u8>),
+    /// either way, a message not written to the pty, see the message module
+    Message(Message),
+    /// client to server, send Screen frames in place of Output, sent before Attach
+    #[cfg(feature = "vt")]
+    ScreenDiffs,
+    /// server to client, the rows of the screen that changed, the first after Attached has
+    /// every row, see vt::ScreenMirror
+    #[cfg(feature = "vt")]
+    Screen(ScreenDiff),
+}
+
+impl Frame {
+    fn code(&self) -> u8 {
+        match self {
+            Frame::Attach { .. } => 1,
+            Frame::Attached { .. } => 2,
+            Frame::Input(_) => 3,
+            Frame::Output(_) => 4,
+            Frame::Resize(_) => 5,
+            Frame::Resized(..) => 6,
+            Frame::Signal(_) => 7,
+            Frame::Detach => 8,
+            Frame::Exited => 9,
+            Frame::Ping => 10,
+            Frame::Pong => 11,
+            Frame::Error(_) => 12,
+            Frame::Resume { .. } => 13,
+            Frame::Auth(_) => 14,
+            Frame::Compress(_) => 15,
+            Frame::Compressed(_) => 16,
+            Frame::Message(_) => 19,
+            Frame::PredictedInput { .. } => 20,
+            Frame::Echo { .. } => 21,
+            #[cfg(feature = "vt")]
+            Frame::ScreenDiffs => 17,
+            #[cfg(feature = "vt")]
+            Frame::Screen(_) => 18,
+        }
+    }
+}
+
+/// Writes frame to w, w is flushed
+pub fn write_frame(w: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    let payload = match frame {
+        Frame::Attach { session } => session.as_bytes().to_vec(),
+        Frame::Attached { session, cursor, missed } => [&cursor.to_be_bytes(), &missed.to_be_bytes(), session.as_bytes()].concat(),
+        Frame::Resume { session, cursor } => [&cursor.to_be_bytes()[..], session.as_bytes()].concat(),
+        Frame::Input(s) | Frame::Output(s) | Frame::Error(s) | Frame::Auth(s) => s.as_bytes().to_vec(),
+        Frame::PredictedInput { seq, input } => [&seq.to_be_bytes()[..], input.as_bytes()].concat(),
+        Frame::Echo { seq, echoed } => [&seq.to_be_bytes()[..], &[*echoed as u8]].concat(),
+        Frame::Resize(size) => [size.rows(), size.cols(), size.cell_width(), size.cell_height()]
+            .iter().flat_map(|n| n.to_be_bytes()).collect(),
+        Frame::Resized(rows, cols) => [rows.to_be_bytes(), cols.to_be_bytes()].concat(),
+        Frame::Signal(signal) => signal.to_be_bytes().to_vec(),
+        Frame::Detach | Frame::Exited | Frame::Ping | Frame::Pong => Vec::new(),
+        Frame::Compress(codecs) => codecs.iter().map(|codec| codec.code()).collect(),
+        Frame::Compressed(data) => data.clone(),
+        Frame::Message(message) => match message {
+            Message::ClipboardRequest => vec![1],
+            Message::Clipboard(text) => [&[2], text.as_bytes()].concat(),
+            Message::OpenUrl(url) => [&[3], url.as_bytes()].concat(),
+            Message::Custom { kind, data } => [&[4][..], &(kind.len() as u32).to_be_bytes(), kind.as_bytes(), data].concat(),
+        },
+        #[cfg(feature = "vt")]
+        Frame::ScreenDiffs => Vec::new(),
+        #[cfg(feature = "vt")]
+        Frame::Screen(diff) => {
+            let mut payload: Vec<u8> = [diff.rows, diff.cols, diff.cursor.row, diff.cursor.col]
+                .iter().flat_map(|n| n.to_be_bytes()).collect();
+            for (row, line) in &diff.lines {
+                payload.extend(row.to_be_bytes());
+                payload.extend((line.len() as u32).to_be_bytes());
+                payload.extend(line.as_bytes());
+            }
+            payload
+        },
+    };
+    if payload.len() > MAX_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame too large"));
+    }
+
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.push(frame.code());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&payload);
+    w.write_all(&buf)?;
+    w.flush()
+}
+
+/// Reads the next frame from r, `None` if r ended cleanly between two frames
+pub fn read_frame(r: &mut impl Read) -> io::Result<Option<Frame>> {
+    read_frame_max(r, MAX_PAYLOAD)
+}
+
+/**
+ * read_frame() rejecting payloads larger than max, e.g. of a peer that is not authenticated yet
+ */
+pub(crate) fn read_frame_max(r: &mut impl Read, max: usize) -> io::Result<Option<Frame>> {
+    let mut header = [0; 5];
+    match r.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => r.read_exact(&mut header[1..])?,
+    }
+
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > max.min(MAX_PAYLOAD) {
+        return Err(invalid("Frame too large"));
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+
+    let text = |payload: Vec<u8>| String::from_utf8(payload).map_err(|_| invalid("Frame is not valid UTF-8"));
+    let u16_at = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+    let u64_at = |i: usize| u64::from_be_bytes(payload[i..i + 8].try_into().unwrap());
+
+    let frame = match (header[0], len) {
+        (1, _) => Frame::Attach { session: text(payload)? },
+        (2, 16..) => Frame::Attached { cursor: u64_at(0), missed: u64_at(8), session: text(payload[16..].to_vec())? },
+        (3, _) => Frame::Input(text(payload)?),
+        (4, _) => Frame::Output(text(payload)?),
+        (5, 8) => Frame::Resize(WindowSize::new(u16_at(0), u16_at(2), u16_at(4), u16_at(6))),
+        (6, 4) => Frame::Resized(u16_at(0), u16_at(2)),
+        (7, 4) => Frame::Signal(i32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])),
+        (8, 0) => Frame::Detach,
+        (9, 0) => Frame::Exited,
+        (10, 0) => Frame::Ping,
+        (11, 0) => Frame::Pong,
+        (12, _) => Frame::Error(text(payload)?),
+        (13, 8..) => Frame::Resume { cursor: u64_at(0), session: text(payload[8..].to_vec())? },
+        (14, _) => Frame::Auth(text(payload)?),
+        // codecs of newer peers are skipped, they cannot be chosen anyway
+        (15, _) => Frame::Compress(payload.iter().filter_map(|&code| Codec::from_code(code)).collect()),
+        (16, _) => Frame::Compressed(payload),
+        (19, _) => Frame::Message(message(&payload)?),
+        (20, 8..) => Frame::PredictedInput { seq: u64_at(0), input: text(payload[8..].to_vec())? },
+        (21, 9) => Frame::Echo { seq: u64_at(0), echoed: payload[8] != 0 },
+        #[cfg(feature = "vt")]
+        (17, 0) => Frame::ScreenDiffs,
+        #[cfg(feature = "vt")]
+        (18, 8..) => Frame::Screen(screen_diff(&payload)?),
+        (code, len) => return Err(invalid(&format!("Invalid frame type {code} of length {len}"))),
+    };
+    Ok(Some(frame))
+}
+
+/**
+ * The payload of a Screen frame, the size and cursor followed by the rows, each its number,
+ * the length of its text and the text
+ */
+#[cfg(feature = "vt")]
+fn screen_diff(payload: &[u8]) -> io::Result<ScreenDiff> {
+    let u16_at = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+    let mut diff = ScreenDiff {
+        rows: u16_at(0),
+        cols: u16_at(2),
+        cursor: Position { row: u16_at(4), col: u16_at(6) },
+        lines: Vec::new(),
+    };
+    let mut rest = &payload[8..];
+    while !rest.is_empty() {
+        if rest.len() < 6 {
+            return Err(invalid("Screen frame cut short"));
+        }
+        let row = u16::from_be_bytes([rest[0], rest[1]]);
+        let len = u32::from_be_bytes(rest[2..6].try_into().unwrap()) as usize;
+        let Some(line) = rest.get(6..6 + len) else {
+            return Err(invalid("Screen frame cut short"));
+        };
+        let line = String::from_utf8(line.to_vec()).map_err(|_| invalid("Frame is not valid UTF-8"))?;
+        diff.lines.push((row, line));
+        rest = &rest[6 + len..];
+    }
+    Ok(diff)
+}
+
+/**
+ * The payload of a Message frame, the type of the message followed by its text, the kind of a
+ * custom message is preceded by its length
+ */
+fn message(payload: &[u8]) -> io::Result<Message> {
+    let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| invalid("Frame is not valid UTF-8"));
+    let message = match payload {
+        [1] => Message::ClipboardRequest,
+        [2, clipboard @ ..] => Message::Clipboard(text(clipboard)?),
+        [3, url @ ..] => Message::OpenUrl(text(url)?),
+        [4, rest @ ..] if rest.len() >= 4 => {
+            let len = u32::from_be_bytes(payload[1..5].try_into().unwrap()) as usize;
+            let Some(kind) = payload.get(5..5 + len) else {
+                return Err(invalid("Message frame cut short"));
+            };
+            Message::Custom { kind: text(kind)?, data: payload[5 + len..].to_vec() }
+        },
+        _ => return Err(invalid("Invalid message")),
+    };
+    Ok(message)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> io::Result<()> {
+        let frames = vec![
+            Frame::Attach { session: "main".into() },
+            Frame::Attached { session: "main".into(), cursor: 1 << 40, missed: 7 },
+            Frame::Resume { session: "main".into(), cursor: 42 },
+            Frame::Auth("secret".into()),
+            Frame::Input("é\r".into()),
+            Frame::PredictedInput { seq: 3, input: "ls".into() },
+            Frame::Echo { seq: 3, echoed: true },
+            Frame::Resize(WindowSize::new(24, 80, 8, 16)),
+            Frame::Resized(24, 80),
+            Frame::Signal(15),
+            Frame::Compress(vec![Codec::Zstd, Codec::Deflate]),
+            Frame::Compressed(vec![0, 0xff]),
+            Frame::Message(Message::ClipboardRequest),
+            Frame::Message(Message::OpenUrl("https://example.com".into())),
+            Frame::Message(Message::Custom { kind: "app/ping".into(), data: vec![0, 1] }),
+            Frame::Exited,
+        ];
+        let mut buf = Vec::new();
+        for frame in &frames {
+            write_frame(&mut buf, frame)?;
+        }
+
+        let mut r = buf.as_slice();
+        for frame in frames {
+            assert_eq!(read_frame(&mut r)?, Some(frame));
+        }
+        assert_eq!(read_frame(&mut r)?, None);
+
+        // a truncated frame is an error, not the end of the stream
+        assert!(read_frame(&mut &buf[..3]).is_err());
+        assert!(read_frame(&mut [6, 0, 0, 0, 1, 0].as_slice()).is_err());
+        assert!(read_frame_max(&mut [12, 0, 0, 0, 2, b'n', b'o'].as_slice(), 1).is_err());
+        // a custom message whose kind is longer than the frame
+        assert!(read_frame(&mut [19, 0, 0, 0, 6, 4, 0, 0, 0, 9, b'a'].as_slice()).is_err());
+        assert_eq!(read_frame(&mut [15, 0, 0, 0, 2, 9, 1].as_slice())?, Some(Frame::Compress(vec![Codec::Deflate])));
+        Ok(())
+    }
+
+    #[cfg(feature = "vt")]
+    #[test]
+    fn screen_diffs() -> io::Result<()> {
+        let diff = ScreenDiff {
+            rows: 24,
+            cols: 80,
+            cursor: Position { row: 1, col: 3 },
+            lines: vec![(0, "$ top".into()), (1, String::new()), (23, "界".into())],
+        };
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &Frame::ScreenDiffs)?;
+        write_frame(&mut buf, &Frame::Screen(diff.clone()))?;
+        let mut r = buf.as_slice();
+        assert_eq!(read_frame(&mut r)?, Some(Frame::ScreenDiffs));
+        assert_eq!(read_frame(&mut r)?, Some(Frame::Screen(diff)));
+
+        // a row longer than the frame
+        assert!(read_frame(&mut [18, 0, 0, 0, 14, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9].as_slice()).is_err());
+        Ok(())
+    }
+}
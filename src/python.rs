@@ -0,0 +1,220 @@
+//! Python bindings, a pexpect like `pty_exec` module built as an extension module with
+//! `maturin build --features python,pyo3/extension-module`, calls waiting on the pty release
+//! the GIL, a failure raises OSError, a timeout TimeoutError and a pty exiting before the
+//! expected output EOFError
+//! ```python
+//! from pty_exec import Pty, run_command
+//!
+//! pty = Pty()
+//! pty.sendline('echo "ready-$((1 + 1))"')
+//! pty.expect("ready-2", timeout=10)
+//! pty.close()  # or left to when the Pty is collected
+//!
+//! print(run_command("ls /"))
+//! ```
+
+use std::error::Error;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use nix::sys::signal::Signal;
+use pyo3::exceptions::{PyEOFError, PyOSError, PyTimeoutError};
+use pyo3::prelude::*;
+use crate::clients::ClientEvent;
+use crate::error::PtyError;
+use crate::handler::PtyHandler;
+use crate::id::PtyId;
+use crate::registry;
+use crate::scrollback::Cursor;
+use crate::unix::window::WindowSize;
+use crate::Pty;
+
+/**
+ * Exception raised for err
+ */
+fn py_err(err: Box<dyn Error>) -> PyErr {
+    match PtyError::copy_of(err.as_ref()).kind() {
+        io::ErrorKind::TimedOut => PyTimeoutError::new_err(err.to_string()),
+        io::ErrorKind::UnexpectedEof => PyEOFError::new_err(err.to_string()),
+        _ => PyOSError::new_err(err.to_string()),
+    }
+}
+
+/// A pty running the user's shell, output is matched by expect() in the order it arrives
+#[pyclass(name = "Pty")]
+struct PyPty {
+    pty: Pty,
+    // output before it was consumed by expect()
+    cursor: Cursor,
+}
+
+#[pymethods]
+impl PyPty {
+    #[new]
+    #[pyo3(signature = (scrollback = 0x100000))]
+    fn new(scrollback: usize) -> PyResult<PyPty> {
+        let pty = Pty::builder().scrollback(scrollback).spawn(|_id, _res| {}, |_id| {}).map_err(py_err)?;
+        Ok(PyPty { pty, cursor: Cursor::new(0) })
+    }
+
+    /// write s as it is
+    fn write(&self, s: &str) -> PyResult<()> {
+        self.pty.write(s).map_err(py_err)
+    }
+
+    /// write s followed by enter
+    fn sendline(&self, s: &str) -> PyResult<()> {
+        self.pty.write(&format!("{s}\r")).map_err(py_err)
+    }
+
+    /// wait up to timeout seconds for pattern in the output not consumed yet, returns the
+    /// output up to and including the match and consumes it, pattern is plain text
+    #[pyo3(signature = (pattern, timeout = 30.0))]
+    fn expect(&mut self, py: Python<'_>, pattern: &str, timeout: f64) -> PyResult<String> {
+        let (pty, cursor) = (&self.pty, self.cursor);
+        let (output, cursor) = py.allow_threads(|| expect(pty, cursor, pattern, Duration::from_secs_f64(timeout)).map_err(py_err))?;
+        self.cursor = cursor;
+        Ok(output)
+    }
+
+    fn resize(&self, rows: u16, cols: u16) -> PyResult<()> {
+        self.pty.resize(WindowSize::new(rows, cols, 0, 0)).map_err(py_err)
+    }
+
+    fn isalive(&self) -> bool {
+        self.pty.is_alive()
+    }
+
+    /// shut the pty down, waits for the shell to exit
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        let pty = &self.pty;
+        py.allow_threads(|| pty.shutdown().map_err(py_err))
+    }
+}
+
+/// a pty that was not closed is shut down once the object is collected
+impl Drop for PyPty {
+    fn drop(&mut self) {
+        if self.pty.is_alive() {
+            let pty = &self.pty;
+            Python::with_gil(|py| py.allow_threads(|| { let _ = pty.shutdown(); }));
+        }
+    }
+}
+
+/// run command in the user's shell and return its output once it exited, the output of the
+/// shell before the command started is left out
+#[pyfunction]
+#[pyo3(signature = (command, timeout = 30.0))]
+fn run_command(py: Python<'_>, command: &str, timeout: f64) -> PyResult<String> {
+    py.allow_threads(|| run(command, Duration::from_secs_f64(timeout)).map_err(py_err))
+}
+
+#[pymodule]
+fn pty_exec(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPty>()?;
+    m.add_function(wrap_pyfunction!(run_command, m)?)?;
+    Ok(())
+}
+
+/**
+ * Waits up to timeout for pattern in the output after cursor, returns the output up to and
+ * including the match and the cursor right after it
+ */
+fn expect(pty: &Pty, cursor: Cursor, pattern: &str, timeout: Duration) -> Result<(String, Cursor), Box<dyn Error>> {
+    let deadline = Instant::now() + timeout;
+    let (tx, events) = mpsc::channel();
+    let client = pty.attach(move |event| { let _ = tx.send(event); })?;
+
+    let res = loop {
+        let since = match pty.read_since(cursor) {
+            Ok(since) => since,
+            Err(_) if !pty.is_alive() => break Err(exited(pty, pattern)),
+            Err(err) => break Err(err),
+        };
+        if let Some(start) = since.output.find(pattern) {
+            let end = start + pattern.len();
+            break Ok((since.output[..end].to_owned(), Cursor::new(cursor.offset() + since.missed + end as u64)));
+        }
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ClientEvent::Exited) | Err(RecvTimeoutError::Disconnected) => break Err(exited(pty, pattern)),
+            Ok(_) => {},
+            Err(RecvTimeoutError::Timeout) => {
+                break Err(Box::new(PtyError::with_kind(format!("{pattern:?} not found in output of {}", pty.id()), io::ErrorKind::TimedOut)));
+            }
+        }
+    };
+    let _ = pty.detach(client);
+    res
+}
+
+fn exited(pty: &Pty, pattern: &str) -> Box<dyn Error> {
+    Box::new(PtyError::with_kind(format!("{} exited before {pattern:?} was output", pty.id()), io::ErrorKind::UnexpectedEof))
+}
+
+/**
+ * Passes on where the output stood once the startup commands were written
+ */
+struct Started(mpsc::Sender<Cursor>);
+
+impl PtyHandler for Started {
+    fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+    fn on_ready(&mut self, id: PtyId) {
+        if let Ok(session) = registry::get(id) {
+            let _ = self.0.send(session.scrollback().cursor());
+        }
+    }
+}
+
+/**
+ * Runs command in place of the shell, written as a startup command so its echo is left out,
+ * returns what it output until it exited
+ */
+fn run(command: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let deadline = Instant::now() + timeout;
+    let (tx, started) = mpsc::channel();
+    let pty = Pty::builder().startup_commands([format!("exec {command}")]).spawn_handler(Started(tx))?;
+    let (events_tx, events) = mpsc::channel();
+    let (_, attached) = pty.attach_at(move |event| { let _ = events_tx.send(event); })?;
+
+    let mut output = String::new();
+    loop {
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ClientEvent::Output(s)) => output.push_str(&s),
+            Ok(ClientEvent::Resized(..) | ClientEvent::Message(_) | ClientEvent::Echo { .. }) => {},
+            Ok(ClientEvent::Exited) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = pty.signal(Signal::SIGKILL);
+                return Err(Box::new(PtyError::with_kind(format!("{command:?} did not exit"), io::ErrorKind::TimedOut)));
+            }
+        }
+    }
+
+    // whatever the shell output before running the command is left out
+    let start = started.try_recv().map_or(0, |cursor| cursor.offset().saturating_sub(attached.offset()) as usize);
+    Ok(output.get(start..).unwrap_or(&output).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expect_and_run() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        pty.write("echo \"one-$((1 + 1))\"; echo \"two-$((1 + 1))\"\r")?;
+        let (output, cursor) = expect(&pty, Cursor::new(0), "one-2", Duration::from_secs(10))?;
+        assert!(output.ends_with("one-2"));
+        // consumed output is not matched again
+        let (output, cursor) = expect(&pty, cursor, "2", Duration::from_secs(10))?;
+        assert!(output.ends_with("two-2"), "{output:?}");
+        assert!(expect(&pty, cursor, "one-2", Duration::from_millis(100)).is_err());
+        pty.shutdown()?;
+
+        // the line editor may still switch modes off on accepting the line
+        let output = run("printf 'ran-%s' \"$((1 + 1))\"", Duration::from_secs(10))?;
+        assert!(output.ends_with("ran-2") && !output.contains("printf"), "{output:?}");
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Caps on what a long running session may hold on to, see PtyBuilder::quota()
+//! each quota limits a resource and says what happens once it is exceeded, crossing the limit is
+//! reported once through PtyHandler::on_quota(), again only after usage fell back under it
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::quota::{QuotaPolicy, QuotaResource};
+//!
+//! let pty = Pty::builder()
+//!     .scrollback(0x100000)
+//!     // a runaway process filling memory faster than clients read gets killed
+//!     .quota(QuotaResource::Buffered, 0x1000000, QuotaPolicy::KillSession)
+//!     .quota(QuotaResource::Recording, 0x40000000, QuotaPolicy::StopRecording)
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::io;
+#[cfg(feature = "attach")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "attach")]
+use std::sync::Arc;
+use crate::error::PtyError;
+
+/// What a quota limits, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QuotaResource {
+    /// output retained by the scrollback
+    Scrollback,
+    /// size of the running recording, see Pty::start_recording()
+    Recording,
+    /// output held in memory, the scrollback plus output queued for clients of server::Server
+    Buffered,
+}
+
+/// What happens once a quota is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QuotaPolicy {
+    /// the oldest output of the scrollback is dropped to get back under the limit,
+    /// for Scrollback and Buffered, output queued for clients is never dropped
+    DropOldest,
+    /// the recording is stopped, for Recording
+    StopRecording,
+    /// the session's process group is sent SIGKILL, for any resource
+    KillSession,
+}
+
+/// A quota was exceeded, see PtyHandler::on_quota()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuotaEvent {
+    pub resource: QuotaResource,
+    pub limit: u64,
+    /// usage when the limit was found exceeded, before the policy was applied
+    pub used: u64,
+    pub policy: QuotaPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Quota {
+    pub resource: QuotaResource,
+    pub limit: u64,
+    pub policy: QuotaPolicy,
+}
+
+/**
+ * Fails for policies that cannot be applied to their resource
+ */
+pub(crate) fn validate(quotas: &[Quota]) -> Result<(), Box<dyn Error>> {
+    for quota in quotas {
+        let valid = match quota.policy {
+            QuotaPolicy::DropOldest => quota.resource != QuotaResource::Recording,
+            QuotaPolicy::StopRecording => quota.resource == QuotaResource::Recording,
+            QuotaPolicy::KillSession => true,
+        };
+        if !valid {
+            let msg = format!("Quota policy {:?} cannot be applied to {:?}", quota.policy, quota.resource);
+            return Err(Box::new(PtyError::with_kind(msg, io::ErrorKind::InvalidInput)));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Output held outside the scrollback, counted against QuotaResource::Buffered until dropped
+ */
+#[cfg(feature = "attach")]
+pub(crate) struct Held {
+    backlog: Arc<AtomicU64>,
+    len: u64,
+}
+
+#[cfg(feature = "attach")]
+impl Held {
+    pub(crate) fn new(backlog: &Arc<AtomicU64>, len: usize) -> Held {
+        backlog.fetch_add(len as u64, Ordering::Relaxed);
+        Held { backlog: backlog.clone(), len: len as u64 }
+    }
+}
+
+#[cfg(feature = "attach")]
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.backlog.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use crate::id::PtyId;
+    use crate::{test_util, Pty, PtyHandler};
+
+    struct Events(Arc<Mutex<Vec<QuotaEvent>>>);
+
+    impl PtyHandler for Events {
+        fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+        fn on_quota(&mut self, _id: PtyId, event: QuotaEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn quotas() -> Result<(), Box<dyn Error>> {
+        let drop_oldest = Quota { resource: QuotaResource::Scrollback, limit: 1, policy: QuotaPolicy::DropOldest };
+        assert!(validate(&[drop_oldest]).is_ok());
+        assert!(validate(&[Quota { resource: QuotaResource::Recording, ..drop_oldest }]).is_err());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let pty = Pty::builder()
+            .scrollback(0x10000)
+            .quota(QuotaResource::Scrollback, 0x100, QuotaPolicy::DropOldest)
+            .spawn_handler(Events(events.clone()))?;
+        pty.write("for i in $(seq 100); do echo \"line-$i\"; done\r")?;
+        test_util::wait_for_output(&pty, "line-100", Duration::from_secs(10))?;
+
+        assert!(pty.scrollback()?.len() <= 0x100);
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].resource, events[0].limit), (QuotaResource::Scrollback, 0x100));
+        drop(events);
+        pty.shutdown()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+//! Recording of pty sessions as [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/) files
+//! output is recorded as "o" events, input written to the pty only if asked for, as "i" events
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//! use pty_exec::recording::Recording;
+//!
+//! let path = std::env::temp_dir().join("pty-exec-doc.cast");
+//! let pty = Pty::builder()
+//!     .record(Recording::new(&path).fsync_interval(Duration::from_secs(1)))
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//! pty.shutdown()?;
+//! # std::fs::remove_file(path)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use crate::builder::SESSION_ID_ENV;
+use crate::clock::Clock;
+use crate::error::PtyError;
+use crate::unix::window::WindowSize;
+
+/// Where and how to record a pty
+#[derive(Debug, Clone)]
+pub struct Recording {
+    path: PathBuf,
+    fsync_interval: Option<Duration>,
+    append: bool,
+    input: InputRecording,
+}
+
+/// What a recording captures of the input written to the pty, see Recording::input()
+/// input counts as hidden while the tty neither echoes it nor hands it to a line editor, like
+/// at the password prompt of sudo or ssh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InputRecording {
+    /// no input
+    #[default]
+    Off,
+    /// all input as written
+    All,
+    /// all input but the hidden input
+    OmitHidden,
+    /// hidden input is replaced by a hash of it, which tells whether the same input was written
+    /// again but does not protect a short secret from being guessed
+    HashHidden,
+}
+
+impl Recording {
+    /// record to path, an existing file is overwritten unless append()
+    pub fn new(path: impl AsRef<Path>) -> Recording {
+        Recording { path: path.as_ref().to_owned(), fsync_interval: None, append: false, input: InputRecording::Off }
+    }
+
+    /// record the input written to the pty as well, as asciicast "i" events, off by default
+    pub fn input(mut self, input: InputRecording) -> Recording {
+        self.input = input;
+        self
+    }
+
+    /// fsync the recording once interval passed since the first event not synced yet, also
+    /// when no event follows it, so a crash of the host loses at most about interval worth of
+    /// events, without it events only reach the page cache
+    pub fn fsync_interval(mut self, interval: Duration) -> Recording {
+        self.fsync_interval = Some(interval);
+        self
+    }
+
+    /// resume an existing recording instead of overwriting it, e.g. of a session that outlived
+    /// the process recording it, a partially written trailing event is repaired first, new
+    /// events continue the timeline where the recording ended, starting with the current size
+    pub fn append(mut self, append: bool) -> Recording {
+        self.append = append;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Result of repair()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Repaired {
+    /// bytes cut off the end of the file
+    pub truncated: u64,
+    /// complete events left in the file
+    pub events: usize,
+}
+
+/// Repairs a recording that was cut short, e.g. when the host crashed mid-session
+/// a partially written trailing event is removed so players accept the file again,
+/// fails if not even the header survived
+pub fn repair(path: impl AsRef<Path>) -> Result<Repaired, Box<dyn Error>> {
+    let data = fs::read(path.as_ref())?;
+
+    // events are written one line at a time, so only the tail can be damaged
+    let mut len = 0;
+    let mut events = 0;
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        let complete = match line.strip_suffix(b"\n") {
+            Some(line) if len == 0 => line.starts_with(b"{") && line.ends_with(b"}"),
+            Some(line) => line.starts_with(b"[") && line.ends_with(b"]"),
+            None => false
+        };
+        if !complete { break }
+
+        events += usize::from(len != 0);
+        len += line.len();
+    }
+
+    if len == 0 {
+        return Err(Box::new(PtyError::new(format!("Recording {} has no header", path.as_ref().display()))));
+    }
+
+    let file = OpenOptions::new().write(true).open(path.as_ref())?;
+    file.set_len(len as u64)?;
+    file.sync_all()?;
+
+    Ok(Repaired { truncated: (data.len() - len) as u64, events })
+}
+
+/**
+ * Writes asciicast v2 events of a running pty
+ */
+pub(crate) struct Recorder {
+    file: BufWriter<File>,
+    started: Instant,
+    fsync_interval: Option<Duration>,
+    // when the first event not synced yet was written
+    unsynced_since: Option<Instant>,
+    // bytes in the file so far
+    written: u64,
+    input: InputRecording,
+    clock: Arc<dyn Clock>,
+}
+
+impl Recorder {
+    pub(crate) fn create(recording: &Recording, size: WindowSize, tag: Option<&str>, clock: Arc<dyn Clock>) -> Result<Recorder, Box<dyn Error>> {
+        if recording.append && fs::metadata(&recording.path).is_ok_and(|metadata| metadata.len() > 0) {
+            return Recorder::resume(recording, size, clock);
+        }
+        let mut file = BufWriter::new(File::create(&recording.path)?);
+        let timestamp = clock.system_now().duration_since(UNIX_EPOCH).map(|t| t.as_secs()).unwrap_or(0);
+
+        // a pty that was never resized reports 0x0, which players reject
+        let (width, height) = match (size.cols(), size.rows()) {
+            (0, _) | (_, 0) => (80, 24),
+            size => size
+        };
+        // the tag of the session as the child sees it
+        let env = match tag {
+            Some(tag) => format!(r#", "env": {{"{SESSION_ID_ENV}": {}}}"#, json_string(tag)),
+            None => String::new()
+        };
+        let header = format!(r#"{{"version": 2, "width": {width}, "height": {height}, "timestamp": {timestamp}{env}}}"#);
+        writeln!(file, "{header}")?;
+        file.flush()?;
+
+        Ok(Recorder {
+            file,
+            started: clock.now(),
+            fsync_interval: recording.fsync_interval,
+            unsynced_since: None,
+            written: header.len() as u64 + 1,
+            input: recording.input,
+            clock,
+        })
+    }
+
+    /**
+     * Appends to the recording at its path, the clock starts where its last event left off
+     */
+    fn resume(recording: &Recording, size: WindowSize, clock: Arc<dyn Clock>) -> Result<Recorder, Box<dyn Error>> {
+        repair(&recording.path)?;
+        let data = fs::read_to_string(&recording.path)?;
+        // [time, code, data], the header line has no time
+        let last = data.lines().skip(1).last()
+            .and_then(|event| event.strip_prefix('[')?.split(',').next()?.trim().parse::<f64>().ok())
+            .filter(|time| time.is_finite() && *time >= 0.0)
+            .unwrap_or(0.0);
+        let now = clock.now();
+        let started = now.checked_sub(Duration::from_secs_f64(last)).unwrap_or(now);
+
+        let file = OpenOptions::new().append(true).open(&recording.path)?;
+        let mut recorder = Recorder {
+            file: BufWriter::new(file),
+            started,
+            fsync_interval: recording.fsync_interval,
+            unsynced_since: None,
+            written: data.len() as u64,
+            input: recording.input,
+            clock,
+        };
+        recorder.resize(size)?;
+        Ok(recorder)
+    }
+
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub(crate) fn output(&mut self, output: &str) -> Result<(), Box<dyn Error>> {
+        self.event("o", output)
+    }
+
+    pub(crate) fn records_input(&self) -> bool {
+        self.input != InputRecording::Off
+    }
+
+    /**
+     * Input written to the pty, hidden if the tty did not echo it, see InputRecording
+     */
+    pub(crate) fn input(&mut self, input: &str, hidden: bool) -> Result<(), Box<dyn Error>> {
+        match (self.input, hidden) {
+            (InputRecording::Off, _) | (InputRecording::OmitHidden, true) => Ok(()),
+            (InputRecording::HashHidden, true) => {
+                let mut hasher = DefaultHasher::new();
+                input.hash(&mut hasher);
+                self.event("i", &format!("<hidden {:016x}>", hasher.finish()))
+            },
+            _ => self.event("i", input)
+        }
+    }
+
+    pub(crate) fn resize(&mut self, size: WindowSize) -> Result<(), Box<dyn Error>> {
+        self.event("r", &format!("{}x{}", size.cols(), size.rows()))
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> Result<(), Box<dyn Error>> {
+        let time = self.clock.elapsed(self.started).as_secs_f64();
+        let event = format!("[{time:.6}, \"{code}\", {}]\n", json_string(data));
+        self.file.write_all(event.as_bytes())?;
+        self.file.flush()?;
+        self.written += event.len() as u64;
+
+        if self.fsync_interval.is_some() {
+            self.unsynced_since.get_or_insert_with(|| self.clock.now());
+        }
+        self.sync_due()
+    }
+
+    /**
+     * How long until events not synced yet are due to be, None if there are none
+     */
+    pub(crate) fn sync_left(&self) -> Option<Duration> {
+        Some(self.fsync_interval?.saturating_sub(self.clock.elapsed(self.unsynced_since?)))
+    }
+
+    /**
+     * Syncs the events not synced yet if they are due, called by the polling thread whenever
+     * sync_left() runs out, output or not
+     */
+    pub(crate) fn sync_due(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.sync_left().is_some_and(|left| left.is_zero()) {
+            self.file.get_ref().sync_data()?;
+            self.unsynced_since = None;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.file.flush();
+        if self.fsync_interval.is_some() {
+            let _ = self.file.get_ref().sync_all();
+        }
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::SystemClock;
+    use super::*;
+
+    #[test]
+    fn repair_truncated() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-exec-repair-{}.cast", std::process::id()));
+
+        let mut recorder = Recorder::create(&Recording::new(&path), WindowSize::new(24, 80, 0, 0), Some("build-42"), Arc::new(SystemClock))?;
+        recorder.output("\x1b[1mhello\r\n")?;
+        recorder.resize(WindowSize::new(40, 120, 0, 0))?;
+        drop(recorder);
+
+        // simulate a crash in the middle of writing an event
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(b"[1.5, \"o\", \"wor")?;
+        drop(file);
+
+        assert_eq!(repair(&path)?, Repaired { truncated: 15, events: 2 });
+        let data = fs::read_to_string(&path)?;
+        assert!(data.lines().next().unwrap().ends_with(r#", "env": {"PTY_EXEC_SESSION_ID": "build-42"}}"#));
+        assert!(data.ends_with("\"r\", \"120x40\"]\n"));
+        assert!(data.contains(r#""o", "\u001b[1mhello\r\n""#));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resume() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-exec-resume-{}.cast", std::process::id()));
+        let recording = Recording::new(&path).fsync_interval(Duration::from_millis(50)).append(true);
+
+        let mut recorder = Recorder::create(&recording, WindowSize::new(24, 80, 0, 0), None, Arc::new(SystemClock))?;
+        assert_eq!(recorder.sync_left(), None);
+        recorder.output("first\r\n")?;
+        // due without another event coming
+        assert!(recorder.sync_left().is_some_and(|left| left <= Duration::from_millis(50)));
+        std::thread::sleep(Duration::from_millis(60));
+        recorder.sync_due()?;
+        assert_eq!(recorder.sync_left(), None);
+        drop(recorder);
+        OpenOptions::new().append(true).open(&path)?.write_all(b"[2.0, \"o\", \"cut")?;
+
+        let mut recorder = Recorder::create(&recording, WindowSize::new(40, 120, 0, 0), None, Arc::new(SystemClock))?;
+        recorder.output("second\r\n")?;
+        drop(recorder);
+        let data = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines.len(), 4, "{data:?}");
+        assert!(lines[1].ends_with(r#""o", "first\r\n"]"#) && lines[2].ends_with(r#""r", "120x40"]"#));
+        assert!(lines[3].ends_with(r#""o", "second\r\n"]"#) && !data.contains("cut"));
+        let time = |line: &str| line[1..].split(',').next().unwrap().parse::<f64>().unwrap();
+        assert!(time(lines[1]) <= time(lines[2]) && time(lines[2]) <= time(lines[3]));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn input() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("pty-exec-input-{}.cast", std::process::id()));
+        for (mode, hashed) in [(InputRecording::OmitHidden, false), (InputRecording::HashHidden, true)] {
+            let mut recorder = Recorder::create(&Recording::new(&path).input(mode), WindowSize::new(24, 80, 0, 0), None, Arc::new(SystemClock))?;
+            recorder.input("sudo ls\r", false)?;
+            recorder.input("hunter2\r", true)?;
+            drop(recorder);
+
+            let data = fs::read_to_string(&path)?;
+            let lines: Vec<&str> = data.lines().collect();
+            assert!(lines[1].ends_with(r#""i", "sudo ls\r"]"#));
+            assert!(!data.contains("hunter2"));
+            assert_eq!(lines.get(2).is_some_and(|line| line.contains(r#""i", "<hidden "#)), hashed);
+        }
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1046 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, MutexGuard, PoisonError, RwLock, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use nix::sys::signal::Signal;
+use nix::unistd::{self, Pid};
+use crate::audit::{self, AuditAction};
+use crate::builder::{Config, StdioMode};
+use crate::cancel::CancellationToken;
+use crate::clients::{ClientId, Clients};
+use crate::echo::Echo;
+use crate::error::PtyError;
+use crate::filter::Direction;
+use crate::history::History;
+use crate::id::PtyId;
+use crate::limit::Slot;
+use crate::lock::{Contention, InputLock};
+use crate::macros::{Macro, MacroRecorder};
+use crate::message::Message;
+use crate::metrics;
+#[cfg(feature = "migrate")]
+use crate::migrate::Handoff;
+use crate::patch::{ConfigPatch, Setting};
+use crate::quota::{QuotaEvent, QuotaPolicy, QuotaResource};
+use crate::recording::{Recorder, Recording};
+use crate::retention::{self, ExitStatus};
+use crate::scrollback::Scrollback;
+use crate::shutdown::ShutdownProgress;
+#[cfg(feature = "triggers")]
+use crate::trigger::Triggers;
+use crate::unix;
+use crate::unix::window::WindowSize;
+#[cfg(feature = "vt")]
+use crate::vt::Screen;
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+// tells the guards of Pty::lock_input() apart
+static NEXT_LOCK: AtomicU64 = AtomicU64::new(1);
+static SESSIONS: LazyLock<Mutex<HashMap<RawFd, Arc<Session>>>> = LazyLock::new(Default::default);
+// sessions closed by their polling thread whose child has not been reaped and reported yet
+static EXITING: LazyLock<Mutex<HashMap<PtyId, Arc<Session>>>> = LazyLock::new(Default::default);
+
+// coalesced input is written once this much is held back
+const MAX_COALESCED: usize = 0x1000;
+
+// the answer to Pty::request_status() is no longer looked for after, the tty gives none while
+// it does not handle signal characters
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// written at once even if writes are coalesced, ^C, ^D, ^Z and ^\ the tty turns into signals or EOF
+const URGENT: [char; 4] = ['\x03', '\x04', '\x1a', '\x1c'];
+
+/**
+ * Live state of a spawned pty, shared between handles and the polling thread
+ */
+pub(crate) struct Session {
+    id: PtyId,
+    child: Pid,
+    shell: String,
+    // replaced as a whole by Pty::update_config(), readers keep the one they started with
+    config: RwLock<Arc<Config>>,
+    // `false` once the fd is closed, held for reading while the fd is in use so the
+    // fd cannot be closed (and reused by the kernel) underneath a writer
+    open: RwLock<bool>,
+    // parent end of the stdin pipe, `None` once closed or if stdin is the pty
+    stdin: Mutex<Option<RawFd>>,
+    // `true` once the child has been reaped
+    exited: Mutex<bool>,
+    exited_cond: Condvar,
+    // called once the child has been reaped, see on_exited()
+    exit_hooks: Mutex<Vec<ExitHook>>,
+    // notices queued for the polling thread, which is woken through the wake pipe
+    notices: Mutex<Vec<Notice>>,
+    // set after a write when the polling thread should busy poll for the echo
+    poked: AtomicBool,
+    // when a status line was asked for with Pty::request_status(), until it is seen
+    status_expected: Mutex<Option<Instant>>,
+    // the child's output was stopped by Pty::flow() or a STOP character written to it
+    output_stopped: AtomicBool,
+    wake: (RawFd, RawFd),
+    // input held back to be written along with what follows, and since when
+    coalesced: Mutex<Option<(String, Instant)>>,
+    scrollback: Mutex<Scrollback>,
+    recorder: Mutex<Option<Recorder>>,
+    // input written since Pty::start_macro()
+    macro_recorder: Mutex<Option<MacroRecorder>>,
+    clients: Mutex<Clients>,
+    history: Mutex<History>,
+    titles: Mutex<Titles>,
+    #[cfg(feature = "vt")]
+    screen: Mutex<Screen>,
+    // working directory last reported by the shell or polled, see set_cwd()
+    cwd: Mutex<Option<PathBuf>>,
+    // how the child ended, once it was reaped
+    exit_status: Mutex<Option<ExitStatus>>,
+    // echo of injected input still to be taken out of the output
+    echo: Mutex<Echo>,
+    // held while input is filtered and written, see writing()
+    writing: Mutex<()>,
+    // the holder of the input, see Pty::lock_input()
+    input_lock: Mutex<Option<InputLock>>,
+    input_unlocked: Condvar,
+    // output queued for clients of a server, see quota::Held
+    backlog: Arc<AtomicU64>,
+    // whether each quota of the config is currently exceeded
+    quotas_hit: Mutex<Vec<bool>>,
+    #[cfg(feature = "triggers")]
+    triggers: Mutex<Triggers>,
+    // the polling thread, set once it is started
+    reader: Mutex<Option<JoinHandle<()>>>,
+    // since when the polling thread is handling what it polled, None while it waits in poll
+    busy_since: Mutex<Option<Instant>>,
+    // when output was last read or input written
+    last_io: Mutex<Instant>,
+    // facts about the spawn, see Pty::info()
+    spawned_at: SystemTime,
+    slave_path: Option<PathBuf>,
+    initial_size: WindowSize,
+    // counts against limit::set_max_sessions() until closed
+    slot: Mutex<Option<Slot>>,
+    // the last step of Pty::shutdown() taken
+    shutdown: Mutex<Option<ShutdownProgress>>,
+}
+
+pub(crate) type ExitHook = Box<dyn FnOnce(PtyId) + Send>;
+
+/**
+ * Something that happened outside the polling thread that its handler must hear about
+ */
+pub(crate) enum Notice {
+    Resized(WindowSize),
+    // the fallback title, now the title of the session
+    Title(String),
+    ConfigChanged(Vec<Setting>),
+    Shutdown(ShutdownProgress),
+    Error(PtyError),
+    // to every client
+    Message(Message),
+    ClientMessage(ClientId, Message),
+    // a prediction of a client that no echo is coming for
+    EchoRejected(ClientId, u64),
+    #[cfg(feature = "migrate")]
+    Migrate(Handoff),
+}
+
+/**
+ * Title and icon name the child set, and the title an embedder set for when the child sets none
+ */
+#[derive(Default)]
+pub(crate) struct Titles {
+    pub title: Option<String>,
+    pub icon_name: Option<String>,
+    pub fallback: Option<String>,
+}
+
+impl Titles {
+    /**
+     * Keeps the title the child set, an empty one hands it back to the fallback, returns the
+     * title of the session now
+     */
+    pub(crate) fn set(&mut self, title: String) -> String {
+        self.title = (!title.is_empty()).then_some(title);
+        self.current().unwrap_or_default()
+    }
+
+    pub(crate) fn current(&self) -> Option<String> {
+        self.title.clone().or_else(|| self.fallback.clone())
+    }
+}
+
+impl Session {
+    pub(crate) fn id(&self) -> PtyId {
+        self.id
+    }
+
+    pub(crate) fn child(&self) -> Pid {
+        self.child
+    }
+
+    pub(crate) fn shell(&self) -> &str {
+        &self.shell
+    }
+
+    pub(crate) fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /**
+     * Applies patch to the config, returns the settings it changed
+     */
+    pub(crate) fn update_config(&self, patch: ConfigPatch) -> Result<Vec<Setting>, Box<dyn Error>> {
+        patch.validate()?;
+        // quotas are enforced under the same lock, their state goes along with them
+        let mut hit = self.quotas_hit.lock().unwrap();
+        let mut config = self.config.write().unwrap_or_else(PoisonError::into_inner);
+        let mut updated = Config::clone(&config);
+        let changed = patch.apply(&mut updated);
+        if updated.quotas != config.quotas {
+            *hit = vec![false; updated.quotas.len()];
+        }
+        *config = Arc::new(updated);
+        Ok(changed)
+    }
+
+    pub(crate) fn spawned_at(&self) -> SystemTime {
+        self.spawned_at
+    }
+
+    pub(crate) fn slave_path(&self) -> Option<&Path> {
+        self.slave_path.as_deref()
+    }
+
+    pub(crate) fn initial_size(&self) -> WindowSize {
+        self.initial_size
+    }
+
+    pub(crate) fn scrollback(&self) -> MutexGuard<'_, Scrollback> {
+        self.scrollback.lock().unwrap()
+    }
+
+    pub(crate) fn clients(&self) -> MutexGuard<'_, Clients> {
+        self.clients.lock().unwrap()
+    }
+
+    pub(crate) fn history(&self) -> MutexGuard<'_, History> {
+        self.history.lock().unwrap()
+    }
+
+    pub(crate) fn titles(&self) -> MutexGuard<'_, Titles> {
+        self.titles.lock().unwrap()
+    }
+
+    #[cfg(feature = "vt")]
+    pub(crate) fn screen(&self) -> MutexGuard<'_, Screen> {
+        self.screen.lock().unwrap()
+    }
+
+    pub(crate) fn cwd(&self) -> Option<PathBuf> {
+        self.cwd.lock().unwrap().clone()
+    }
+
+    /**
+     * Records the working directory of the shell however it was learned, returns whether it
+     * changed
+     */
+    pub(crate) fn set_cwd(&self, cwd: &Path) -> bool {
+        let mut current = self.cwd.lock().unwrap();
+        if current.as_deref() == Some(cwd) {
+            return false;
+        }
+        *current = Some(cwd.to_owned());
+        true
+    }
+
+    pub(crate) fn exit_status(&self) -> Option<ExitStatus> {
+        *self.exit_status.lock().unwrap()
+    }
+
+    pub(crate) fn set_exit_status(&self, status: ExitStatus) {
+        *self.exit_status.lock().unwrap() = Some(status);
+    }
+
+    pub(crate) fn echo(&self) -> MutexGuard<'_, Echo> {
+        self.echo.lock().unwrap()
+    }
+
+    #[cfg(feature = "triggers")]
+    pub(crate) fn triggers(&self) -> MutexGuard<'_, Triggers> {
+        self.triggers.lock().unwrap()
+    }
+
+    /**
+     * Runs f with the master fd, fails if the session has already been closed
+     */
+    pub(crate) fn with_fd<T, F>(&self, f: F) -> Result<T, Box<dyn Error>>
+        where F: FnOnce(RawFd) -> Result<T, Box<dyn Error>>
+    {
+        let open = self.open.read().unwrap();
+        if !*open {
+            return Err(Box::new(PtyError::with_kind(format!("Stale pty handle: {}", self.id), io::ErrorKind::NotConnected)));
+        }
+        f(self.id.fd())
+    }
+
+    /**
+     * Runs f with the fd feeding the child's stdin, the stdin pipe if piped otherwise master
+     */
+    pub(crate) fn with_input_fd<T, F>(&self, f: F) -> Result<T, Box<dyn Error>>
+        where F: FnOnce(RawFd) -> Result<T, Box<dyn Error>>
+    {
+        let res = self.with_fd(|master| match self.config().stdin {
+            StdioMode::Pty => f(master),
+            StdioMode::Piped => match *self.stdin.lock().unwrap() {
+                Some(stdin) => f(stdin),
+                None => Err(Box::new(PtyError::with_kind(format!("Stdin of {} is closed", self.id), io::ErrorKind::BrokenPipe)))
+            }
+        });
+        if res.is_ok() {
+            self.touch();
+        }
+        res
+    }
+
+    /**
+     * Writes input through the filters of the session, the echo of injected input is taken
+     * out of the output, see Pty::inject()
+     */
+    pub(crate) fn write_input(&self, s: &str, injected: bool) -> Result<(), Box<dyn Error>> {
+        let _writing = self.writing_as(None)?;
+        self.write_held(s, injected)
+    }
+
+    /**
+     * write_input() by the holder of the input lock with token, see InputGuard::write()
+     */
+    pub(crate) fn write_locked(&self, token: u64, s: &str) -> Result<(), Box<dyn Error>> {
+        let _writing = self.writing_as(Some(token))?;
+        self.write_held(s, false)
+    }
+
+    /**
+     * The lock of writing() for the holder of token, see wait_input_lock(), the input lock is
+     * checked again once writing() is held, in case it was taken in between
+     */
+    pub(crate) fn writing_as(&self, token: Option<u64>) -> Result<MutexGuard<'_, ()>, Box<dyn Error>> {
+        loop {
+            self.wait_input_lock(token)?;
+            let writing = self.writing();
+            if self.check_input_lock(token).is_ok() {
+                return Ok(writing);
+            }
+        }
+    }
+
+    /**
+     * Locks the input for owner, returns the token of the lock, fails if it is locked already,
+     * a write under way is finished first, nothing else is written once this returns
+     */
+    pub(crate) fn lock_input(&self, owner: &str, contention: Contention) -> Result<u64, Box<dyn Error>> {
+        let _writing = self.writing();
+        let mut lock = self.input_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(held) = &*lock {
+            return Err(self.input_locked(&held.owner));
+        }
+        let token = NEXT_LOCK.fetch_add(1, Ordering::Relaxed);
+        *lock = Some(InputLock { owner: owner.to_owned(), token, contention });
+        Ok(token)
+    }
+
+    pub(crate) fn unlock_input(&self, token: u64) {
+        let mut lock = self.input_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if lock.as_ref().is_some_and(|held| held.token == token) {
+            *lock = None;
+            self.input_unlocked.notify_all();
+        }
+    }
+
+    /**
+     * Returns once the input may be written by the holder of token, None for anyone but the
+     * holder of the lock, fails or waits while someone else holds it, see Contention
+     */
+    pub(crate) fn wait_input_lock(&self, token: Option<u64>) -> Result<(), Box<dyn Error>> {
+        let mut lock = self.input_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            match &*lock {
+                None => return Ok(()),
+                Some(held) if Some(held.token) == token => return Ok(()),
+                Some(held) if held.contention == Contention::Queue && !self.on_reader_thread() => {
+                    if !*self.open.read().unwrap() {
+                        return Err(Box::new(PtyError::with_kind(format!("Stale pty handle: {}", self.id), io::ErrorKind::NotConnected)));
+                    }
+                    // the guard of a session closing may never be dropped, the wait checks now and then
+                    lock = self.input_unlocked.wait_timeout(lock, Duration::from_millis(100)).unwrap_or_else(PoisonError::into_inner).0;
+                },
+                Some(held) => return Err(self.input_locked(&held.owner)),
+            }
+        }
+    }
+
+    /**
+     * Runs write unless the input is locked, the lock cannot be taken while it runs, for the
+     * polling thread, which writes without the lock of writing()
+     */
+    pub(crate) fn unless_input_locked<T>(&self, write: impl FnOnce() -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+        let lock = self.input_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(held) = &*lock {
+            return Err(self.input_locked(&held.owner));
+        }
+        write()
+    }
+
+    fn input_locked(&self, owner: &str) -> Box<dyn Error> {
+        Box::new(PtyError::with_kind(format!("Input of {} is locked by {owner}", self.id), io::ErrorKind::ResourceBusy))
+    }
+
+    /**
+     * wait_input_lock() failing rather than waiting, for a caller holding the lock of writing()
+     */
+    pub(crate) fn check_input_lock(&self, token: Option<u64>) -> Result<(), Box<dyn Error>> {
+        match &*self.input_lock.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(held) if Some(held.token) != token => {
+                Err(self.input_locked(&held.owner))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * write_input() for a caller already holding the lock of writing()
+     */
+    pub(crate) fn write_held(&self, s: &str, injected: bool) -> Result<(), Box<dyn Error>> {
+        let filters = &self.config().filters;
+        let s = match filters.is_empty() {
+            true => s.to_owned(),
+            false => match filters.run(self.id, Direction::Input, s.to_owned()) {
+                Some(s) => s,
+                None => return Ok(())
+            }
+        };
+        #[cfg(feature = "chaos")]
+        if self.config().chaos.as_ref().is_some_and(|chaos| chaos.drops_write()) {
+            return Ok(());
+        }
+        if injected {
+            // before the write, the echo may be read before it returns
+            self.echo().expect(&s);
+        }
+        let recorded = self.recorder.lock().unwrap().as_ref().is_some_and(Recorder::records_input);
+        let in_macro = !injected && self.macro_recorder.lock().unwrap().is_some();
+        // the last of ^S and ^Q decides, with IXON on as it is by default
+        if let Some(c) = s.chars().rev().find(|c| matches!(c, '\x11' | '\x13')) {
+            self.set_output_stopped(c == '\x13');
+        }
+        let started = Instant::now();
+        let hidden = self.with_input_fd(|fd| {
+            if self.send_input(fd, &s)? && self.config().poll_after_write {
+                self.poke();
+            }
+            // a pipe has no echo to turn off
+            Ok((recorded || in_macro) && self.config().stdin == StdioMode::Pty && unix::pty::input_hidden(fd))
+        })?;
+        metrics::write(started.elapsed());
+        metrics::written(s.len());
+        if in_macro && !hidden {
+            if let Some(recorder) = self.macro_recorder.lock().unwrap().as_mut() {
+                recorder.input(&s, self.config().clock.now());
+            }
+        }
+        if recorded {
+            // the input was written, a failing recording is only reported
+            if let Err(err) = self.record(|recorder| recorder.input(&s, hidden)) {
+                let _ = self.notify(Notice::Error(PtyError::copy_of(err.as_ref())));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes input to fd, or holds it back to go with the input following it within the window
+     * of PtyBuilder::coalesce_writes(), returns whether anything was written
+     */
+    fn send_input(&self, fd: RawFd, s: &str) -> Result<bool, Box<dyn Error>> {
+        let config = self.config();
+        let Some(_) = config.coalesce else {
+            unix::pty::write(fd, s.as_bytes())?;
+            return Ok(true);
+        };
+        let mut coalesced = self.coalesced.lock().unwrap();
+        let first = coalesced.is_none();
+        let (held, _) = coalesced.get_or_insert_with(|| (String::new(), config.clock.now()));
+        held.push_str(s);
+        if held.len() >= MAX_COALESCED || s.contains(URGENT) {
+            let (held, _) = coalesced.take().unwrap();
+            self.write_coalesced(fd, &held)?;
+            return Ok(true);
+        }
+        if first {
+            // the polling thread flushes it once the window is over
+            let _ = unistd::write(self.wake.1, &[0]);
+        }
+        Ok(false)
+    }
+
+    /**
+     * How long until input held back by send_input() is due, None if there is none, input held
+     * back before coalescing was turned off is due at once
+     */
+    pub(crate) fn coalesced_left(&self) -> Option<Duration> {
+        let since = self.coalesced.lock().unwrap().as_ref()?.1;
+        let config = self.config();
+        Some(config.coalesce.unwrap_or_default().saturating_sub(config.clock.elapsed(since)))
+    }
+
+    /**
+     * Writes input held back by send_input() if it is due, or at once if early, called by the
+     * polling thread and before the shutdown input, a writer busy with the lock of writing()
+     * writes it itself or leaves it for the next call
+     */
+    pub(crate) fn flush_coalesced(&self, early: bool) -> Result<(), Box<dyn Error>> {
+        if !early && self.coalesced_left().is_none_or(|left| !left.is_zero()) {
+            return Ok(());
+        }
+        let Ok(_writing) = self.writing.try_lock() else { return Ok(()) };
+        // taken only once there is an fd to write it to, else it is reported at exit
+        self.with_input_fd(|fd| match self.coalesced.lock().unwrap().take() {
+            Some((held, _)) => self.write_coalesced(fd, &held),
+            None => Ok(())
+        })?;
+        if self.config().poll_after_write {
+            self.poke();
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes input held back by send_input() to fd, what a failure left unwritten is reported
+     * through PtyHandler::on_error() besides failing the write
+     */
+    fn write_coalesced(&self, fd: RawFd, held: &str) -> Result<(), Box<dyn Error>> {
+        unix::pty::write_counted(fd, held.as_bytes()).map_err(|(written, err)| {
+            let lost = PtyError::new(format!("{} bytes of coalesced input of {} were not written: {err}", held.len() - written, self.id));
+            let _ = self.notify(Notice::Error(lost));
+            err
+        })
+    }
+
+    /**
+     * Drops input held back by send_input() that will never be written, returns how much
+     */
+    pub(crate) fn discard_coalesced(&self) -> usize {
+        self.coalesced.lock().unwrap().take().map_or(0, |(held, _)| held.len())
+    }
+
+    /**
+     * Lock held from filtering input until it is written, so input is written in the order it
+     * was filtered and input written at once to several sessions is not interleaved with other
+     * input, see InputGroup::broadcast()
+     */
+    pub(crate) fn writing(&self) -> MutexGuard<'_, ()> {
+        self.writing.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /**
+     * Notes that input or output went through the pty just now
+     */
+    pub(crate) fn touch(&self) {
+        *self.last_io.lock().unwrap() = self.config().clock.now();
+    }
+
+    pub(crate) fn last_io(&self) -> Instant {
+        *self.last_io.lock().unwrap()
+    }
+
+    pub(crate) fn set_busy(&self, busy: bool) {
+        let mut busy_since = self.busy_since.lock().unwrap();
+        match busy {
+            true => { busy_since.get_or_insert_with(|| self.config().clock.now()); },
+            false => *busy_since = None,
+        }
+    }
+
+    pub(crate) fn busy_since(&self) -> Option<Instant> {
+        *self.busy_since.lock().unwrap()
+    }
+
+    pub(crate) fn set_reader(&self, reader: JoinHandle<()>) {
+        *self.reader.lock().unwrap() = Some(reader);
+    }
+
+    /**
+     * Whether the caller runs on the polling thread, e.g. inside a callback of the handler
+     */
+    pub(crate) fn on_reader_thread(&self) -> bool {
+        self.reader.lock().unwrap().as_ref().is_some_and(|reader| reader.thread().id() == thread::current().id())
+    }
+
+    /**
+     * Waits for the polling thread to end, returns right away when called from it
+     */
+    pub(crate) fn join_reader(&self) {
+        if self.on_reader_thread() { return }
+        let mut reader = self.reader.lock().unwrap();
+        let reader = reader.take();
+        if let Some(reader) = reader {
+            let _ = reader.join();
+        }
+    }
+
+    /**
+     * Whether the polling thread is running, or about to be started
+     */
+    pub(crate) fn reader_alive(&self) -> bool {
+        self.reader.lock().unwrap().as_ref().is_none_or(|reader| !reader.is_finished())
+    }
+
+    /**
+     * Queues a notice for the polling thread and wakes it up
+     */
+    pub(crate) fn notify(&self, notice: Notice) -> Result<(), Box<dyn Error>> {
+        self.with_fd(|_| {
+            self.notices.lock().unwrap().push(notice);
+            // a full pipe already guarantees a wake up
+            let _ = unistd::write(self.wake.1, &[0]);
+            Ok(())
+        })
+    }
+
+    /**
+     * Wakes the polling thread to busy poll for output following a write,
+     * only call it inside with_fd() so the wake pipe is still open
+     */
+    pub(crate) fn poke(&self) {
+        if !self.poked.swap(true, Ordering::Relaxed) {
+            let _ = unistd::write(self.wake.1, &[0]);
+        }
+    }
+
+    pub(crate) fn take_poke(&self) -> bool {
+        self.poked.swap(false, Ordering::Relaxed)
+    }
+
+    /**
+     * Whether the child's output is stopped like ^S does, a paste waits until it resumes
+     */
+    pub(crate) fn set_output_stopped(&self, stopped: bool) {
+        self.output_stopped.store(stopped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn output_stopped(&self) -> bool {
+        self.output_stopped.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Whether the polling thread looks for a status line in the output, see Pty::request_status()
+     */
+    pub(crate) fn expect_status(&self, expected: bool) {
+        *self.status_expected.lock().unwrap() = expected.then(|| self.config().clock.now());
+    }
+
+    /**
+     * Whether a status line is still expected, one that did not come within STATUS_TIMEOUT is not
+     */
+    pub(crate) fn status_expected(&self) -> bool {
+        let mut expected = self.status_expected.lock().unwrap();
+        if expected.is_some_and(|since| self.config().clock.elapsed(since) >= STATUS_TIMEOUT) {
+            *expected = None;
+        }
+        expected.is_some()
+    }
+
+    /**
+     * Read end of the wake pipe, polled by the polling thread
+     */
+    pub(crate) fn wake_fd(&self) -> RawFd {
+        self.wake.0
+    }
+
+    /**
+     * Drains the wake pipe and takes every queued notice
+     */
+    pub(crate) fn take_notices(&self) -> Vec<Notice> {
+        let mut buf = [0; 0x40];
+        while let Ok(n) = unistd::read(self.wake.0, &mut buf) {
+            if n < buf.len() { break }
+        }
+        self.pending_notices()
+    }
+
+    /**
+     * Takes the queued notices without touching the wake pipe, e.g. after the session was closed
+     */
+    pub(crate) fn pending_notices(&self) -> Vec<Notice> {
+        std::mem::take(&mut *self.notices.lock().unwrap())
+    }
+
+    /**
+     * Records a step of a shutdown and queues it for the handler, before the step is taken,
+     * so it is not lost to the child dying of it
+     */
+    pub(crate) fn shutdown_step(&self, progress: ShutdownProgress) {
+        *self.shutdown.lock().unwrap() = Some(progress);
+        let _ = self.notify(Notice::Shutdown(progress));
+    }
+
+    /**
+     * The last step of a shutdown taken, None if the session was not shut down
+     */
+    pub(crate) fn shutdown_progress(&self) -> Option<ShutdownProgress> {
+        *self.shutdown.lock().unwrap()
+    }
+
+    /**
+     * Starts recording the session, replacing any running recording
+     */
+    pub(crate) fn start_recording(&self, recording: &Recording) -> Result<(), Box<dyn Error>> {
+        let size = self.with_fd(unix::pty::window_size)?;
+        let config = self.config();
+        *self.recorder.lock().unwrap() = Some(Recorder::create(recording, size, config.tag.as_deref(), config.clock.clone())?);
+        audit::emit(self, None, AuditAction::RecordingStarted { path: recording.path().to_owned() });
+        Ok(())
+    }
+
+    pub(crate) fn stop_recording(&self) {
+        self.recorder.lock().unwrap().take();
+    }
+
+    /**
+     * Starts recording input as a macro, replacing any macro being recorded
+     */
+    pub(crate) fn start_macro(&self, name: &str) {
+        *self.macro_recorder.lock().unwrap() = Some(MacroRecorder::new(name, self.config().clock.now()));
+    }
+
+    /**
+     * The macro recorded since start_macro(), None if none is being recorded
+     */
+    pub(crate) fn stop_macro(&self) -> Option<Macro> {
+        self.macro_recorder.lock().unwrap().take().map(MacroRecorder::finish)
+    }
+
+    /**
+     * How long until the recording is due to be synced, see Recorder::sync_left()
+     */
+    pub(crate) fn recording_sync_left(&self) -> Option<Duration> {
+        self.recorder.lock().unwrap().as_ref()?.sync_left()
+    }
+
+    /**
+     * Passes the recorder to f if the session is being recorded, a failing recording is stopped
+     */
+    pub(crate) fn record<F>(&self, f: F) -> Result<(), Box<dyn Error>>
+        where F: FnOnce(&mut Recorder) -> Result<(), Box<dyn Error>>
+    {
+        let mut recorder = self.recorder.lock().unwrap();
+        match recorder.as_mut().map(f) {
+            Some(Err(err)) => {
+                recorder.take();
+                Err(Box::new(PtyError::new(format!("Recording of {} stopped: {err}", self.id))))
+            },
+            _ => Ok(())
+        }
+    }
+
+    #[cfg(feature = "attach")]
+    pub(crate) fn backlog(&self) -> &Arc<AtomicU64> {
+        &self.backlog
+    }
+
+    /**
+     * Applies the policy of every exceeded quota, returns the quotas exceeded since the last call
+     */
+    pub(crate) fn enforce_quotas(&self) -> Vec<QuotaEvent> {
+        let mut hit = self.quotas_hit.lock().unwrap();
+        let mut events = Vec::new();
+
+        for (quota, hit) in self.config().quotas.iter().zip(hit.iter_mut()) {
+            let backlog = self.backlog.load(Ordering::Relaxed);
+            let used = match quota.resource {
+                QuotaResource::Scrollback => self.scrollback().text().len() as u64,
+                QuotaResource::Recording => self.recorder.lock().unwrap().as_ref().map_or(0, Recorder::written),
+                QuotaResource::Buffered => self.scrollback().text().len() as u64 + backlog,
+            };
+            if used <= quota.limit {
+                // a scrollback trimmed to the limit stays at it, only falling under re-arms the quota
+                if used < quota.limit {
+                    *hit = false;
+                }
+                continue;
+            }
+
+            match quota.policy {
+                QuotaPolicy::DropOldest => {
+                    let max_len = match quota.resource {
+                        QuotaResource::Buffered => quota.limit.saturating_sub(backlog),
+                        _ => quota.limit
+                    };
+                    self.scrollback().trim(max_len as usize);
+                },
+                QuotaPolicy::StopRecording => self.stop_recording(),
+                QuotaPolicy::KillSession if !*hit => {
+                    audit::emit(self, None, AuditAction::SessionKilled);
+                    let _ = self.with_fd(|_| unix::pty::signal(self.child, Signal::SIGKILL));
+                },
+                QuotaPolicy::KillSession => {},
+            }
+            if !*hit {
+                events.push(QuotaEvent { resource: quota.resource, limit: quota.limit, used, policy: quota.policy });
+            }
+            *hit = true;
+        }
+        events
+    }
+
+    /**
+     * Closes the stdin pipe so the child reads EOF
+     */
+    pub(crate) fn close_stdin(&self) -> Result<(), Box<dyn Error>> {
+        if self.config().stdin != StdioMode::Piped {
+            return Err(Box::new(PtyError::with_kind(format!("Stdin of {} is not piped", self.id), io::ErrorKind::Unsupported)));
+        }
+        if let Some(stdin) = self.stdin.lock().unwrap().take() {
+            let _ = unistd::close(stdin);
+        }
+        Ok(())
+    }
+
+    /**
+     * Removes the session from the registry and closes the master fd
+     */
+    pub(crate) fn close(&self) {
+        let mut open = self.open.write().unwrap();
+        if !*open { return }
+
+        SESSIONS.lock().unwrap().remove(&self.id.fd());
+        let _ = unistd::close(self.id.fd());
+        if let Some(stdin) = self.stdin.lock().unwrap().take() {
+            let _ = unistd::close(stdin);
+        }
+        let _ = unistd::close(self.wake.0);
+        let _ = unistd::close(self.wake.1);
+        self.recorder.lock().unwrap().take();
+        self.slot.lock().unwrap().take();
+        *open = false;
+    }
+
+    /**
+     * Closes the fds of a session inherited over a fork, leaving the child process to the parent,
+     * locks held by threads that did not survive the fork are skipped rather than waited on
+     */
+    fn abandon(&self) {
+        let open = self.open.try_write();
+        let _ = unistd::close(self.id.fd());
+        if let Ok(Some(stdin)) = self.stdin.try_lock().as_deref_mut().map(Option::take) {
+            let _ = unistd::close(stdin);
+        }
+        let _ = unistd::close(self.wake.0);
+        let _ = unistd::close(self.wake.1);
+        // every event is flushed as written, dropping only closes the fd
+        if let Ok(mut recorder) = self.recorder.try_lock() {
+            recorder.take();
+        }
+        if let Ok(mut slot) = self.slot.try_lock() {
+            slot.take();
+        }
+        if let Ok(mut open) = open {
+            *open = false;
+        }
+    }
+
+    /**
+     * Marks the child as reaped and wakes everyone waiting on it
+     */
+    pub(crate) fn set_exited(&self) {
+        // run before waiters are woken, so a pty is out of its groups once Pty::shutdown() returns
+        let hooks = std::mem::take(&mut *self.exit_hooks.lock().unwrap());
+        for hook in hooks {
+            hook(self.id);
+        }
+        // gone before waiters are woken, so a session discarded once they are is gone for good
+        EXITING.lock().unwrap().remove(&self.id);
+        let late = {
+            let mut exited = self.exited.lock().unwrap();
+            *exited = true;
+            self.exited_cond.notify_all();
+            std::mem::take(&mut *self.exit_hooks.lock().unwrap())
+        };
+        for hook in late {
+            hook(self.id);
+        }
+    }
+
+    /**
+     * Calls f on the polling thread once the child has been reaped, after the handler heard
+     * about it, or right away if it already was
+     */
+    pub(crate) fn on_exited(&self, f: ExitHook) {
+        let exited = self.exited.lock().unwrap();
+        if !*exited {
+            return self.exit_hooks.lock().unwrap().push(f);
+        }
+        drop(exited);
+        f(self.id);
+    }
+
+    /**
+     * Waits up to timeout for the child to be reaped, returns whether it was
+     */
+    pub(crate) fn wait_exited(&self, timeout: Duration) -> bool {
+        let exited = self.exited.lock().unwrap();
+        let (exited, _) = self.exited_cond.wait_timeout_while(exited, timeout, |exited| !*exited).unwrap();
+        *exited
+    }
+
+    /**
+     * wait_exited() without a timeout, giving up once cancel is cancelled
+     */
+    pub(crate) fn wait_exited_cancellable(self: &Arc<Self>, cancel: &CancellationToken) -> bool {
+        let session = self.clone();
+        let _waker = cancel.on_cancel(move || {
+            // under the lock, so the wake up cannot fall between a check and the wait
+            let _exited = session.exited.lock().unwrap();
+            session.exited_cond.notify_all();
+        });
+        let exited = self.exited.lock().unwrap();
+        let exited = self.exited_cond.wait_while(exited, |exited| !*exited && !cancel.is_cancelled()).unwrap();
+        *exited
+    }
+}
+
+/**
+ * Next unused generation, for ids of things that are not ptys spawned here
+ */
+pub(crate) fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/**
+ * Registers a freshly opened master fd under a new generation
+ */
+pub(crate) fn register(fd: RawFd, child: Pid, shell: String, stdin: Option<RawFd>, config: Config, slot: Slot) -> Result<Arc<Session>, Box<dyn Error>> {
+    let wake = unix::pty::wake_pipe()?;
+    let id = PtyId::new(fd, next_generation());
+    let initial_size = unix::pty::window_size(fd).unwrap_or(WindowSize::new(0, 0, 0, 0));
+    let session = Arc::new(Session {
+        id,
+        child,
+        shell,
+        open: RwLock::new(true),
+        stdin: Mutex::new(stdin),
+        exited: Mutex::new(false),
+        exited_cond: Condvar::new(),
+        exit_hooks: Mutex::new(Vec::new()),
+        notices: Mutex::new(Vec::new()),
+        poked: AtomicBool::new(false),
+        status_expected: Mutex::new(None),
+        output_stopped: AtomicBool::new(false),
+        coalesced: Mutex::new(None),
+        wake,
+        scrollback: Mutex::new(Scrollback::new(config.scrollback, config.scrollback_spill)),
+        recorder: Mutex::new(None),
+        macro_recorder: Mutex::new(None),
+        clients: Mutex::new(Clients::default()),
+        history: Mutex::new(History::new(config.clock.clone())),
+        titles: Mutex::new(Titles::default()),
+        #[cfg(feature = "vt")]
+        screen: Mutex::new(Screen::new(initial_size)),
+        cwd: Mutex::new(None),
+        exit_status: Mutex::new(None),
+        echo: Mutex::new(Echo::new(config.clock.clone())),
+        writing: Mutex::new(()),
+        input_lock: Mutex::new(None),
+        input_unlocked: Condvar::new(),
+        backlog: Arc::new(AtomicU64::new(0)),
+        quotas_hit: Mutex::new(vec![false; config.quotas.len()]),
+        #[cfg(feature = "triggers")]
+        triggers: Mutex::new(Triggers::default()),
+        reader: Mutex::new(None),
+        busy_since: Mutex::new(None),
+        last_io: Mutex::new(config.clock.now()),
+        spawned_at: config.clock.system_now(),
+        slave_path: unix::pty::slave_path(fd),
+        initial_size,
+        slot: Mutex::new(Some(slot)),
+        shutdown: Mutex::new(None),
+        config: RwLock::new(Arc::new(config)),
+    });
+
+    SESSIONS.lock().unwrap().insert(fd, session.clone());
+    Ok(session)
+}
+
+/**
+ * Looks up a live session, fails if the id is stale
+ */
+pub(crate) fn get(id: PtyId) -> Result<Arc<Session>, Box<dyn Error>> {
+    match SESSIONS.lock().unwrap().get(&id.fd()) {
+        Some(session) if session.id == id => Ok(session.clone()),
+        _ => Err(Box::new(PtyError::with_kind(format!("Stale pty handle: {id}"), io::ErrorKind::NotConnected)))
+    }
+}
+
+/**
+ * get() also finding sessions whose child is exiting or that are retained after it exited,
+ * for reading what they left
+ */
+pub(crate) fn get_any(id: PtyId) -> Result<Arc<Session>, Box<dyn Error>> {
+    get(id).or_else(|err| exiting(id).or_else(|| retention::get(id)).ok_or(err))
+}
+
+/**
+ * Marks session as exiting before its polling thread closes it, until set_exited()
+ */
+pub(crate) fn begin_exit(session: &Arc<Session>) {
+    EXITING.lock().unwrap().insert(session.id, session.clone());
+}
+
+/**
+ * The session of id if it was closed but its exit is not reported yet
+ */
+pub(crate) fn exiting(id: PtyId) -> Option<Arc<Session>> {
+    EXITING.lock().unwrap().get(&id).cloned()
+}
+
+/**
+ * Every live session
+ */
+pub(crate) fn sessions() -> Vec<Arc<Session>> {
+    SESSIONS.lock().unwrap().values().cloned().collect()
+}
+
+/**
+ * Empties the registry of a forked child, see fork::at_fork(), a registry another thread of
+ * the parent held locked at the fork stays locked forever, it fails rather than hang then
+ */
+pub(crate) fn abandon_all() -> Result<(), Box<dyn Error>> {
+    let mut guard = match SESSIONS.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => {
+            let msg = "Registry locked by another thread at the fork, the inherited ptys are left open";
+            return Err(Box::new(PtyError::with_kind(msg, io::ErrorKind::WouldBlock)));
+        },
+    };
+    let sessions = std::mem::take(&mut *guard);
+    drop(guard);
+    // the polling threads are gone, nothing reports their exits here
+    if let Ok(mut exiting) = EXITING.try_lock() {
+        exiting.clear();
+    }
+    for session in sessions.into_values() {
+        session.abandon();
+    }
+    Ok(())
+}
+
+/**
+ * Id of the session currently owning fd, if any
+ */
+pub(crate) fn current(fd: RawFd) -> Option<PtyId> {
+    SESSIONS.lock().unwrap().get(&fd).map(|session| session.id)
+}
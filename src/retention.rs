@@ -0,0 +1,146 @@
+//! Sessions kept after their child exited, for post-mortem inspection, e.g. a UI showing the
+//! final screen and the exit status before the session goes away, see PtyBuilder::retain_exited()
+//! a retained session can be read but not written, it is dropped once the retention period
+//! is over or with Pty::discard()
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//! use pty_exec::cancel::CancellationToken;
+//! use pty_exec::retention::{ExitStatus, SessionState};
+//!
+//! let pty = Pty::builder()
+//!     .scrollback(0x10000)
+//!     .retain_exited(Duration::from_secs(60))
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//! pty.write("exit 3\r")?;
+//! pty.wait(&CancellationToken::new())?;
+//!
+//! assert_eq!(pty.state()?, SessionState::Exited(ExitStatus::Code(3)));
+//! let final_screen = pty.scrollback()?;
+//! pty.discard()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use nix::sys::wait::WaitStatus;
+use crate::id::PtyId;
+use crate::registry::Session;
+
+/// Whether the child of a session still runs, see Pty::state()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SessionState {
+    Running,
+    /// the pty was closed, the child is not reaped yet, e.g. it ignores the SIGHUP of the close
+    Exiting,
+    /// the child exited, the session is retained
+    Exited(ExitStatus),
+}
+
+/// How the child of a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExitStatus {
+    /// exited with this code
+    Code(i32),
+    /// killed by this signal
+    Signal(i32),
+    /// reaped by someone else
+    Unknown,
+}
+
+impl From<nix::Result<WaitStatus>> for ExitStatus {
+    fn from(status: nix::Result<WaitStatus>) -> ExitStatus {
+        match status {
+            Ok(WaitStatus::Exited(_, code)) => ExitStatus::Code(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => ExitStatus::Signal(signal as i32),
+            _ => ExitStatus::Unknown,
+        }
+    }
+}
+
+// sessions retained after their child exited, with when they are dropped
+static RETAINED: Mutex<Vec<(Arc<Session>, Instant)>> = Mutex::new(Vec::new());
+
+/**
+ * Keeps session for period after its child exited
+ */
+pub(crate) fn retain(session: Arc<Session>, period: Duration) {
+    let mut retained = RETAINED.lock().unwrap();
+    purge(&mut retained);
+    let until = session.config().clock.now() + period;
+    retained.push((session, until));
+}
+
+/**
+ * The retained session of id, if its retention period is not over
+ */
+pub(crate) fn get(id: PtyId) -> Option<Arc<Session>> {
+    let mut retained = RETAINED.lock().unwrap();
+    purge(&mut retained);
+    retained.iter().find(|(session, _)| session.id() == id).map(|(session, _)| session.clone())
+}
+
+/**
+ * Drops the retained session of id, returns whether there was one
+ */
+pub(crate) fn discard(id: PtyId) -> bool {
+    let mut retained = RETAINED.lock().unwrap();
+    let len = retained.len();
+    retained.retain(|(session, _)| session.id() != id);
+    retained.len() != len
+}
+
+/**
+ * Drops the sessions whose retention period is over by their clock, the registry is only
+ * looked at when a session is, so that is when they go
+ */
+fn purge(retained: &mut Vec<(Arc<Session>, Instant)>) {
+    retained.retain(|(session, until)| *until > session.config().clock.now());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use crate::cancel::CancellationToken;
+    use crate::Pty;
+    use super::*;
+
+    #[test]
+    fn retained_after_exit() -> Result<(), Box<dyn Error>> {
+        let spawn = |period| Pty::builder().scrollback(0x10000).retain_exited(period).spawn(|_id, _res| {}, |_id| {});
+
+        let pty = spawn(Duration::from_secs(60))?;
+        assert_eq!(pty.state()?, SessionState::Running);
+        pty.write("echo \"last-$((1 + 1))\"; exit 3\r")?;
+        pty.wait(&CancellationToken::new())?;
+        assert_eq!(pty.state()?, SessionState::Exited(ExitStatus::Code(3)));
+        assert!(pty.scrollback()?.contains("last-2"));
+        // read only
+        assert!(!pty.is_alive());
+        assert!(pty.write("echo\r").is_err());
+
+        pty.discard()?;
+        assert!(pty.state().is_err());
+        assert!(pty.discard().is_err());
+
+        // the child let go of the pty and ignores its hangup, state() tells rather than wait for it
+        let pty = spawn(Duration::from_secs(60))?;
+        pty.write("trap '' HUP; exec sleep 2 </dev/null >/dev/null 2>&1\r")?;
+        let started = Instant::now();
+        while pty.is_alive() && started.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pty.state()?, SessionState::Exiting);
+        pty.wait(&CancellationToken::new())?;
+        assert_eq!(pty.state()?, SessionState::Exited(ExitStatus::Code(0)));
+        pty.discard()?;
+
+        let pty = spawn(Duration::ZERO)?;
+        pty.write("exit\r")?;
+        pty.wait(&CancellationToken::new())?;
+        assert!(pty.state().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+//! Restrictions applied to the child before it starts the shell, see PtyBuilder::sandbox()
+//! for hosts handing out shells, e.g. web terminals, that want to limit what a shell can do
+//! without running it in a container, presets add up, each one restricts the child further
+//!
+//! the child is started with no_new_privs, so setuid binaries like sudo no longer gain privileges
+//! ```rust,no_run
+//! use pty_exec::Pty;
+//! use pty_exec::sandbox::Sandbox;
+//!
+//! let pty = Pty::builder()
+//!     .sandbox(Sandbox::NoNetwork)
+//!     .sandbox(Sandbox::ReadOnlyFs(vec!["/home/guest".into(), "/tmp".into()]))
+//!     .spawn(|_id, _res| {}, |_id| {})?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use nix::libc::{self, sock_filter, sock_fprog};
+use crate::error::PtyError;
+
+/// A sandbox preset
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Sandbox {
+    /// no sockets but unix sockets, socket() fails with EACCES, through seccomp,
+    /// io_uring is refused as well and so are 32 bit syscalls, only x86_64 and aarch64
+    NoNetwork,
+    /// files can only be written to beneath these paths, and devices, the rest of the
+    /// filesystem is read only, through landlock, Linux 5.13 or newer
+    ReadOnlyFs(Vec<PathBuf>),
+}
+
+// landlock, see linux/landlock.h
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+// remove_dir, remove_file and the make_* rights up to make_sym
+const ACCESS_FS_MODIFY_TREE: u64 = 0x1ff << 4;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+// seccomp, see linux/audit.h and linux/seccomp.h
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+// x32 syscalls share the arch of x86_64 with this bit set in the number
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+// offsets into struct seccomp_data, the first argument's low half on little endian
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARG0: u32 = 16;
+
+/**
+ * Sandboxes set up in the parent, only entered by the child, so errors are reported by spawn
+ */
+pub(crate) struct Prepared {
+    rulesets: Vec<OwnedFd>,
+    filters: Vec<Vec<sock_filter>>,
+}
+
+pub(crate) fn prepare(sandboxes: &[Sandbox]) -> Result<Prepared, Box<dyn Error>> {
+    let mut prepared = Prepared { rulesets: Vec::new(), filters: Vec::new() };
+    for sandbox in sandboxes {
+        match sandbox {
+            Sandbox::NoNetwork => prepared.filters.push(no_network()?),
+            Sandbox::ReadOnlyFs(paths) => prepared.rulesets.push(read_only_fs(paths)?),
+        }
+    }
+    Ok(prepared)
+}
+
+impl Prepared {
+    /**
+     * Enters the sandboxes, called in pre_exec so only async-signal-safe calls are made
+     */
+    pub(crate) unsafe fn enter(&self) -> io::Result<()> {
+        if self.rulesets.is_empty() && self.filters.is_empty() {
+            return Ok(());
+        }
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for ruleset in &self.rulesets {
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for filter in &self.filters {
+            let prog = sock_fprog { len: filter.len() as u16, filter: filter.as_ptr() as *mut sock_filter };
+            if libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, 0, &prog as *const sock_fprog) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_only_fs(paths: &[PathBuf]) -> Result<OwnedFd, Box<dyn Error>> {
+    let abi = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION)
+    };
+    if abi < 0 {
+        let msg = format!("Landlock is not available: {}", io::Error::last_os_error());
+        return Err(Box::new(PtyError::with_kind(msg, io::ErrorKind::Unsupported)));
+    }
+
+    // only writes are handled, reading and executing stays allowed everywhere
+    let mut write = ACCESS_FS_WRITE_FILE | ACCESS_FS_MODIFY_TREE;
+    if abi >= 2 { write |= ACCESS_FS_REFER }
+    if abi >= 3 { write |= ACCESS_FS_TRUNCATE }
+
+    let attr = RulesetAttr { handled_access_fs: write };
+    let ruleset = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const RulesetAttr, std::mem::size_of::<RulesetAttr>(), 0)
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as libc::c_int) };
+
+    // the shell opens /dev/null and /dev/tty for writing, nothing can be created there
+    allow(&ruleset, Path::new("/dev"), ACCESS_FS_WRITE_FILE | (write & ACCESS_FS_TRUNCATE))?;
+    for path in paths {
+        allow(&ruleset, path, write)?;
+    }
+    Ok(ruleset)
+}
+
+fn allow(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<(), Box<dyn Error>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        return Err(Box::new(PtyError::with_kind(format!("Sandbox path '{}': {err}", path.display()), err.kind())));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let attr = PathBeneathAttr { allowed_access: access, parent_fd: fd.as_raw_fd() };
+    let res = unsafe {
+        libc::syscall(libc::SYS_landlock_add_rule, ruleset.as_raw_fd(), LANDLOCK_RULE_PATH_BENEATH, &attr as *const PathBeneathAttr, 0)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn no_network() -> Result<Vec<sock_filter>, Box<dyn Error>> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let op = |code: u32, k, jt, jf| sock_filter { code: code as u16, jt, jf, k };
+    let load = |offset| op(BPF_LD | BPF_W | BPF_ABS, offset, 0, 0);
+    let jeq = |k, jt, jf| op(BPF_JMP | BPF_JEQ | BPF_K, k, jt, jf);
+    let ret = |action| op(BPF_RET | BPF_K, action, 0, 0);
+    let deny = |errno: libc::c_int| ret(libc::SECCOMP_RET_ERRNO | errno as u32);
+
+    let mut filter = vec![
+        load(DATA_ARCH),
+        jeq(AUDIT_ARCH, 1, 0),
+        deny(libc::EPERM),
+        load(DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        op(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        deny(libc::EPERM),
+    ]);
+    filter.extend([
+        // io_uring can open sockets of its own
+        jeq(libc::SYS_io_uring_setup as u32, 0, 1),
+        deny(libc::EPERM),
+        jeq(libc::SYS_socket as u32, 1, 0),
+        ret(libc::SECCOMP_RET_ALLOW),
+        load(DATA_ARG0),
+        jeq(libc::AF_UNIX as u32, 0, 1),
+        ret(libc::SECCOMP_RET_ALLOW),
+        deny(libc::EACCES),
+    ]);
+    Ok(filter)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn no_network() -> Result<Vec<sock_filter>, Box<dyn Error>> {
+    Err(Box::new(PtyError::with_kind("Sandbox::NoNetwork is only supported on x86_64 and aarch64", io::ErrorKind::Unsupported)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use crate::unix::shell::{ShellUser, UserLookup};
+    use crate::{test_util, Pty};
+
+    #[test]
+    fn presets() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("pty-exec-sandbox-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        assert!(prepare(&[Sandbox::ReadOnlyFs(vec![dir.join("missing")])]).is_err());
+
+        let user = ShellUser { shell: "/bin/bash".into(), ..ShellUser::from_env(UserLookup::Env, "/bin/sh") };
+        let pty = Pty::builder()
+            .user(user)
+            .scrollback(0x10000)
+            .sandbox(Sandbox::NoNetwork)
+            .sandbox(Sandbox::ReadOnlyFs(vec![dir.clone()]))
+            .spawn(|_id, _res| {}, |_id| {})?;
+        pty.write(&format!(concat!(
+            "exec 3<>/dev/tcp/127.0.0.1/1; ",
+            "touch /var/tmp/pty-exec-sandbox 2>/dev/null; echo \"outside-$?\"; ",
+            "echo ok > {}/inside && echo \"inside-$((1 + 1))\"\r"
+        ), dir.display()))?;
+        test_util::wait_for_output(&pty, "inside-2", Duration::from_secs(10))?;
+
+        let output = pty.scrollback()?;
+        assert!(output.contains("socket: Permission denied"), "{output}");
+        assert!(output.contains("outside-1"), "{output}");
+        pty.shutdown()?;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
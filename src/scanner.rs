@@ -0,0 +1,417 @@
+use std::path::PathBuf;
+use crate::answer::Query;
+use crate::handler::{contain, PtyHandler};
+use crate::id::PtyId;
+use crate::image::{ImageFormat, InlineImage};
+use crate::sequence::{self, ControlString, SequenceOverflow};
+use crate::shell_integration::ShellMark;
+
+/**
+ * Control sequences of interest found in the output of a pty
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Sequence {
+    Bell,
+    // OSC 0 or OSC 2
+    Title(String),
+    // OSC 0 or OSC 1
+    IconName(String),
+    // OSC 7
+    Cwd(PathBuf),
+    // OSC 133
+    Mark(ShellMark),
+    // what was echoed between the end of the prompt and the start of the output, i.e. the
+    // command line typed, reported right before the OutputStart mark
+    CommandLine(String),
+    // a query to the terminal, answered by the pty if PtyBuilder::answer_queries() says so
+    Query(Query),
+    // DEC mode 2026 set (true) or reset, only reported when it changes
+    Synchronized(bool),
+    // aid= option of OSC 133;A, the tag of the session the shell runs in, only reported when it changes
+    SessionTag(String),
+    // OSC 1337 File= or APC G, whole however it was split
+    InlineImage(InlineImage),
+    // a control string longer than the scanner keeps, dropped
+    Overflow(SequenceOverflow),
+}
+
+impl Sequence {
+    /**
+     * Passes the sequence to the matching callback of handler
+     */
+    pub(crate) fn notify<H: PtyHandler + ?Sized>(self, handler: &mut H, id: PtyId) {
+        match self {
+            Sequence::Bell => contain(handler, id, |handler| handler.on_bell(id)),
+            Sequence::Title(title) => contain(handler, id, |handler| handler.on_title(id, title)),
+            Sequence::IconName(name) => contain(handler, id, |handler| handler.on_icon_name(id, name)),
+            Sequence::Cwd(cwd) => contain(handler, id, |handler| handler.on_cwd(id, cwd)),
+            Sequence::Mark(mark) => contain(handler, id, |handler| handler.on_shell_mark(id, mark)),
+            Sequence::Synchronized(active) => contain(handler, id, |handler| handler.on_synchronized_output(id, active)),
+            Sequence::SessionTag(tag) => contain(handler, id, |handler| handler.on_session_tag(id, tag)),
+            Sequence::InlineImage(image) => contain(handler, id, |handler| handler.on_inline_image(id, image)),
+            Sequence::Overflow(overflow) => contain(handler, id, |handler| handler.on_sequence_overflow(id, overflow)),
+            // only kept for the command history and answered by the pty
+            Sequence::CommandLine(_) | Sequence::Query(_) => {},
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    String(ControlString),
+    StringEscape(ControlString),
+}
+
+// longer command lines are not kept whole, nobody types a megabyte command
+const MAX_LINE_LEN: usize = 0x1000;
+// parameters of the queries answered are a few bytes, longer ones are not kept
+const MAX_CSI_LEN: usize = 0x20;
+
+/**
+ * Scans pty output for bells and control strings, keeps state between chunks
+ * so sequences split across reads are still recognized
+ */
+pub(crate) struct Scanner {
+    state: State,
+    // the OSC or APC being scanned, the contents of other control strings are not kept
+    string: String,
+    // length of the control string being scanned, it is dropped once longer than max_len
+    string_len: usize,
+    max_len: usize,
+    // parameter and intermediate bytes of the CSI sequence being scanned
+    csi: String,
+    // whether the child is in the middle of a synchronized update (mode 2026)
+    synchronized: bool,
+    // the session tag last reported by the shell
+    tag: Option<String>,
+    // echo since the last CommandStart mark, until the OutputStart mark
+    command_line: Option<String>,
+    // text outside of escape sequences since the last take_text()
+    #[cfg(feature = "triggers")]
+    text: String,
+}
+
+impl Scanner {
+    pub(crate) fn new(max_len: usize) -> Scanner {
+        Scanner {
+            state: State::Ground,
+            string: String::new(),
+            string_len: 0,
+            max_len,
+            csi: String::new(),
+            synchronized: false,
+            tag: None,
+            command_line: None,
+            #[cfg(feature = "triggers")]
+            text: String::new(),
+        }
+    }
+
+    pub(crate) fn scan(&mut self, output: &str) -> Vec<Sequence> {
+        let mut sequences = Vec::new();
+
+        for c in output.chars() {
+            self.state = match (self.state, c) {
+                (State::Ground, '\x07') => {
+                    sequences.push(Sequence::Bell);
+                    State::Ground
+                },
+                (State::Ground, '\x1b') => State::Escape,
+                (State::Ground, _) => {
+                    #[cfg(feature = "triggers")]
+                    if c == '\n' || !c.is_control() {
+                        self.text.push(c);
+                    }
+                    if let Some(line) = self.command_line.as_mut().filter(|line| line.len() < MAX_LINE_LEN) {
+                        match c {
+                            // line editing, the cursor moves back over what is retyped
+                            '\x08' => { line.pop(); },
+                            _ if c.is_control() => {},
+                            _ => line.push(c),
+                        }
+                    }
+                    State::Ground
+                },
+                (State::Escape, _) => self.escape(c),
+                (State::Csi, '\x40'..='\x7e') => {
+                    sequences.extend(Query::from_csi(&self.csi, c).map(Sequence::Query));
+                    if let ("?2026", 'h' | 'l') = (self.csi.as_str(), c) {
+                        if self.synchronized != (c == 'h') {
+                            self.synchronized = c == 'h';
+                            sequences.push(Sequence::Synchronized(self.synchronized));
+                        }
+                    }
+                    State::Ground
+                },
+                (State::Csi, _) => {
+                    if self.csi.len() < MAX_CSI_LEN {
+                        self.csi.push(c);
+                    }
+                    State::Csi
+                },
+                // OSC is terminated by BEL or ST (ESC \), the other strings by ST only
+                (State::String(ControlString::Osc), '\x07') => {
+                    self.finish_string(ControlString::Osc, &mut sequences);
+                    State::Ground
+                },
+                (State::StringEscape(kind), '\\') => {
+                    self.finish_string(kind, &mut sequences);
+                    State::Ground
+                },
+                (State::String(kind), '\x1b') => State::StringEscape(kind),
+                (State::String(kind), _) => {
+                    self.string_len += c.len_utf8();
+                    let max_len = sequence::max_len_of(kind, &self.string, self.max_len);
+                    if self.string_len <= max_len && matches!(kind, ControlString::Osc | ControlString::Apc) {
+                        self.string.push(c);
+                    }
+                    State::String(kind)
+                },
+                // any other escape aborts the string
+                (State::StringEscape(_), _) => self.escape(c),
+            }
+        }
+
+        sequences
+    }
+
+    /**
+     * Whether a synchronized update begun in the output scanned so far has not ended yet
+     */
+    pub(crate) fn synchronized(&self) -> bool {
+        self.synchronized
+    }
+
+    /**
+     * Text scanned since the last call, without escape sequences and control characters but newlines
+     */
+    #[cfg(feature = "triggers")]
+    pub(crate) fn take_text(&mut self) -> String {
+        std::mem::take(&mut self.text)
+    }
+
+    /**
+     * The state after ESC and c
+     */
+    fn escape(&mut self, c: char) -> State {
+        let kind = match c {
+            '[' => {
+                self.csi.clear();
+                return State::Csi;
+            },
+            '\x1b' => return State::Escape,
+            ']' => ControlString::Osc,
+            'P' => ControlString::Dcs,
+            '_' => ControlString::Apc,
+            'X' => ControlString::Sos,
+            '^' => ControlString::Pm,
+            _ => return State::Ground,
+        };
+        self.string.clear();
+        self.string_len = 0;
+        State::String(kind)
+    }
+
+    fn finish_string(&mut self, kind: ControlString, sequences: &mut Vec<Sequence>) {
+        let string = std::mem::take(&mut self.string);
+        if self.string_len > sequence::max_len_of(kind, &string, self.max_len) {
+            sequences.push(Sequence::Overflow(SequenceOverflow { kind, len: self.string_len }));
+            return;
+        }
+        match kind {
+            ControlString::Osc => self.finish_osc(string, sequences),
+            ControlString::Apc => {
+                if let Some(data) = string.strip_prefix('G') {
+                    sequences.push(Sequence::InlineImage(InlineImage { format: ImageFormat::Kitty, data: data.to_owned() }));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn finish_osc(&mut self, osc: String, sequences: &mut Vec<Sequence>) {
+        let tag = session_tag(&osc).filter(|&tag| self.tag.as_deref() != Some(tag)).map(str::to_owned);
+        let icon_name = icon_name(&osc).map(str::to_owned);
+        let sequence = osc_sequence(&osc);
+        match sequence {
+            Some(Sequence::Mark(ShellMark::CommandStart)) => self.command_line = Some(String::new()),
+            Some(Sequence::Mark(ShellMark::OutputStart)) => {
+                if let Some(line) = self.command_line.take() {
+                    sequences.push(Sequence::CommandLine(line.trim().to_owned()));
+                }
+            },
+            Some(Sequence::Mark(_)) => self.command_line = None,
+            _ => {}
+        }
+        sequences.extend(sequence);
+        sequences.extend(icon_name.map(Sequence::IconName));
+        if let Some(tag) = tag {
+            self.tag = Some(tag.clone());
+            sequences.push(Sequence::SessionTag(tag));
+        }
+    }
+}
+
+/**
+ * The sequence an OSC payload stands for, if it is of interest
+ */
+fn osc_sequence(osc: &str) -> Option<Sequence> {
+    let (code, payload) = osc.split_once(';')?;
+
+    match code {
+        "0" | "2" => Some(Sequence::Title(payload.to_owned())),
+        // file://host/path, the host is not checked, the shell runs on this one
+        "7" => {
+            let path = payload.strip_prefix("file://")?;
+            Some(Sequence::Cwd(PathBuf::from(percent_decode(&path[path.find('/')?..]))))
+        },
+        "1337" => {
+            let data = payload.strip_prefix("File=")?;
+            Some(Sequence::InlineImage(InlineImage { format: ImageFormat::Iterm2, data: data.to_owned() }))
+        },
+        "133" => {
+            let mut params = payload.split(';');
+            match params.next()? {
+                "A" => Some(Sequence::Mark(ShellMark::PromptStart)),
+                "B" => Some(Sequence::Mark(ShellMark::CommandStart)),
+                "C" => Some(Sequence::Mark(ShellMark::OutputStart)),
+                "D" => Some(Sequence::Mark(ShellMark::CommandFinished { exit_code: params.next().and_then(|code| code.parse().ok()) })),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+/**
+ * Decodes the %XX escapes of a url path, malformed escapes are kept as they are
+ */
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (b, hex) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            },
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/**
+ * The aid= option of an OSC 133;A payload, which the shell integration sets to the session tag
+ */
+fn session_tag(osc: &str) -> Option<&str> {
+    let options = osc.strip_prefix("133;A;")?;
+    options.split(';').find_map(|option| option.strip_prefix("aid=")).filter(|tag| !tag.is_empty())
+}
+
+/**
+ * Icon name set by an OSC 0 or OSC 1
+ */
+fn icon_name(osc: &str) -> Option<&str> {
+    osc.strip_prefix("0;").or_else(|| osc.strip_prefix("1;"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sequence::{DEFAULT_MAX_LEN, MAX_OSC_LEN};
+    use super::*;
+
+    #[test]
+    fn split_sequences() {
+        let mut scanner = Scanner::new(DEFAULT_MAX_LEN);
+
+        assert_eq!(scanner.scan("ding\x07 \x1b]0;ti"), vec![Sequence::Bell]);
+        assert_eq!(scanner.scan("tle\x1b"), vec![]);
+        assert_eq!(scanner.scan("\\\x1b]2;other\x07\x1b]1;icon\x07"), vec![
+            Sequence::Title("title".into()),
+            Sequence::IconName("title".into()),
+            Sequence::Title("other".into()),
+            Sequence::IconName("icon".into()),
+        ]);
+        // a BEL terminating an OSC is not a bell, unknown OSCs are ignored
+        assert_eq!(scanner.scan("\x1b]52;c;Zm9v\x07\x1b[31mred"), vec![]);
+        assert_eq!(scanner.scan("\x1b[>c\x1b[6"), vec![Sequence::Query(Query::SecondaryDa)]);
+        assert_eq!(scanner.scan("n"), vec![Sequence::Query(Query::CursorPosition)]);
+        // synchronized updates, repeats are not reported
+        assert_eq!(scanner.scan("\x1b[?2026h\x1b[?2026hframe"), vec![Sequence::Synchronized(true)]);
+        assert!(scanner.synchronized());
+        assert_eq!(scanner.scan("\x1b[?2026l"), vec![Sequence::Synchronized(false)]);
+    }
+
+    #[test]
+    fn shell_integration() {
+        let mut scanner = Scanner::new(DEFAULT_MAX_LEN);
+
+        assert_eq!(scanner.scan("\x1b]7;file://host/tmp/a%20b\x07\x1b]133;D;1\x07\x1b]133;A\x1b\\"), vec![
+            Sequence::Cwd("/tmp/a b".into()),
+            Sequence::Mark(ShellMark::CommandFinished { exit_code: Some(1) }),
+            Sequence::Mark(ShellMark::PromptStart),
+        ]);
+        // the tag is only reported when it changes
+        assert_eq!(scanner.scan("\x1b]133;A;aid=build-42\x07$ \x1b]133;A;aid=build-42\x07"), vec![
+            Sequence::Mark(ShellMark::PromptStart),
+            Sequence::SessionTag("build-42".into()),
+            Sequence::Mark(ShellMark::PromptStart),
+        ]);
+        assert_eq!(scanner.scan("\x1b]133;D\x07\x1b]7;file://host\x07"), vec![
+            Sequence::Mark(ShellMark::CommandFinished { exit_code: None }),
+        ]);
+        // the echo of the command line, minus escapes and edits
+        assert_eq!(scanner.scan("$ \x1b]133;B\x07maek\x08\x08ke\x1b[K all\r\n\x1b]133;C\x07"), vec![
+            Sequence::Mark(ShellMark::CommandStart),
+            Sequence::CommandLine("make all".into()),
+            Sequence::Mark(ShellMark::OutputStart),
+        ]);
+    }
+
+    #[test]
+    fn inline_images() {
+        let mut scanner = Scanner::new(DEFAULT_MAX_LEN);
+
+        let iterm2 = format!("File=inline=1:{}", "QUJD".repeat(0x1000));
+        assert_eq!(scanner.scan(&format!("\x1b]1337;{}", &iterm2[..10])), vec![]);
+        assert_eq!(scanner.scan(&format!("{}\x07", &iterm2[10..])), vec![
+            Sequence::InlineImage(InlineImage { format: ImageFormat::Iterm2, data: iterm2["File=".len()..].to_owned() }),
+        ]);
+        // only images are kept that long
+        let title = "t".repeat(MAX_OSC_LEN);
+        assert_eq!(scanner.scan(&format!("\x1b]2;{title}\x07")), vec![
+            Sequence::Overflow(SequenceOverflow { kind: ControlString::Osc, len: MAX_OSC_LEN + 2 }),
+        ]);
+        // other APCs are ignored, BEL does not end an APC
+        assert_eq!(scanner.scan("\x1b_Ga=T,f=100;QU\x07JD\x1b\\\x1b_other\x1b\\"), vec![
+            Sequence::InlineImage(InlineImage { format: ImageFormat::Kitty, data: "a=T,f=100;QU\x07JD".into() }),
+        ]);
+    }
+
+    #[test]
+    fn control_strings() {
+        let mut scanner = Scanner::new(8);
+
+        // a BEL within a DCS is not a bell, an ESC aborts it and begins another sequence
+        assert_eq!(scanner.scan("\x1bPq#0\x07\x1b\\\x1bX\x07\x1b]2;ti"), vec![]);
+        assert_eq!(scanner.scan("tle\x1b\\\x1b^pm\x1b]2;other\x07"), vec![
+            Sequence::Title("title".into()),
+            Sequence::Title("other".into()),
+        ]);
+        // longer strings are dropped whole and reported once they end
+        assert_eq!(scanner.scan("\x1b]2;a long "), vec![]);
+        assert_eq!(scanner.scan("title\x07\x1b_Gf=100;QUJD\x1b\\"), vec![
+            Sequence::Overflow(SequenceOverflow { kind: ControlString::Osc, len: 14 }),
+            Sequence::Overflow(SequenceOverflow { kind: ControlString::Apc, len: 11 }),
+        ]);
+        assert_eq!(scanner.scan("\x1bP12345678\x1b\\\x1b]2;short\x07"), vec![Sequence::Title("short".into())]);
+    }
+}
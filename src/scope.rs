@@ -0,0 +1,137 @@
+//! Ptys that do not outlive a scope, see Pty::scope(), the easy way not to leak a shell in tests
+//! and short lived automation, ptys spawned any other way stay detached as usual
+//!
+//! when the scope ends, also by a panic, every pty of the scope is shut down, its fds closed and
+//! its polling thread joined before Pty::scope() returns
+//! ```rust
+//! use pty_exec::{Pty, PtyBuilder};
+//!
+//! let id = Pty::scope(|scope| {
+//!     let pty = scope.spawn(PtyBuilder::new(), |_id, _res| {}, |_id| {})?;
+//!     pty.write("ls\r")?;
+//!     Ok::<_, Box<dyn std::error::Error>>(pty.id())
+//! })?;
+//! // the shell is gone
+//! # let _ = id;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::builder::PtyBuilder;
+use crate::handler::PtyHandler;
+use crate::id::PtyId;
+use crate::registry::{self, Session};
+use crate::shutdown::{self, ShutdownPolicy};
+use crate::Pty;
+
+/// Owns the ptys spawned through it, see the scope module
+pub struct Scope {
+    // kept after their child exits, the polling thread is joined all the same
+    ptys: Mutex<Vec<Arc<Session>>>,
+    policy: Mutex<(ShutdownPolicy, Duration)>,
+}
+
+impl Scope {
+    pub(crate) fn new() -> Scope {
+        Scope { ptys: Mutex::new(Vec::new()), policy: Mutex::new((ShutdownPolicy::Graceful, Duration::from_secs(5))) }
+    }
+
+    /// spawns builder's pty, ended with the scope
+    pub fn spawn<F, G>(&self, builder: PtyBuilder, on_read: F, on_death: G) -> Result<Pty, Box<dyn Error>>
+        where
+            F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+            G: FnMut(PtyId) + Send + 'static
+    {
+        Ok(self.adopt(builder.spawn(on_read, on_death)?))
+    }
+
+    /// spawns builder's pty with handler, ended with the scope
+    pub fn spawn_handler<H: PtyHandler>(&self, builder: PtyBuilder, handler: H) -> Result<Pty, Box<dyn Error>> {
+        Ok(self.adopt(builder.spawn_handler(handler)?))
+    }
+
+    /// ends pty with the scope although it was spawned outside of it
+    pub fn adopt(&self, pty: Pty) -> Pty {
+        if let Ok(session) = registry::get_any(pty.id()) {
+            self.ptys.lock().unwrap().push(session);
+        }
+        pty
+    }
+
+    /// how the ptys are ended when the scope ends, whatever is left after timeout is sent
+    /// SIGKILL, Graceful within 5 seconds by default
+    pub fn shutdown_with(&self, policy: ShutdownPolicy, timeout: Duration) {
+        *self.policy.lock().unwrap() = (policy, timeout);
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let sessions = std::mem::take(&mut *self.ptys.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        let (policy, timeout) = *self.policy.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // a child that exited already is not signalled, its pid may be someone else's by now
+        let live = sessions.iter().filter(|session| registry::get(session.id()).is_ok()).cloned().collect();
+        let report = shutdown::shutdown_sessions(live, policy, timeout);
+        for session in sessions {
+            match report.stuck.contains(&session.id()) {
+                // nothing to join, the polling thread waits for a child that never exits
+                true => session.close(),
+                false => session.join_reader(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use nix::sys::signal;
+    use crate::registry;
+    use crate::{Pty, PtyBuilder};
+
+    #[test]
+    fn ends_with_scope() -> Result<(), Box<dyn Error>> {
+        let (pty, child) = Pty::scope(|scope| {
+            let pty = scope.spawn(PtyBuilder::new().shutdown_input(""), |_id, _res| {}, |_id| {})?;
+            let child = registry::get(pty.id())?.child();
+            Ok::<_, Box<dyn Error>>((pty, child))
+        })?;
+        assert!(!pty.is_alive());
+        // reaped, not just signalled
+        assert!(signal::kill(child, None).is_err());
+
+        // a panic ends the scope as well
+        let mut spawned = None;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| Pty::scope(|scope| {
+            spawned = scope.spawn(PtyBuilder::new(), |_id, _res| {}, |_id| {}).ok();
+            panic!("test failed");
+        })));
+        assert!(res.is_err());
+        assert!(spawned.is_some_and(|pty| !pty.is_alive()));
+
+        // the polling thread of a pty that exited on its own is joined as well
+        let held = Arc::new(());
+        let holder = held.clone();
+        Pty::scope(|scope| {
+            let pty = scope.spawn(PtyBuilder::new(), |_id, _res| {}, move |_id| {
+                thread::sleep(Duration::from_millis(500));
+                let _ = &holder;
+            })?;
+            pty.write("exit\r")?;
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while pty.is_alive() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Ok::<_, Box<dyn Error>>(())
+        })?;
+        assert_eq!(Arc::strong_count(&held), 1);
+        Ok(())
+    }
+}
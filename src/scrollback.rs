@@ -0,0 +1,496 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::num::NonZeroUsize;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{env, io, process, slice};
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "dragonfly"))]
+use nix::fcntl::posix_fallocate;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use crate::id::PtyId;
+use crate::registry;
+
+/// Position of a search match in the output of a pty
+/// offsets count bytes of output as delivered to on_output since the pty was spawned,
+/// so a position stays valid while older output is dropped from the scrollback
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchPos {
+    /// offset of the first byte of the match
+    pub offset: u64,
+    /// length of the match in the raw output, including escape sequences inside it
+    pub len: usize,
+    /// line of the match, counted from the first line the pty ever printed
+    pub line: u64,
+}
+
+/// Order of the matches returned by Pty::search()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    /// oldest match first
+    Forward,
+    /// newest match first
+    Backward,
+}
+
+/// Position in the output of a pty, see Pty::cursor() and Pty::read_since()
+/// a cursor is just a stream offset so it can be handed to a client and rebuilt with Cursor::new()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cursor(u64);
+
+impl Cursor {
+    pub fn new(offset: u64) -> Cursor {
+        Cursor(offset)
+    }
+
+    /// bytes of output delivered before this position
+    pub fn offset(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Output read with Pty::read_since()
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OutputSince {
+    /// everything still retained after the cursor
+    pub output: String,
+    /// cursor at the end of output, pass it to the next read
+    pub cursor: Cursor,
+    /// bytes after the cursor that were dropped from the scrollback before they could be read
+    pub missed: u64,
+}
+
+/// Search that keeps up with new output, each update only returns matches not reported yet
+/// ```rust
+/// use pty_exec::Pty;
+///
+/// let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+/// let mut search = pty.incremental_search("error");
+///
+/// pty.write("echo error\r")?;
+/// // ... some time later
+/// for pos in search.update()? {
+///     println!("match on line {}", pos.line);
+/// }
+/// # pty.shutdown()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Search {
+    id: PtyId,
+    pattern: String,
+    // matches starting before this offset have been reported
+    reported: u64,
+}
+
+impl Search {
+    pub(crate) fn new(id: PtyId, pattern: &str) -> Search {
+        Search { id, pattern: pattern.to_owned(), reported: 0 }
+    }
+
+    /// matches that appeared since the last update, oldest first
+    pub fn update(&mut self) -> Result<Vec<MatchPos>, Box<dyn Error>> {
+        let matches: Vec<MatchPos> = registry::get(self.id)?
+            .scrollback()
+            .search(&self.pattern)
+            .into_iter()
+            .filter(|pos| pos.offset >= self.reported)
+            .collect();
+
+        if let Some(last) = matches.last() {
+            self.reported = last.offset + 1;
+        }
+        Ok(matches)
+    }
+}
+
+/**
+ * Bounded buffer of the most recent output of a pty, output falling out of it is spilled to
+ * disk if PtyBuilder::scrollback_spill() asks for it
+ */
+pub(crate) struct Scrollback {
+    max_len: usize,
+    text: String,
+    // stream offset and line of the first byte in memory
+    start: u64,
+    start_line: u64,
+    // bytes the spill may hold, created with the first output to spill
+    spill_len: usize,
+    spill: Option<Spill>,
+}
+
+impl Scrollback {
+    pub(crate) fn new(max_len: usize, spill_len: usize) -> Scrollback {
+        Scrollback { max_len, text: String::new(), start: 0, start_line: 0, spill_len, spill: None }
+    }
+
+    /**
+     * The output held in memory, the newest part of all()
+     */
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+
+    /**
+     * Everything retained, the spilled output followed by the output in memory
+     */
+    pub(crate) fn all(&self) -> Cow<'_, str> {
+        self.retained().0
+    }
+
+    /**
+     * all() with the stream offset and line of its first byte
+     */
+    fn retained(&self) -> (Cow<'_, str>, u64, u64) {
+        let (text, start) = self.retained_from(0);
+        let start_line = self.spill.as_ref().filter(|spill| spill.len > 0).map_or(self.start_line, |spill| spill.start_line);
+        (text, start, start_line)
+    }
+
+    /**
+     * The retained output from the stream offset from on, starting at a character, with the
+     * offset of its first byte, the spill is only read as far as it is needed
+     */
+    fn retained_from(&self, from: u64) -> (Cow<'_, str>, u64) {
+        match &self.spill {
+            Some(spill) if spill.len > 0 && from < self.start => {
+                let spill_start = self.start - spill.len as u64;
+                let (older, newer) = spill.stretches();
+                let skip = from.saturating_sub(spill_start) as usize;
+                let (older, newer) = match older.get(skip..) {
+                    Some(older) => (older, newer),
+                    None => (&[][..], &newer[skip - older.len()..]),
+                };
+                let mut text = [older, newer, self.text.as_bytes()].concat();
+                // a cursor built by hand may point inside a character
+                let inside = text.iter().take_while(|&&b| b & 0xc0 == 0x80).count();
+                text.drain(..inside);
+                // only whole characters are spilled and dropped from the spill
+                let text = String::from_utf8(text).unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned());
+                (Cow::Owned(text), spill_start + (skip + inside) as u64)
+            },
+            _ => (Cow::Borrowed(&self.text), self.start)
+        }
+    }
+
+    /**
+     * Cursor past the last byte of output
+     */
+    pub(crate) fn cursor(&self) -> Cursor {
+        Cursor(self.start + self.text.len() as u64)
+    }
+
+    /**
+     * Everything retained after cursor
+     */
+    pub(crate) fn since(&self, cursor: Cursor) -> OutputSince {
+        let (text, start) = self.retained_from(cursor.0);
+        let end = self.cursor();
+        let missed = start.saturating_sub(cursor.0);
+
+        // a cursor built by hand may point inside a character
+        let mut from = (cursor.0.clamp(start, end.0) - start) as usize;
+        while !text.is_char_boundary(from) {
+            from += 1;
+        }
+
+        OutputSince { output: text[from..].to_owned(), cursor: end, missed }
+    }
+
+    /**
+     * The last n bytes or a little less, so as not to start inside a character
+     */
+    pub(crate) fn tail(&self, n: usize) -> &str {
+        let mut from = self.text.len().saturating_sub(n);
+        while !self.text.is_char_boundary(from) {
+            from += 1;
+        }
+        &self.text[from..]
+    }
+
+    pub(crate) fn push(&mut self, output: &str) {
+        if self.max_len == 0 {
+            self.start += output.len() as u64;
+            return;
+        }
+        self.text.push_str(output);
+        self.trim(self.max_len);
+    }
+
+    /**
+     * Drops the oldest output until at most max_len bytes are left in memory, into the spill if
+     * there is one
+     */
+    pub(crate) fn trim(&mut self, max_len: usize) {
+        if self.text.len() > max_len {
+            let mut excess = self.text.len() - max_len;
+            while !self.text.is_char_boundary(excess) {
+                excess += 1;
+            }
+            if self.spill.is_none() && self.spill_len > 0 {
+                // output is dropped as without a spill if the temp file cannot be set up
+                self.spill = Spill::new(self.spill_len, self.start_line).ok();
+                self.spill_len = 0;
+            }
+            if let Some(spill) = &mut self.spill {
+                spill.push(&self.text[..excess]);
+            }
+            self.start_line += self.text[..excess].matches('\n').count() as u64;
+            self.start += excess as u64;
+            self.text.drain(..excess);
+        }
+    }
+
+    /**
+     * Finds every occurrence of pattern in the retained output, oldest first
+     * escape sequences are skipped so text split by color changes still matches
+     */
+    pub(crate) fn search(&self, pattern: &str) -> Vec<MatchPos> {
+        if pattern.is_empty() { return Vec::new() }
+
+        let (text, start, start_line) = self.retained();
+        let (plain, runs) = plain_text(&text);
+        // maps an offset in the plain text to one in the retained raw text
+        let to_raw = |offset: usize| {
+            let run = runs.partition_point(|&(plain_start, _)| plain_start <= offset) - 1;
+            let (plain_start, raw_start) = runs[run];
+            raw_start + offset - plain_start
+        };
+
+        let (mut line, mut counted) = (start_line, 0);
+        plain.match_indices(pattern).map(|(offset, m)| {
+            let raw_start = to_raw(offset);
+            let raw_end = to_raw(offset + m.len() - 1) + 1;
+
+            line += text[counted..raw_start].matches('\n').count() as u64;
+            counted = raw_start;
+
+            MatchPos { offset: start + raw_start as u64, len: raw_end - raw_start, line }
+        }).collect()
+    }
+}
+
+/**
+ * Ring buffer of output that fell out of a scrollback, in a memory-mapped temp file that is
+ * unlinked right away, the kernel writes its pages back to disk rather than keeping them resident
+ */
+struct Spill {
+    map: *mut u8,
+    cap: usize,
+    // ring position of the oldest byte and how many bytes are held
+    head: usize,
+    len: usize,
+    // line of the oldest byte
+    start_line: u64,
+}
+
+// the mapping is owned by the spill and only reached through the mutex of its scrollback
+unsafe impl Send for Spill {}
+
+impl Spill {
+    fn new(cap: usize, start_line: u64) -> io::Result<Spill> {
+        static SPILLS: AtomicU64 = AtomicU64::new(0);
+        let name = format!("pty-exec-scrollback-{}-{}", process::id(), SPILLS.fetch_add(1, Ordering::Relaxed));
+        let path = env::temp_dir().join(name);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path)?;
+        fs::remove_file(&path)?;
+        // taken up front, a write to a shared mapping the disk has no room for raises SIGBUS
+        allocate(&file, cap)?;
+
+        let len = NonZeroUsize::new(cap).ok_or(io::ErrorKind::InvalidInput)?;
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        // the mapping outlives the fd
+        let map = unsafe { mmap(None, len, prot, MapFlags::MAP_SHARED, file.as_raw_fd(), 0) }?;
+        Ok(Spill { map: map as *mut u8, cap, head: 0, len: 0, start_line })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.map, self.cap) }
+    }
+
+    /**
+     * The bytes held, oldest first, in the two stretches the ring wraps into
+     */
+    fn stretches(&self) -> (&[u8], &[u8]) {
+        let (bytes, end) = (self.bytes(), self.head + self.len);
+        match end <= self.cap {
+            true => (&bytes[self.head..end], &[]),
+            false => (&bytes[self.head..], &bytes[..end - self.cap])
+        }
+    }
+
+    /**
+     * Appends text, dropping the oldest bytes to make room, text larger than the spill is
+     * only kept in part
+     */
+    fn push(&mut self, mut text: &str) {
+        if text.len() > self.cap {
+            self.drop_oldest(self.len);
+            let mut skip = text.len() - self.cap;
+            while !text.is_char_boundary(skip) {
+                skip += 1;
+            }
+            self.start_line += text[..skip].matches('\n').count() as u64;
+            text = &text[skip..];
+        }
+        if self.len + text.len() > self.cap {
+            self.drop_oldest(self.len + text.len() - self.cap);
+        }
+
+        let at = (self.head + self.len) % self.cap;
+        let (first, rest) = text.as_bytes().split_at(text.len().min(self.cap - at));
+        let bytes = unsafe { slice::from_raw_parts_mut(self.map, self.cap) };
+        bytes[at..at + first.len()].copy_from_slice(first);
+        bytes[..rest.len()].copy_from_slice(rest);
+        self.len += text.len();
+    }
+
+    /**
+     * Drops at least n of the oldest bytes, up to the next character
+     */
+    fn drop_oldest(&mut self, n: usize) {
+        let byte = |i: usize| self.bytes()[(self.head + i) % self.cap];
+        let mut n = n;
+        // continuation bytes of UTF-8 are 0b10xxxxxx
+        while n < self.len && byte(n) & 0xc0 == 0x80 {
+            n += 1;
+        }
+        self.start_line += (0..n).filter(|&i| byte(i) == b'\n').count() as u64;
+        self.head = (self.head + n) % self.cap;
+        self.len -= n;
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.map as *mut _, self.cap) };
+    }
+}
+
+/**
+ * Takes len bytes of disk for file
+ */
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "dragonfly"))]
+fn allocate(file: &File, len: usize) -> io::Result<()> {
+    Ok(posix_fallocate(file.as_raw_fd(), 0, len as _)?)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "dragonfly")))]
+fn allocate(mut file: &File, len: usize) -> io::Result<()> {
+    // no posix_fallocate(), written zeros take the disk all the same
+    io::copy(&mut io::Read::take(io::repeat(0), len as u64), &mut file).map(|_| ())
+}
+
+/**
+ * Strips escape sequences from text, returns the plain text and its runs
+ * each run is (offset in plain, offset in text) of a stretch copied over verbatim
+ */
+pub(crate) fn plain_text(text: &str) -> (String, Vec<(usize, usize)>) {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State { Text, Escape, Csi, String, StringEscape }
+
+    let mut plain = String::with_capacity(text.len());
+    let mut runs = Vec::new();
+    let mut state = State::Text;
+    // end in text of the last character copied to plain
+    let mut copied_to = None;
+
+    for (i, c) in text.char_indices() {
+        state = match (state, c) {
+            (State::Text, '\x1b') => State::Escape,
+            (State::Text, _) => {
+                // a new run starts after every escape sequence
+                if copied_to != Some(i) {
+                    runs.push((plain.len(), i));
+                }
+                plain.push(c);
+                copied_to = Some(i + c.len_utf8());
+                State::Text
+            },
+            (State::Escape, '[') => State::Csi,
+            // OSC, DCS, APC, PM and SOS all run until ST
+            (State::Escape, ']' | 'P' | '_' | '^' | 'X') => State::String,
+            (State::Escape, _) => State::Text,
+            (State::Csi, '\x40'..='\x7e') => State::Text,
+            (State::Csi, _) => State::Csi,
+            (State::String, '\x07') => State::Text,
+            (State::String, '\x1b') => State::StringEscape,
+            (State::String, _) => State::String,
+            (State::StringEscape, _) => State::Text,
+        };
+    }
+
+    (plain, runs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_since() {
+        let mut scrollback = Scrollback::new(8, 0);
+        let start = scrollback.cursor();
+
+        scrollback.push("abcdef");
+        let cursor = scrollback.cursor();
+        scrollback.push("ghij");
+
+        assert_eq!(scrollback.since(cursor), OutputSince { output: "ghij".into(), cursor: Cursor(10), missed: 0 });
+        assert_eq!(scrollback.since(start), OutputSince { output: "cdefghij".into(), cursor: Cursor(10), missed: 2 });
+        assert_eq!(scrollback.since(Cursor(10)).output, "");
+    }
+
+    #[test]
+    fn tail() {
+        let mut scrollback = Scrollback::new(8, 0);
+        scrollback.push("abcéf");
+
+        assert_eq!(scrollback.tail(2), "f");
+        assert_eq!(scrollback.tail(3), "éf");
+        assert_eq!(scrollback.tail(100), "abcéf");
+    }
+
+    #[test]
+    fn search_across_escapes() {
+        let mut scrollback = Scrollback::new(26, 0);
+
+        scrollback.push("first line\r\n");
+        scrollback.push("an \x1b[31merr\x1b[0mor\r\nerror\r\n");
+        let matches = scrollback.search("error");
+
+        // the first line has been dropped, positions still count it
+        assert_eq!(scrollback.start, 12);
+        assert_eq!(matches, vec![
+            MatchPos { offset: 20, len: 9, line: 1 },
+            MatchPos { offset: 31, len: 5, line: 2 },
+        ]);
+    }
+
+    #[test]
+    fn spill() {
+        let mut scrollback = Scrollback::new(6, 12);
+        scrollback.push("error 1\n");
+        scrollback.push("errör 2\n");
+        scrollback.push("x\n");
+
+        // only the newest output is in memory, reading and searching still see the spill
+        assert_eq!(scrollback.text(), "r 2\nx\n");
+        assert_eq!(scrollback.since(Cursor(0)), OutputSince { output: "rror 1\nerrör 2\nx\n".into(), cursor: Cursor(19), missed: 1 });
+        assert_eq!(scrollback.search("errö"), vec![MatchPos { offset: 8, len: 5, line: 1 }]);
+        // the spill is only read from the cursor on
+        assert_eq!(scrollback.since(Cursor(9)).output, "rrör 2\nx\n");
+        assert_eq!(scrollback.since(Cursor(12)).output, "r 2\nx\n");
+        assert_eq!(scrollback.since(Cursor(15)).output, "2\nx\n");
+
+        // the spill drops its oldest output in turn, never half a character
+        scrollback.push("abcdefghijk");
+        assert_eq!(scrollback.since(Cursor(0)), OutputSince { output: "r 2\nx\nabcdefghijk".into(), cursor: Cursor(30), missed: 13 });
+        assert_eq!(scrollback.search("x"), vec![MatchPos { offset: 17, len: 1, line: 2 }]);
+    }
+}
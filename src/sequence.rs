@@ -0,0 +1,212 @@
+//! Control strings in the output of a pty, OSC, DCS, APC, SOS and PM sequences, which the pty
+//! reassembles however they were split across reads before acting on them, e.g. a title or an
+//! inline image, a string longer than PtyBuilder::max_sequence_len() is dropped whole rather
+//! than acted on torn, and reported with PtyHandler::on_sequence_overflow() once it ends
+//! the output passed on keeps every sequence as the child wrote it, a read ending in the middle
+//! of a control string is held back until the string ends, so PtyHandler::on_output() and the
+//! clients get it whole as well, unless it gets too long or the child takes too long to end it
+//! OSCs other than inline images are kept to MAX_OSC_LEN, a title or a mark is a few bytes
+
+use std::time::{Duration, Instant};
+
+/// Control strings longer than this are dropped unless PtyBuilder::max_sequence_len() says
+/// otherwise, in bytes
+pub const DEFAULT_MAX_LEN: usize = 0x100_0000;
+
+/// OSCs other than inline images longer than this are dropped, whatever
+/// PtyBuilder::max_sequence_len() says, in bytes
+pub const MAX_OSC_LEN: usize = 0x1000;
+
+const IMAGE_PREFIX: &str = "1337;File=";
+
+// how long output ending in the middle of a control string is held back for the rest of it
+const MAX_SPLIT_HOLD: Duration = Duration::from_millis(100);
+
+/// The kind of a control string, by the escape introducing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ControlString {
+    /// ESC ], ended by BEL or ST
+    Osc,
+    /// ESC P
+    Dcs,
+    /// ESC _
+    Apc,
+    /// ESC X
+    Sos,
+    /// ESC ^
+    Pm,
+}
+
+/// A control string longer than PtyBuilder::max_sequence_len(), dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequenceOverflow {
+    pub kind: ControlString,
+    /// the length of the whole string without its introducer and terminator, in bytes
+    pub len: usize,
+}
+
+/**
+ * The longest a control string of kind starting with start may be, max_len or less
+ */
+pub(crate) fn max_len_of(kind: ControlString, start: &str, max_len: usize) -> usize {
+    // a string shorter than the prefix may still turn out to be an image
+    let image = start.starts_with(IMAGE_PREFIX) || IMAGE_PREFIX.starts_with(start);
+    match kind {
+        ControlString::Osc if !image => max_len.min(MAX_OSC_LEN),
+        _ => max_len,
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    String(ControlString),
+    StringEscape(ControlString),
+}
+
+/**
+ * Holds back the control string output ends in the middle of, so output is passed on with every
+ * string whole
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Unsplit {
+    state: State,
+    // from the start of the string or escape output ended in
+    held: String,
+    // when the string held was begun, in real time like the pty is read
+    since: Option<Instant>,
+    given_up: bool,
+}
+
+impl Unsplit {
+    /**
+     * Output to pass on, held output followed by output up to the control string it ends in if
+     * any, a string longer than max_len_of() is passed on as it comes and only reported
+     */
+    pub(crate) fn join(&mut self, output: String, max_len: usize) -> String {
+        let mut text = std::mem::take(&mut self.held);
+        let scanned = text.len();
+        text.push_str(&output);
+        // where the string or escape being held starts
+        let mut start = (scanned > 0).then_some(0);
+        if std::mem::take(&mut self.given_up) {
+            (start, self.state) = (None, State::Ground);
+        }
+        for (i, c) in text[scanned..].char_indices() {
+            let i = scanned + i;
+            self.state = match (self.state, c) {
+                (State::Ground, '\x1b') => {
+                    start = Some(i);
+                    State::Escape
+                },
+                (State::Ground, _) => State::Ground,
+                (State::Escape, c) => escape(c, i, &mut start),
+                (State::String(ControlString::Osc), '\x07') | (State::StringEscape(_), '\\') => {
+                    start = None;
+                    State::Ground
+                },
+                (State::String(kind), '\x1b') => State::StringEscape(kind),
+                (State::String(kind), _) => State::String(kind),
+                // any other escape aborts the string
+                (State::StringEscape(_), c) => {
+                    start = Some(i - 1);
+                    escape(c, i, &mut start)
+                },
+            };
+            if let (State::String(kind) | State::StringEscape(kind), Some(at)) = (self.state, start) {
+                // without its introducer
+                let len = i + c.len_utf8() - at - 2;
+                if len > max_len.min(MAX_OSC_LEN) && len > max_len_of(kind, &text[at + 2..], max_len) {
+                    // passed on as it comes, the scanner drops it
+                    start = None;
+                    self.state = State::Ground;
+                }
+            }
+        }
+        match start {
+            Some(at) => {
+                self.held = text.split_off(at);
+                if at > 0 || scanned == 0 {
+                    self.since = Some(Instant::now());
+                }
+            },
+            None => self.since = None,
+        }
+        text
+    }
+
+    /**
+     * How long until output held is given up on
+     */
+    pub(crate) fn left(&self) -> Option<Duration> {
+        self.since.map(|since| MAX_SPLIT_HOLD.saturating_sub(since.elapsed()))
+    }
+
+    /**
+     * The next join() passes on what is held and the rest of its string as it comes
+     */
+    pub(crate) fn give_up(&mut self) {
+        self.given_up = !self.held.is_empty();
+    }
+}
+
+/**
+ * The state after ESC and c at i, start stays at the ESC if c begins a control string
+ */
+fn escape(c: char, i: usize, start: &mut Option<usize>) -> State {
+    let kind = match c {
+        ']' => ControlString::Osc,
+        'P' => ControlString::Dcs,
+        '_' => ControlString::Apc,
+        'X' => ControlString::Sos,
+        '^' => ControlString::Pm,
+        '\x1b' => {
+            *start = Some(i);
+            return State::Escape;
+        },
+        _ => {
+            *start = None;
+            return State::Ground;
+        }
+    };
+    State::String(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsplit() {
+        let mut unsplit = Unsplit::default();
+
+        assert_eq!(unsplit.join("ab\x1b]0;ti".into(), DEFAULT_MAX_LEN), "ab");
+        assert_eq!(unsplit.join("t".into(), DEFAULT_MAX_LEN), "");
+        assert_eq!(unsplit.join("le\x07cd\x1b".into(), DEFAULT_MAX_LEN), "\x1b]0;title\x07cd");
+        assert_eq!(unsplit.join("[31mred\x1bP1$".into(), DEFAULT_MAX_LEN), "\x1b[31mred");
+        assert!(unsplit.left().is_some());
+        // another escape aborts the DCS, the one it begins is held instead
+        assert_eq!(unsplit.join("r\x1b\x1b_Gf".into(), DEFAULT_MAX_LEN), "\x1bP1$r\x1b");
+        assert_eq!(unsplit.join("=100\x1b\\".into(), DEFAULT_MAX_LEN), "\x1b_Gf=100\x1b\\");
+        assert_eq!(unsplit.left(), None);
+
+        // longer strings are not held, nor the rest of them
+        assert_eq!(unsplit.join("\x1bPsixel".into(), 4), "\x1bPsixel");
+        assert_eq!(unsplit.join("s\x1b\\".into(), 4), "s\x1b\\");
+        let title = "t".repeat(MAX_OSC_LEN);
+        assert_eq!(unsplit.join(format!("\x1b]2;{title}"), DEFAULT_MAX_LEN), format!("\x1b]2;{title}"));
+        assert_eq!(unsplit.join("\x07".into(), DEFAULT_MAX_LEN), "\x07");
+        let image = format!("\x1b]1337;File=:{title}");
+        assert_eq!(unsplit.join(image.clone(), DEFAULT_MAX_LEN), "");
+
+        // nor strings the child takes too long to end
+        unsplit.give_up();
+        assert_eq!(unsplit.join("QUJD".into(), DEFAULT_MAX_LEN), image + "QUJD");
+        assert_eq!(unsplit.join("\x07".into(), DEFAULT_MAX_LEN), "\x07");
+        assert_eq!(unsplit.left(), None);
+    }
+}
@@ -0,0 +1,702 @@
+//! Serves named sessions over a unix socket with the attach protocol, see protocol::Frame
+//! sessions outlive their clients, a client detaching or vanishing leaves its session running
+//! with the `vt` feature a client may ask for the rows of the screen that changed in place of
+//! output, see Client::connect_diffs(), it gets the whole screen on attaching, not a replay
+//! ```rust,no_run
+//! use pty_exec::server::{default_socket_path, Server};
+//!
+//! let server = Server::bind(default_socket_path())?;
+//! server.run()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufReader, BufWriter};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::sys::signal::Signal;
+use nix::unistd::getuid;
+use crate::audit::AuditAction;
+use crate::auth::{Authenticator, Identity, Peer, SameUser};
+use crate::clients::{ClientEvent, ClientId};
+use crate::compress::{Codec, Encoder};
+use crate::error::PtyError;
+use crate::protocol::{read_frame, read_frame_max, write_frame, Frame};
+use crate::quota::Held;
+use crate::registry;
+use crate::scrollback::{Cursor, OutputSince};
+#[cfg(feature = "vt")]
+use crate::vt::ScreenMirror;
+use crate::Pty;
+
+type Spawn = Arc<dyn Fn(&str) -> Result<Pty, Box<dyn Error>> + Send + Sync>;
+type OnEvent = Arc<dyn Fn(ServerEvent) + Send + Sync>;
+// a frame waiting for the writer, output frames count against the quotas while they wait
+type Queued = (Frame, Option<Held>);
+
+// largest frame read from a client before it is authenticated, enough for a token and a name
+const MAX_UNAUTHENTICATED: usize = 0x1000;
+
+/// Socket the daemon listens on unless told otherwise, in $XDG_RUNTIME_DIR if set, else in a
+/// directory of the user only accessible by them, created in the temp dir if missing
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("pty-execd.sock"),
+        None => {
+            let dir = env::temp_dir().join(format!("pty-execd-{}", getuid()));
+            // a directory someone else created is turned away by Server::bind()
+            let _ = fs::DirBuilder::new().mode(0o700).create(&dir);
+            dir.join("pty-execd.sock")
+        }
+    }
+}
+
+/// Something that happened to the clients of a server, see Server::on_event()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    ClientAttached { session: String, client: ClientId, identity: Identity },
+    /// the session keeps running whatever the reason
+    ClientDetached { session: String, client: ClientId, identity: Identity, reason: DetachReason },
+    /// the authenticator refused peer, nothing was spawned
+    ClientDenied { session: String, peer: Peer, reason: String },
+}
+
+/// Why a client is no longer attached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetachReason {
+    /// the client sent Detach
+    Requested,
+    /// the connection was closed or broke
+    Disconnected,
+    /// the client stopped answering keepalive pings, see Server::keepalive()
+    TimedOut,
+    /// the session died
+    SessionExited,
+}
+
+/// Hosts sessions and lets clients attach to them over a unix socket
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+    shared: Shared,
+}
+
+/**
+ * Everything a connection needs from its server
+ */
+#[derive(Clone)]
+struct Shared {
+    sessions: Arc<Mutex<HashMap<String, Pty>>>,
+    spawn: Spawn,
+    on_event: OnEvent,
+    authenticator: Arc<dyn Authenticator>,
+    // ping interval and how long a client may stay silent
+    keepalive: Option<(Duration, Duration)>,
+    // codecs output may be compressed with, preferred first
+    compress: Vec<Codec>,
+}
+
+impl Server {
+    /// listens on path, a socket of this user left behind by a server that is gone is replaced,
+    /// the socket is only accessible by this user, fails if path is in a directory others can
+    /// replace it in
+    pub fn bind(path: impl AsRef<Path>) -> Result<Server, Box<dyn Error>> {
+        let path = path.as_ref().to_owned();
+        check_dir(path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")))?;
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() || meta.uid() != getuid().as_raw() {
+                return Err(Box::new(PtyError::with_kind(format!("{} is not a socket of this user", path.display()), io::ErrorKind::AlreadyExists)));
+            }
+            if UnixStream::connect(&path).is_err() {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        // clients are authenticated anyway, others should not even get to connect
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        Ok(Server {
+            listener,
+            path,
+            shared: Shared {
+                sessions: Arc::new(Mutex::new(HashMap::new())),
+                spawn: Arc::new(|_name| Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})),
+                on_event: Arc::new(|_event| {}),
+                authenticator: Arc::new(SameUser),
+                keepalive: None,
+                compress: Vec::new(),
+            },
+        })
+    }
+
+    /// how sessions are spawned when a client attaches to a name that has none,
+    /// a shell with a 64k scrollback by default
+    pub fn spawn_with<F>(mut self, spawn: F) -> Server
+        where F: Fn(&str) -> Result<Pty, Box<dyn Error>> + Send + Sync + 'static
+    {
+        self.shared.spawn = Arc::new(spawn);
+        self
+    }
+
+    /// called whenever a client attaches or detaches, on the thread serving that client
+    pub fn on_event<F>(mut self, on_event: F) -> Server
+        where F: Fn(ServerEvent) + Send + Sync + 'static
+    {
+        self.shared.on_event = Arc::new(on_event);
+        self
+    }
+
+    /// decides who may attach to which session, only the user running the server and root
+    /// may by default, see the auth module
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Server {
+        self.shared.authenticator = Arc::new(authenticator);
+        self
+    }
+
+    /// ping every client each interval, a client that sends nothing for timeout is considered
+    /// gone and detached, e.g. a laptop that went to sleep, off by default
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Server {
+        self.shared.keepalive = Some((interval, timeout));
+        self
+    }
+
+    /// compress the output sent to clients that can decompress one of codecs, the first of
+    /// codecs the client offers is used, codecs not available in this build are skipped,
+    /// off by default, see the compress module
+    pub fn compress(mut self, codecs: impl IntoIterator<Item = Codec>) -> Server {
+        self.shared.compress = codecs.into_iter().filter(|codec| codec.is_available()).collect();
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// names of the sessions still alive
+    pub fn sessions(&self) -> Vec<String> {
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        sessions.retain(|_, pty| pty.is_alive());
+        sessions.keys().cloned().collect()
+    }
+
+    /// accepts clients until the listener fails, each client is served on its own thread
+    pub fn run(&self) -> Result<(), Box<dyn Error>> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let shared = self.shared.clone();
+            thread::Builder::new().name("pty-exec/client".into()).spawn(move || {
+                let _ = serve(stream, &shared);
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/**
+ * Fails unless dir is owned by this user or root and nobody else can write to it, or only
+ * remove their own files from it like /tmp
+ */
+fn check_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let meta = fs::metadata(dir)?;
+    let owner = meta.uid() == getuid().as_raw() || meta.uid() == 0;
+    let sticky = meta.mode() & 0o1000 != 0;
+    if !owner || (meta.mode() & 0o022 != 0 && !sticky) {
+        let msg = format!("Socket directory {} is not private", dir.display());
+        return Err(Box::new(PtyError::with_kind(msg, io::ErrorKind::PermissionDenied)));
+    }
+    Ok(())
+}
+
+/**
+ * State of one connection, shared by its reading, writing and keepalive threads
+ */
+#[derive(Default)]
+struct Connection {
+    timed_out: AtomicBool,
+    exited: AtomicBool,
+    closed: AtomicBool,
+}
+
+/**
+ * Serves one connection until the client detaches, disconnects or its session dies
+ */
+fn serve(stream: UnixStream, shared: &Shared) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut first = read_frame_max(&mut reader, MAX_UNAUTHENTICATED)?;
+    let token = match first {
+        Some(Frame::Auth(token)) => {
+            first = read_frame_max(&mut reader, MAX_UNAUTHENTICATED)?;
+            Some(token)
+        },
+        _ => None
+    };
+    let offered = match first {
+        Some(Frame::Compress(offered)) => {
+            first = read_frame_max(&mut reader, MAX_UNAUTHENTICATED)?;
+            Some(offered)
+        },
+        _ => None
+    };
+    #[cfg(feature = "vt")]
+    let diffs = match first {
+        Some(Frame::ScreenDiffs) => {
+            first = read_frame_max(&mut reader, MAX_UNAUTHENTICATED)?;
+            true
+        },
+        _ => false
+    };
+    let (name, resume) = match first {
+        Some(Frame::Attach { session }) => (session, None),
+        Some(Frame::Resume { session, cursor }) => (session, Some(Cursor::new(cursor))),
+        _ => {
+            let _ = write_frame(&mut &stream, &Frame::Error("Expected Attach".into()));
+            return Err(Box::new(PtyError::new("Client did not attach")));
+        }
+    };
+
+    let peer = Peer::of(&stream, token)?;
+    let identity = match shared.authenticator.authenticate(&peer, &name) {
+        Ok(identity) => identity,
+        Err(err) => {
+            let _ = write_frame(&mut &stream, &Frame::Error(format!("Permission denied: {err}")));
+            (shared.on_event)(ServerEvent::ClientDenied { session: name, peer, reason: err.to_string() });
+            return Err(err);
+        }
+    };
+
+    // no encoder is set up for a client that is turned away
+    let mut encoder = None;
+    if let Some(offered) = offered {
+        let codec = shared.compress.iter().copied().find(|codec| offered.contains(codec));
+        write_frame(&mut &stream, &Frame::Compress(codec.into_iter().collect()))?;
+        encoder = codec.map(Encoder::new).transpose()?;
+    }
+
+    let pty = {
+        let mut sessions = shared.sessions.lock().unwrap();
+        match sessions.get(&name) {
+            Some(pty) if pty.is_alive() => Pty { id: pty.id() },
+            // the cursor of a resume token is meaningless for any other session
+            _ if resume.is_some() => {
+                let _ = write_frame(&mut &stream, &Frame::Error(format!("No session named {name:?} to resume")));
+                return Err(Box::new(PtyError::new(format!("No session named {name:?} to resume"))));
+            },
+            _ => {
+                let pty = (shared.spawn)(&name)?;
+                sessions.insert(name.clone(), Pty { id: pty.id() });
+                pty
+            }
+        }
+    };
+    let connection = Arc::new(Connection::default());
+
+    // a slow client must not stall the thread reading the pty, so frames are queued
+    // and written by a thread of their own, queued output counts against the session's quotas
+    let (tx, rx) = mpsc::channel::<Queued>();
+    let writer = stream.try_clone()?;
+    let writer_connection = connection.clone();
+    thread::Builder::new().name("pty-exec/client-writer".into()).spawn(move || {
+        let mut w = BufWriter::new(&writer);
+        for (frame, _held) in rx {
+            let exited = frame == Frame::Exited;
+            let frame = match (&mut encoder, frame) {
+                (Some(encoder), Frame::Output(output)) => match encoder.encode(output.as_bytes()) {
+                    Ok(data) => Frame::Compressed(data),
+                    Err(_) => break
+                },
+                (_, frame) => frame
+            };
+            if write_frame(&mut w, &frame).is_err() { break }
+            if exited {
+                writer_connection.exited.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+        // unblocks the reading side too
+        let _ = writer.shutdown(Shutdown::Both);
+    })?;
+
+    // live output waits until Attached and the replay are queued
+    let gate = Arc::new(Mutex::new(()));
+    let replaying = gate.lock().unwrap();
+    let backlog = registry::get(pty.id())?.backlog().clone();
+    let (events, gate_async, backlog_async) = (tx.clone(), gate.clone(), backlog.clone());
+    #[cfg(feature = "vt")]
+    let (mirror, id) = (Arc::new(Mutex::new(ScreenMirror::new())), pty.id());
+    #[cfg(feature = "vt")]
+    let mirror_async = mirror.clone();
+    let profile = identity.profile();
+    let (client, cursor) = pty.attach_with(profile, move |event| {
+        let _replayed = gate_async.lock().unwrap();
+        #[cfg(feature = "vt")]
+        if diffs && matches!(event, ClientEvent::Output(_) | ClientEvent::Resized(..)) {
+            let Ok(session) = registry::get_any(id) else { return };
+            let screen = session.screen();
+            if let ClientEvent::Resized(rows, cols) = event {
+                // the diff follows the resize it shows, and only once the screen has that size
+                let _ = events.send((Frame::Resized(rows, cols), None));
+                if screen.size() != (rows, cols) {
+                    return;
+                }
+            }
+            let Some(diff) = screen.diff(&mut mirror_async.lock().unwrap()) else { return };
+            drop(screen);
+            let held = Held::new(&backlog_async, diff.lines.iter().map(|(_, line)| line.len()).sum());
+            let _ = events.send((Frame::Screen(diff), Some(held)));
+            return;
+        }
+        let _ = events.send(match event {
+            ClientEvent::Output(output) => {
+                let held = Held::new(&backlog_async, output.len());
+                (Frame::Output(output), Some(held))
+            },
+            ClientEvent::Resized(rows, cols) => (Frame::Resized(rows, cols), None),
+            ClientEvent::Message(message) => (Frame::Message(message), None),
+            ClientEvent::Echo { seq, echoed } => (Frame::Echo { seq, echoed }, None),
+            ClientEvent::Exited => (Frame::Exited, None),
+        });
+    })?;
+    #[cfg(feature = "vt")]
+    if diffs {
+        // the whole screen stands in for the replay
+        tx.send((Frame::Attached { session: name.clone(), cursor: cursor.offset(), missed: 0 }, None))?;
+        let diff = registry::get_any(pty.id())?.screen().diff(&mut mirror.lock().unwrap());
+        if let Some(diff) = diff {
+            let held = Held::new(&backlog, diff.lines.iter().map(|(_, line)| line.len()).sum());
+            tx.send((Frame::Screen(diff), Some(held)))?;
+        }
+    }
+    #[cfg(not(feature = "vt"))]
+    let diffs = false;
+    if !diffs {
+        let (start, missed, replay) = replay(&pty, resume.unwrap_or(cursor), cursor)?;
+        // a filter of its own, the replay ends where the live output starts, with a chunk
+        let replay = profile.filter().apply(&replay);
+        tx.send((Frame::Attached { session: name.clone(), cursor: start.offset(), missed }, None))?;
+        if !replay.is_empty() {
+            let held = Held::new(&backlog, replay.len());
+            tx.send((Frame::Output(replay), Some(held)))?;
+        }
+    }
+    drop(replaying);
+    pty.audit(Some(identity.name()), AuditAction::ClientAttached { client, uid: Some(peer.uid()), pid: peer.pid() });
+    (shared.on_event)(ServerEvent::ClientAttached { session: name.clone(), client, identity: identity.clone() });
+
+    let last_seen = Arc::new(Mutex::new(Instant::now()));
+    if let Some((interval, timeout)) = shared.keepalive {
+        let (stream, pings) = (stream.try_clone()?, tx.clone());
+        let (connection, last_seen) = (connection.clone(), last_seen.clone());
+        thread::Builder::new().name("pty-exec/keepalive".into()).spawn(move || {
+            while !connection.closed.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+                if last_seen.lock().unwrap().elapsed() > timeout {
+                    connection.timed_out.store(true, Ordering::Relaxed);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+                if pings.send((Frame::Ping, None)).is_err() { break }
+            }
+        })?;
+    }
+
+    let res = handle_frames(&mut reader, &pty, client, &identity, &tx, &last_seen);
+    connection.closed.store(true, Ordering::Relaxed);
+    let _ = pty.detach(client);
+    pty.audit(Some(identity.name()), AuditAction::ClientDetached { client });
+    let _ = stream.shutdown(Shutdown::Both);
+
+    let reason = match res {
+        Ok(true) => DetachReason::Requested,
+        _ if connection.exited.load(Ordering::Relaxed) => DetachReason::SessionExited,
+        _ if connection.timed_out.load(Ordering::Relaxed) => DetachReason::TimedOut,
+        _ => DetachReason::Disconnected,
+    };
+    (shared.on_event)(ServerEvent::ClientDetached { session: name, client, identity, reason });
+    res.map(|_| ())
+}
+
+/**
+ * Output of pty between from and to as far as the scrollback still has it,
+ * returns where the replay starts, how many bytes after from were lost and the replay
+ */
+fn replay(pty: &Pty, from: Cursor, to: Cursor) -> Result<(Cursor, u64, String), Box<dyn Error>> {
+    let from = from.min(to);
+    let OutputSince { mut output, cursor, .. } = pty.read_since(from)?;
+    // the scrollback may have dropped output past to by now
+    let start = Cursor::new(cursor.offset() - output.len() as u64).min(to);
+    // to is the end of a chunk so it is a char boundary
+    output.truncate((to.offset() - start.offset()) as usize);
+    Ok((start, start.offset() - from.offset(), output))
+}
+
+/**
+ * Handles frames of an attached client until the connection ends, returns whether the client detached
+ */
+fn handle_frames(
+    reader: &mut BufReader<UnixStream>,
+    pty: &Pty,
+    client: ClientId,
+    identity: &Identity,
+    tx: &mpsc::Sender<Queued>,
+    last_seen: &Mutex<Instant>
+) -> Result<bool, Box<dyn Error>> {
+    while let Some(frame) = read_frame(reader).or_else(disconnected)? {
+        *last_seen.lock().unwrap() = Instant::now();
+        let res = match frame {
+            Frame::Input(input) => pty.write_client(&input),
+            Frame::PredictedInput { seq, input } => pty.write_predicted(client, seq, &input),
+            Frame::Resize(size) => pty.client_resize(client, size).map(|_| ()),
+            Frame::Signal(signal) => Signal::try_from(signal)
+                .map_err(|err| Box::new(PtyError::from(err)) as Box<dyn Error>)
+                .and_then(|signal| pty.signal_as(signal, Some(identity.name()))),
+            Frame::Message(message) => pty.client_message(client, message),
+            Frame::Ping => { let _ = tx.send((Frame::Pong, None)); Ok(()) },
+            Frame::Pong => Ok(()),
+            Frame::Detach => return Ok(true),
+            frame => Err(Box::new(PtyError::new(format!("Unexpected frame {frame:?}"))) as Box<dyn Error>)
+        };
+        if let Err(err) = res {
+            let _ = tx.send((Frame::Error(err.to_string()), None));
+        }
+    }
+    Ok(false)
+}
+
+/**
+ * A client going away mid frame is a disconnect like any other
+ */
+fn disconnected(err: io::Error) -> io::Result<Option<Frame>> {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => Ok(None),
+        _ => Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Tokens;
+    use crate::clients::OutputProfile;
+    use crate::WindowSize;
+
+    fn next_output(r: &mut impl io::Read, pattern: &str) -> io::Result<bool> {
+        let mut output = String::new();
+        while let Some(frame) = read_frame(r)? {
+            if let Frame::Output(s) = frame { output.push_str(&s) }
+            if output.contains(pattern) { return Ok(true) }
+        }
+        Ok(false)
+    }
+
+    #[test]
+    fn attach_detach() -> Result<(), Box<dyn Error>> {
+        let path = env::temp_dir().join(format!("pty-execd-test-{}.sock", std::process::id()));
+        let server = Server::bind(&path)?;
+        thread::spawn(move || { let _ = server.run(); });
+
+        let mut stream = UnixStream::connect(&path)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+        write_frame(&mut stream, &Frame::Attach { session: "main".into() })?;
+        assert!(matches!(read_frame(&mut stream)?, Some(Frame::Attached { missed: 0, .. })));
+
+        write_frame(&mut stream, &Frame::Input("export MARK=\"kept-$((1 + 1))\"; echo $MARK\r".into()))?;
+        assert!(next_output(&mut stream, "kept-2\r\n")?);
+        write_frame(&mut stream, &Frame::Detach)?;
+        // the server hangs up
+        while read_frame(&mut stream).or_else(disconnected)?.is_some() {}
+
+        // the session outlived the first client
+        let mut stream = UnixStream::connect(&path)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+        write_frame(&mut stream, &Frame::Attach { session: "main".into() })?;
+        write_frame(&mut stream, &Frame::Input("echo \"still-$MARK\"; exit\r".into()))?;
+        assert!(next_output(&mut stream, "still-kept-2")?);
+
+        let mut exited = false;
+        while let Some(frame) = read_frame(&mut stream).or_else(disconnected)? {
+            exited |= frame == Frame::Exited;
+        }
+        assert!(exited);
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "vt")]
+    #[test]
+    fn screen_diffs() -> Result<(), Box<dyn Error>> {
+        let path = env::temp_dir().join(format!("pty-execd-diffs-{}.sock", std::process::id()));
+        let server = Server::bind(&path)?;
+        thread::spawn(move || { let _ = server.run(); });
+
+        let mut stream = UnixStream::connect(&path)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write_frame(&mut stream, &Frame::ScreenDiffs)?;
+        write_frame(&mut stream, &Frame::Attach { session: "diffs".into() })?;
+        assert!(matches!(read_frame(&mut stream)?, Some(Frame::Attached { missed: 0, .. })));
+
+        write_frame(&mut stream, &Frame::Input("echo \"diffed-$((1 + 1))\"\r".into()))?;
+        let mut mirror = ScreenMirror::new();
+        while !mirror.lines().iter().any(|line| line == "diffed-2") {
+            match read_frame(&mut stream)? {
+                Some(Frame::Screen(diff)) => mirror.apply(&diff),
+                Some(Frame::Output(_)) => panic!("Output sent to a client of diffs"),
+                Some(_) => {},
+                None => panic!("Connection closed"),
+            }
+        }
+        assert_eq!(mirror.size(), (24, 80));
+
+        // the resize comes first, then the diff of the resized screen
+        write_frame(&mut stream, &Frame::Resize(WindowSize::new(30, 100, 0, 0)))?;
+        loop {
+            match read_frame(&mut stream)? {
+                Some(Frame::Resized(30, 100)) => break,
+                Some(Frame::Screen(diff)) => mirror.apply(&diff),
+                Some(_) => {},
+                None => panic!("Connection closed"),
+            }
+        }
+        while mirror.size() != (30, 100) {
+            match read_frame(&mut stream)? {
+                Some(Frame::Screen(diff)) => mirror.apply(&diff),
+                Some(_) => {},
+                None => panic!("Connection closed"),
+            }
+        }
+        write_frame(&mut stream, &Frame::Input("exit\r".into()))?;
+        while read_frame(&mut stream).or_else(disconnected)?.is_some() {}
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn socket_permissions() -> Result<(), Box<dyn Error>> {
+        let dir = env::temp_dir().join(format!("pty-execd-perms-{}", std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let path = dir.join("pty-execd.sock");
+        let server = Server::bind(&path)?;
+        assert_eq!(fs::metadata(&path)?.mode() & 0o777, 0o600);
+        drop(server);
+
+        // only a stale socket is replaced, never some other file
+        fs::write(&path, "not a socket")?;
+        assert!(Server::bind(&path).is_err());
+        assert_eq!(fs::read_to_string(&path)?, "not a socket");
+        fs::remove_file(&path)?;
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777))?;
+        assert!(Server::bind(&path).is_err());
+        fs::remove_dir(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn keepalive_detaches() -> Result<(), Box<dyn Error>> {
+        let path = env::temp_dir().join(format!("pty-execd-keepalive-{}.sock", std::process::id()));
+        let (events_tx, events) = mpsc::channel();
+        let server = Server::bind(&path)?
+            .keepalive(Duration::from_millis(50), Duration::from_millis(300))
+            .on_event(move |event| { let _ = events_tx.send(event); });
+        let server = Arc::new(server);
+        let server_async = server.clone();
+        thread::spawn(move || { let _ = server_async.run(); });
+
+        // a client that never answers pings, like one whose network went away
+        let mut stream = UnixStream::connect(&path)?;
+        write_frame(&mut stream, &Frame::Attach { session: "idle".into() })?;
+
+        let timeout = Duration::from_secs(10);
+        assert!(matches!(events.recv_timeout(timeout)?, ServerEvent::ClientAttached { .. }));
+        assert!(matches!(events.recv_timeout(timeout)?, ServerEvent::ClientDetached { reason: DetachReason::TimedOut, .. }));
+        assert_eq!(server.sessions(), vec!["idle".to_owned()]);
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn authentication() -> Result<(), Box<dyn Error>> {
+        let path = env::temp_dir().join(format!("pty-execd-auth-{}.sock", std::process::id()));
+        let (events_tx, events) = mpsc::channel();
+        let server = Server::bind(&path)?
+            .authenticator(Tokens::new().allow("secret", Identity::new("ci")))
+            .on_event(move |event| { let _ = events_tx.send(event); });
+        thread::spawn(move || { let _ = server.run(); });
+        let timeout = Duration::from_secs(10);
+
+        let mut stream = UnixStream::connect(&path)?;
+        write_frame(&mut stream, &Frame::Attach { session: "guarded".into() })?;
+        assert!(matches!(read_frame(&mut stream)?, Some(Frame::Error(_))));
+        assert!(matches!(events.recv_timeout(timeout)?, ServerEvent::ClientDenied { peer, .. } if peer.token().is_none()));
+
+        // a client that is not authenticated yet cannot make the server buffer large frames
+        let mut stream = UnixStream::connect(&path)?;
+        write_frame(&mut stream, &Frame::Auth("x".repeat(MAX_UNAUTHENTICATED + 1)))?;
+        assert_eq!(read_frame(&mut stream).or_else(disconnected)?, None);
+
+        let mut stream = UnixStream::connect(&path)?;
+        write_frame(&mut stream, &Frame::Auth("secret".into()))?;
+        write_frame(&mut stream, &Frame::Attach { session: "guarded".into() })?;
+        assert!(matches!(read_frame(&mut stream)?, Some(Frame::Attached { .. })));
+        assert!(matches!(events.recv_timeout(timeout)?, ServerEvent::ClientAttached { identity, .. } if identity.name() == "ci"));
+        write_frame(&mut stream, &Frame::Input("exit\r".into()))?;
+        while read_frame(&mut stream).or_else(disconnected)?.is_some() {}
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn output_profile() -> Result<(), Box<dyn Error>> {
+        let path = env::temp_dir().join(format!("pty-execd-profile-{}.sock", std::process::id()));
+        let server = Server::bind(&path)?
+            .authenticator(|peer: &Peer, _session: &str| Ok(Identity::uid(peer.uid()).with_profile(OutputProfile::Strict)));
+        thread::spawn(move || { let _ = server.run(); });
+        // the title is only in the output, not in the echo of the command
+        let sanitized = |stream: &mut UnixStream| -> Result<bool, Box<dyn Error>> {
+            let mut output = String::new();
+            while !output.contains("shown-2\r\n") {
+                match read_frame(stream)? {
+                    Some(Frame::Output(s)) => output.push_str(&s),
+                    Some(_) => {},
+                    None => return Ok(false),
+                }
+            }
+            Ok(!output.contains("title-2"))
+        };
+
+        let mut stream = UnixStream::connect(&path)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write_frame(&mut stream, &Frame::Attach { session: "viewed".into() })?;
+        write_frame(&mut stream, &Frame::Input("printf '\\033]2;%s\\007shown-%s\\n' \"title-$((1 + 1))\" $((1 + 1))\r".into()))?;
+        assert!(sanitized(&mut stream)?);
+        write_frame(&mut stream, &Frame::Detach)?;
+
+        // the replay as well
+        let mut stream = UnixStream::connect(&path)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write_frame(&mut stream, &Frame::Resume { session: "viewed".into(), cursor: 0 })?;
+        assert!(sanitized(&mut stream)?);
+        write_frame(&mut stream, &Frame::Input("exit\r".into()))?;
+        while read_frame(&mut stream).or_else(disconnected)?.is_some() {}
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Shell integration for shells the user has not set up, see PtyBuilder::shell_integration()
+//! the snippets make the shell report its working directory (OSC 7), a title (OSC 2) and the
+//! prompt and command boundaries (OSC 133), which arrive as PtyHandler::on_cwd(),
+//! PtyHandler::on_title() and PtyHandler::on_shell_mark(), the start of the prompt carries
+//! the tag of the session from PTY_EXEC_SESSION_ID, see PtyHandler::on_session_tag()
+//! ```rust
+//! use pty_exec::shell_integration::{snippet_for, Shell};
+//!
+//! // e.g. to print for the user to add to their ~/.zshrc
+//! println!("{}", snippet_for(Shell::Zsh));
+//! ```
+
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::Mutex;
+
+/// A shell there is a snippet for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// the shell at path judging by its file name, a leading - of login shells is ignored
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Shell> {
+        let name = path.as_ref().file_name()?.to_str()?;
+        match name.trim_start_matches('-') {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None
+        }
+    }
+}
+
+/// Prompt and command boundaries a shell marks with OSC 133
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ShellMark {
+    /// the prompt is about to be printed (A)
+    PromptStart,
+    /// the prompt was printed, what follows is typed by the user (B)
+    CommandStart,
+    /// the command line was entered, what follows is its output (C)
+    OutputStart,
+    /// the command finished, with its exit status if the shell told (D)
+    CommandFinished { exit_code: Option<i32> },
+}
+
+const BASH: &str = r#"__pty_exec_prompt() {
+    local ret=$?
+    # every prompt but the first follows a command line, possibly an empty one
+    if [ -n "$__pty_exec_ran" ]; then printf '\033]133;D;%s\007' "$ret"; fi
+    __pty_exec_ran=1
+    printf '\033]7;file://%s%s\007' "$HOSTNAME" "$PWD"
+    printf '\033]2;%s\007' "${PWD/#$HOME/\~}"
+    return $ret
+}
+PROMPT_COMMAND="__pty_exec_prompt${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+PS1="\[\033]133;A${PTY_EXEC_SESSION_ID:+;aid=$PTY_EXEC_SESSION_ID}\007\]$PS1\[\033]133;B\007\]"
+PS0="\033]133;C\007$PS0"
+"#;
+
+const ZSH: &str = r#"autoload -Uz add-zsh-hook
+__pty_exec_precmd() {
+    local ret=$?
+    if [[ -n $__pty_exec_ran ]]; then printf '\033]133;D;%s\007' "$ret"; fi
+    __pty_exec_ran=
+    printf '\033]7;file://%s%s\007' "$HOST" "$PWD"
+    printf '\033]2;%s\007' "${(%):-%~}"
+    printf '\033]133;A%s\007' "${PTY_EXEC_SESSION_ID:+;aid=$PTY_EXEC_SESSION_ID}"
+}
+__pty_exec_preexec() {
+    __pty_exec_ran=1
+    printf '\033]2;%s\007' "$1"
+    printf '\033]133;C\007'
+}
+add-zsh-hook precmd __pty_exec_precmd
+add-zsh-hook preexec __pty_exec_preexec
+PS1="$PS1%{"$'\033]133;B\007'"%}"
+"#;
+
+const FISH: &str = r#"function __pty_exec_prompt --on-event fish_prompt
+    set -l last_status $status
+    if set -q __pty_exec_ran; printf '\033]133;D;%s\007' $last_status; end
+    set -e __pty_exec_ran
+    printf '\033]7;file://%s%s\007' (hostname) "$PWD"
+    if set -q PTY_EXEC_SESSION_ID; printf '\033]133;A;aid=%s\007' $PTY_EXEC_SESSION_ID; else; printf '\033]133;A\007'; end
+end
+function __pty_exec_preexec --on-event fish_preexec
+    set -g __pty_exec_ran 1
+    printf '\033]133;C\007'
+end
+"#;
+
+/// the snippet for shell, to be sourced at the end of its rc file
+pub fn snippet_for(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+    }
+}
+
+/**
+ * Makes command source the snippet for shell after the user's own rc files,
+ * bash gets an --rcfile, zsh a ZDOTDIR and fish an --init-command
+ */
+pub(crate) fn inject(shell: Shell, home: &str, command: &mut Command) -> Result<(), Box<dyn Error>> {
+    let (args, env) = arguments(shell, home)?;
+    command.args(args).envs(env);
+    Ok(())
+}
+
+// arguments and environment variables a shell is started with
+type Arguments = (Vec<OsString>, Vec<(OsString, OsString)>);
+
+/**
+ * Arguments and environment variables inject() adds, for spawning without a Command
+ */
+pub(crate) fn arguments(shell: Shell, home: &str) -> Result<Arguments, Box<dyn Error>> {
+    Ok(match shell {
+        Shell::Bash => (vec!["--rcfile".into(), rc_dir()?.join("bashrc").into()], Vec::new()),
+        Shell::Zsh => {
+            // the zshrc puts the user's ZDOTDIR back before sourcing their own
+            let zdotdir = std::env::var_os("ZDOTDIR").unwrap_or_else(|| home.into());
+            (Vec::new(), vec![("PTY_EXEC_ZDOTDIR".into(), zdotdir), ("ZDOTDIR".into(), rc_dir()?.into())])
+        },
+        Shell::Fish => (vec!["--init-command".into(), FISH.into()], Vec::new()),
+    })
+}
+
+/**
+ * Directory holding the rc files of this process, created once on first use,
+ * a fresh name is picked so nobody else can have put files in it
+ */
+fn rc_dir() -> Result<PathBuf, Box<dyn Error>> {
+    static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    let mut dir = DIR.lock().unwrap();
+    if let Some(dir) = dir.as_ref() {
+        return Ok(dir.clone());
+    }
+
+    for n in 0.. {
+        let path = std::env::temp_dir().join(format!("pty-exec-{}-{n}", process::id()));
+        // readable by all, the shell may run as another user
+        match DirBuilder::new().mode(0o755).create(&path) {
+            Ok(()) => {
+                fs::write(path.join("bashrc"), format!("[ -f ~/.bashrc ] && . ~/.bashrc\n{BASH}"))?;
+                fs::write(path.join(".zshenv"), "[[ -f $PTY_EXEC_ZDOTDIR/.zshenv ]] && . $PTY_EXEC_ZDOTDIR/.zshenv\n")?;
+                fs::write(path.join(".zshrc"), format!(
+                    "ZDOTDIR=$PTY_EXEC_ZDOTDIR\nunset PTY_EXEC_ZDOTDIR\n[[ -f $ZDOTDIR/.zshrc ]] && . $ZDOTDIR/.zshrc\n{ZSH}"
+                ))?;
+                return Ok(dir.insert(path).clone());
+            },
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::id::PtyId;
+    use crate::unix::shell::{ShellUser, UserLookup};
+    use crate::{Pty, PtyHandler};
+
+    enum Event {
+        Cwd(PathBuf),
+        Mark(ShellMark),
+        Tag(String),
+    }
+
+    struct Events(mpsc::Sender<Event>);
+
+    impl PtyHandler for Events {
+        fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+        fn on_cwd(&mut self, _id: PtyId, cwd: PathBuf) {
+            let _ = self.0.send(Event::Cwd(cwd));
+        }
+
+        fn on_shell_mark(&mut self, _id: PtyId, mark: ShellMark) {
+            let _ = self.0.send(Event::Mark(mark));
+        }
+
+        fn on_session_tag(&mut self, _id: PtyId, tag: String) {
+            let _ = self.0.send(Event::Tag(tag));
+        }
+    }
+
+    #[test]
+    fn bash_integration() -> Result<(), Box<dyn Error>> {
+        assert_eq!(Shell::from_path("/usr/bin/-zsh"), Some(Shell::Zsh));
+        assert_eq!(Shell::from_path("/bin/sh"), None);
+
+        let (tx, rx) = mpsc::channel();
+        let user = ShellUser { shell: "/bin/bash".into(), ..ShellUser::from_env(UserLookup::Env, "/bin/sh") };
+        let pty = Pty::builder().user(user).shell_integration(true).tag("build-42").spawn_handler(Events(tx))?;
+        let wait_for = |expected: &dyn Fn(&Event) -> bool| {
+            while let Ok(event) = rx.recv_timeout(Duration::from_secs(10)) {
+                if expected(&event) { return true }
+            }
+            false
+        };
+
+        assert!(wait_for(&|event| matches!(event, Event::Tag(tag) if tag == "build-42")));
+        assert!(wait_for(&|event| matches!(event, Event::Mark(ShellMark::CommandStart))));
+        pty.write("cd /tmp && false\r")?;
+        assert!(wait_for(&|event| matches!(event, Event::Mark(ShellMark::OutputStart))));
+        assert!(wait_for(&|event| matches!(event, Event::Mark(ShellMark::CommandFinished { exit_code: Some(1) }))));
+        assert!(wait_for(&|event| matches!(event, Event::Cwd(cwd) if cwd == Path::new("/tmp"))));
+
+        let history = pty.command_history()?;
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].text.as_str(), history[0].exit_code), ("cd /tmp && false", Some(1)));
+        pty.shutdown()?;
+
+        assert!(Pty::builder().tag("build 42").spawn(|_id, _res| {}, |_id| {}).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn polled_cwd() -> Result<(), Box<dyn Error>> {
+        let (tx, rx) = mpsc::channel();
+        let pty = Pty::builder().cwd("/").poll_cwd(Duration::from_millis(20)).spawn_handler(Events(tx))?;
+        let wait_for = |expected: &Path| {
+            while let Ok(event) = rx.recv_timeout(Duration::from_secs(10)) {
+                if matches!(event, Event::Cwd(cwd) if cwd == expected) { return true }
+            }
+            false
+        };
+
+        assert!(wait_for(Path::new("/")));
+        pty.write("cd /tmp\r")?;
+        assert!(wait_for(Path::new("/tmp")));
+        assert_eq!(pty.cwd()?.as_deref(), Some(Path::new("/tmp")));
+        // the foreground process is what counts, the shell waiting for it stays where it is
+        pty.write("sh -c 'cd /usr && sleep 2'\r")?;
+        assert!(wait_for(Path::new("/usr")));
+        assert!(wait_for(Path::new("/tmp")));
+        pty.shutdown()?;
+        Ok(())
+    }
+}
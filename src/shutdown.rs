@@ -0,0 +1,194 @@
+//! Shutting down every pty of the process at once, e.g. before a daemon exits, and the steps
+//! of shutting down one, see PtyHandler::on_shutdown()
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::shutdown::ShutdownPolicy;
+//!
+//! let report = pty_exec::shutdown_all(ShutdownPolicy::Graceful, Duration::from_secs(5));
+//! if !report.stuck.is_empty() {
+//!     eprintln!("{} sessions did not exit", report.stuck.len());
+//! }
+//! ```
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::sys::signal::Signal;
+use crate::audit::{self, AuditAction};
+use crate::id::PtyId;
+use crate::registry::{self, Session};
+use crate::unix;
+
+// how long a session SIGKILLed at the deadline gets to be reaped
+const KILL_GRACE: Duration = Duration::from_millis(500);
+
+/// How shutdown_all() ends each session, whatever is left at the deadline is sent SIGKILL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ShutdownPolicy {
+    /// like Pty::shutdown(), the shutdown input then SIGHUP, SIGTERM and SIGKILL, all sessions at once
+    #[default]
+    Graceful,
+    /// SIGHUP the process group, like closing a terminal window
+    HangUp,
+    /// SIGKILL the process group
+    Kill,
+}
+
+/// A step of Pty::shutdown(), passed to PtyHandler::on_shutdown() as it is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ShutdownProgress {
+    /// the shutdown input was written, see PtyBuilder::shutdown_input()
+    SentInput,
+    /// the process group was sent SIGHUP
+    SentHup,
+    /// the process group was sent SIGTERM
+    SentTerm,
+    /// the process group was sent SIGKILL
+    SentKill,
+    /// the child was reaped, forced if a signal had been sent, right before PtyHandler::on_exit()
+    Reaped { forced: bool },
+}
+
+/// How long Pty::shutdown() waits for the child to exit after each step before taking the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ShutdownTimeouts {
+    /// after the shutdown input
+    pub input: Duration,
+    /// after SIGHUP
+    pub hup: Duration,
+    /// after SIGTERM
+    pub term: Duration,
+    /// after SIGKILL, shutdown fails if the child has not exited by then
+    pub kill: Duration,
+}
+
+impl ShutdownTimeouts {
+    /// timeout after every step
+    pub fn uniform(timeout: Duration) -> ShutdownTimeouts {
+        ShutdownTimeouts { input: timeout, hup: timeout, term: timeout, kill: timeout }
+    }
+}
+
+/// a second after every step
+impl Default for ShutdownTimeouts {
+    fn default() -> ShutdownTimeouts {
+        ShutdownTimeouts::uniform(Duration::from_secs(1))
+    }
+}
+
+/// Outcome of shutdown_all()
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ShutdownReport {
+    /// sessions that exited as the policy asked
+    pub exited: Vec<PtyId>,
+    /// sessions that were still running at the deadline and exited on SIGKILL
+    pub killed: Vec<PtyId>,
+    /// sessions that did not even exit on SIGKILL, e.g. stuck in uninterruptible sleep
+    pub stuck: Vec<PtyId>,
+}
+
+/// Ends every live pty according to policy and waits until their reader threads have finished,
+/// at most timeout plus a moment for the sessions SIGKILLed at the deadline
+pub fn shutdown_all(policy: ShutdownPolicy, timeout: Duration) -> ShutdownReport {
+    shutdown_sessions(registry::sessions(), policy, timeout)
+}
+
+pub(crate) fn shutdown_sessions(sessions: Vec<Arc<Session>>, policy: ShutdownPolicy, timeout: Duration) -> ShutdownReport {
+    let deadline = Instant::now() + timeout;
+
+    for session in &sessions {
+        audit::emit(session, None, AuditAction::SessionKilled);
+        match policy {
+            ShutdownPolicy::Graceful => {
+                // each shutdown blocks while escalating, so they run side by side
+                let session = session.clone();
+                let _ = thread::Builder::new().name(format!("pty-exec/shutdown={}", session.id())).spawn(move || {
+                    let _ = unix::pty::shutdown(&session);
+                });
+            },
+            ShutdownPolicy::HangUp => { let _ = session.with_fd(|_| unix::pty::signal(session.child(), Signal::SIGHUP)); },
+            ShutdownPolicy::Kill => { let _ = session.with_fd(|_| unix::pty::signal(session.child(), Signal::SIGKILL)); },
+        }
+    }
+
+    let mut report = ShutdownReport::default();
+    for session in sessions {
+        // the reader thread marks the session exited last thing before it ends
+        if session.wait_exited(deadline.saturating_duration_since(Instant::now())) {
+            report.exited.push(session.id());
+            continue;
+        }
+        let _ = session.with_fd(|_| unix::pty::signal(session.child(), Signal::SIGKILL));
+        match session.wait_exited(KILL_GRACE) {
+            true => report.killed.push(session.id()),
+            false => report.stuck.push(session.id()),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::sync::Mutex;
+    use crate::{test_util, Pty, PtyHandler};
+
+    #[derive(Clone, Default)]
+    struct Steps(Arc<Mutex<Vec<ShutdownProgress>>>);
+
+    impl PtyHandler for Steps {
+        fn on_output(&mut self, _id: PtyId, _output: String) {}
+
+        fn on_shutdown(&mut self, _id: PtyId, progress: ShutdownProgress) {
+            self.0.lock().unwrap().push(progress);
+        }
+    }
+
+    #[test]
+    fn progress() -> Result<(), Box<dyn Error>> {
+        let steps = Steps::default();
+        let pty = Pty::builder().scrollback(0x10000).spawn_handler(steps.clone())?;
+        pty.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        assert_eq!(*steps.0.lock().unwrap(), [ShutdownProgress::SentInput, ShutdownProgress::Reaped { forced: false }]);
+
+        // an interactive shell ignores SIGTERM, with SIGHUP trapped only SIGKILL is left
+        let steps = Steps::default();
+        let timeouts = ShutdownTimeouts { hup: Duration::from_millis(100), term: Duration::from_millis(100), ..Default::default() };
+        let pty = Pty::builder().scrollback(0x10000).shutdown_input("").shutdown_timeouts(timeouts).spawn_handler(steps.clone())?;
+        pty.write("trap '' HUP; echo \"trapped-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&pty, "trapped-2", Duration::from_secs(10))?;
+        pty.shutdown()?;
+        assert_eq!(*steps.0.lock().unwrap(), [
+            ShutdownProgress::SentHup,
+            ShutdownProgress::SentTerm,
+            ShutdownProgress::SentKill,
+            ShutdownProgress::Reaped { forced: true },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn graceful_then_killed() -> Result<(), Box<dyn Error>> {
+        let polite = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        polite.write("echo \"ready-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&polite, "ready-2", Duration::from_secs(10))?;
+        // ignores the shutdown input and the signals before SIGKILL
+        let stubborn = Pty::builder().scrollback(0x10000).shutdown_input("").spawn(|_id, _res| {}, |_id| {})?;
+        stubborn.write("trap '' HUP TERM; echo \"trapped-$((1 + 1))\"\r")?;
+        test_util::wait_for_output(&stubborn, "trapped-2", Duration::from_secs(10))?;
+
+        // only the sessions of this test, other tests run in the same process
+        let sessions = vec![registry::get(polite.id())?, registry::get(stubborn.id())?];
+        let report = shutdown_sessions(sessions, ShutdownPolicy::Graceful, Duration::from_millis(500));
+        assert_eq!(report, ShutdownReport { exited: vec![polite.id()], killed: vec![stubborn.id()], stuck: vec![] });
+        assert!(!polite.is_alive() && !stubborn.is_alive());
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! The two halves of a pty, see Pty::split(), each can be moved to its own thread or component
+//! and kept for as long as it is needed, independent of the other
+//! ```rust
+//! use std::io::{BufRead, BufReader, Write};
+//! use std::thread;
+//! use pty_exec::Pty;
+//!
+//! let (reader, mut writer) = Pty::spawn(|_id, _res| {}, |_id| {})?.split()?;
+//! let lines = thread::spawn(move || {
+//!     BufReader::new(reader).lines().map_while(Result::ok).take_while(|line| !line.contains("done-2")).count()
+//! });
+//!
+//! writer.write_all(b"echo \"done-$((1 + 1))\"\r")?;
+//! lines.join().unwrap();
+//! writer.write_all(b"exit\r")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::str;
+use std::sync::mpsc::{self, Receiver};
+use nix::sys::signal::Signal;
+use crate::clients::{ClientEvent, ClientId};
+use crate::error::PtyError;
+use crate::id::PtyId;
+use crate::input::Key;
+use crate::unix::window::WindowSize;
+use crate::{Pty, Terminal};
+
+/// Reading half of a pty, receives the output from the moment the pty was split, as an
+/// attached client, output it does not read is queued, the client is detached on drop
+pub struct PtyReader {
+    pty: Pty,
+    client: ClientId,
+    events: Receiver<ClientEvent>,
+    // output received but not read yet through io::Read
+    unread: Vec<u8>,
+}
+
+/// Writing half of a pty, write and control the child, see the Terminal trait
+pub struct PtyWriter {
+    pty: Pty,
+    // the start of a char split across writes through io::Write
+    pending: Vec<u8>,
+}
+
+impl Pty {
+    /// splits the pty into a reading and a writing half, the handler or callbacks the pty was
+    /// spawned with keep getting everything as before, see PtyReader::reunite()
+    pub fn split(self) -> Result<(PtyReader, PtyWriter), Box<dyn Error>> {
+        let (tx, events) = mpsc::channel();
+        let client = self.attach(move |event| { let _ = tx.send(event); })?;
+        let reader = PtyReader { pty: Pty { id: self.id }, client, events, unread: Vec::new() };
+        Ok((reader, PtyWriter { pty: self, pending: Vec::new() }))
+    }
+}
+
+impl PtyReader {
+    pub fn id(&self) -> PtyId {
+        self.pty.id()
+    }
+
+    /// the next output, waits until there is some, `None` once the pty died and all its output was received
+    pub fn recv(&mut self) -> Option<String> {
+        if !self.unread.is_empty() {
+            let unread = std::mem::take(&mut self.unread);
+            // left over by io::Read, which may have stopped in the middle of a char
+            return Some(String::from_utf8_lossy(&unread).into_owned());
+        }
+        loop {
+            match self.events.recv() {
+                Ok(ClientEvent::Output(output)) => return Some(output),
+                Ok(ClientEvent::Resized(..) | ClientEvent::Message(_) | ClientEvent::Echo { .. }) => continue,
+                Ok(ClientEvent::Exited) | Err(_) => return None,
+            }
+        }
+    }
+
+    /// puts the halves back together, fails if writer is the half of another pty
+    pub fn reunite(self, writer: PtyWriter) -> Result<Pty, Box<dyn Error>> {
+        if writer.pty.id() != self.pty.id() {
+            let msg = format!("{} and {} are halves of different ptys", self.pty.id(), writer.pty.id());
+            return Err(Box::new(PtyError::with_kind(msg, io::ErrorKind::InvalidInput)));
+        }
+        Ok(writer.pty)
+    }
+}
+
+/// reads the output as bytes, Ok(0) once the pty died
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.unread.is_empty() {
+            match self.recv() {
+                Some(output) => self.unread = output.into_bytes(),
+                None => return Ok(0)
+            }
+        }
+        let len = buf.len().min(self.unread.len());
+        buf[..len].copy_from_slice(&self.unread[..len]);
+        self.unread.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Drop for PtyReader {
+    fn drop(&mut self) {
+        let _ = self.pty.detach(self.client);
+    }
+}
+
+impl PtyWriter {
+    pub fn id(&self) -> PtyId {
+        self.pty.id()
+    }
+
+    /// see Pty::write()
+    pub fn write_str(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        self.pty.write(s)
+    }
+
+    /// see Pty::write_raw()
+    pub fn write_raw(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        self.pty.write_raw(s)
+    }
+
+    /// see Pty::write_key()
+    pub fn write_key(&self, key: Key) -> Result<(), Box<dyn Error>> {
+        self.pty.write_key(key)
+    }
+
+    /// see Pty::close_stdin()
+    pub fn close_stdin(&self) -> Result<(), Box<dyn Error>> {
+        self.pty.close_stdin()
+    }
+
+    /// see Pty::shutdown()
+    pub fn shutdown(&self) -> Result<(), Box<dyn Error>> {
+        self.pty.shutdown()
+    }
+}
+
+/// writes bytes as they are, like Pty::write_raw(), they must be UTF-8 but a char may be split
+/// across writes
+impl Write for PtyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let valid = match str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(_) => {
+                self.pending.clear();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Input is not valid UTF-8"));
+            }
+        };
+        if valid > 0 {
+            let rest = self.pending.split_off(valid);
+            let input = String::from_utf8(std::mem::replace(&mut self.pending, rest))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.pty.write_raw(&input).map_err(|err| io::Error::from(PtyError::copy_of(err.as_ref())))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Terminal for PtyWriter {
+    fn id(&self) -> PtyId {
+        self.pty.id()
+    }
+
+    fn write(&self, s: &str) -> Result<(), Box<dyn Error>> {
+        self.pty.write(s)
+    }
+
+    fn resize(&self, window_size: WindowSize) -> Result<(), Box<dyn Error>> {
+        self.pty.resize(window_size)
+    }
+
+    fn signal(&self, signal: Signal) -> Result<(), Box<dyn Error>> {
+        self.pty.signal(signal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn halves() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::spawn(|_id, _res| {}, |_id| {})?;
+        let id = pty.id();
+        let (mut reader, mut writer) = pty.split()?;
+        assert_eq!((reader.id(), writer.id()), (id, id));
+
+        // é split across two writes
+        writer.write_all(b"echo \"caf\xc3")?;
+        writer.write_all(b"\xa9-$((1 + 1))\"\r")?;
+        assert!(writer.write_all(b"\xff").is_err());
+
+        let output = thread::spawn(move || {
+            let mut output = String::new();
+            while !output.contains("café-2\r\n") {
+                output.push_str(&reader.recv()?);
+            }
+            Some(reader)
+        });
+        let reader = output.join().unwrap().unwrap();
+
+        let pty = reader.reunite(writer)?;
+        assert_eq!(pty.id(), id);
+        pty.write("exit\r")?;
+        Ok(())
+    }
+}
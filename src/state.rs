@@ -0,0 +1,276 @@
+//! Metadata of a session in a form that outlives the version of the crate that wrote it, e.g.
+//! for a new daemon binary adopting the sessions of the old one, see Pty::snapshot() and the
+//! migrate module, the live process and its pty are not part of it
+//! state is tagged with the epoch of its format and the oldest epoch able to read it, fields
+//! are tagged too, a reader skips the fields it does not know, so an epoch adding fields
+//! stays readable by the epochs before it, one changing what a field means raises the oldest
+//! reader, state a reader cannot read fails with a StateError saying why
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::state::SessionSnapshot;
+//!
+//! let pty = Pty::builder().scrollback(0x10000).tag("build-42").spawn(|_id, _res| {}, |_id| {})?;
+//! let state = pty.snapshot()?.encode();
+//! let snapshot = SessionSnapshot::decode(&state)?;
+//! assert_eq!(snapshot.tag.as_deref(), Some("build-42"));
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, UNIX_EPOCH};
+use crate::history::CommandRecord;
+use crate::registry::Session;
+
+/// Epoch of the state this version writes
+pub const EPOCH: u32 = 1;
+
+/// Oldest epoch of state this version reads
+pub const MIN_EPOCH: u32 = 1;
+
+// every state starts with it, so anything else is told apart
+const MAGIC: &[u8; 4] = b"PTYX";
+
+// epoch 1
+const CHILD_PID: u16 = 1;
+const SHELL: u16 = 2;
+const TAG: u16 = 3;
+const TITLE: u16 = 4;
+const ICON_NAME: u16 = 5;
+const SCROLLBACK: u16 = 6;
+const COMMAND: u16 = 7;
+
+/// Metadata of a session, see the state module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    pub child_pid: i32,
+    pub shell: String,
+    pub tag: Option<String>,
+    pub title: Option<String>,
+    pub icon_name: Option<String>,
+    pub scrollback: String,
+    pub command_history: Vec<CommandRecord>,
+}
+
+/// Why state could not be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// written by a newer version whose state needs a reader of oldest_reader or later
+    TooNew { epoch: u32, oldest_reader: u32 },
+    /// written by a version older than MIN_EPOCH
+    TooOld { epoch: u32 },
+    /// not session state, or cut short
+    Malformed(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::TooNew { epoch, oldest_reader } => {
+                write!(f, "Session state of epoch {epoch} needs a reader of epoch {oldest_reader} or later, this one is {EPOCH}")
+            },
+            StateError::TooOld { epoch } => write!(f, "Session state of epoch {epoch} is older than epoch {MIN_EPOCH}, the oldest read"),
+            StateError::Malformed(reason) => write!(f, "Malformed session state: {reason}"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+impl SessionSnapshot {
+    /**
+     * Snapshot of session as it is now
+     */
+    pub(crate) fn of(session: &Session) -> SessionSnapshot {
+        let (title, icon_name) = {
+            let titles = session.titles();
+            (titles.title.clone(), titles.icon_name.clone())
+        };
+        SessionSnapshot {
+            child_pid: session.child().as_raw(),
+            shell: session.shell().to_owned(),
+            tag: session.config().tag.clone(),
+            title,
+            icon_name,
+            scrollback: session.scrollback().all().into_owned(),
+            command_history: session.history().records(),
+        }
+    }
+
+    /// the state in the format of EPOCH
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend(EPOCH.to_be_bytes());
+        // nothing changed its meaning yet
+        buf.extend(1u32.to_be_bytes());
+
+        put_field(&mut buf, CHILD_PID, &self.child_pid.to_be_bytes());
+        put_field(&mut buf, SHELL, self.shell.as_bytes());
+        let optional = [(TAG, &self.tag), (TITLE, &self.title), (ICON_NAME, &self.icon_name)];
+        for (field, value) in optional {
+            if let Some(value) = value {
+                put_field(&mut buf, field, value.as_bytes());
+            }
+        }
+        put_field(&mut buf, SCROLLBACK, self.scrollback.as_bytes());
+        for record in &self.command_history {
+            let mut value = Vec::new();
+            put_time(&mut value, record.started_at.duration_since(UNIX_EPOCH).unwrap_or_default());
+            put_time(&mut value, record.duration);
+            match record.exit_code {
+                Some(code) => {
+                    value.push(1);
+                    value.extend(code.to_be_bytes());
+                },
+                None => value.push(0),
+            }
+            value.extend(record.text.as_bytes());
+            put_field(&mut buf, COMMAND, &value);
+        }
+        buf
+    }
+
+    /// state written by encode() of this or another version
+    pub fn decode(mut state: &[u8]) -> Result<SessionSnapshot, StateError> {
+        if take(&mut state, 4)? != MAGIC {
+            return Err(StateError::Malformed("not session state".to_owned()));
+        }
+        let epoch = take_u32(&mut state)?;
+        let oldest_reader = take_u32(&mut state)?;
+        if oldest_reader > EPOCH {
+            return Err(StateError::TooNew { epoch, oldest_reader });
+        }
+        if epoch < MIN_EPOCH {
+            return Err(StateError::TooOld { epoch });
+        }
+
+        let (mut child_pid, mut shell) = (None, None);
+        let mut snapshot = SessionSnapshot {
+            child_pid: 0,
+            shell: String::new(),
+            tag: None,
+            title: None,
+            icon_name: None,
+            scrollback: String::new(),
+            command_history: Vec::new(),
+        };
+        while !state.is_empty() {
+            let field = u16::from_be_bytes(take(&mut state, 2)?.try_into().unwrap());
+            let len = take_u32(&mut state)? as usize;
+            let mut value = take(&mut state, len)?;
+            match field {
+                CHILD_PID => child_pid = Some(take_u32(&mut value)? as i32),
+                SHELL => shell = Some(text(value)?),
+                TAG => snapshot.tag = Some(text(value)?),
+                TITLE => snapshot.title = Some(text(value)?),
+                ICON_NAME => snapshot.icon_name = Some(text(value)?),
+                SCROLLBACK => snapshot.scrollback = text(value)?,
+                COMMAND => {
+                    let started_at = UNIX_EPOCH.checked_add(take_time(&mut value)?)
+                        .ok_or_else(|| StateError::Malformed("time out of range".to_owned()))?;
+                    let duration = take_time(&mut value)?;
+                    let exit_code = match take(&mut value, 1)?[0] {
+                        0 => None,
+                        _ => Some(take_u32(&mut value)? as i32),
+                    };
+                    snapshot.command_history.push(CommandRecord { text: text(value)?, started_at, duration, exit_code });
+                },
+                // added by a later epoch
+                _ => {}
+            }
+        }
+        match (child_pid, shell) {
+            (Some(child_pid), Some(shell)) => Ok(SessionSnapshot { child_pid, shell, ..snapshot }),
+            _ => Err(StateError::Malformed("child or shell missing".to_owned())),
+        }
+    }
+}
+
+fn put_field(buf: &mut Vec<u8>, field: u16, value: &[u8]) {
+    buf.extend(field.to_be_bytes());
+    buf.extend((value.len() as u32).to_be_bytes());
+    buf.extend(value);
+}
+
+fn put_time(buf: &mut Vec<u8>, time: Duration) {
+    buf.extend(time.as_secs().to_be_bytes());
+    buf.extend(time.subsec_nanos().to_be_bytes());
+}
+
+fn take<'a>(state: &mut &'a [u8], n: usize) -> Result<&'a [u8], StateError> {
+    if state.len() < n {
+        return Err(StateError::Malformed("cut short".to_owned()));
+    }
+    let (taken, rest) = state.split_at(n);
+    *state = rest;
+    Ok(taken)
+}
+
+fn take_u32(state: &mut &[u8]) -> Result<u32, StateError> {
+    Ok(u32::from_be_bytes(take(state, 4)?.try_into().unwrap()))
+}
+
+fn take_time(state: &mut &[u8]) -> Result<Duration, StateError> {
+    let secs = u64::from_be_bytes(take(state, 8)?.try_into().unwrap());
+    match take_u32(state)? {
+        nanos @ 0..=999_999_999 => Ok(Duration::new(secs, nanos)),
+        _ => Err(StateError::Malformed("time out of range".to_owned())),
+    }
+}
+
+fn text(value: &[u8]) -> Result<String, StateError> {
+    String::from_utf8(value.to_vec()).map_err(|_| StateError::Malformed("text not UTF-8".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compatibility() {
+        let snapshot = SessionSnapshot {
+            child_pid: 42,
+            shell: "/bin/sh".to_owned(),
+            tag: Some("build-42".to_owned()),
+            title: None,
+            icon_name: Some("sh".to_owned()),
+            scrollback: "$ make\r\n".to_owned(),
+            command_history: vec![CommandRecord {
+                text: "make".to_owned(),
+                started_at: UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+                duration: Duration::from_millis(1500),
+                exit_code: Some(2),
+            }],
+        };
+        let state = snapshot.encode();
+        assert_eq!(SessionSnapshot::decode(&state), Ok(snapshot.clone()));
+
+        // a later epoch adding a field is still read
+        let mut later = state.clone();
+        later[4..8].copy_from_slice(&(EPOCH + 1).to_be_bytes());
+        put_field(&mut later, 0x100, b"new");
+        assert_eq!(SessionSnapshot::decode(&later), Ok(snapshot));
+
+        // one changing a field is not
+        let mut changed = later.clone();
+        changed[8..12].copy_from_slice(&(EPOCH + 1).to_be_bytes());
+        assert_eq!(SessionSnapshot::decode(&changed), Err(StateError::TooNew { epoch: EPOCH + 1, oldest_reader: EPOCH + 1 }));
+
+        let mut old = state.clone();
+        old[4..8].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(SessionSnapshot::decode(&old), Err(StateError::TooOld { epoch: 0 }));
+        assert!(matches!(SessionSnapshot::decode(&state[..state.len() - 1]), Err(StateError::Malformed(_))));
+        assert!(matches!(SessionSnapshot::decode(b"{}"), Err(StateError::Malformed(_))));
+
+        // times that do not fit
+        for (secs, nanos) in [(0, 1_000_000_000), (u64::MAX, 0)] {
+            let mut time = Vec::new();
+            time.extend(secs.to_be_bytes());
+            time.extend(u32::to_be_bytes(nanos));
+            let mut bad = state.clone();
+            put_field(&mut bad, COMMAND, &[&time[..], &time, &[0], b"make"].concat());
+            assert_eq!(SessionSnapshot::decode(&bad), Err(StateError::Malformed("time out of range".to_owned())));
+        }
+    }
+}
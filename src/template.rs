@@ -0,0 +1,245 @@
+//! Session templates, reusable descriptions of a kind of session, e.g. a "Python REPL" pane or
+//! "SSH to prod", with the program to run and what to do once it is ready, to the output and
+//! input and once it exits, each spawn() of a template is a session of its own
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::filter;
+//! use pty_exec::template::{ExitAction, SessionTemplate};
+//!
+//! let repl = SessionTemplate::new("python-repl")
+//!     .program(["python3", "-q"])
+//!     .env("PYTHONDONTWRITEBYTECODE", "1")
+//!     .on_ready("import os")
+//!     .filter(|| filter::from_fn("redact", |_id, _direction, chunk: String| Some(chunk.replace("hunter2", "*******"))))
+//!     .on_exit(ExitAction::Retain(Duration::from_secs(60)));
+//! let first = repl.spawn(|_id, _res| {}, |_id| {})?;
+//! let second = repl.spawn(|_id, _res| {}, |_id| {})?;
+//! # first.shutdown()?;
+//! # second.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::builder::PtyBuilder;
+use crate::filter::Filter;
+use crate::handler::{Callbacks, PtyHandler};
+use crate::id::PtyId;
+use crate::recording::Recording;
+use crate::registry;
+use crate::unix::window::WindowSize;
+use crate::Pty;
+
+type MakeFilter = Arc<dyn Fn() -> Box<dyn Filter> + Send + Sync>;
+
+// tells apart the recordings of sessions spawned within the same second
+static RECORDINGS: AtomicU64 = AtomicU64::new(0);
+
+/// A kind of session spawned any number of times, see the template module
+#[derive(Clone)]
+pub struct SessionTemplate {
+    name: String,
+    program: Vec<String>,
+    env: Vec<(String, String)>,
+    cwd: Option<PathBuf>,
+    window_size: Option<WindowSize>,
+    on_ready: Vec<String>,
+    on_exit: ExitAction,
+    filters: Vec<MakeFilter>,
+    record_to: Option<PathBuf>,
+}
+
+/// What happens once the child of a session spawned from a template exits
+#[derive(Clone, Default)]
+pub enum ExitAction {
+    /// the session goes once the child is reaped
+    #[default]
+    Close,
+    /// the session is kept for period, see PtyBuilder::retain_exited()
+    Retain(Duration),
+    /// the function is called with the id of the session once the child was reaped, on the
+    /// thread that read the pty
+    Call(Arc<dyn Fn(PtyId) + Send + Sync>),
+}
+
+impl fmt::Debug for ExitAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitAction::Close => f.write_str("Close"),
+            ExitAction::Retain(period) => f.debug_tuple("Retain").field(period).finish(),
+            ExitAction::Call(_) => f.write_str("Call(..)"),
+        }
+    }
+}
+
+impl SessionTemplate {
+    /// a template running the user's shell, name goes into the names of recordings
+    pub fn new(name: impl Into<String>) -> SessionTemplate {
+        SessionTemplate {
+            name: name.into(),
+            program: Vec::new(),
+            env: Vec::new(),
+            cwd: None,
+            window_size: None,
+            on_ready: Vec::new(),
+            on_exit: ExitAction::Close,
+            filters: Vec::new(),
+            record_to: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// program and its arguments, spawned in place of the user's shell, see
+    /// PtyBuilder::program(), the user's shell by default
+    pub fn program<I, S>(mut self, program: I) -> SessionTemplate
+        where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.program = program.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// see PtyBuilder::env()
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> SessionTemplate {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// see PtyBuilder::cwd()
+    pub fn cwd(mut self, dir: impl AsRef<Path>) -> SessionTemplate {
+        self.cwd = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// see PtyBuilder::window_size()
+    pub fn window_size(mut self, window_size: WindowSize) -> SessionTemplate {
+        self.window_size = Some(window_size);
+        self
+    }
+
+    /// a command written to the program or shell once it is ready, after the ones added before,
+    /// see PtyBuilder::startup_commands()
+    pub fn on_ready(mut self, command: impl Into<String>) -> SessionTemplate {
+        self.on_ready.push(command.into());
+        self
+    }
+
+    pub fn on_exit(mut self, action: ExitAction) -> SessionTemplate {
+        self.on_exit = action;
+        self
+    }
+
+    /// a filter made by make_filter for every session, after the filters added before, see
+    /// PtyBuilder::filter()
+    pub fn filter<F, M>(mut self, make_filter: M) -> SessionTemplate
+        where F: Filter + 'static, M: Fn() -> F + Send + Sync + 'static
+    {
+        self.filters.push(Arc::new(move || Box::new(make_filter())));
+        self
+    }
+
+    /// record every session to a file of its own in dir, named after the template, the time
+    /// it was spawned and a counter, not recorded by default, see PtyBuilder::record()
+    pub fn record_to(mut self, dir: impl AsRef<Path>) -> SessionTemplate {
+        self.record_to = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// stop recording the sessions spawned from now on
+    pub fn no_recording(mut self) -> SessionTemplate {
+        self.record_to = None;
+        self
+    }
+
+    /// builder spawning a session of the template, for settings the template has nothing to
+    /// say about, the ExitAction::Call action is only taken by spawn() and spawn_handler()
+    pub fn builder(&self) -> PtyBuilder {
+        let mut builder = PtyBuilder::new();
+        for (key, value) in &self.env {
+            builder = builder.env(key, value);
+        }
+        if let Some(cwd) = &self.cwd {
+            builder = builder.cwd(cwd);
+        }
+        if let Some(window_size) = self.window_size {
+            builder = builder.window_size(window_size);
+        }
+        for make_filter in &self.filters {
+            builder.config.filters.push(make_filter());
+        }
+        if let ExitAction::Retain(period) = self.on_exit {
+            builder = builder.retain_exited(period);
+        }
+        if let Some(dir) = &self.record_to {
+            let spawned = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_secs()).unwrap_or(0);
+            let n = RECORDINGS.fetch_add(1, Ordering::Relaxed);
+            builder = builder.record(Recording::new(dir.join(format!("{}-{spawned}-{n}.cast", self.name))));
+        }
+        builder.program(&self.program).startup_commands(&self.on_ready)
+    }
+
+    /// spawns a session of the template, see PtyBuilder::spawn()
+    pub fn spawn<F, G>(&self, on_read: F, on_death: G) -> Result<Pty, Box<dyn Error>>
+        where
+            F: FnMut(PtyId, Result<String, Box<dyn Error>>) + Send + 'static,
+            G: FnMut(PtyId) + Send + 'static
+    {
+        self.spawn_handler(Callbacks { on_read, on_death })
+    }
+
+    /// spawns a session of the template, everything happening on it is passed to handler
+    pub fn spawn_handler<H: PtyHandler>(&self, handler: H) -> Result<Pty, Box<dyn Error>> {
+        let pty = self.builder().spawn_handler(handler)?;
+        if let ExitAction::Call(f) = &self.on_exit {
+            let f = f.clone();
+            // the child may be gone already, then the hook runs right away
+            match registry::get_any(pty.id()) {
+                Ok(session) => session.on_exited(Box::new(move |id| f(id))),
+                Err(_) => f(pty.id()),
+            }
+        }
+        Ok(pty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Mutex};
+    use crate::filter;
+    use super::*;
+
+    #[test]
+    fn spawn_twice() -> Result<(), Box<dyn Error>> {
+        let (tx, exited) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let template = SessionTemplate::new("greeter")
+            .program(["sh", "-c", "echo \"$GREETING\" hunter2"])
+            .env("GREETING", "templated")
+            .on_ready("true")
+            .filter(|| filter::from_fn("redact", |_id, _direction, chunk: String| Some(chunk.replace("hunter2", "*******"))))
+            .on_exit(ExitAction::Call(Arc::new(move |id| { let _ = tx.lock().unwrap().send(id); })));
+
+        for _ in 0..2 {
+            let output = Arc::new(Mutex::new(String::new()));
+            let output_async = output.clone();
+            let pty = template.spawn(move |_id, res| {
+                if let Ok(s) = res {
+                    output_async.lock().unwrap().push_str(&s);
+                }
+            }, |_id| {})?;
+            assert_eq!(exited.recv_timeout(Duration::from_secs(10))?, pty.id());
+            let output = output.lock().unwrap();
+            assert!(output.contains("templated *******"), "{output:?}");
+            assert!(!output.contains("hunter2"));
+            // spawned as the child, not typed into a shell
+            assert!(!output.contains("exec"), "{output:?}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! Helpers for tests driving a pty, enabled with the `test-util` feature
+//! they wait on the output itself, so tests neither sleep nor break on slow machines
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::Pty;
+//! use pty_exec::test_util::{collect_until_exit, wait_for_output};
+//!
+//! // the scrollback lets wait_for_output() see output that arrived before it was called
+//! let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+//! pty.write("echo \"ready-$((1 + 1))\"\r")?;
+//! wait_for_output(&pty, "ready-2", Duration::from_secs(10))?;
+//!
+//! pty.write("exit\r")?;
+//! let output = collect_until_exit(&pty, Duration::from_secs(10))?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//! stub() spawns STUB_PROGRAM in place of the user's shell, for tests that must pass on
+//! machines without a usable shell, e.g. CI in a minimal container, it echoes each line back
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::test_util::{stub, wait_for_output};
+//!
+//! let pty = stub().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+//! pty.write("ping\n")?;
+//! wait_for_output(&pty, "ping\r\nping", Duration::from_secs(10))?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::error::Error;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+use crate::cancel::CancellationToken;
+use crate::clients::{ClientEvent, ClientId};
+use crate::builder::PtyBuilder;
+use crate::error::PtyError;
+use crate::unix::shell::{ShellUser, UserLookup};
+use crate::Pty;
+
+// events of a client, None is not an event but a wake up
+type Events = (Receiver<Option<ClientEvent>>, Sender<Option<ClientEvent>>);
+
+/// What stub() runs, it writes back what it reads, a line at a time as the tty passes it on
+pub const STUB_PROGRAM: &str = "/bin/cat";
+
+/// A builder running STUB_PROGRAM as the current user in place of their shell, without
+/// fallback shells, the environment says who the user is, the password database is not asked,
+/// Pty::shutdown() ends it with ^D, every other setting is as with PtyBuilder::new()
+pub fn stub() -> PtyBuilder {
+    let user = ShellUser { shell: STUB_PROGRAM.to_owned(), ..ShellUser::from_env(UserLookup::Env, STUB_PROGRAM) };
+    // ^U first, ^D only ends the input at the start of a line
+    PtyBuilder::new().user(user).fallback_shells(Vec::<String>::new()).shutdown_input("\x15\x04")
+}
+
+/// Waits up to timeout for pattern to show up in the output of pty, returns the output up to
+/// and including the match, output retained by the scrollback is searched as well,
+/// without a scrollback only output arriving after the call is seen
+pub fn wait_for_output(pty: &Pty, pattern: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    wait_for_output_cancellable(pty, pattern, timeout, &CancellationToken::new())
+}
+
+/// wait_for_output() giving up with ErrorKind::Interrupted once cancel is cancelled
+pub fn wait_for_output_cancellable(pty: &Pty, pattern: &str, timeout: Duration, cancel: &CancellationToken) -> Result<String, Box<dyn Error>> {
+    let (client, (events, tx)) = subscribe(pty)?;
+    let mut output = pty.scrollback()?;
+
+    let res = {
+        // a None in the channel stands for the cancellation
+        let _waker = cancel.on_cancel(move || { let _ = tx.send(None); });
+        collect(&events, &mut output, timeout, |output| output.contains(pattern))
+    };
+    let _ = pty.detach(client);
+    if cancel.is_cancelled() && !output.contains(pattern) {
+        return Err(Box::new(cancel.error()));
+    }
+    match res? {
+        true => {
+            let end = output.find(pattern).map_or(output.len(), |start| start + pattern.len());
+            output.truncate(end);
+            Ok(output)
+        },
+        false => Err(Box::new(PtyError::with_kind(format!("{pattern:?} not found in output of {}", pty.id()), io::ErrorKind::TimedOut)))
+    }
+}
+
+/// Collects the output of pty until it exits, starting with what the scrollback retains,
+/// fails if it is still alive after timeout or has already exited when called
+pub fn collect_until_exit(pty: &Pty, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let (client, (events, _)) = subscribe(pty)?;
+    let mut output = pty.scrollback()?;
+
+    match collect(&events, &mut output, timeout, |_| false)? {
+        true => Ok(output),
+        false => {
+            let _ = pty.detach(client);
+            Err(Box::new(PtyError::with_kind(format!("{} did not exit", pty.id()), io::ErrorKind::TimedOut)))
+        }
+    }
+}
+
+/**
+ * Attaches a client passing every event to the returned receiver, attaching before reading
+ * the scrollback means output may be seen twice but never missed, the sender is for waking
+ * the receiving end up
+ */
+fn subscribe(pty: &Pty) -> Result<(ClientId, Events), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    let tx_async = tx.clone();
+    let client = pty.attach(move |event| { let _ = tx_async.send(Some(event)); })?;
+    Ok((client, (rx, tx)))
+}
+
+/**
+ * Appends output events to output until done returns true or the pty exits, returns whether
+ * either happened before timeout, a None ends it early
+ */
+fn collect(events: &Receiver<Option<ClientEvent>>, output: &mut String, timeout: Duration, done: impl Fn(&str) -> bool) -> Result<bool, Box<dyn Error>> {
+    let deadline = Instant::now() + timeout;
+
+    while !done(output) {
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Some(ClientEvent::Output(s))) => output.push_str(&s),
+            Ok(Some(ClientEvent::Resized(..) | ClientEvent::Message(_) | ClientEvent::Echo { .. })) => {},
+            Ok(None) => return Ok(false),
+            Ok(Some(ClientEvent::Exited)) | Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(true),
+            Err(mpsc::RecvTimeoutError::Timeout) => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_and_exit() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+
+        pty.write("echo \"first-$((1 + 1))\"\r")?;
+        assert!(wait_for_output(&pty, "first-2", Duration::from_secs(10))?.ends_with("first-2"));
+        assert!(wait_for_output(&pty, "never", Duration::from_millis(100)).is_err());
+
+        let cancel = CancellationToken::new();
+        let cancel_async = cancel.clone();
+        std::thread::spawn(move || cancel_async.cancel());
+        let res = wait_for_output_cancellable(&pty, "never", Duration::from_secs(60), &cancel);
+        assert!(res.is_err_and(|err| PtyError::copy_of(err.as_ref()).kind() == io::ErrorKind::Interrupted));
+
+        pty.write("echo \"last-$((1 + 1))\"; exit\r")?;
+        assert!(collect_until_exit(&pty, Duration::from_secs(10))?.contains("last-2"));
+        Ok(())
+    }
+
+    #[test]
+    fn stub_program() -> Result<(), Box<dyn Error>> {
+        let pty = stub().env("SHELL", "/nonexistent/zsh").scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        assert_eq!(pty.shell()?, STUB_PROGRAM);
+        pty.write("half")?;
+        pty.write(" line\n")?;
+        wait_for_output(&pty, "half line\r\nhalf line\r\n", Duration::from_secs(10))?;
+
+        // not escalated to signals
+        let started = Instant::now();
+        pty.write("left on the line")?;
+        pty.shutdown()?;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        Ok(())
+    }
+}
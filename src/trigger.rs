@@ -0,0 +1,225 @@
+//! Actions run when the output of a pty matches a pattern, see Pty::add_trigger()
+//! patterns are matched against the text of each line with escape sequences removed, as it
+//! arrives, so a prompt waiting for input without a newline is matched too, a match never
+//! spans lines and each part of a line matches a trigger at most once, empty matches are ignored
+//! ```rust
+//! use pty_exec::Pty;
+//! use pty_exec::trigger::{Regex, TriggerAction};
+//!
+//! let pty = Pty::spawn(|_id, _res| {}, |_id| {})?;
+//! // answer yes to every confirmation
+//! pty.add_trigger(Regex::new(r"\[y/N\] $")?, TriggerAction::input("y\r"))?;
+//! // and shout about errors
+//! pty.add_trigger(Regex::new("ERROR: (.*)")?, TriggerAction::callback(|_id, m| eprintln!("{}", m.text)))?;
+//! pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+use crate::id::PtyId;
+
+pub use regex::Regex;
+
+// longer lines are dropped unmatched, the output of `cat` on a binary file has no newlines
+const MAX_LINE_LEN: usize = 0x10000;
+
+/// Identifies a trigger of a pty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TriggerId(u64);
+
+impl fmt::Display for TriggerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trigger-{}", self.0)
+    }
+}
+
+/// A match of a trigger's pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TriggerMatch {
+    pub trigger: TriggerId,
+    /// the matched text
+    pub text: String,
+    /// capture groups of the pattern, None for groups that did not participate
+    pub groups: Vec<Option<String>>,
+}
+
+/// Function run by a TriggerAction::Callback action
+pub type TriggerCallback = Arc<dyn Fn(PtyId, &TriggerMatch) + Send + Sync>;
+
+/// What a trigger does when its pattern matches
+#[derive(Clone)]
+pub enum TriggerAction {
+    /// call a function, on the thread reading the pty
+    Callback(TriggerCallback),
+    /// write input to the pty, as is
+    Input(String),
+    /// pass the match to PtyHandler::on_trigger() under a name
+    Event(String),
+}
+
+impl TriggerAction {
+    pub fn callback<F>(f: F) -> TriggerAction
+        where F: Fn(PtyId, &TriggerMatch) + Send + Sync + 'static
+    {
+        TriggerAction::Callback(Arc::new(f))
+    }
+
+    pub fn input(input: impl Into<String>) -> TriggerAction {
+        TriggerAction::Input(input.into())
+    }
+
+    pub fn event(name: impl Into<String>) -> TriggerAction {
+        TriggerAction::Event(name.into())
+    }
+}
+
+impl fmt::Debug for TriggerAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TriggerAction::Callback(_) => f.write_str("Callback"),
+            TriggerAction::Input(input) => f.debug_tuple("Input").field(input).finish(),
+            TriggerAction::Event(name) => f.debug_tuple("Event").field(name).finish(),
+        }
+    }
+}
+
+struct Trigger {
+    id: TriggerId,
+    pattern: Regex,
+    action: TriggerAction,
+    enabled: bool,
+    // end of the last match in the current line, the line before it is not matched again
+    matched_to: usize,
+}
+
+/**
+ * Triggers of a session with the line they are matched against
+ */
+#[derive(Default)]
+pub(crate) struct Triggers {
+    triggers: Vec<Trigger>,
+    next_id: u64,
+    line: String,
+}
+
+impl Triggers {
+    pub(crate) fn add(&mut self, pattern: Regex, action: TriggerAction) -> TriggerId {
+        let id = TriggerId(self.next_id);
+        self.next_id += 1;
+        // only output from now on, not the rest of the current line
+        self.triggers.push(Trigger { id, pattern, action, enabled: true, matched_to: self.line.len() });
+        id
+    }
+
+    /**
+     * Removes a trigger, returns whether there was one
+     */
+    pub(crate) fn remove(&mut self, id: TriggerId) -> bool {
+        let len = self.triggers.len();
+        self.triggers.retain(|trigger| trigger.id != id);
+        self.triggers.len() != len
+    }
+
+    /**
+     * Enables or disables a trigger, returns whether there was one
+     */
+    pub(crate) fn set_enabled(&mut self, id: TriggerId, enabled: bool) -> bool {
+        match self.triggers.iter_mut().find(|trigger| trigger.id == id) {
+            Some(trigger) => {
+                trigger.enabled = enabled;
+                // a re-enabled trigger does not fire on what it missed
+                trigger.matched_to = self.line.len();
+                true
+            },
+            None => false
+        }
+    }
+
+    /**
+     * Matches text, output without escape sequences, and returns the actions to run in order
+     */
+    pub(crate) fn feed(&mut self, text: &str) -> Vec<(TriggerAction, TriggerMatch)> {
+        let mut fired = Vec::new();
+        if self.triggers.is_empty() {
+            return fired;
+        }
+
+        let mut lines = text.split('\n').peekable();
+        while let Some(part) = lines.next() {
+            self.line.push_str(part);
+            self.matches(&mut fired);
+
+            let complete = lines.peek().is_some();
+            if complete || self.line.len() > MAX_LINE_LEN {
+                self.line.clear();
+                for trigger in &mut self.triggers {
+                    trigger.matched_to = 0;
+                }
+            }
+        }
+        fired
+    }
+
+    fn matches(&mut self, fired: &mut Vec<(TriggerAction, TriggerMatch)>) {
+        for trigger in self.triggers.iter_mut().filter(|trigger| trigger.enabled) {
+            while let Some(captures) = trigger.pattern.captures_at(&self.line, trigger.matched_to) {
+                let whole = captures.get(0).unwrap();
+                // an empty match would match again at the same place forever
+                if whole.is_empty() { break }
+                trigger.matched_to = whole.end();
+                fired.push((trigger.action.clone(), TriggerMatch {
+                    trigger: trigger.id,
+                    text: whole.as_str().to_owned(),
+                    groups: captures.iter().skip(1).map(|group| group.map(|group| group.as_str().to_owned())).collect(),
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::time::Duration;
+    use crate::{test_util, Pty};
+
+    #[test]
+    fn split_matches() {
+        let mut triggers = Triggers::default();
+        let error = triggers.add(Regex::new("ERROR: (\\w+)").unwrap(), TriggerAction::event("error"));
+        let prompt = triggers.add(Regex::new("\\[y/N\\] $").unwrap(), TriggerAction::input("y\r"));
+        let texts = |fired: Vec<(TriggerAction, TriggerMatch)>| -> Vec<String> {
+            fired.into_iter().map(|(_, m)| m.text).collect()
+        };
+
+        assert!(triggers.feed("ERR").is_empty());
+        let fired = triggers.feed("OR: disk full\nContinue? [y/N] ");
+        assert_eq!(texts(fired.clone()), ["ERROR: disk", "[y/N] "]);
+        assert_eq!(fired[0].1.groups, [Some("disk".to_owned())]);
+        assert_eq!((fired[0].1.trigger, fired[1].1.trigger), (error, prompt));
+        // nothing fires twice for the same line
+        assert!(triggers.feed("").is_empty());
+
+        assert!(triggers.set_enabled(error, false));
+        assert!(texts(triggers.feed("y\nERROR: again\n")).is_empty());
+        assert!(triggers.remove(prompt));
+        assert!(!triggers.remove(prompt));
+    }
+
+    #[test]
+    fn answers_prompts() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::builder().scrollback(0x10000).spawn(|_id, _res| {}, |_id| {})?;
+        let trigger = pty.add_trigger(Regex::new(r"\[y/N\] $")?, TriggerAction::input("y\r"))?;
+        pty.write("printf 'Continue? [y/N] '; read answer; echo \"answer-$answer\"\r")?;
+        test_util::wait_for_output(&pty, "answer-y", Duration::from_secs(10))?;
+
+        pty.remove_trigger(trigger)?;
+        assert!(pty.set_trigger_enabled(trigger, true).is_err());
+        pty.shutdown()?;
+        Ok(())
+    }
+}
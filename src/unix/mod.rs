@@ -1,3 +1,10 @@
+// only the window size is shared with wasm32 builds, see the wasm module
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod proc;
+#[cfg(not(target_arch = "wasm32"))]
 pub(crate) mod pty;
+#[cfg(all(target_os = "linux", any(target_env = "gnu", feature = "vfork")))]
+pub(crate) mod spawn;
 pub(crate) mod window;
-mod shell;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod shell;
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use nix::unistd::Pid;
+
+/// Resources used by the processes of a pty's session, see Pty::resource_usage()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Usage {
+    /// user and system time of the processes, on linux including exited children they waited for
+    pub cpu_time: Duration,
+    /// resident memory of the processes in bytes, shared pages are counted once per process
+    pub rss: u64,
+    pub n_processes: usize,
+}
+
+/// The process in the foreground of a pty, see Pty::foreground()
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ForegroundProcess {
+    /// leader of the foreground process group, the shell itself while it waits at its prompt
+    pub pid: i32,
+    /// name of the program it runs as ps shows it, cut to 15 bytes on linux
+    pub name: String,
+}
+
+/// The status line the kernel prints when the status character (^T) is typed, see
+/// Pty::request_status(), of the process it picked among the foreground process group
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatusLine {
+    /// load average of the last minute
+    pub load: f64,
+    pub command: String,
+    pub pid: i32,
+    /// what it is doing, e.g. running or the channel it sleeps on such as nanslp
+    pub state: String,
+    /// time since it started, not printed on every platform
+    pub real_time: Option<Duration>,
+    pub user_time: Duration,
+    pub system_time: Duration,
+}
+
+/**
+ * Environment pid was started with, later changes by the process itself are not visible
+ */
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn environ(pid: Pid) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let data = std::fs::read(format!("/proc/{pid}/environ"))?;
+    Ok(parse_environ(data.split(|b| *b == 0)))
+}
+
+/**
+ * Environment pid was started with, from the argument area KERN_PROCARGS2 returns
+ */
+#[cfg(target_os = "macos")]
+pub(crate) fn environ(pid: Pid) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    use nix::errno::Errno;
+    use nix::libc;
+    use crate::error::PtyError;
+
+    let mut mib = [libc::CTL_KERN, libc::KERN_PROCARGS2, pid.as_raw()];
+    let mut len: libc::size_t = 0;
+    if unsafe { libc::sysctl(mib.as_mut_ptr(), 3, std::ptr::null_mut(), &mut len, std::ptr::null_mut(), 0) } < 0 {
+        return Err(Box::new(PtyError::from_errno("Process arguments failure", Errno::last())));
+    }
+    let mut data = vec![0u8; len];
+    if unsafe { libc::sysctl(mib.as_mut_ptr(), 3, data.as_mut_ptr() as *mut _, &mut len, std::ptr::null_mut(), 0) } < 0 {
+        return Err(Box::new(PtyError::from_errno("Process arguments failure", Errno::last())));
+    }
+    data.truncate(len);
+
+    // argc, the executable path, padding, argc arguments and then the environment
+    let argc = i32::from_ne_bytes(data.get(..4).ok_or_else(|| PtyError::new("Process arguments truncated"))?.try_into()?) as usize;
+    let mut strings = data[4..].split(|b| *b == 0).filter(|s| !s.is_empty());
+    strings.nth(argc);
+    Ok(parse_environ(strings))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) fn environ(_pid: Pid) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    Err(Box::new(crate::error::PtyError::with_kind("Reading the environment of a process is not supported on this platform", std::io::ErrorKind::Unsupported)))
+}
+
+/**
+ * Current working directory of pid
+ */
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn cwd(pid: Pid) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(std::fs::read_link(format!("/proc/{pid}/cwd"))?)
+}
+
+/**
+ * Current working directory of pid, from the vnode info of libproc
+ */
+#[cfg(target_os = "macos")]
+pub(crate) fn cwd(pid: Pid) -> Result<PathBuf, Box<dyn Error>> {
+    use std::ffi::CStr;
+    use nix::errno::Errno;
+    use nix::libc;
+    use crate::error::PtyError;
+
+    let mut info: libc::proc_vnodepathinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_vnodepathinfo>() as libc::c_int;
+    let n = unsafe { libc::proc_pidinfo(pid.as_raw(), libc::PROC_PIDVNODEPATHINFO, 0, &mut info as *mut _ as *mut libc::c_void, size) };
+    if n < size {
+        return Err(Box::new(PtyError::from_errno("Process cwd failure", Errno::last())));
+    }
+    let path = unsafe { CStr::from_ptr(info.pvi_cdir.vip_path.as_ptr() as *const libc::c_char) };
+    Ok(PathBuf::from(path.to_str()?))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) fn cwd(_pid: Pid) -> Result<PathBuf, Box<dyn Error>> {
+    Err(Box::new(crate::error::PtyError::with_kind("Reading the cwd of a process is not supported on this platform", std::io::ErrorKind::Unsupported)))
+}
+
+/**
+ * Name of the program pid runs, it changes when pid execs another
+ */
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn name(pid: Pid) -> Result<String, Box<dyn Error>> {
+    Ok(std::fs::read_to_string(format!("/proc/{pid}/comm"))?.trim_end_matches('\n').to_owned())
+}
+
+/**
+ * Name of the program pid runs, from libproc
+ */
+#[cfg(target_os = "macos")]
+pub(crate) fn name(pid: Pid) -> Result<String, Box<dyn Error>> {
+    use nix::errno::Errno;
+    use nix::libc;
+    use crate::error::PtyError;
+
+    let mut buf = [0u8; 256];
+    let n = unsafe { libc::proc_name(pid.as_raw(), buf.as_mut_ptr() as *mut libc::c_void, buf.len() as u32) };
+    if n <= 0 {
+        return Err(Box::new(PtyError::from_errno("Process name failure", Errno::last())));
+    }
+    Ok(String::from_utf8_lossy(&buf[..n as usize]).into_owned())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) fn name(_pid: Pid) -> Result<String, Box<dyn Error>> {
+    Err(Box::new(crate::error::PtyError::with_kind("Reading the name of a process is not supported on this platform", std::io::ErrorKind::Unsupported)))
+}
+
+/**
+ * Usage summed over every process of the session led by sid, from /proc/<pid>/stat
+ */
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn usage(sid: Pid) -> Result<Usage, Box<dyn Error>> {
+    use nix::unistd::{sysconf, SysconfVar};
+
+    let ticks = sysconf(SysconfVar::CLK_TCK)?.unwrap_or(100) as u64;
+    let page_size = sysconf(SysconfVar::PAGE_SIZE)?.unwrap_or(4096) as u64;
+    let (mut total_ticks, mut usage) = (0, Usage::default());
+
+    for entry in std::fs::read_dir("/proc")? {
+        let Some(pid) = entry?.file_name().to_str().and_then(|name| name.parse::<i32>().ok()) else { continue };
+        // processes exit while we look, a missing one has nothing left to count
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else { continue };
+        // the command name before the fields is in parentheses and may contain anything
+        let Some((_, fields)) = stat.rsplit_once(')') else { continue };
+        let fields: Vec<u64> = fields.split_whitespace().skip(1).map(|field| field.parse().unwrap_or(0)).collect();
+
+        // session, utime, stime, cutime, cstime and rss, counted from the field after the state
+        if fields.len() < 21 || fields[2] != sid.as_raw() as u64 { continue }
+        total_ticks += fields[10..14].iter().sum::<u64>();
+        usage.rss += fields[20] * page_size;
+        usage.n_processes += 1;
+    }
+
+    usage.cpu_time = Duration::from_nanos(total_ticks * 1_000_000_000 / ticks);
+    Ok(usage)
+}
+
+/**
+ * Usage summed over every process of the session led by sid, from the task info of libproc
+ */
+#[cfg(target_os = "macos")]
+pub(crate) fn usage(sid: Pid) -> Result<Usage, Box<dyn Error>> {
+    use nix::errno::Errno;
+    use nix::libc;
+    use crate::error::PtyError;
+
+    let n = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+    if n < 0 {
+        return Err(Box::new(PtyError::from_errno("Process list failure", Errno::last())));
+    }
+    // room for processes started in between
+    let mut pids = vec![0 as libc::pid_t; n as usize + 64];
+    let size = (pids.len() * std::mem::size_of::<libc::pid_t>()) as libc::c_int;
+    let n = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut libc::c_void, size) };
+    if n < 0 {
+        return Err(Box::new(PtyError::from_errno("Process list failure", Errno::last())));
+    }
+    pids.truncate(n as usize);
+
+    // task times are in mach absolute time units, nanoseconds only on intel
+    let mut timebase = libc::mach_timebase_info { numer: 1, denom: 1 };
+    unsafe { libc::mach_timebase_info(&mut timebase) };
+    let (mut total_time, mut usage) = (0u64, Usage::default());
+
+    for pid in pids {
+        if unsafe { libc::getsid(pid) } != sid.as_raw() { continue }
+        let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::proc_taskinfo>() as libc::c_int;
+        // processes exit while we look, a missing one has nothing left to count
+        if unsafe { libc::proc_pidinfo(pid, libc::PROC_PIDTASKINFO, 0, &mut info as *mut _ as *mut libc::c_void, size) } < size { continue }
+        total_time += info.pti_total_user + info.pti_total_system;
+        usage.rss += info.pti_resident_size;
+        usage.n_processes += 1;
+    }
+
+    usage.cpu_time = Duration::from_nanos(total_time * timebase.numer as u64 / timebase.denom.max(1) as u64);
+    Ok(usage)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) fn usage(_sid: Pid) -> Result<Usage, Box<dyn Error>> {
+    Err(Box::new(crate::error::PtyError::with_kind("Reading the resource usage of processes is not supported on this platform", std::io::ErrorKind::Unsupported)))
+}
+
+/**
+ * The status line in line, e.g. `load: 0.35  cmd: sleep 1234 [nanslp] 1.23r 0.00u 0.00s 0% 1920k`,
+ * the echo of ^T may come before it
+ */
+pub(crate) fn parse_status(line: &str) -> Option<StatusLine> {
+    let line = &line[line.find("load: ")? + "load: ".len()..];
+    let (load, line) = line.split_once("cmd: ")?;
+    let mut words = line.split_whitespace();
+    let (command, pid) = (words.next()?, words.next()?.parse().ok()?);
+
+    let mut status = StatusLine {
+        load: load.trim().parse().ok()?,
+        command: command.to_owned(),
+        pid,
+        state: String::new(),
+        real_time: None,
+        user_time: Duration::ZERO,
+        system_time: Duration::ZERO,
+    };
+    let mut times = 0;
+    for word in words {
+        let time = word.get(..word.len() - 1).and_then(|secs| secs.parse::<f64>().ok()).and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+        match (time, word.chars().last()) {
+            (Some(time), Some('r')) => status.real_time = Some(time),
+            (Some(time), Some('u')) => { status.user_time = time; times += 1 },
+            (Some(time), Some('s')) => { status.system_time = time; times += 1 },
+            // what follows the times, e.g. the share of the cpu and the memory
+            _ if times > 0 => break,
+            _ => {
+                if !status.state.is_empty() {
+                    status.state.push(' ');
+                }
+                status.state.push_str(word.trim_start_matches('[').trim_end_matches(']'));
+            },
+        }
+    }
+    (times == 2).then_some(status)
+}
+
+/**
+ * KEY=value strings to a map, anything without an = is skipped
+ */
+fn parse_environ<'a>(strings: impl Iterator<Item = &'a [u8]>) -> HashMap<String, String> {
+    strings
+        .filter_map(|var| {
+            let var = String::from_utf8_lossy(var);
+            var.split_once('=').map(|(key, value)| (key.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_lines() {
+        assert_eq!(parse_status("^Tload: 0.35  cmd: sleep 1234 [nanslp] 1.50r 0.00u 0.25s 0% 1920k\r\n"), Some(StatusLine {
+            load: 0.35,
+            command: "sleep".into(),
+            pid: 1234,
+            state: "nanslp".into(),
+            real_time: Some(Duration::from_millis(1500)),
+            user_time: Duration::ZERO,
+            system_time: Duration::from_millis(250),
+        }));
+        let status = parse_status("load: 2.07  cmd: make 4567 running 1.00u 0.50s").unwrap();
+        assert_eq!((status.state.as_str(), status.real_time, status.user_time), ("running", None, Duration::from_secs(1)));
+        assert_eq!(parse_status("load: 2.07  cmd: make"), None);
+        assert_eq!(parse_status("no status here"), None);
+    }
+}
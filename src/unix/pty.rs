@@ -1,23 +1,109 @@
 use std::error::Error;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
 use std::io::ErrorKind;
-use std::os::fd::{FromRawFd, RawFd};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::prelude::CommandExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::sync::Arc;
 use std::thread;
-use nix::errno::errno;
-use nix::libc::{self, EBADFD, EINTR, F_GETFD, F_GETFL, F_SETFL, O_NONBLOCK, POLLERR, POLLHUP, POLLIN, POLLNVAL, TIOCSCTTY, winsize};
+use std::time::{Duration, Instant};
+use nix::errno::{errno, Errno};
+use nix::libc::{self, EBADFD, FD_CLOEXEC, F_GETFD, F_SETFD, F_GETFL, F_SETFL, O_NONBLOCK, POLLERR, POLLHUP, POLLIN, POLLNVAL, TIOCSCTTY, winsize};
 use nix::poll::{PollFd, PollFlags};
+use nix::sys::time::TimeSpec;
 use nix::pty::openpty;
+#[cfg(target_os = "linux")]
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::wait::waitpid;
+use nix::sys::termios::{self, FlowArg, FlushArg};
 #[cfg(any(target_os = "linux", target_os = "macos"))]
-use nix::sys::termios::{self, InputFlags, SetArg};
-use nix::unistd;
+use nix::sys::termios::{InputFlags, SetArg};
+use nix::unistd::{self, Pid};
+use crate::builder::{PtyBuilder, ReadCallback, StdioMode, SESSION_ID_ENV};
+use crate::clients::{ClientCallback, ClientEvent};
+use crate::clock::Clock;
 use crate::error::PtyError;
+use crate::filter::Direction;
+use crate::handler::{contain, panic_message, PtyHandler};
+use crate::id::PtyId;
+use crate::metrics;
+use crate::recording::Recorder;
+use crate::registry::{self, Notice, Session};
+use crate::retention::{self, ExitStatus};
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+use crate::sandbox;
+use crate::scanner::{Scanner, Sequence};
+use crate::sequence::Unsplit;
+use crate::shell_integration::{self, Shell, ShellMark};
+use crate::shutdown::ShutdownProgress;
+#[cfg(feature = "triggers")]
+use crate::trigger::TriggerAction;
+use crate::unix::proc::{self, ForegroundProcess, StatusLine};
 use crate::unix::shell::ShellUser;
 use crate::unix::window::WindowSize;
+use crate::watchdog::ReaderFailure;
 
-pub(crate) fn spawn() -> Result<RawFd, Box<dyn Error>> {
-    let ends = openpty(None, None)?;
-    let (master, slave) = (ends.master, ends.slave);
+/**
+ * Handles to a freshly spawned child
+ */
+pub(crate) struct Child {
+    pub master: RawFd,
+    pub pid: Pid,
+    // the shell that was started, the user's or a fallback
+    pub shell: String,
+    // parent ends of the stdio pipes, for every stream not attached to the pty
+    pub stdin: Option<RawFd>,
+    pub stdout: Option<RawFd>,
+    pub stderr: Option<RawFd>,
+}
+
+pub(crate) fn spawn(config: &PtyBuilder) -> Result<Child, Box<dyn Error>> {
+    let user = match &config.config.user {
+        Some(user) => user.clone(),
+        None => ShellUser::from_env(config.user_lookup, &config.default_shell)
+    };
+    // switching accounts needs root, the child fails to start otherwise, looked up before
+    // anything is opened as it may fail
+    let switch_to = match user.uid != unistd::getuid().as_raw() {
+        true => Some((user.uid, user.gid, Arc::new(group_list(&CString::new(user.user.as_str())?, user.gid)?))),
+        false => None
+    };
+
+    // set up here so the child only has to enter it, a profile that is rejected fails the
+    // spawn before anything is opened
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    let sandbox = Arc::new(sandbox::prepare(&config.sandbox)?);
+
+    let ends = openpty(config.window_size.map(WindowSize::to_winsize).as_ref(), None)?;
+    // owned until the child is spawned so a failure on the way closes them, only the master
+    // is handed out, the slave is closed at the end of this scope
+    let (master_file, slave_file) = (unsafe { File::from_raw_fd(ends.master) }, unsafe { File::from_raw_fd(ends.slave) });
+    let (master, slave) = (master_file.as_raw_fd(), slave_file.as_raw_fd());
+
+    // keep both ends from leaking into children spawned for other ptys, a leaked slave
+    // would hold the pty open and the death of its shell would never be noticed
+    for fd in [master, slave] {
+        set_cloexec(fd);
+    }
+
+    // streams not attached to the pty go through pipes, the parent's read ends are polled next to master
+    let stdin_pipe = match config.config.stdin {
+        StdioMode::Piped => Some(pipe(false)?),
+        StdioMode::Pty => None
+    };
+    let stdout_pipe = match config.config.stdout {
+        StdioMode::Piped => Some(pipe(true)?),
+        StdioMode::Pty => None
+    };
+    let stderr_pipe = match config.on_stderr {
+        Some(_) => Some(pipe(true)?),
+        None => None
+    };
 
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     if let Ok(mut termios) = termios::tcgetattr(master) {
@@ -26,124 +112,953 @@ pub(crate) fn spawn() -> Result<RawFd, Box<dyn Error>> {
         let _ = termios::tcsetattr(master, SetArg::TCSANOW, &termios);
     }
 
-    let user = ShellUser::from_env()?;
+    // the program, or the user's shell first, then the fallbacks, see PtyBuilder::fallback_shells()
+    let shells = config.execs(&user);
+    // login runs the user's shell itself, as a login shell, a program is run without it
+    #[cfg(target_os = "macos")]
+    let login = config.config.login && config.program.is_empty();
+    #[cfg(target_os = "macos")]
+    let shells = match login {
+        true => shells[..1].to_vec(),
+        false => shells
+    };
 
-    let mut builder = Command::new(user.shell);
+    // arguments would go to login, not the shell, a program is not a shell
+    #[cfg(target_os = "macos")]
+    let integrate = config.shell_integration && !login && config.program.is_empty();
+    #[cfg(not(target_os = "macos"))]
+    let integrate = config.shell_integration && config.program.is_empty();
 
     // Setup child stdin/stdout/stderr as slave fd of PTY.
-    // Ownership of fd is transferred to the Stdio structs and will be closed by them at the end of
-    // this scope. (It is not an issue that the fd is closed three times since File::drop ignores
-    // error on libc::close.).
-    builder
-        .stdin (unsafe { Stdio::from_raw_fd(slave) })
-        .stderr(unsafe { Stdio::from_raw_fd(slave) })
-        .stdout(unsafe { Stdio::from_raw_fd(slave) })
-        .env("USER", user.user)
-        .env("HOME", user.home);
+    // Each Stdio gets its own duplicate of the slave fd so every fd is closed exactly once.
+    let stdio = |pipe: &Option<(File, File)>| match pipe {
+        Some((_, child_end)) => child_end.try_clone(),
+        None => slave_file.try_clone()
+    };
+    let command = |shell: &str| -> Result<Command, Box<dyn Error>> {
+        #[cfg(target_os = "macos")]
+        let mut builder = match login {
+            true => {
+                let mut login = Command::new("/usr/bin/login");
+                login.arg("-fp").arg(&user.user);
+                login
+            },
+            false => Command::new(shell)
+        };
+        #[cfg(not(target_os = "macos"))]
+        let mut builder = Command::new(shell);
 
-    unsafe {
-        builder.pre_exec(move || {
-            // create new process group
-            if libc::setsid() < 0 {
-                return Err(std::io::Error::new(ErrorKind::Other, "failed to set session id"));
-            }
+        builder.args(config.program.iter().skip(1));
+        if config.env_clear {
+            builder.env_clear();
+        }
+        if let Some(cwd) = &config.cwd {
+            builder.current_dir(cwd);
+        }
+        builder
+            .envs(config.env.iter().map(|(key, value)| (key, value)))
+            .stdin (Stdio::from(stdio(&stdin_pipe)?))
+            .stderr(Stdio::from(stdio(&stderr_pipe)?))
+            .stdout(Stdio::from(stdio(&stdout_pipe)?))
+            .env("USER", &user.user)
+            .env("HOME", &user.home)
+            .env("SHELL", if config.program.is_empty() { shell } else { &user.shell });
+        if let Some(tag) = &config.config.tag {
+            builder.env(SESSION_ID_ENV, tag);
+        }
+        if let Some(integration) = integrate.then(|| Shell::from_path(shell)).flatten() {
+            shell_integration::inject(integration, &user.home, &mut builder)?;
+        }
 
-            // TIOCSCTTY changes based on platform and the `ioctl` call is different
-            // based on architecture (32/64). So a generic cast is used to make sure
-            // there are no issues. To allow such a generic cast the clippy warning
-            // is disabled.
-            #[allow(clippy::cast_lossless)]
-            if libc::ioctl(slave, TIOCSCTTY as _, 0) < 0 {
-                return Err(std::io::Error::new(ErrorKind::Other, "ioctl failure on TIOCSCTTY"));
-            }
+        let switch_to = switch_to.clone();
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        let sandbox = sandbox.clone();
+        unsafe {
+            builder.pre_exec(move || {
+                // create new process group
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::other("failed to set session id"));
+                }
 
-            // No longer need slave/master fds.
-            libc::close(slave);
-            libc::close(master);
+                // TIOCSCTTY changes based on platform and the `ioctl` call is different
+                // based on architecture (32/64). So a generic cast is used to make sure
+                // there are no issues. To allow such a generic cast the clippy warning
+                // is disabled.
+                #[allow(clippy::cast_lossless)]
+                if libc::ioctl(slave, TIOCSCTTY as _, 0) < 0 {
+                    return Err(std::io::Error::other("ioctl failure on TIOCSCTTY"));
+                }
 
-            libc::signal(libc::SIGCHLD, libc::SIG_DFL);
-            libc::signal(libc::SIGHUP, libc::SIG_DFL);
-            libc::signal(libc::SIGINT, libc::SIG_DFL);
-            libc::signal(libc::SIGQUIT, libc::SIG_DFL);
-            libc::signal(libc::SIGTERM, libc::SIG_DFL);
-            libc::signal(libc::SIGALRM, libc::SIG_DFL);
+                // No longer need slave/master fds.
+                libc::close(slave);
+                libc::close(master);
 
-            Ok(())
-        });
+                libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+                libc::signal(libc::SIGHUP, libc::SIG_DFL);
+                libc::signal(libc::SIGINT, libc::SIG_DFL);
+                libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+                libc::signal(libc::SIGTERM, libc::SIG_DFL);
+                libc::signal(libc::SIGALRM, libc::SIG_DFL);
+
+                // groups first, they can no longer be changed once the uid is dropped, they were
+                // looked up before forking, reading the group database here may deadlock
+                if let Some((uid, gid, groups)) = &switch_to {
+                    if libc::setgroups(groups.len() as _, groups.as_ptr()) < 0 || libc::setgid(*gid) < 0 || libc::setuid(*uid) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                // last, entering it may take away what the steps above need
+                #[cfg(all(feature = "sandbox", target_os = "linux"))]
+                sandbox.enter()?;
+
+                Ok(())
+            });
+        }
+        Ok(builder)
+    };
+
+    let (mut failures, mut kind) = (Vec::new(), ErrorKind::Other);
+    for shell in &shells {
+        let mut builder = command(shell)?;
+        match builder.spawn() {
+            Ok(child) => {
+                return Ok(Child {
+                    master: master_file.into_raw_fd(),
+                    pid: Pid::from_raw(child.id() as i32),
+                    shell: shell.clone(),
+                    stdin: stdin_pipe.map(|(parent_end, _)| parent_end.into_raw_fd()),
+                    stdout: stdout_pipe.map(|(parent_end, _)| parent_end.into_raw_fd()),
+                    stderr: stderr_pipe.map(|(parent_end, _)| parent_end.into_raw_fd()),
+                });
+            },
+            // exec failed, e.g. a container without the user's shell, the next one may do
+            Err(err) => {
+                failures.push(format!("'{}': {err}", builder.get_program().to_string_lossy()));
+                kind = err.kind();
+                if !matches!(kind, ErrorKind::NotFound | ErrorKind::PermissionDenied) { break }
+            }
+        }
     }
 
-    match builder.spawn() {
-        Ok(_child) => unsafe {
-            // set non blocking
-            let res = libc::fcntl(master, F_SETFL, libc::fcntl(master, F_GETFL, 0) | O_NONBLOCK);
-            assert_eq!(res, 0);
+    Err(Box::new(PtyError::with_kind(format!("failed to spawn command {}", failures.join(", ")), kind)))
+}
 
-            Ok(master)
-        },
-        Err(err) => Err(Box::new(std::io::Error::new(
-            err.kind(),
-            format!(
-                "failed to spawn command '{}': {}",
-                builder.get_program().to_string_lossy(),
-                err
-            )
-        )))
+/**
+ * Supplementary groups of the user called name whose primary group is gid, like initgroups()
+ * would set them
+ */
+fn group_list(name: &CStr, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, Box<dyn Error>> {
+    #[cfg(target_os = "macos")]
+    type Group = libc::c_int;
+    #[cfg(not(target_os = "macos"))]
+    type Group = libc::gid_t;
+
+    let mut len: libc::c_int = 32;
+    loop {
+        let mut groups: Vec<Group> = vec![0; len as usize];
+        let capacity = len;
+        if unsafe { libc::getgrouplist(name.as_ptr(), gid as _, groups.as_mut_ptr(), &mut len) } >= 0 {
+            groups.truncate(len as usize);
+            return Ok(groups.into_iter().map(|group| group as libc::gid_t).collect());
+        }
+        // glibc reports the number needed, others leave len alone
+        len = len.max(capacity * 2);
+        if len > 0x10000 {
+            return Err(Box::new(PtyError::new(format!("Groups of {} failure", name.to_string_lossy()))));
+        }
     }
 }
 
+// how often a polling thread is restarted after a panic before the pty is given up
+const MAX_RESTARTS: usize = 3;
+
+// how long the polling thread busy polls for the echo of a write, see PtyBuilder::poll_after_write()
+const ECHO_SPIN: Duration = Duration::from_micros(500);
+
+// quiet after output not ending a line that makes the shell count as ready, see PtyHandler::on_ready()
+const READY_QUIET: Duration = Duration::from_millis(150);
+
+// the shell counts as ready by then even if it never looked ready
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long output the child wrote before exiting is still read for, when its exit is seen
+// through a pidfd before the pty hangs up
+const EXIT_GRACE: Duration = Duration::from_millis(50);
+
+// how long a write waits for a full tty to take more before it tries again
+const WRITE_WAIT: Duration = Duration::from_millis(50);
+
+// output looked through for the answer to Pty::request_status() without a line ending
+const MAX_STATUS_LEN: usize = 0x400;
+
 /**
  * Polls a file descriptor, we call read in this thread to ensure blocking
+ * stdout and stderr are the child's piped streams, polled alongside fd, stdout is
+ * delivered to the handler like pty output and stderr through its own callback
  */
-pub(crate) fn poll<F, G>(fd: RawFd, mut on_read: F, mut on_death: G) -> Result<(), Box<dyn Error>>
-    where
-        F: FnMut(RawFd, Result<String, Box<dyn Error>>) + Send + 'static,
-        G: FnMut(RawFd) + Send + 'static {
+pub(crate) fn poll<H: PtyHandler>(
+    session: Arc<Session>,
+    stdout: Option<RawFd>,
+    stderr: Option<(RawFd, ReadCallback)>,
+    mut handler: H
+) -> Result<(), Box<dyn Error>> {
 
-    const ERR_BITS: i16 = POLLERR | POLLHUP | POLLNVAL;
+    let (id, fd) = (session.id(), session.id().fd());
     validate_fd(fd)?;
 
     // poll the newly created fd
-    thread::spawn(move || {
+    let owner = session.clone();
+    let thread = thread::Builder::new().name(format!("pty-exec/fd={fd}")).spawn(move || {
+        #[cfg(target_os = "linux")]
+        if let Err(err) = configure_reader(&session) {
+            contain(&mut handler, id, |handler| handler.on_error(id, err));
+        }
+
         let flags = PollFlags::from_bits(POLLIN).unwrap();
-        let mut fds = [PollFd::new(fd, flags)];
+        let pidfd = child_pidfd(session.child());
+        let ready = Some(Readiness { spawned: session.config().clock.now(), output: None, prompted: false });
+        let mut reader = Reader { scanner: Scanner::new(session.config().max_sequence_len), unsplit: Unsplit::default(), held: None, gave_up: false, ready, echo_off: false, exited: None, migrated: false, cwd_polled: None, foreground: None, foreground_polled: None, status: String::new() };
+        let (stderr_fd, mut on_stderr) = match stderr {
+            Some((stderr_fd, on_stderr)) => (stderr_fd, Some(on_stderr)),
+            // negative fds are ignored by poll
+            None => (-1, None)
+        };
+        let mut fds = [
+            PollFd::new(fd, flags),
+            PollFd::new(session.wake_fd(), flags),
+            PollFd::new(stdout.unwrap_or(-1), flags),
+            PollFd::new(stderr_fd, flags),
+            PollFd::new(pidfd, flags),
+        ];
 
-        while let Ok(n) = nix::poll::ppoll(&mut fds, None, None) {
-            if n <= 0 {
-                if errno() == EINTR { continue } else { break }
+        let mut restarts = 0;
+        loop {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                poll_fds(&session, &mut handler, &mut reader, &mut fds, &mut on_stderr)
+            }));
+            let failure = match res {
+                Ok(Ok(())) => break,
+                Ok(Err(errno)) => ReaderFailure::PollFailed(PtyError::from_errno("Poll failure", errno)),
+                Err(panic) => ReaderFailure::Panicked(panic_message(panic.as_ref())),
+            };
+            // a panic may have been a one off, failing polls only get worse
+            let restart = matches!(failure, ReaderFailure::Panicked(_)) && restarts < MAX_RESTARTS;
+            contain(&mut handler, id, |handler| handler.on_reader_failure(id, failure, restart));
+            if !restart { break }
+            restarts += 1;
+        }
+        reader.flush_split(&session, &mut handler);
+        reader.release(&session, &mut handler);
+        let discarded = session.discard_coalesced();
+        if discarded > 0 {
+            let err = PtyError::from(format!("{discarded} bytes of coalesced input of {id} were never written"));
+            contain(&mut handler, id, |handler| handler.on_error(id, Box::new(err)));
+        }
+        for pipe in fds[2..4].iter().map(|fd| fd.as_raw_fd()).chain([pidfd]).filter(|&pipe| pipe >= 0) {
+            let _ = unistd::close(pipe);
+        }
+        // retained before it goes from the registry, so it can be found all along
+        if let Some(period) = session.config().retain_exited.filter(|_| !reader.migrated) {
+            retention::retain(session.clone(), period);
+        }
+        // close before notifying so the id is already stale inside on_exit, closing the
+        // master also hangs up the child's session, Pty::shutdown() still finds it until its
+        // exit was reported
+        registry::begin_exit(&session);
+        session.close();
+        match reader.migrated {
+            // the child lives on under another process, it is still ours to reap once it exits
+            true => {
+                let child = session.child();
+                let _ = thread::Builder::new().name(format!("pty-exec/reap={child}")).spawn(move || waitpid(child, None));
+            },
+            false => session.set_exit_status(ExitStatus::from(waitpid(session.child(), None))),
+        }
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &session.config().chaos {
+            thread::sleep(chaos.exit_delay_of());
+        }
+        // steps of a shutdown taken while the child was dying
+        for notice in session.pending_notices() {
+            if let Notice::Shutdown(_) = notice {
+                take_notice(&session, &mut handler, notice);
             }
+        }
+        if let Some(last) = session.shutdown_progress() {
+            let progress = ShutdownProgress::Reaped { forced: last != ShutdownProgress::SentInput };
+            contain(&mut handler, id, |handler| handler.on_shutdown(id, progress));
+        }
+        contain(&mut handler, id, |handler| handler.on_exit(id));
+        broadcast(&session, &mut handler, ClientEvent::Exited);
+        metrics::died();
+        session.set_exited();
+    })?;
+    owner.set_reader(thread);
 
-            match fds[0].revents() {
-                Some(events) => {
-                    if events.bits() & ERR_BITS != 0 { break }
-                    // skip if no buffer data
-                    if events.bits() & POLLIN == 0 { continue }
-                },
-                None => continue
+    Ok(())
+}
+
+/**
+ * Acts on a notice queued by another thread
+ */
+fn take_notice<H: PtyHandler>(session: &Session, handler: &mut H, notice: Notice) {
+    let id = session.id();
+    match notice {
+        Notice::Resized(size) => {
+            #[cfg(feature = "vt")]
+            session.screen().resize(size);
+            if let Err(err) = session.record(|recorder| recorder.resize(size)) {
+                contain(handler, id, |handler| handler.on_error(id, err));
+            }
+            contain(handler, id, |handler| handler.on_resize_ack(id, size));
+            broadcast(session, handler, ClientEvent::Resized(size.rows(), size.cols()));
+        },
+        Notice::Title(title) => contain(handler, id, |handler| handler.on_title(id, title)),
+        Notice::ConfigChanged(changed) => contain(handler, id, |handler| handler.on_config_change(id, changed)),
+        Notice::Shutdown(progress) => contain(handler, id, |handler| handler.on_shutdown(id, progress)),
+        Notice::Error(err) => contain(handler, id, |handler| handler.on_error(id, Box::new(err))),
+        Notice::Message(message) => broadcast(session, handler, ClientEvent::Message(message)),
+        Notice::ClientMessage(client, message) => contain(handler, id, |handler| handler.on_message(id, Some(client), message)),
+        Notice::EchoRejected(client, seq) => {
+            let callbacks = session.clients().next_event_for(client);
+            send_to(callbacks, id, handler, ClientEvent::Echo { seq, echoed: false });
+        },
+        // taken by poll_fds(), it stops polling
+        #[cfg(feature = "migrate")]
+        Notice::Migrate(_) => {},
+    }
+}
+
+/**
+ * Passes the result of a read to the handler, followed by the sequences found in it
+ */
+fn deliver<H: PtyHandler>(session: &Session, handler: &mut H, reader: &mut Reader, res: Result<String, Box<dyn Error>>) {
+    let id = session.id();
+
+    match res {
+        Ok(output) => {
+            // a control string split across reads is passed on whole
+            let output = reader.unsplit.join(output, session.config().max_sequence_len);
+            if output.is_empty() {
+                return;
+            }
+            metrics::read(output.len());
+            session.touch();
+            if let Some(ready) = &mut reader.ready {
+                ready.output = Some((session.config().clock.now(), output.ends_with('\n')));
+            }
+            // a prompt for a secret follows turning echo off, so the change is seen along with it
+            let echo_off = session.with_fd(|fd| Ok(input_hidden(fd))).unwrap_or(reader.echo_off);
+            if echo_off != reader.echo_off {
+                reader.echo_off = echo_off;
+                contain(handler, id, |handler| handler.on_echo_change(id, echo_off));
+            }
+            let filters = &session.config().filters;
+            let Some(output) = filters.run(id, Direction::Output, output) else { return };
+            let output = session.echo().strip(output);
+            if output.is_empty() {
+                return;
+            }
+            let callbacks = {
+                let mut scrollback = session.scrollback();
+                scrollback.push(&output);
+                #[cfg(feature = "vt")]
+                session.screen().feed(&output);
+                // taken with the scrollback locked, a client attached with Pty::attach_at() gets
+                // each chunk either from the scrollback or as an event, never both
+                session.clients().next_event()
             };
+            if let Err(err) = session.record(|recorder| recorder.output(&output)) {
+                contain(handler, id, |handler| handler.on_error(id, err));
+            }
+            for event in session.enforce_quotas() {
+                contain(handler, id, |handler| handler.on_quota(id, event));
+            }
+            match session.status_expected() {
+                true => if let Some(status) = reader.status_line(&output) {
+                    session.expect_status(false);
+                    contain(handler, id, |handler| handler.on_status(id, status));
+                },
+                // what was gathered for a status line that never came
+                false => reader.status.clear(),
+            }
+            let sequences = reader.scanner.scan(&output);
+            answer(session, handler, &sequences);
+            send_to(callbacks, id, handler, ClientEvent::Output(output.clone()));
+            let settled = session.clients().settle(&output, session.config().clock.now());
+            for (callbacks, event) in settled {
+                send_to(callbacks, id, handler, event);
+            }
 
-            // return read buffer if data available
-            on_read(fd, read(fd));
+            reader.pass(session, handler, output);
+
+            for sequence in sequences {
+                let sequence = match sequence {
+                    Sequence::Title(title) => Sequence::Title(session.titles().set(title)),
+                    Sequence::IconName(name) => {
+                        session.titles().icon_name = (!name.is_empty()).then(|| name.clone());
+                        Sequence::IconName(name)
+                    },
+                    sequence => sequence,
+                };
+                match &sequence {
+                    // reported already, or found by polling
+                    Sequence::Cwd(cwd) if !session.set_cwd(cwd) => continue,
+                    Sequence::CommandLine(text) => session.history().started(text.clone()),
+                    Sequence::Mark(mark @ ShellMark::PromptStart) => {
+                        session.history().mark(*mark);
+                        if let Some(ready) = &mut reader.ready {
+                            ready.prompted = true;
+                        }
+                    },
+                    Sequence::Mark(mark) => session.history().mark(*mark),
+                    _ => {}
+                }
+                sequence.notify(handler, id);
+            }
+            #[cfg(feature = "triggers")]
+            run_triggers(session, handler, &reader.scanner.take_text());
+        },
+        Err(err) => {
+            metrics::read_failed();
+            contain(handler, id, |handler| handler.on_error(id, err))
+        }
+    }
+}
+
+/**
+ * deliver() for the result of a read, disturbed by the chaos of the session if it has any
+ */
+fn deliver_read<H: PtyHandler>(session: &Session, handler: &mut H, reader: &mut Reader, res: Result<String, Box<dyn Error>>) {
+    #[cfg(feature = "chaos")]
+    if let (Some(chaos), Ok(output)) = (&session.config().chaos, &res) {
+        for piece in chaos.pieces(output) {
+            thread::sleep(chaos.read_delay_of());
+            deliver(session, handler, reader, Ok(piece));
+        }
+        return;
+    }
+    deliver(session, handler, reader, res)
+}
+
+/**
+ * What the polling thread keeps between reads
+ */
+struct Reader {
+    scanner: Scanner,
+    // output ending in the middle of a control string, held back until the string ends
+    unsplit: Unsplit,
+    // output held back during a synchronized update, and since when
+    held: Option<(String, Instant)>,
+    // the current synchronized update was held for too long, the rest of it is not held
+    gave_up: bool,
+    // how ready the shell looks, None once it was found ready
+    ready: Option<Readiness>,
+    // whether input was hidden when output was last read, see PtyHandler::on_echo_change()
+    echo_off: bool,
+    // when the pidfd of the child said it exited, in real time, the pty drains in real time
+    exited: Option<Instant>,
+    // the session was handed over to another process, see Pty::migrate()
+    migrated: bool,
+    // when the working directory was last polled, see PtyBuilder::poll_cwd()
+    cwd_polled: Option<Instant>,
+    // the foreground process last polled and when, see PtyBuilder::poll_foreground()
+    foreground: Option<ForegroundProcess>,
+    foreground_polled: Option<Instant>,
+    // output since Pty::request_status() that did not end a line yet
+    status: String,
+}
+
+/**
+ * Signs of the shell being ready for input, see PtyHandler::on_ready()
+ */
+struct Readiness {
+    spawned: Instant,
+    // when output was last read and whether it ended a line
+    output: Option<(Instant, bool)>,
+    // the shell integration marked a prompt
+    prompted: bool,
+}
+
+impl Readiness {
+    /**
+     * How long until the shell counts as ready unless more output comes
+     */
+    fn left(&self, clock: &dyn Clock) -> Duration {
+        let timeout = READY_TIMEOUT.saturating_sub(clock.elapsed(self.spawned));
+        match self.output {
+            _ if self.prompted => Duration::ZERO,
+            Some((at, false)) => READY_QUIET.saturating_sub(clock.elapsed(at)).min(timeout),
+            _ => timeout
+        }
+    }
+}
+
+impl Reader {
+    /**
+     * The status line the tty printed in answer to Pty::request_status(), once output ends it
+     */
+    fn status_line(&mut self, output: &str) -> Option<StatusLine> {
+        self.status.push_str(output);
+        while let Some(end) = self.status.find('\n') {
+            let line: String = self.status.drain(..=end).collect();
+            if let Some(status) = proc::parse_status(&line) {
+                self.status.clear();
+                return Some(status);
+            }
+        }
+        // longer than any status line, a full screen of a program not ending lines
+        if self.status.len() > MAX_STATUS_LEN {
+            self.status.clear();
+        }
+        None
+    }
+
+    /**
+     * Passes output to the handler, or holds it while a synchronized update is going on,
+     * see PtyBuilder::hold_synchronized_output()
+     */
+    fn pass<H: PtyHandler>(&mut self, session: &Session, handler: &mut H, output: String) {
+        let synchronized = self.scanner.synchronized();
+        if !synchronized {
+            self.gave_up = false;
+        }
+        let config = session.config();
+        if let Some(max_hold) = config.sync_hold.filter(|_| synchronized && !self.gave_up) {
+            match self.held.as_mut() {
+                Some((held, since)) if config.clock.elapsed(*since) < max_hold => return held.push_str(&output),
+                Some(_) => self.gave_up = true,
+                None => return self.held = Some((output, config.clock.now())),
+            }
+        }
+
+        let output = match self.held.take() {
+            Some((held, _)) => held + &output,
+            None => output
+        };
+        let id = session.id();
+        contain(handler, id, |handler| handler.on_output(id, output));
+    }
+
+    /**
+     * Passes on output held back for the rest of a control string, it is not waited for
+     */
+    fn flush_split<H: PtyHandler>(&mut self, session: &Session, handler: &mut H) {
+        self.unsplit.give_up();
+        deliver(session, handler, self, Ok(String::new()));
+    }
+
+    /**
+     * Passes on held output, the update is no longer held
+     */
+    fn release<H: PtyHandler>(&mut self, session: &Session, handler: &mut H) {
+        if let Some((held, _)) = self.held.take() {
+            self.gave_up = true;
+            let id = session.id();
+            contain(handler, id, |handler| handler.on_output(id, held));
+        }
+    }
+
+    /**
+     * Writes the startup commands and tells the handler once the shell looks ready
+     */
+    fn start<H: PtyHandler>(&mut self, session: &Session, handler: &mut H) {
+        let clock = session.config().clock.clone();
+        if self.ready.take_if(|ready| ready.left(clock.as_ref()).is_zero()).is_none() {
+            return;
+        }
+        let id = session.id();
+        let startup = &session.config().startup;
+        if !startup.is_empty() {
+            let input: String = startup.iter().map(|command| format!("{command}\r")).collect();
+            // written by the polling thread, it cannot wait for the lock to be released
+            let res = session.unless_input_locked(|| {
+                session.echo().expect(&input);
+                session.with_input_fd(|fd| write(fd, input.as_bytes()))
+            });
+            match res {
+                Ok(()) => metrics::written(input.len()),
+                Err(err) => contain(handler, id, |handler| handler.on_error(id, err))
+            }
+        }
+        contain(handler, id, |handler| handler.on_ready(id));
+    }
+
+    /**
+     * Reads the working directory of the foreground process once a poll is due, the handler
+     * hears of it if it changed
+     */
+    fn poll_cwd<H: PtyHandler>(&mut self, session: &Session, handler: &mut H) {
+        let Some(interval) = session.config().poll_cwd else { return };
+        if !due(&mut self.cwd_polled, interval) {
+            return;
+        }
+        let id = session.id();
+        // the foreground process may have exited since, the shell has not
+        let foreground = foreground(id.fd()).unwrap_or(session.child());
+        let Ok(cwd) = proc::cwd(foreground).or_else(|_| proc::cwd(session.child())) else { return };
+        if session.set_cwd(&cwd) {
+            contain(handler, id, |handler| handler.on_cwd(id, cwd));
         }
-        on_death(fd);
-        let _ = unistd::close(fd);
+    }
+
+    /**
+     * Looks at the foreground process once a poll is due, the handler hears of it if another
+     * one took its place or it execs another program
+     */
+    fn poll_foreground<H: PtyHandler>(&mut self, session: &Session, handler: &mut H) {
+        let Some(interval) = session.config().poll_foreground else { return };
+        if !due(&mut self.foreground_polled, interval) {
+            return;
+        }
+        let id = session.id();
+        // it may exit before it is named, the next poll sees what follows it
+        let Ok(process) = foreground_process(id.fd()) else { return };
+        if self.foreground.as_ref() != Some(&process) {
+            self.foreground = Some(process.clone());
+            contain(handler, id, |handler| handler.on_foreground(id, process));
+        }
+    }
+
+    /**
+     * How long until the polling thread has something to do without any input
+     */
+    fn timeout(&self, session: &Session) -> Option<Duration> {
+        let clock = session.config().clock.clone();
+        let ready = self.ready.as_ref().map(|ready| ready.left(clock.as_ref()));
+        let exited = self.exited.map(|_| EXIT_GRACE);
+        let config = session.config();
+        let cwd_poll = config.poll_cwd.map(|interval| poll_left(self.cwd_polled, interval));
+        let foreground_poll = config.poll_foreground.map(|interval| poll_left(self.foreground_polled, interval));
+        let polls = [cwd_poll, foreground_poll];
+        let predictions = session.clients().prediction_left(clock.now());
+        [self.hold_left(session), self.unsplit.left(), ready, predictions, session.recording_sync_left(), session.coalesced_left(), exited].into_iter().chain(polls).flatten().min()
+    }
+
+    /**
+     * How long held output may still be held
+     */
+    fn hold_left(&self, session: &Session) -> Option<Duration> {
+        let (_, since) = self.held.as_ref()?;
+        let config = session.config();
+        Some(config.sync_hold?.saturating_sub(config.clock.elapsed(*since)))
+    }
+}
+
+/**
+ * Whether a poll every interval last done at polled is due, in real time like the pty is read,
+ * it counts as done if so
+ */
+fn due(polled: &mut Option<Instant>, interval: Duration) -> bool {
+    if polled.is_some_and(|polled| polled.elapsed() < interval) {
+        return false;
+    }
+    *polled = Some(Instant::now());
+    true
+}
+
+/**
+ * How long until a poll every interval last done at polled is due
+ */
+fn poll_left(polled: Option<Instant>, interval: Duration) -> Duration {
+    polled.map_or(Duration::ZERO, |polled| interval.saturating_sub(polled.elapsed()))
+}
+
+/**
+ * Replies to the queries among sequences the config of session answers, as soon as they are seen
+ */
+fn answer<H: PtyHandler>(session: &Session, handler: &mut H, sequences: &[Sequence]) {
+    let id = session.id();
+    let replies = sequences.iter().filter_map(|sequence| match sequence {
+        Sequence::Query(query) => session.config().answers.reply(*query),
+        _ => None
     });
+    for reply in replies {
+        if let Err(err) = session.with_fd(|fd| write(fd, reply.as_bytes())) {
+            contain(handler, id, |handler| handler.on_error(id, err));
+        }
+    }
+}
+
+/**
+ * Matches text against the triggers of session and runs the actions of those matching
+ */
+#[cfg(feature = "triggers")]
+fn run_triggers<H: PtyHandler>(session: &Session, handler: &mut H, text: &str) {
+    let id = session.id();
+    // the lock is released before any action runs, actions may add or remove triggers
+    let fired = session.triggers().feed(text);
+
+    for (action, matched) in fired {
+        match action {
+            TriggerAction::Callback(f) => contain(handler, id, |_| f(id, &matched)),
+            TriggerAction::Input(input) => {
+                match session.unless_input_locked(|| session.with_input_fd(|fd| write(fd, input.as_bytes()))) {
+                    Ok(()) => metrics::written(input.len()),
+                    Err(err) => contain(handler, id, |handler| handler.on_error(id, err))
+                }
+            },
+            TriggerAction::Event(name) => contain(handler, id, |handler| handler.on_trigger(id, name, matched)),
+        }
+    }
+}
+
+/**
+ * Polls the pty and the pipes until the pty hangs up, or the pty went quiet after the pidfd of
+ * the child said it exited, fails only if polling itself does
+ */
+fn poll_fds<H: PtyHandler>(
+    session: &Session,
+    handler: &mut H,
+    reader: &mut Reader,
+    fds: &mut [PollFd; 5],
+    on_stderr: &mut Option<ReadCallback>
+) -> Result<(), Errno> {
+    const ERR_BITS: i16 = POLLERR | POLLHUP | POLLNVAL;
+    let (id, fd) = (session.id(), session.id().fd());
+    let flags = PollFlags::from_bits(POLLIN).unwrap();
+
+    loop {
+        reader.start(session, handler);
+        reader.poll_cwd(session, handler);
+        reader.poll_foreground(session, handler);
+        if let Err(err) = session.flush_coalesced(false) {
+            contain(handler, id, |handler| handler.on_error(id, err));
+        }
+        session.set_busy(false);
+        let timeout = reader.timeout(session).map(TimeSpec::from_duration);
+        match nix::poll::ppoll(fds, timeout, None) {
+            Ok(0) => {
+                if reader.exited.is_some_and(|exited| exited.elapsed() >= EXIT_GRACE) { break }
+                session.set_busy(true);
+                // the child took too long to end a control string
+                if reader.unsplit.left().is_some_and(|left| left.is_zero()) {
+                    reader.flush_split(session, handler);
+                }
+                // a synchronized update outlasted the max hold
+                if reader.hold_left(session).is_some_and(|left| left.is_zero()) {
+                    reader.release(session, handler);
+                }
+                // events written before the pty went quiet
+                if let Err(err) = session.record(Recorder::sync_due) {
+                    contain(handler, id, |handler| handler.on_error(id, err));
+                }
+                // predictions no echo came for
+                let settled = session.clients().settle("", session.config().clock.now());
+                for (callbacks, event) in settled {
+                    send_to(callbacks, id, handler, event);
+                }
+                continue;
+            },
+            Ok(_) => {},
+            // a signal handled on this thread, e.g. SIGCHLD of a process that installed one
+            Err(Errno::EINTR) => continue,
+            Err(errno) => return Err(errno),
+        }
+        session.set_busy(true);
+
+        if fds[1].revents().is_some_and(|events| events.bits() & POLLIN != 0) {
+            let notices = session.take_notices();
+            if session.take_poke() {
+                spin_until_readable(fd, ECHO_SPIN);
+            }
+            let mut notices = notices.into_iter();
+            for notice in notices.by_ref() {
+                match notice {
+                    // output held back goes to the handler that saw the rest of the update
+                    #[cfg(feature = "migrate")]
+                    Notice::Migrate(handoff) => {
+                        reader.flush_split(session, handler);
+                        reader.release(session, handler);
+                        if handoff.pause() {
+                            reader.migrated = true;
+                            // queued along with the migration, still meant for this handler
+                            notices.for_each(|notice| take_notice(session, handler, notice));
+                            return Ok(());
+                        }
+                    },
+                    notice => take_notice(session, handler, notice),
+                }
+            }
+        }
+
+        // the pty may not hang up yet, e.g. while a background job still holds it, the child is
+        // gone though, what it wrote is read and the pty is given up once it goes quiet
+        if fds[4].revents().is_some_and(|events| events.bits() & (POLLIN | ERR_BITS) != 0) {
+            fds[4] = PollFd::new(-1, flags);
+            reader.exited = Some(Instant::now());
+        }
+
+        for (i, poll_fd) in fds.iter_mut().enumerate().take(4).skip(2) {
+            let (pipe, Some(events)) = (poll_fd.as_raw_fd(), poll_fd.revents()) else { continue };
+            if events.bits() & (POLLIN | ERR_BITS) == 0 { continue }
+
+            match read(pipe) {
+                Ok(s) if !s.is_empty() => match on_stderr.as_mut() {
+                    Some(on_stderr) if i == 3 => contain(handler, id, |_| on_stderr(id, Ok(s))),
+                    _ => deliver_read(session, handler, reader, Ok(s))
+                },
+                // every writer of the pipe is gone, stop polling it
+                _ => {
+                    let _ = unistd::close(pipe);
+                    *poll_fd = PollFd::new(-1, flags);
+                }
+            }
+        }
+
+        let Some(events) = fds[0].revents() else { continue };
+        // skip if no buffer data
+        if events.bits() & POLLIN == 0 {
+            if events.bits() & ERR_BITS != 0 { break } else { continue }
+        }
+
+        // return read buffer if data available, after a hangup the child's last output
+        // is still buffered and is read until the read fails
+        let hung_up = events.bits() & ERR_BITS != 0;
+        match read(fd) {
+            Err(_) if hung_up => break,
+            Ok(s) if s.is_empty() && hung_up => break,
+            res => deliver_read(session, handler, reader, res)
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Busy polls fd until it is readable or budget runs out, the next ppoll then returns at once
+ */
+fn spin_until_readable(fd: RawFd, budget: Duration) {
+    let started = Instant::now();
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+    while started.elapsed() < budget {
+        if nix::poll::poll(&mut fds, 0).is_ok_and(|n| n > 0) { return }
+        std::hint::spin_loop();
+    }
+}
+
+/**
+ * Passes event to every attached client, a panicking client is reported to the handler
+ */
+fn broadcast<H: PtyHandler>(session: &Session, handler: &mut H, event: ClientEvent) {
+    // clients may attach or detach from inside their callback, so the list is not held
+    let callbacks = session.clients().next_event();
+    send_to(callbacks, session.id(), handler, event);
+}
+
+/**
+ * Passes event numbered seq to each callback, a panicking callback is reported to handler
+ */
+fn send_to<H: PtyHandler>((seq, callbacks): (u64, Vec<ClientCallback>), id: PtyId, handler: &mut H, event: ClientEvent) {
+    for on_event in callbacks {
+        let mut on_event = on_event.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        contain(handler, id, |_| on_event(seq, event.clone()));
+    }
+}
+
+/**
+ * Applies the configured nice value and cpu affinity to the calling polling thread
+ */
+#[cfg(target_os = "linux")]
+fn configure_reader(session: &Session) -> Result<(), Box<dyn Error>> {
+    if let Some(nice) = session.config().reader_nice {
+        // on linux the priority of a tid only affects that thread
+        let tid = unistd::gettid().as_raw() as libc::id_t;
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } < 0 {
+            return Err(Box::new(PtyError::from_errno("Reader priority failure", Errno::last())));
+        }
+    }
+
+    if let Some(cpus) = &session.config().reader_affinity {
+        let mut cpu_set = CpuSet::new();
+        for &cpu in cpus {
+            cpu_set.set(cpu)?;
+        }
+        // pid 0 is the calling thread
+        sched_setaffinity(Pid::from_raw(0), &cpu_set)?;
+    }
 
     Ok(())
 }
 
+/**
+ * Opens a pidfd of child, readable once it exited, so its exit is seen without a SIGCHLD
+ * handler or a thread blocked in waitpid, -1 where there are no pidfds (before linux 5.3 or
+ * elsewhere), the pty hanging up is all there is then
+ */
+fn child_pidfd(child: Pid) -> RawFd {
+    #[cfg(target_os = "linux")]
+    {
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, child.as_raw(), 0) };
+        if pidfd >= 0 {
+            return pidfd as RawFd;
+        }
+    }
+    let _ = child;
+    -1
+}
+
+/**
+ * Creates the pipe used to wake a polling thread, both ends are non blocking
+ */
+pub(crate) fn wake_pipe() -> Result<(RawFd, RawFd), Box<dyn Error>> {
+    let (read_end, write_end) = unistd::pipe()?;
+    for fd in [read_end, write_end] {
+        set_cloexec(fd);
+        unsafe { libc::fcntl(fd, F_SETFL, libc::fcntl(fd, F_GETFL, 0) | O_NONBLOCK) };
+    }
+    Ok((read_end, write_end))
+}
+
 pub(crate) fn read(fd: RawFd) -> Result<String, Box<dyn Error>> {
     let mut buf: [u8; 0x1000] = [0; 0x1000];
 
     match unistd::read(fd, &mut buf) {
         Ok(r) => Ok(String::from_utf8_lossy(&buf[..r]).into()),
-        Err(e) => Err(Box::new(PtyError(format!("Read failure {e}"))))
+        Err(e) => Err(Box::new(PtyError::from_errno("Read failure", e)))
     }
 }
 
+/**
+ * Writes all of buf, waiting for fd to take more whenever it is full
+ */
 pub(crate) fn write(fd: RawFd, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+    write_counted(fd, buf).map_err(|(_, err)| err)
+}
+
+/**
+ * Writes all of buf like write(), a failure comes with how much of buf was written before it
+ */
+pub(crate) fn write_counted(fd: RawFd, buf: &[u8]) -> Result<(), (usize, Box<dyn Error>)> {
+    let mut written = 0;
+    while written < buf.len() {
+        match write_some(fd, &buf[written..]).map_err(|err| (written, err))? {
+            0 => wait_writable(fd, WRITE_WAIT).map_err(|err| (written, err))?,
+            n => written += n,
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Writes as much of buf as fd takes without blocking, returns how much that was
+ */
+pub(crate) fn write_some(fd: RawFd, buf: &[u8]) -> Result<usize, Box<dyn Error>> {
     match unistd::write(fd, buf) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Box::new(PtyError(format!("Write failure {e}"))))
+        Ok(n) => Ok(n),
+        Err(Errno::EAGAIN | Errno::EINTR) => Ok(0),
+        Err(e) => Err(Box::new(PtyError::from_errno("Write failure", e)))
+    }
+}
+
+/**
+ * Waits up to timeout for fd to take more input
+ */
+pub(crate) fn wait_writable(fd: RawFd, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let mut fds = [PollFd::new(fd, PollFlags::POLLOUT)];
+    match nix::poll::poll(&mut fds, timeout.as_millis() as libc::c_int) {
+        Ok(_) | Err(Errno::EINTR) => Ok(()),
+        Err(e) => Err(Box::new(PtyError::from_errno("Poll failure", e)))
     }
 }
 
@@ -151,13 +1066,183 @@ pub(crate) fn resize(fd: RawFd, window_size: WindowSize) -> Result<(), Box<dyn E
     let window_size: winsize = window_size.to_winsize();
 
     if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &window_size as *const _) } < 0 {
-        return Err(Box::new(PtyError("Window resize failure".into())));
+        return Err(Box::new(PtyError::from_errno("Window resize failure", Errno::last())));
     }
     Ok(())
 }
 
-pub(crate) fn kill(fd: RawFd) {
-    let _ = write(fd, "exit\r".as_bytes());
+/**
+ * Process group in the foreground of the pty fd, the process running there is its leader
+ */
+pub(crate) fn foreground(fd: RawFd) -> Result<Pid, Box<dyn Error>> {
+    Ok(unistd::tcgetpgrp(fd)?)
+}
+
+pub(crate) fn foreground_process(fd: RawFd) -> Result<ForegroundProcess, Box<dyn Error>> {
+    let pid = foreground(fd)?;
+    Ok(ForegroundProcess { pid: pid.as_raw(), name: proc::name(pid)? })
+}
+
+pub(crate) fn window_size(fd: RawFd) -> Result<WindowSize, Box<dyn Error>> {
+    let mut window_size: winsize = WindowSize::new(0, 0, 0, 0).to_winsize();
+
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut window_size as *mut _) } < 0 {
+        return Err(Box::new(PtyError::from_errno("Window size failure", Errno::last())));
+    }
+    Ok(WindowSize::from_winsize(window_size))
+}
+
+/**
+ * Path of the slave of the pty with master fd, None if fd is no pty master
+ */
+pub(crate) fn slave_path(fd: RawFd) -> Option<PathBuf> {
+    // as long as the TIOCPTYGNAME buffer of macos
+    let mut buf = [0 as libc::c_char; 128];
+    #[cfg(target_os = "linux")]
+    let res = unsafe { libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) };
+    #[cfg(target_os = "macos")]
+    let res = unsafe { libc::ioctl(fd, libc::TIOCPTYGNAME as _, buf.as_mut_ptr()) };
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    let res = { let _ = fd; -1 };
+    if res != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Some(PathBuf::from(OsStr::from_bytes(name.to_bytes())))
+}
+
+/**
+ * Whether input written to the tty of fd now is hidden, echo off in canonical mode like at a
+ * password prompt, a line editor turning both off to echo by itself does not count
+ */
+pub(crate) fn input_hidden(fd: RawFd) -> bool {
+    termios::tcgetattr(fd).is_ok_and(|termios| {
+        let flags = termios.local_flags;
+        !flags.contains(termios::LocalFlags::ECHO) && flags.contains(termios::LocalFlags::ICANON)
+    })
+}
+
+/**
+ * The status character of the tty of fd, ^T unless the child changed it
+ */
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd"))]
+pub(crate) fn status_char(fd: RawFd) -> Result<u8, Box<dyn Error>> {
+    let termios = termios::tcgetattr(fd).map_err(|e| PtyError::from_errno("Terminal attributes failure", e))?;
+    match termios.control_chars[termios::SpecialCharacterIndices::VSTATUS as usize] {
+        termios::_POSIX_VDISABLE => Err(Box::new(PtyError::new("The status character of the tty is disabled"))),
+        c => Ok(c),
+    }
+}
+
+pub(crate) fn flush(fd: RawFd, queue: FlushArg) -> Result<(), Box<dyn Error>> {
+    match termios::tcflush(fd, queue) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Box::new(PtyError::from_errno("Flush failure", e)))
+    }
+}
+
+pub(crate) fn flow(fd: RawFd, action: FlowArg) -> Result<(), Box<dyn Error>> {
+    match termios::tcflow(fd, action) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Box::new(PtyError::from_errno("Flow control failure", e)))
+    }
+}
+
+pub(crate) fn signal(child: Pid, signal: Signal) -> Result<(), Box<dyn Error>> {
+    // the child called setsid() so its pid is also its process group id
+    match killpg(child, signal) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Box::new(PtyError::from_errno("Signal failure", e)))
+    }
+}
+
+/**
+ * Whether child has not exited yet, without reaping it, that is left to the polling thread,
+ * a child adopted through a migration is not ours to wait for, it is alive while its pid is
+ */
+pub(crate) fn child_alive(child: Pid) -> Result<bool, Box<dyn Error>> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    match unsafe { libc::waitid(libc::P_PID, child.as_raw() as libc::id_t, &mut info, flags) } {
+        // a child that has not exited leaves info zeroed
+        0 => Ok(unsafe { info.si_pid() } == 0),
+        // already reaped, or adopted from the process that spawned it
+        _ if errno() == libc::ECHILD => match nix::sys::signal::kill(child, None) {
+            Ok(_) | Err(Errno::EPERM) => Ok(true),
+            Err(_) => Ok(false)
+        },
+        _ => Err(Box::new(PtyError::from_errno("Wait failure", Errno::last())))
+    }
+}
+
+pub(crate) fn fd_valid(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, F_GETFD) != -1 }
+}
+
+pub(crate) fn kill(fd: RawFd, input: &[u8]) {
+    let _ = write(fd, input);
+}
+
+/**
+ * Writes the shutdown input then escalates through SIGHUP, SIGTERM and SIGKILL,
+ * sent to the child's whole process group, until the child has been reaped
+ */
+pub(crate) fn shutdown(session: &Session) -> Result<(), Box<dyn Error>> {
+    let timeouts = session.config().shutdown_timeouts;
+
+    // stopped by DetachPolicy::Stop, it would neither read the input nor handle the signals
+    {
+        let mut clients = session.clients();
+        if clients.stopped {
+            clients.stopped = false;
+            let _ = killpg(session.child(), Signal::SIGCONT);
+        }
+    }
+
+    // input written before the shutdown goes ahead of it
+    let _ = session.flush_coalesced(true);
+    if !session.config().shutdown_input.is_empty() {
+        session.shutdown_step(ShutdownProgress::SentInput);
+        let _ = session.with_input_fd(|fd| write(fd, &session.config().shutdown_input));
+        if session.wait_exited(timeouts.input) { return Ok(()) }
+    }
+
+    let steps = [
+        (Signal::SIGHUP, ShutdownProgress::SentHup, timeouts.hup),
+        (Signal::SIGTERM, ShutdownProgress::SentTerm, timeouts.term),
+        (Signal::SIGKILL, ShutdownProgress::SentKill, timeouts.kill),
+    ];
+    for (signal, progress, timeout) in steps {
+        session.shutdown_step(progress);
+        // the child called setsid() so its pid is also its process group id
+        let _ = killpg(session.child(), signal);
+        if session.wait_exited(timeout) { return Ok(()) }
+    }
+
+    Err(Box::new(PtyError::with_kind(format!("Shutdown failure, child {} did not exit", session.child()), std::io::ErrorKind::TimedOut)))
+}
+
+/**
+ * Creates a pipe for one of the child's stdio streams, returns the parent's end and the child's end
+ * both ends are close on exec, the parent's end is also non blocking like master
+ */
+pub(crate) fn pipe(parent_reads: bool) -> Result<(File, File), Box<dyn Error>> {
+    let (read_end, write_end) = unistd::pipe()?;
+    set_cloexec(read_end);
+    set_cloexec(write_end);
+
+    let (parent_end, child_end) = if parent_reads { (read_end, write_end) } else { (write_end, read_end) };
+    unsafe { libc::fcntl(parent_end, F_SETFL, libc::fcntl(parent_end, F_GETFL, 0) | O_NONBLOCK) };
+
+    Ok((unsafe { File::from_raw_fd(parent_end) }, unsafe { File::from_raw_fd(child_end) }))
+}
+
+pub(crate) fn set_cloexec(fd: RawFd) {
+    unsafe { libc::fcntl(fd, F_SETFD, libc::fcntl(fd, F_GETFD) | FD_CLOEXEC) };
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd) {
+    unsafe { libc::fcntl(fd, F_SETFL, libc::fcntl(fd, F_GETFL, 0) | O_NONBLOCK) };
 }
 
 fn validate_fd(fd: RawFd) -> Result<(), Box<dyn Error>> {
@@ -165,7 +1250,7 @@ fn validate_fd(fd: RawFd) -> Result<(), Box<dyn Error>> {
         if libc::fcntl(fd, F_GETFD) != -1 || errno() != EBADFD {
             Ok(())
         } else {
-            Err(Box::new(PtyError(format!("Invalid file descriptor: {fd}"))))
+            Err(Box::new(PtyError::from_errno(format!("Invalid file descriptor: {fd}"), Errno::EBADF)))
         }
     }
 }
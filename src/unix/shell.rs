@@ -1,76 +1,138 @@
+use nix::errno::Errno;
 use nix::libc;
 use std::mem::MaybeUninit;
-use std::ffi::{CStr};
+use std::ffi::{CStr, CString};
 use std::{env, ptr};
 use std::error::Error;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 use crate::error::PtyError;
 
-/**
- * Shell User composed of environment variables
- */
-pub(crate) struct ShellUser {
+/// Account a pty's shell runs as, see PtyBuilder::user()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShellUser {
     pub user: String,
     pub home: String,
     pub shell: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// How the account of the current user is found when spawning, see PtyBuilder::user_lookup()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UserLookup {
+    /// ask the password database for what $USER, $HOME and $SHELL do not say, giving up after
+    /// timeout, NSS backed by a directory service that is down can otherwise hang forever
+    Passwd { timeout: Duration },
+    /// only the environment, never the password database
+    Env,
+}
+
+impl Default for UserLookup {
+    fn default() -> UserLookup {
+        UserLookup::Passwd { timeout: Duration::from_secs(2) }
+    }
 }
 
 impl ShellUser {
     /**
-     * Constructs a shell user from environment
+     * Constructs a shell user from environment, the password database fills in what it lacks,
+     * default_shell is used if neither knows or the lookup fails
      */
-    pub(crate) fn from_env() -> Result<ShellUser, Box<dyn Error>> {
-        let mut buf: [u8; 1024] = [0; 1024];
-        // Create zeroed passwd struct.
-        let mut entry: MaybeUninit<libc::passwd> = MaybeUninit::uninit();
-        let mut res: *mut libc::passwd = ptr::null_mut();
+    pub(crate) fn from_env(lookup: UserLookup, default_shell: &str) -> ShellUser {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let (user, home, shell) = (env::var("USER").ok(), env::var("HOME").ok(), env::var("SHELL").ok());
 
-        // Try and read the pw file.
-        let uid = unsafe { libc::getuid() };
-        let status = unsafe { libc::getpwuid_r(
-            uid,
-            entry.as_mut_ptr(),
-            buf.as_mut_ptr() as *mut _,
-            buf.len(),
-            &mut res
-        )};
-        let entry = unsafe { entry.assume_init() };
+        let entry = match (lookup, &user, &home, &shell) {
+            // nothing to look up
+            (_, Some(_), Some(_), Some(_)) | (UserLookup::Env, ..) => None,
+            (UserLookup::Passwd { timeout }, ..) => {
+                let (tx, rx) = mpsc::channel();
+                // a hung lookup is left behind, there is no way to cancel it
+                let _ = thread::Builder::new().name("pty-exec/getpwuid".into()).spawn(move || {
+                    let entry = lookup_uid(uid).map_err(|err| PtyError::copy_of(err.as_ref()));
+                    let _ = tx.send(entry);
+                });
+                rx.recv_timeout(timeout).ok().and_then(|entry| entry.ok()).flatten()
+            }
+        };
 
-        if status < 0 {
-            return Err(Box::new(PtyError("session password UID status error".into())));
+        match entry {
+            Some(entry) => ShellUser {
+                user: user.unwrap_or(entry.user),
+                home: home.unwrap_or(entry.home),
+                shell: shell.unwrap_or(entry.shell),
+                ..entry
+            },
+            None => ShellUser {
+                user: user.unwrap_or_else(|| uid.to_string()),
+                home: home.unwrap_or_else(|| "/".to_owned()),
+                shell: shell.unwrap_or_else(|| default_shell.to_owned()),
+                uid,
+                gid,
+            }
         }
+    }
 
-        if res.is_null() {
-            return Err(Box::new(PtyError("session password response error".into())));
-        }
-        // Sanity check.
-        assert_eq!(entry.pw_uid, uid);
+    /**
+     * The shells a spawn tries in order, the user's shell and then fallbacks other than it
+     */
+    pub(crate) fn shells(&self, fallbacks: &[String]) -> Vec<String> {
+        let fallbacks = fallbacks.iter().filter(|fallback| **fallback != self.shell).cloned();
+        std::iter::once(self.shell.clone()).chain(fallbacks).collect()
+    }
 
-        let user = match env::var("USER") {
-            Ok(user) => user,
-            Err(_) => unsafe {
-                CStr::from_ptr(entry.pw_name).to_str()?.to_owned()
-            }
-        };
+    /// the account called name as the password database has it, the environment is ignored
+    pub fn from_name(name: &str) -> Result<ShellUser, Box<dyn Error>> {
+        let c_name = CString::new(name)?;
+        let entry = lookup(|entry, buf, len, res| unsafe { libc::getpwnam_r(c_name.as_ptr(), entry, buf, len, res) })?;
+        entry.ok_or_else(|| PtyError::new(format!("No user named {name:?}")).into())
+    }
+}
 
-        let home = match env::var("HOME") {
-            Ok(home) => home,
-            Err(_) => unsafe {
-                CStr::from_ptr(entry.pw_dir).to_str()?.to_owned()
-            }
-        };
+/**
+ * Password database entry of uid
+ */
+fn lookup_uid(uid: libc::uid_t) -> Result<Option<ShellUser>, Box<dyn Error>> {
+    let entry = lookup(|entry, buf, len, res| unsafe { libc::getpwuid_r(uid, entry, buf, len, res) })?;
+    // Sanity check.
+    assert!(entry.as_ref().is_none_or(|entry| entry.uid == uid));
+    Ok(entry)
+}
 
-        let shell = match env::var("SHELL") {
-            Ok(shell) => shell,
-            Err(_) => unsafe {
-                CStr::from_ptr(entry.pw_shell).to_str()?.to_owned()
-            }
-        };
+/**
+ * Runs a getpw*_r function with a buffer large enough for the entry, `None` if there is no entry
+ */
+fn lookup<F>(getpw: F) -> Result<Option<ShellUser>, Box<dyn Error>>
+    where F: Fn(*mut libc::passwd, *mut libc::c_char, usize, *mut *mut libc::passwd) -> libc::c_int
+{
+    let mut buf: Vec<u8> = vec![0; 1024];
+    loop {
+        let mut entry: MaybeUninit<libc::passwd> = MaybeUninit::uninit();
+        let mut res: *mut libc::passwd = ptr::null_mut();
 
-        Ok(Self {
-            user,
-            home,
-            shell
-        })
+        // getpw*_r return the errno instead of setting it
+        match getpw(entry.as_mut_ptr(), buf.as_mut_ptr() as *mut _, buf.len(), &mut res) {
+            // entries with long member lists need more room
+            libc::ERANGE if buf.len() < 0x100000 => buf.resize(buf.len() * 2, 0),
+            0 if res.is_null() => return Ok(None),
+            0 => {
+                let entry = unsafe { entry.assume_init() };
+                let field = |ptr: *const libc::c_char| -> Result<String, Box<dyn Error>> {
+                    Ok(unsafe { CStr::from_ptr(ptr) }.to_str()?.to_owned())
+                };
+                return Ok(Some(ShellUser {
+                    user: field(entry.pw_name)?,
+                    home: field(entry.pw_dir)?,
+                    shell: field(entry.pw_shell)?,
+                    uid: entry.pw_uid,
+                    gid: entry.pw_gid,
+                }));
+            },
+            status => return Err(Box::new(PtyError::from_errno("session password UID status error", Errno::from_i32(status)))),
+        }
     }
 }
 
@@ -80,6 +142,21 @@ mod tests {
 
     #[test]
     fn shell_from_env() {
-        let _shell_user = ShellUser::from_env().unwrap();
+        let _shell_user = ShellUser::from_env(UserLookup::default(), "/bin/sh");
+    }
+
+    #[test]
+    fn shell_env_only() {
+        let user = ShellUser::from_env(UserLookup::Env, "/bin/sh");
+        assert_eq!(user.shell, env::var("SHELL").unwrap_or("/bin/sh".into()));
+        assert_eq!(user.uid, unsafe { libc::getuid() });
+    }
+
+    #[test]
+    fn shell_from_name() -> Result<(), Box<dyn Error>> {
+        let root = ShellUser::from_name("root")?;
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(ShellUser::from_name("no-such-user-pty-exec").is_err());
+        Ok(())
     }
 }
@@ -0,0 +1,434 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::env;
+use nix::libc;
+use nix::pty::openpty;
+use nix::sys::termios::{self, InputFlags, SetArg};
+use nix::unistd::{self, Pid};
+use crate::builder::{PtyBuilder, StdioMode, SESSION_ID_ENV};
+use crate::error::PtyError;
+use crate::shell_integration::{self, Shell};
+use crate::unix::pty::{self, Child};
+use crate::unix::shell::ShellUser;
+use crate::unix::window::WindowSize;
+
+// the signals the fork path resets to their default before the shell starts
+const DEFAULT_SIGNALS: [libc::c_int; 6] = [libc::SIGCHLD, libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM, libc::SIGALRM];
+
+/**
+ * What spawning without fork needs set up beforehand, the pty, the pipes and the shells to try
+ */
+struct Prepared {
+    user: ShellUser,
+    master: File,
+    slave: File,
+    // stdin, stdout and stderr, the parent's end and the child's end of those not on the pty
+    pipes: [Option<(File, File)>; 3],
+    shells: Vec<String>,
+}
+
+/**
+ * Opens the pty and pipes of config, None if config asks for something only the fork path can
+ * do: running as another user or in a sandbox
+ */
+fn prepare(config: &PtyBuilder) -> Result<Option<Prepared>, Box<dyn Error>> {
+    let user = match &config.config.user {
+        Some(user) => user.clone(),
+        None => ShellUser::from_env(config.user_lookup, &config.default_shell)
+    };
+    #[cfg(feature = "sandbox")]
+    let sandboxed = !config.sandbox.is_empty();
+    #[cfg(not(feature = "sandbox"))]
+    let sandboxed = false;
+    if user.uid != unistd::getuid().as_raw() || sandboxed {
+        return Ok(None);
+    }
+
+    let ends = openpty(config.window_size.map(WindowSize::to_winsize).as_ref(), None)?;
+    let (master, slave) = (unsafe { File::from_raw_fd(ends.master) }, unsafe { File::from_raw_fd(ends.slave) });
+    for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+        pty::set_cloexec(fd);
+    }
+    if let Ok(mut termios) = termios::tcgetattr(master.as_raw_fd()) {
+        termios.input_flags.set(InputFlags::IUTF8, true);
+        let _ = termios::tcsetattr(master.as_raw_fd(), SetArg::TCSANOW, &termios);
+    }
+
+    let pipes = [
+        (config.config.stdin == StdioMode::Piped).then(|| pty::pipe(false)).transpose()?,
+        (config.config.stdout == StdioMode::Piped).then(|| pty::pipe(true)).transpose()?,
+        config.on_stderr.is_some().then(|| pty::pipe(true)).transpose()?,
+    ];
+    let shells = config.execs(&user);
+    Ok(Some(Prepared { user, master, slave, pipes, shells }))
+}
+
+impl Prepared {
+    /**
+     * The fds the child's stdin, stdout and stderr are dup'd from
+     */
+    fn stdio(&self) -> [RawFd; 3] {
+        let slave = self.slave.as_raw_fd();
+        [0, 1, 2].map(|n| self.pipes[n].as_ref().map_or(slave, |(_, child_end)| child_end.as_raw_fd()))
+    }
+
+    /**
+     * Handles to the child started, the child's ends are closed in the parent
+     */
+    fn child(self, pid: libc::pid_t, shell: &str) -> Child {
+        let [stdin, stdout, stderr] = self.pipes.map(|pipe| pipe.map(|(parent_end, _)| parent_end.into_raw_fd()));
+        Child { master: self.master.into_raw_fd(), pid: Pid::from_raw(pid), shell: shell.to_owned(), stdin, stdout, stderr }
+    }
+
+    /**
+     * No shell could be started, failures being why each of them could not
+     */
+    fn failed(self, failures: Vec<String>, kind: ErrorKind) -> Box<dyn Error> {
+        Box::new(PtyError::with_kind(format!("failed to spawn command {}", failures.join(", ")), kind))
+    }
+}
+
+/**
+ * Arguments and environment of shell, what the fork path sets on its Command
+ */
+fn command(config: &PtyBuilder, user: &ShellUser, shell: &str) -> Result<(Vec<CString>, Vec<CString>), Box<dyn Error>> {
+    let mut args: Vec<OsString> = vec![shell.into()];
+    args.extend(config.program.iter().skip(1).map(OsString::from));
+    let mut vars: BTreeMap<OsString, OsString> = match config.env_clear {
+        true => BTreeMap::new(),
+        false => env::vars_os().collect()
+    };
+    vars.extend(config.env.iter().map(|(key, value)| (key.into(), value.into())));
+    vars.insert("USER".into(), user.user.clone().into());
+    vars.insert("HOME".into(), user.home.clone().into());
+    vars.insert("SHELL".into(), if config.program.is_empty() { shell } else { &user.shell }.into());
+    if let Some(tag) = &config.config.tag {
+        vars.insert(SESSION_ID_ENV.into(), tag.into());
+    }
+    let integrate = config.shell_integration && config.program.is_empty();
+    if let Some(integration) = integrate.then(|| Shell::from_path(shell)).flatten() {
+        let (extra_args, extra_vars) = shell_integration::arguments(integration, &user.home)?;
+        args.extend(extra_args);
+        vars.extend(extra_vars);
+    }
+
+    let argv = args.into_iter().map(|arg| CString::new(arg.into_vec())).collect::<Result<_, _>>()?;
+    let envp = vars.into_iter().map(|(key, value)| {
+        let mut var = key.into_vec();
+        var.push(b'=');
+        var.extend(value.into_vec());
+        CString::new(var)
+    }).collect::<Result<_, _>>()?;
+    Ok((argv, envp))
+}
+
+/**
+ * Path shell is exec'd from, looked up in the PATH the child gets like Command does rather
+ * than in the parent's like posix_spawnp, shell itself if it has a slash or is not found
+ */
+fn resolve(config: &PtyBuilder, shell: &str) -> OsString {
+    if shell.contains('/') {
+        return shell.into();
+    }
+    let path = match config.env.iter().rev().find(|(key, _)| key == "PATH") {
+        Some((_, path)) => Some(OsString::from(path)),
+        None if config.env_clear => None,
+        None => env::var_os("PATH")
+    };
+    // execvp's default when there is no PATH
+    let path = path.unwrap_or_else(|| "/bin:/usr/bin".into());
+    env::split_paths(&path)
+        .map(|dir| dir.join(shell))
+        .find(|candidate| CString::new(candidate.as_os_str().as_bytes()).is_ok_and(|candidate| unsafe { libc::access(candidate.as_ptr(), libc::X_OK) } == 0))
+        .map_or_else(|| shell.into(), |candidate| candidate.into_os_string())
+}
+
+/**
+ * Null terminated array of pointers to strings, as exec takes its argv and envp
+ */
+fn pointers(strings: &[CString]) -> Vec<*mut libc::c_char> {
+    strings.iter().map(|s| s.as_ptr() as *mut _).chain([std::ptr::null_mut()]).collect()
+}
+
+#[cfg(target_env = "gnu")]
+pub(crate) use posix::posix_spawn;
+
+#[cfg(target_env = "gnu")]
+mod posix {
+    use std::mem::MaybeUninit;
+    use nix::errno::Errno;
+    use super::*;
+
+    /**
+     * Spawns the shell of config with posix_spawn, the new session and its controlling tty are
+     * set up by posix_spawn itself, None if only the fork path can spawn it
+     */
+    pub(crate) fn posix_spawn(config: &PtyBuilder) -> Result<Option<Child>, Box<dyn Error>> {
+        let Some(prepared) = prepare(config)? else { return Ok(None) };
+        let tty = CString::new(unistd::ttyname(prepared.slave.as_raw_fd())?.into_os_string().into_vec())?;
+
+        // the tty is opened by the child after setsid, which makes it the controlling tty, above
+        // every fd the file actions dup from so it cannot clobber one of them
+        let stdio = prepared.stdio();
+        let tty_fd = stdio.iter().chain(&[prepared.master.as_raw_fd(), prepared.slave.as_raw_fd()]).max().copied().unwrap_or(2) + 1;
+        let actions = FileActions::new()?;
+        check(unsafe { libc::posix_spawn_file_actions_addopen(actions.as_ptr(), tty_fd, tty.as_ptr(), libc::O_RDWR, 0) })?;
+        for (n, from) in stdio.into_iter().enumerate() {
+            let from = if from == prepared.slave.as_raw_fd() { tty_fd } else { from };
+            check(unsafe { libc::posix_spawn_file_actions_adddup2(actions.as_ptr(), from, n as libc::c_int) })?;
+        }
+        check(unsafe { libc::posix_spawn_file_actions_addclose(actions.as_ptr(), tty_fd) })?;
+        if let Some(cwd) = &config.cwd {
+            let cwd = CString::new(cwd.as_os_str().to_owned().into_vec())?;
+            check(unsafe { libc::posix_spawn_file_actions_addchdir_np(actions.as_ptr(), cwd.as_ptr()) })?;
+        }
+        let attributes = Attributes::new()?;
+
+        let (mut failures, mut kind) = (Vec::new(), ErrorKind::Other);
+        for shell in prepared.shells.clone() {
+            let (argv, envp) = command(config, &prepared.user, &shell)?;
+            let path = CString::new(resolve(config, &shell).into_vec())?;
+            let mut pid: libc::pid_t = 0;
+            let res = unsafe {
+                libc::posix_spawn(&mut pid, path.as_ptr(), actions.as_ptr(), attributes.as_ptr(), pointers(&argv).as_ptr(), pointers(&envp).as_ptr())
+            };
+            if res == 0 {
+                return Ok(Some(prepared.child(pid, &shell)));
+            }
+            let err = std::io::Error::from_raw_os_error(res);
+            failures.push(format!("'{shell}': {err}"));
+            kind = err.kind();
+            // posix_spawn reports the errno of the failed exec, any other error ends the search
+            if !matches!(kind, ErrorKind::NotFound | ErrorKind::PermissionDenied) { break }
+        }
+        Err(prepared.failed(failures, kind))
+    }
+
+    fn check(res: libc::c_int) -> Result<(), Box<dyn Error>> {
+        match res {
+            0 => Ok(()),
+            errno => Err(Box::new(PtyError::from_errno("Posix spawn setup failure", Errno::from_i32(errno))))
+        }
+    }
+
+    /**
+     * posix_spawn_file_actions_t, destroyed on drop
+     */
+    struct FileActions(Box<MaybeUninit<libc::posix_spawn_file_actions_t>>);
+
+    impl FileActions {
+        fn new() -> Result<FileActions, Box<dyn Error>> {
+            let mut actions = Box::new(MaybeUninit::uninit());
+            check(unsafe { libc::posix_spawn_file_actions_init(actions.as_mut_ptr()) })?;
+            Ok(FileActions(actions))
+        }
+
+        fn as_ptr(&self) -> *mut libc::posix_spawn_file_actions_t {
+            self.0.as_ptr() as *mut _
+        }
+    }
+
+    impl Drop for FileActions {
+        fn drop(&mut self) {
+            unsafe { libc::posix_spawn_file_actions_destroy(self.as_ptr()) };
+        }
+    }
+
+    /**
+     * posix_spawnattr_t of a new session with the signals of the fork path reset, destroyed on drop
+     */
+    struct Attributes(Box<MaybeUninit<libc::posix_spawnattr_t>>);
+
+    impl Attributes {
+        fn new() -> Result<Attributes, Box<dyn Error>> {
+            let mut attributes = Box::new(MaybeUninit::uninit());
+            check(unsafe { libc::posix_spawnattr_init(attributes.as_mut_ptr()) })?;
+            let attributes = Attributes(attributes);
+
+            unsafe {
+                let mut default = MaybeUninit::<libc::sigset_t>::uninit();
+                libc::sigemptyset(default.as_mut_ptr());
+                for signal in DEFAULT_SIGNALS {
+                    libc::sigaddset(default.as_mut_ptr(), signal);
+                }
+                let mut mask = MaybeUninit::<libc::sigset_t>::uninit();
+                libc::sigemptyset(mask.as_mut_ptr());
+
+                let flags = libc::POSIX_SPAWN_SETSID | libc::POSIX_SPAWN_SETSIGDEF | libc::POSIX_SPAWN_SETSIGMASK;
+                check(libc::posix_spawnattr_setflags(attributes.as_ptr(), flags as libc::c_short))?;
+                check(libc::posix_spawnattr_setsigdefault(attributes.as_ptr(), default.as_ptr()))?;
+                check(libc::posix_spawnattr_setsigmask(attributes.as_ptr(), mask.as_ptr()))?;
+            }
+            Ok(attributes)
+        }
+
+        fn as_ptr(&self) -> *mut libc::posix_spawnattr_t {
+            self.0.as_ptr() as *mut _
+        }
+    }
+
+    impl Drop for Attributes {
+        fn drop(&mut self) {
+            unsafe { libc::posix_spawnattr_destroy(self.as_ptr()) };
+        }
+    }
+}
+
+#[cfg(feature = "vfork")]
+pub(crate) use vfork::vfork;
+
+#[cfg(feature = "vfork")]
+mod vfork {
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use nix::errno::errno;
+    use nix::sys::wait::waitpid;
+    use super::*;
+
+    // the child only makes a few system calls before it execs
+    const STACK_SIZE: usize = 0x20000;
+
+    /**
+     * What the child does, set up by the parent beforehand, the child shares the parent's memory
+     * and must not allocate or lock anything
+     */
+    struct Job {
+        // path, argv and envp of each shell to try
+        execs: Vec<(*const libc::c_char, Vec<*mut libc::c_char>, Vec<*mut libc::c_char>)>,
+        slave: RawFd,
+        stdio: [RawFd; 3],
+        cwd: Option<CString>,
+        // written by the child when it gives up: the errno of the step that failed before exec,
+        // or of each shell it tried to exec
+        setup_errno: libc::c_int,
+        exec_errnos: Vec<libc::c_int>,
+        tried: usize,
+        failed: bool,
+    }
+
+    /**
+     * Spawns the shell of config with clone(CLONE_VM | CLONE_VFORK), the child runs on a stack of
+     * its own in the memory of the parent until it execs, sparing the copy of the page tables
+     * fork makes, None if only the fork path can spawn it
+     */
+    pub(crate) fn vfork(config: &PtyBuilder) -> Result<Option<Child>, Box<dyn Error>> {
+        let Some(prepared) = prepare(config)? else { return Ok(None) };
+        let commands = prepared.shells.iter().map(|shell| command(config, &prepared.user, shell)).collect::<Result<Vec<_>, _>>()?;
+        // the child cannot search PATH without allocating, the shells are looked up beforehand
+        let paths = prepared.shells.iter().map(|shell| CString::new(resolve(config, shell).into_vec())).collect::<Result<Vec<_>, _>>()?;
+        let mut job = Job {
+            execs: paths.iter().zip(&commands).map(|(path, (argv, envp))| (path.as_ptr(), pointers(argv), pointers(envp))).collect(),
+            slave: prepared.slave.as_raw_fd(),
+            stdio: prepared.stdio(),
+            cwd: config.cwd.as_ref().map(|cwd| CString::new(cwd.as_os_str().to_owned().into_vec())).transpose()?,
+            setup_errno: 0,
+            exec_errnos: vec![0; commands.len()],
+            tried: 0,
+            failed: false,
+        };
+
+        let stack = unsafe {
+            libc::mmap(ptr::null_mut(), STACK_SIZE, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK, -1, 0)
+        };
+        if stack == libc::MAP_FAILED {
+            return Err(Box::new(PtyError::from(io::Error::last_os_error())));
+        }
+        let pid = unsafe {
+            // a handler of the parent must not run on the child's stack in the parent's memory,
+            // the child resets the handlers before it unblocks signals again
+            let (mut all, mut old) = (mem::zeroed(), mem::zeroed());
+            libc::sigfillset(&mut all);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &all, &mut old);
+            let top = (stack as *mut u8).add(STACK_SIZE) as *mut libc::c_void;
+            let pid = libc::clone(child, top, libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD, &mut job as *mut Job as *mut libc::c_void);
+            let clone_errno = errno();
+            libc::pthread_sigmask(libc::SIG_SETMASK, &old, ptr::null_mut());
+            libc::munmap(stack, STACK_SIZE);
+            if pid < 0 {
+                return Err(Box::new(PtyError::from(io::Error::from_raw_os_error(clone_errno))));
+            }
+            pid
+        };
+
+        // the parent goes on once the child exec'd or gave up
+        if !job.failed {
+            if let Some(shell) = prepared.shells.get(job.tried).cloned() {
+                return Ok(Some(prepared.child(pid, &shell)));
+            }
+        }
+        let _ = waitpid(Pid::from_raw(pid), None);
+        if job.setup_errno != 0 {
+            // the child gives up before it execs any shell
+            let err = io::Error::from_raw_os_error(job.setup_errno);
+            let (kind, shell) = (err.kind(), prepared.shells.first().cloned().unwrap_or_default());
+            return Err(prepared.failed(vec![format!("'{shell}': {err}")], kind));
+        }
+        let failures: Vec<String> = prepared.shells.iter().zip(&job.exec_errnos).take(job.tried)
+            .map(|(shell, &errno)| format!("'{shell}': {}", io::Error::from_raw_os_error(errno)))
+            .collect();
+        let Some(&last) = job.tried.checked_sub(1).and_then(|last| job.exec_errnos.get(last)) else {
+            return Err(prepared.failed(vec!["child exited before exec".into()], ErrorKind::Other));
+        };
+        Err(prepared.failed(failures, io::Error::from_raw_os_error(last).kind()))
+    }
+
+    /**
+     * The child, does what the pre_exec of the fork path does and execs the first shell it can
+     */
+    extern "C" fn child(job: *mut libc::c_void) -> libc::c_int {
+        let job = unsafe { &mut *(job as *mut Job) };
+        unsafe {
+            // handlers run the parent's code, exec would reset them anyway
+            for signal in 1..=libc::SIGRTMAX() {
+                if signal == libc::SIGKILL || signal == libc::SIGSTOP { continue }
+                let mut action: libc::sigaction = mem::zeroed();
+                if libc::sigaction(signal, ptr::null(), &mut action) < 0 || action.sa_sigaction == libc::SIG_DFL { continue }
+                if action.sa_sigaction != libc::SIG_IGN || DEFAULT_SIGNALS.contains(&signal) {
+                    let mut default: libc::sigaction = mem::zeroed();
+                    default.sa_sigaction = libc::SIG_DFL;
+                    libc::sigaction(signal, &default, ptr::null_mut());
+                }
+            }
+            let mut empty = mem::zeroed();
+            libc::sigemptyset(&mut empty);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &empty, ptr::null_mut());
+
+            #[allow(clippy::cast_lossless)]
+            let mut ok = libc::setsid() >= 0 && libc::ioctl(job.slave, libc::TIOCSCTTY as _, 0) >= 0;
+            for (n, from) in job.stdio.into_iter().enumerate() {
+                ok = ok && match from == n as RawFd {
+                    // dup2 onto itself would leave it close on exec
+                    true => libc::fcntl(from, libc::F_SETFD, 0) >= 0,
+                    false => libc::dup2(from, n as RawFd) >= 0
+                };
+            }
+            if let Some(cwd) = &job.cwd {
+                ok = ok && libc::chdir(cwd.as_ptr()) >= 0;
+            }
+            if !ok {
+                job.setup_errno = errno();
+                job.failed = true;
+                libc::_exit(127);
+            }
+
+            for (path, argv, envp) in &job.execs {
+                libc::execve(*path, argv.as_ptr() as *const _, envp.as_ptr() as *const _);
+                let exec_errno = errno();
+                if let Some(slot) = job.exec_errnos.get_mut(job.tried) {
+                    *slot = exec_errno;
+                }
+                job.tried += 1;
+                // only a shell that is missing or not executable is worth trading for the next
+                if !matches!(exec_errno, libc::ENOENT | libc::EACCES) { break }
+            }
+            job.failed = true;
+            libc::_exit(127)
+        }
+    }
+}
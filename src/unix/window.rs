@@ -1,15 +1,54 @@
+#[cfg(not(target_arch = "wasm32"))]
 use nix::libc::winsize;
 
+/// Size of a pty in character cells and pixels
 #[allow(non_snake_case)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WindowSize {
+    #[cfg_attr(feature = "serde", serde(rename = "rows"))]
     numRows: u16,
+    #[cfg_attr(feature = "serde", serde(rename = "cols"))]
     numCols: u16,
+    #[cfg_attr(feature = "serde", serde(rename = "cell_width"))]
     cellWidth: u16,
+    #[cfg_attr(feature = "serde", serde(rename = "cell_height"))]
     cellHeight: u16,
 }
 
 impl WindowSize {
-    pub(crate) fn to_winsize(&self) -> winsize {
+    pub fn new(rows: u16, cols: u16, cell_width: u16, cell_height: u16) -> WindowSize {
+        WindowSize {
+            numRows: rows,
+            numCols: cols,
+            cellWidth: cell_width,
+            cellHeight: cell_height,
+        }
+    }
+
+    pub fn rows(&self) -> u16 {
+        self.numRows
+    }
+
+    pub fn cols(&self) -> u16 {
+        self.numCols
+    }
+
+    pub fn cell_width(&self) -> u16 {
+        self.cellWidth
+    }
+
+    pub fn cell_height(&self) -> u16 {
+        self.cellHeight
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn from_winsize(ws: winsize) -> WindowSize {
+        WindowSize::new(ws.ws_row, ws.ws_col, ws.ws_xpixel, ws.ws_ypixel)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn to_winsize(self) -> winsize {
         winsize {
             ws_row: self.numRows,
             ws_col: self.numCols,
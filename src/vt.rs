@@ -0,0 +1,578 @@
+//! A minimal model of the screen of a pty, enabled with the `vt` feature, see
+//! Pty::screen_text(), Pty::cursor_position() and Pty::screen_size()
+//! output is applied to a grid of characters as a terminal would, cursor movement, erasing,
+//! scrolling regions, insertion and deletion and the alternate screen of full screen apps
+//! included, attributes such as colors are not kept, so what is on screen can be asserted on
+//! instead of the bytes that drew it, e.g. for golden screen tests of a TUI app
+//! a pty without a size, see PtyBuilder::window_size(), is modelled as 24x80, the size
+//! programs fall back to
+//! clients of a server may ask for ScreenDiff frames in place of output, the rows that changed,
+//! see Client::connect_diffs(), for a full screen app redrawing a lot of it over a slow link
+//! that is a fraction of the bytes, a ScreenMirror rebuilds the screen from them
+//! ```rust
+//! use std::time::Duration;
+//! use pty_exec::{Pty, WindowSize};
+//!
+//! let pty = Pty::builder().window_size(WindowSize::new(24, 80, 0, 0)).spawn(|_id, _res| {}, |_id| {})?;
+//! pty.write("printf '\\033[2J\\033[Htop\\n\\033[5;3Hmoved\\n'\r")?;
+//! # let deadline = std::time::Instant::now() + Duration::from_secs(10);
+//! # while !pty.screen_text()?.iter().any(|line| line == "  moved") && std::time::Instant::now() < deadline {
+//! #     std::thread::sleep(Duration::from_millis(10));
+//! # }
+//! let screen = pty.screen_text()?;
+//! assert_eq!(screen[4], "  moved");
+//! assert_eq!(pty.screen_size()?, (24, 80));
+//! // the prompt after the output
+//! assert!(pty.cursor_position()?.row > 4);
+//! # pty.shutdown()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::mem;
+use unicode_width::UnicodeWidthChar;
+use crate::unix::window::WindowSize;
+
+// the size of a pty that has none
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_COLS: u16 = 80;
+
+// longer parameters are not kept, no sequence modelled needs them
+const MAX_PARAMS_LEN: usize = 0x40;
+
+// the second half of a wide character
+const WIDE_TAIL: char = '\0';
+
+type Grid = Vec<Vec<char>>;
+
+/// Where the cursor is on the screen, row and column counted from 0 at the top left
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Position {
+    pub row: u16,
+    pub col: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    // ESC followed by an intermediate, e.g. the charset designation ESC ( B
+    EscapeIntermediate,
+    Csi,
+    // OSC, DCS, APC, PM and SOS, skipped until ST or BEL
+    String,
+    StringEscape,
+}
+
+/// The rows of a screen that changed since the last diff, with the size of the screen and
+/// where the cursor is, a diff after a change of size has every row, see ScreenMirror
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScreenDiff {
+    pub rows: u16,
+    pub cols: u16,
+    pub cursor: Position,
+    /// the changed rows and their text, trailing blanks trimmed
+    pub lines: Vec<(u16, String)>,
+}
+
+/// A copy of a screen kept up to date by diffs, the sending side takes diffs from it, the
+/// receiving side applies them to its own, both copies then hold the same screen
+/// ```rust
+/// use pty_exec::vt::{Position, ScreenMirror};
+///
+/// let (mut sent, mut received) = (ScreenMirror::new(), ScreenMirror::new());
+/// let lines = vec!["$ top".to_owned(), String::new()];
+/// let diff = sent.diff(2, 80, &lines, Position { row: 1, col: 0 }).unwrap();
+/// received.apply(&diff);
+/// assert_eq!(received.lines(), lines);
+/// // nothing changed
+/// assert!(sent.diff(2, 80, &lines, Position { row: 1, col: 0 }).is_none());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScreenMirror {
+    rows: u16,
+    cols: u16,
+    lines: Vec<String>,
+    cursor: Position,
+}
+
+impl ScreenMirror {
+    /// a copy of no screen, the first diff has every row
+    pub fn new() -> ScreenMirror {
+        ScreenMirror::default()
+    }
+
+    /// the diff bringing the copy to a screen of rows and cols showing lines with the cursor
+    /// at cursor, it is applied to the copy, `None` if nothing changed
+    pub fn diff(&mut self, rows: u16, cols: u16, lines: &[String], cursor: Position) -> Option<ScreenDiff> {
+        let resized = (rows, cols) != (self.rows, self.cols) || lines.len() != self.lines.len();
+        let changed: Vec<(u16, String)> = lines.iter().enumerate()
+            .filter(|&(row, line)| resized || self.lines[row] != *line)
+            .map(|(row, line)| (row as u16, line.clone()))
+            .collect();
+        if changed.is_empty() && cursor == self.cursor && !resized {
+            return None;
+        }
+        let diff = ScreenDiff { rows, cols, cursor, lines: changed };
+        self.apply(&diff);
+        Some(diff)
+    }
+
+    /// brings the copy up to date with diff, rows the screen no longer has are dropped
+    pub fn apply(&mut self, diff: &ScreenDiff) {
+        (self.rows, self.cols, self.cursor) = (diff.rows, diff.cols, diff.cursor);
+        self.lines.resize(diff.rows as usize, String::new());
+        for (row, line) in &diff.lines {
+            if let Some(copy) = self.lines.get_mut(*row as usize) {
+                copy.clone_from(line);
+            }
+        }
+    }
+
+    /// the rows of the screen, trailing blanks trimmed
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn cursor(&self) -> Position {
+        self.cursor
+    }
+
+    /// rows and cols of the screen
+    pub fn size(&self) -> (u16, u16) {
+        (self.rows, self.cols)
+    }
+}
+
+/**
+ * The screen of a pty as output drew it
+ */
+pub(crate) struct Screen {
+    rows: u16,
+    cols: u16,
+    grid: Grid,
+    // the primary screen while the alternate one is shown
+    primary: Option<Grid>,
+    row: u16,
+    col: u16,
+    // a character was written to the last column, the next one goes to the next line
+    pending_wrap: bool,
+    autowrap: bool,
+    saved: (u16, u16),
+    // scrolling region, first and last row
+    top: u16,
+    bottom: u16,
+    state: State,
+    params: String,
+}
+
+impl Screen {
+    pub(crate) fn new(size: WindowSize) -> Screen {
+        let (rows, cols) = dimensions(size);
+        Screen {
+            rows,
+            cols,
+            grid: blank(rows, cols),
+            primary: None,
+            row: 0,
+            col: 0,
+            pending_wrap: false,
+            autowrap: true,
+            saved: (0, 0),
+            top: 0,
+            bottom: rows - 1,
+            state: State::Ground,
+            params: String::new(),
+        }
+    }
+
+    /**
+     * The visible lines, trailing blanks trimmed
+     */
+    pub(crate) fn text(&self) -> Vec<String> {
+        self.grid.iter().map(|row| {
+            let line: String = row.iter().filter(|&&c| c != WIDE_TAIL).collect();
+            line.trim_end().to_owned()
+        }).collect()
+    }
+
+    pub(crate) fn cursor(&self) -> Position {
+        Position { row: self.row, col: self.col }
+    }
+
+    /**
+     * What changed on screen since mirror was brought up to date
+     */
+    pub(crate) fn diff(&self, mirror: &mut ScreenMirror) -> Option<ScreenDiff> {
+        mirror.diff(self.rows, self.cols, &self.text(), self.cursor())
+    }
+
+    /**
+     * Rows and columns modelled
+     */
+    pub(crate) fn size(&self) -> (u16, u16) {
+        (self.rows, self.cols)
+    }
+
+    /**
+     * Keeps what is on screen at the new size, rows above the cursor go first when it shrinks
+     */
+    pub(crate) fn resize(&mut self, size: WindowSize) {
+        let (rows, cols) = dimensions(size);
+        if (rows, cols) == (self.rows, self.cols) {
+            return;
+        }
+        let cut = (self.row + 1).saturating_sub(rows) as usize;
+        self.grid.drain(..cut);
+        self.row -= cut as u16;
+        for grid in [Some(&mut self.grid), self.primary.as_mut()].into_iter().flatten() {
+            grid.resize(rows as usize, vec![' '; cols as usize]);
+            for row in grid.iter_mut() {
+                row.resize(cols as usize, ' ');
+            }
+        }
+        (self.rows, self.cols) = (rows, cols);
+        self.row = self.row.min(rows - 1);
+        self.col = self.col.min(cols - 1);
+        self.pending_wrap = false;
+        (self.top, self.bottom) = (0, rows - 1);
+    }
+
+    /**
+     * Applies output to the screen, sequences split across calls are picked up where they
+     * were cut
+     */
+    pub(crate) fn feed(&mut self, output: &str) {
+        for c in output.chars() {
+            self.state = match (self.state, c) {
+                (State::Ground, '\x1b') => State::Escape,
+                (State::Ground, c) => {
+                    self.ground(c);
+                    State::Ground
+                },
+                (State::Escape, '[') => {
+                    self.params.clear();
+                    State::Csi
+                },
+                (State::Escape, ']' | 'P' | '_' | '^' | 'X') => State::String,
+                (State::Escape, '\x20'..='\x2f') => State::EscapeIntermediate,
+                (State::Escape, c) => {
+                    self.escape(c);
+                    State::Ground
+                },
+                (State::EscapeIntermediate, _) => State::Ground,
+                (State::Csi, '\x40'..='\x7e') => {
+                    self.csi(c);
+                    State::Ground
+                },
+                (State::Csi, '\x1b') => State::Escape,
+                (State::Csi, c) => {
+                    if self.params.len() < MAX_PARAMS_LEN {
+                        self.params.push(c);
+                    }
+                    State::Csi
+                },
+                (State::String, '\x07') => State::Ground,
+                (State::String, '\x1b') => State::StringEscape,
+                (State::String, _) => State::String,
+                (State::StringEscape, _) => State::Ground,
+            };
+        }
+    }
+
+    fn ground(&mut self, c: char) {
+        match c {
+            '\r' => self.move_to(self.row, 0),
+            '\n' | '\x0b' | '\x0c' => self.index(),
+            '\x08' => self.move_to(self.row, self.col.saturating_sub(1)),
+            '\t' => self.move_to(self.row, ((self.col / 8 + 1) * 8).min(self.cols - 1)),
+            c if c.is_control() => {},
+            c => self.print(c),
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        // combining characters are not modelled
+        let width = match c.width() {
+            Some(width) if width > 0 => width as u16,
+            _ => return,
+        };
+        if self.pending_wrap || (width > 1 && self.col + width > self.cols && self.autowrap) {
+            self.move_to(self.row, 0);
+            self.index();
+        }
+        let (row, col) = (self.row as usize, self.col as usize);
+        self.grid[row][col] = c;
+        if width > 1 && col + 1 < self.cols as usize {
+            self.grid[row][col + 1] = WIDE_TAIL;
+        }
+        if self.col + width < self.cols {
+            self.col += width;
+        } else {
+            self.col = self.cols - 1;
+            self.pending_wrap = self.autowrap;
+        }
+    }
+
+    fn escape(&mut self, c: char) {
+        match c {
+            'D' => self.index(),
+            'E' => {
+                self.move_to(self.row, 0);
+                self.index();
+            },
+            'M' => self.reverse_index(),
+            '7' => self.saved = (self.row, self.col),
+            '8' => self.move_to(self.saved.0, self.saved.1),
+            'c' => *self = Screen::new(WindowSize::new(self.rows, self.cols, 0, 0)),
+            _ => {},
+        }
+    }
+
+    fn csi(&mut self, c: char) {
+        let private = self.params.starts_with('?');
+        let params: Vec<u16> = self.params.trim_start_matches(['?', '>', '<', '=']).split(';')
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        let param = |i: usize| params.get(i).copied().unwrap_or(0);
+        // counts and positions of 0 mean 1
+        let n = param(0).max(1);
+        let (row, col) = (self.row, self.col);
+
+        match (private, c) {
+            (false, 'A') => self.move_to(row.saturating_sub(n), col),
+            (false, 'B' | 'e') => self.move_to(row.saturating_add(n), col),
+            (false, 'C' | 'a') => self.move_to(row, col.saturating_add(n)),
+            (false, 'D') => self.move_to(row, col.saturating_sub(n)),
+            (false, 'E') => self.move_to(row.saturating_add(n), 0),
+            (false, 'F') => self.move_to(row.saturating_sub(n), 0),
+            (false, 'G' | '`') => self.move_to(row, n - 1),
+            (false, 'd') => self.move_to(n - 1, col),
+            (false, 'H' | 'f') => self.move_to(n - 1, param(1).max(1) - 1),
+            (false, 'J') => match param(0) {
+                0 => {
+                    self.erase(row, col, self.cols);
+                    self.clear_rows(row + 1, self.rows);
+                },
+                1 => {
+                    self.clear_rows(0, row);
+                    self.erase(row, 0, col + 1);
+                },
+                _ => self.clear_rows(0, self.rows),
+            },
+            (false, 'K') => match param(0) {
+                0 => self.erase(row, col, self.cols),
+                1 => self.erase(row, 0, col + 1),
+                _ => self.erase(row, 0, self.cols),
+            },
+            (false, 'X') => self.erase(row, col, col.saturating_add(n)),
+            (false, 'P') => {
+                let line = &mut self.grid[row as usize];
+                let n = (n as usize).min(line.len() - col as usize);
+                line.drain(col as usize..col as usize + n);
+                line.resize(self.cols as usize, ' ');
+            },
+            (false, '@') => {
+                let line = &mut self.grid[row as usize];
+                let n = (n as usize).min(line.len() - col as usize);
+                line.splice(col as usize..col as usize, std::iter::repeat_n(' ', n));
+                line.truncate(self.cols as usize);
+            },
+            (false, 'L') if (self.top..=self.bottom).contains(&row) => self.scroll_down(row, n),
+            (false, 'M') if (self.top..=self.bottom).contains(&row) => self.scroll_up(row, n),
+            (false, 'S') => self.scroll_up(self.top, n),
+            (false, 'T') => self.scroll_down(self.top, n),
+            (false, 'r') => {
+                let bottom = match param(1) {
+                    0 => self.rows,
+                    bottom => bottom.min(self.rows),
+                };
+                if n < bottom {
+                    (self.top, self.bottom) = (n - 1, bottom - 1);
+                    self.move_to(0, 0);
+                }
+            },
+            (false, 's') => self.saved = (row, col),
+            (false, 'u') => self.move_to(self.saved.0, self.saved.1),
+            (true, 'h' | 'l') => {
+                let set = c == 'h';
+                for mode in params {
+                    match mode {
+                        7 => self.autowrap = set,
+                        47 | 1047 => self.alternate(set),
+                        1049 => {
+                            if set {
+                                self.saved = (self.row, self.col);
+                                self.alternate(true);
+                            } else {
+                                self.alternate(false);
+                                self.move_to(self.saved.0, self.saved.1);
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /**
+     * Moves the cursor, kept on screen
+     */
+    fn move_to(&mut self, row: u16, col: u16) {
+        self.row = row.min(self.rows - 1);
+        self.col = col.min(self.cols - 1);
+        self.pending_wrap = false;
+    }
+
+    /**
+     * Moves the cursor down, scrolling the region at its bottom
+     */
+    fn index(&mut self) {
+        self.pending_wrap = false;
+        if self.row == self.bottom {
+            self.scroll_up(self.top, 1);
+        } else if self.row < self.rows - 1 {
+            self.row += 1;
+        }
+    }
+
+    /**
+     * Moves the cursor up, scrolling the region at its top
+     */
+    fn reverse_index(&mut self) {
+        self.pending_wrap = false;
+        if self.row == self.top {
+            self.scroll_down(self.top, 1);
+        } else {
+            self.row = self.row.saturating_sub(1);
+        }
+    }
+
+    /**
+     * Scrolls the rows from `from` to the bottom of the region up by n, blank rows come in
+     */
+    fn scroll_up(&mut self, from: u16, n: u16) {
+        let (from, bottom) = (from as usize, self.bottom as usize + 1);
+        let n = (n as usize).min(bottom - from);
+        self.grid[from..bottom].rotate_left(n);
+        self.clear_rows((bottom - n) as u16, bottom as u16);
+    }
+
+    /**
+     * Scrolls the rows from `from` to the bottom of the region down by n, blank rows come in
+     */
+    fn scroll_down(&mut self, from: u16, n: u16) {
+        let (from, bottom) = (from as usize, self.bottom as usize + 1);
+        let n = (n as usize).min(bottom - from);
+        self.grid[from..bottom].rotate_right(n);
+        self.clear_rows(from as u16, (from + n) as u16);
+    }
+
+    fn erase(&mut self, row: u16, from: u16, to: u16) {
+        let line = &mut self.grid[row as usize];
+        let to = (to as usize).min(line.len());
+        line[(from as usize).min(to)..to].fill(' ');
+    }
+
+    fn clear_rows(&mut self, from: u16, to: u16) {
+        for row in from..to.min(self.rows) {
+            self.erase(row, 0, self.cols);
+        }
+    }
+
+    /**
+     * Shows the alternate screen, blank, or the primary screen again
+     */
+    fn alternate(&mut self, on: bool) {
+        match (on, self.primary.take()) {
+            (true, None) => self.primary = Some(mem::replace(&mut self.grid, blank(self.rows, self.cols))),
+            (true, primary) => self.primary = primary,
+            (false, Some(primary)) => self.grid = primary,
+            (false, None) => {},
+        }
+    }
+}
+
+fn dimensions(size: WindowSize) -> (u16, u16) {
+    match (size.rows(), size.cols()) {
+        (0, _) | (_, 0) => (DEFAULT_ROWS, DEFAULT_COLS),
+        size => size,
+    }
+}
+
+fn blank(rows: u16, cols: u16) -> Grid {
+    vec![vec![' '; cols as usize]; rows as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drawing() {
+        let mut screen = Screen::new(WindowSize::new(4, 10, 0, 0));
+        screen.feed("$ ls\r\na  b\r\n$ ");
+        assert_eq!(screen.text(), ["$ ls", "a  b", "$", ""]);
+        assert_eq!(screen.cursor(), Position { row: 2, col: 2 });
+
+        // wrapping and scrolling at the bottom
+        screen.feed("0123456789ab\r\nlast");
+        assert_eq!(screen.text(), ["a  b", "$ 01234567", "89ab", "last"]);
+        assert_eq!(screen.cursor(), Position { row: 3, col: 4 });
+        // the cursor stays in the last column until the next character wraps
+        screen.feed("\x1b[1;9Hxy");
+        assert_eq!(screen.cursor(), Position { row: 0, col: 9 });
+
+        // a sequence split across reads, positioning, erasing and wide characters
+        screen.feed("\x1b[2;");
+        screen.feed("3H\x1b[K界x\x1b[1;1H\x1b[2@>");
+        assert_eq!(screen.text(), ["> a  b", "$ 界x", "89ab", "last"]);
+
+        // the alternate screen leaves the primary one as it was
+        screen.feed("\x1b[?1049h\x1b[Hhtop\x1b]0;title\x07");
+        assert_eq!(screen.text(), ["htop", "", "", ""]);
+        screen.feed("\x1b[?1049l");
+        assert_eq!(screen.text(), ["> a  b", "$ 界x", "89ab", "last"]);
+
+        // a scrolling region keeps the rows outside of it
+        screen.feed("\x1b[2;3r\x1b[3;1H\n\x1b[r");
+        assert_eq!(screen.text(), ["> a  b", "89ab", "", "last"]);
+
+        // rows above the cursor give way when the screen shrinks
+        screen.feed("\x1b[4;1H");
+        screen.resize(WindowSize::new(2, 4, 0, 0));
+        assert_eq!(screen.text(), ["", "last"]);
+        assert_eq!((screen.size(), screen.cursor()), ((2, 4), Position { row: 1, col: 0 }));
+    }
+
+    #[test]
+    fn mirroring() {
+        let mut screen = Screen::new(WindowSize::new(3, 10, 0, 0));
+        let (mut sent, mut received) = (ScreenMirror::new(), ScreenMirror::new());
+        screen.feed("$ ls\r\na");
+        // every row at first
+        let diff = screen.diff(&mut sent).unwrap();
+        assert_eq!(diff.lines.len(), 3);
+        received.apply(&diff);
+
+        screen.feed("\x1b[1;3Htop");
+        let diff = screen.diff(&mut sent).unwrap();
+        assert_eq!(diff.lines, [(0, "$ top".to_owned())]);
+        received.apply(&diff);
+        assert_eq!(received.lines(), ["$ top", "a", ""]);
+        assert_eq!(received.cursor(), Position { row: 0, col: 5 });
+        assert!(screen.diff(&mut sent).is_none());
+
+        // a move of the cursor alone has no rows
+        screen.feed("\x1b[3;1H");
+        assert!(screen.diff(&mut sent).unwrap().lines.is_empty());
+
+        screen.resize(WindowSize::new(2, 10, 0, 0));
+        let diff = screen.diff(&mut sent).unwrap();
+        received.apply(&diff);
+        assert_eq!((received.size(), received.lines()), ((2, 10), screen.text().as_slice()));
+    }
+}
@@ -0,0 +1,157 @@
+//! Framing of the attach protocol for browser frontends, exported with wasm-bindgen so a
+//! client in the browser, e.g. one driving xterm.js, speaks exactly the frames of
+//! protocol::Frame, frames cross into JavaScript as the JSON of their serde form, typed by
+//! the exported Frame TypeScript type
+//! ```js
+//! import { encodeFrame, FrameReader } from "pty-exec";
+//!
+//! const reader = new FrameReader();
+//! socket.onmessage = (msg) => {
+//!     reader.push(new Uint8Array(msg.data));
+//!     for (let frame; (frame = reader.nextFrame()) !== undefined;) {
+//!         const parsed = JSON.parse(frame);
+//!         if (parsed.Output !== undefined) term.write(parsed.Output);
+//!     }
+//! };
+//! socket.send(encodeFrame(JSON.stringify({ Attach: { session: "main" } })));
+//! ```
+
+use wasm_bindgen::prelude::*;
+use crate::protocol::{read_frame, write_frame, Frame, MAX_PAYLOAD};
+
+/** the typescript type of a frame in JSON, checked against `Frame` in the tests */
+// the custom section is only emitted on wasm32
+#[cfg_attr(not(any(test, target_arch = "wasm32")), allow(unused_macros))]
+macro_rules! frame_ts {
+    () => { r#"
+export type Frame =
+    | { Auth: string }
+    | { Attach: { session: string } }
+    | { Resume: { session: string, cursor: number } }
+    | { Attached: { session: string, cursor: number, missed: number } }
+    | { Input: string }
+    | { PredictedInput: { seq: number, input: string } }
+    | { Echo: { seq: number, echoed: boolean } }
+    | { Output: string }
+    | { Resize: { rows: number, cols: number, cell_width: number, cell_height: number } }
+    | { Resized: [number, number] }
+    | { Signal: number }
+    | "Detach"
+    | "Exited"
+    | "Ping"
+    | "Pong"
+    | { Error: string }
+    | { Compress: ("Deflate" | "Zstd")[] }
+    | { Compressed: number[] }
+    | { Message: "ClipboardRequest" | { Clipboard: string } | { OpenUrl: string } | { Custom: { kind: string, data: number[] } } }
+    // with the vt feature
+    | "ScreenDiffs"
+    | { Screen: { rows: number, cols: number, cursor: { row: number, col: number }, lines: [number, string][] } };
+"# };
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const FRAME_TS: &str = frame_ts!();
+
+/// bytes to send for the JSON of a frame, e.g. `{"Input":"ls\r"}`
+#[wasm_bindgen(js_name = encodeFrame)]
+pub fn encode_frame(json: &str) -> Result<Vec<u8>, String> {
+    let frame: Frame = serde_json::from_str(json).map_err(|err| format!("Invalid frame: {err}"))?;
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &frame).map_err(|err| err.to_string())?;
+    Ok(buf)
+}
+
+/// Splits the bytes received from a server into frames, whatever chunks they arrive in
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl FrameReader {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> FrameReader {
+        FrameReader::default()
+    }
+
+    /// adds bytes received from the server
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// JSON of the next frame received whole, undefined until there is one
+    #[wasm_bindgen(js_name = nextFrame)]
+    pub fn next_frame(&mut self) -> Result<Option<String>, String> {
+        let Some(header) = self.buf.get(..5) else { return Ok(None) };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_PAYLOAD {
+            return Err("Frame too large".to_owned());
+        }
+        if self.buf.len() < 5 + len {
+            return Ok(None);
+        }
+
+        let frame = read_frame(&mut &self.buf[..5 + len]).map_err(|err| err.to_string());
+        self.buf.drain(..5 + len);
+        match frame? {
+            Some(frame) => serde_json::to_string(&frame).map(Some).map_err(|err| err.to_string()),
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_as_json() -> Result<(), String> {
+        let mut bytes = encode_frame(r#"{"Attached":{"session":"main","cursor":3,"missed":0}}"#)?;
+        bytes.extend(encode_frame(r#"{"Output":"hi\r\n"}"#)?);
+        bytes.extend(encode_frame(r#""Exited""#)?);
+
+        // byte by byte, no frame is seen before it is whole
+        let mut reader = FrameReader::new();
+        let mut frames = Vec::new();
+        for byte in bytes {
+            reader.push(&[byte]);
+            frames.extend(reader.next_frame()?);
+        }
+        assert_eq!(frames, [r#"{"Attached":{"session":"main","cursor":3,"missed":0}}"#, r#"{"Output":"hi\r\n"}"#, r#""Exited""#]);
+
+        assert!(encode_frame(r#"{"Launch":"rockets"}"#).is_err());
+        reader.push(&[4, 0xff, 0xff, 0xff, 0xff]);
+        assert!(reader.next_frame().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn frame_ts_has_every_frame() {
+        // a frame of every type the protocol reads, a type added later is checked as well
+        let payloads = [vec![], vec![0; 4], vec![0; 8], vec![0; 9], vec![0; 16], vec![1]];
+        let mut types = 0;
+        for code in 0..=u8::MAX {
+            let frame = payloads.iter().find_map(|payload| {
+                let bytes = [&[code][..], &(payload.len() as u32).to_be_bytes(), payload].concat();
+                read_frame(&mut bytes.as_slice()).ok().flatten()
+            });
+            let Some(frame) = frame else { continue };
+            types += 1;
+            match serde_json::to_value(&frame).unwrap() {
+                serde_json::Value::String(tag) => assert!(frame_ts!().contains(&format!("| \"{tag}\"")), "{tag} is not in FRAME_TS"),
+                serde_json::Value::Object(object) => {
+                    let (tag, value) = object.iter().next().unwrap();
+                    let line = frame_ts!().lines().find(|line| line.contains(&format!("| {{ {tag}: ")));
+                    let line = line.unwrap_or_else(|| panic!("{tag} is not in FRAME_TS"));
+                    for field in value.as_object().into_iter().flat_map(|fields| fields.keys()) {
+                        assert!(line.contains(&format!("{field}: ")), "{field} of {tag} is not in FRAME_TS");
+                    }
+                },
+                value => panic!("unexpected JSON of a frame {value}"),
+            }
+        }
+        assert!(types >= 19);
+    }
+}
@@ -0,0 +1,142 @@
+//! Keeping an eye on the threads polling ptys
+//! a polling thread that panics outside a callback is restarted on the same pty a few times,
+//! one whose poll fails gives up, both are reported through PtyHandler::on_reader_failure(),
+//! a thread stuck in a callback that never returns cannot report anything itself, watch_stalls()
+//! reports those from a thread of its own
+//! ```rust
+//! use std::time::Duration;
+//!
+//! pty_exec::watchdog::watch_stalls(Duration::from_secs(30), |id, stalled| {
+//!     eprintln!("{id} has not read output for {stalled:?}");
+//! });
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::error::PtyError;
+use crate::id::PtyId;
+use crate::registry;
+
+/// How the thread polling a pty failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReaderFailure {
+    /// it panicked outside the callbacks, with this message, panics of callbacks go to on_error
+    Panicked(String),
+    /// polling the pty failed
+    PollFailed(PtyError),
+}
+
+impl fmt::Display for ReaderFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReaderFailure::Panicked(msg) => write!(f, "Polling thread panicked: {msg}"),
+            ReaderFailure::PollFailed(err) => write!(f, "Polling thread failed: {}", err.message()),
+        }
+    }
+}
+
+impl Error for ReaderFailure {}
+
+/// Function called by the watchdog for a stalled pty, see watch_stalls()
+pub type StallCallback = Arc<dyn Fn(PtyId, Duration) + Send + Sync>;
+
+struct Watch {
+    timeout: Duration,
+    on_stall: StallCallback,
+}
+
+static WATCH: Mutex<Option<Watch>> = Mutex::new(None);
+static CHANGED: Condvar = Condvar::new();
+
+/// Reports every pty whose polling thread has been busy with a batch of output for longer
+/// than timeout, once per stall, on_stall runs on the watchdog thread, started on first use,
+/// calling it again replaces the timeout and callback
+pub fn watch_stalls<F>(timeout: Duration, on_stall: F)
+    where F: Fn(PtyId, Duration) + Send + Sync + 'static
+{
+    let mut watch = WATCH.lock().unwrap();
+    if watch.is_none() {
+        let _ = thread::Builder::new().name("pty-exec/watchdog".into()).spawn(run);
+    }
+    *watch = Some(Watch { timeout, on_stall: Arc::new(on_stall) });
+    CHANGED.notify_all();
+}
+
+/// Stops reporting stalls, the watchdog thread ends
+pub fn unwatch_stalls() {
+    WATCH.lock().unwrap().take();
+    CHANGED.notify_all();
+}
+
+fn run() {
+    // start of the busy period each pty was last reported for
+    let mut reported: HashMap<PtyId, Instant> = HashMap::new();
+    let mut watch = WATCH.lock().unwrap();
+
+    while let Some(Watch { timeout, on_stall }) = watch.as_ref() {
+        let (timeout, on_stall) = (*timeout, on_stall.clone());
+        drop(watch);
+
+        let mut stalled = Vec::new();
+        let sessions = registry::sessions();
+        for session in &sessions {
+            let Some(since) = session.busy_since() else { continue };
+            let busy = session.config().clock.elapsed(since);
+            if busy >= timeout && reported.get(&session.id()) != Some(&since) {
+                reported.insert(session.id(), since);
+                stalled.push((session.id(), busy));
+            }
+        }
+        reported.retain(|id, _| sessions.iter().any(|session| session.id() == *id));
+        for (id, busy) in stalled {
+            on_stall(id, busy);
+        }
+
+        let interval = (timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        watch = CHANGED.wait_timeout(WATCH.lock().unwrap(), interval).unwrap().0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use crate::{Pty, PtyHandler};
+
+    struct Slow(bool);
+
+    impl PtyHandler for Slow {
+        fn on_output(&mut self, _id: PtyId, output: String) {
+            if output.contains("stall-2") && !self.0 {
+                self.0 = true;
+                thread::sleep(Duration::from_millis(500));
+            }
+        }
+    }
+
+    #[test]
+    fn stalls() -> Result<(), Box<dyn Error>> {
+        let pty = Pty::spawn_handler(Slow(false))?;
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let id = pty.id();
+        watch_stalls(Duration::from_millis(100), move |stalled, busy| {
+            if stalled == id {
+                let _ = tx.lock().unwrap().send(busy);
+            }
+        });
+
+        pty.write("echo \"stall-$((1 + 1))\"\r")?;
+        let busy = rx.recv_timeout(Duration::from_secs(10))?;
+        assert!(busy >= Duration::from_millis(100));
+        // once per stall
+        assert!(rx.recv_timeout(Duration::from_millis(600)).is_err());
+        unwatch_stalls();
+        pty.shutdown()?;
+        Ok(())
+    }
+}